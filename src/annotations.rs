@@ -0,0 +1,61 @@
+//! The `annotations` file, where a task can note anything about itself worth keeping,
+//! like the version of a model it used, or how many rows it processed.
+//!
+//! Each line is a `key=value` pair. Lines without an `=` are ignored, and a key that
+//! appears more than once keeps its last value.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// The name of the file in each task's destination directory that holds its annotations.
+pub(crate) const ANNOTATIONS_FILE: &str = "annotations";
+
+/// Where the task with destination directory `task_dir` writes its annotations.
+pub(crate) fn path(task_dir: &Path) -> PathBuf {
+    task_dir.join(ANNOTATIONS_FILE)
+}
+
+/// Read the annotations in a task's destination directory, if there are any.
+pub(crate) async fn read(task_dir: &Path) -> io::Result<BTreeMap<String, String>> {
+    match fs::read(path(task_dir)).await {
+        Ok(contents) => Ok(parse(&String::from_utf8_lossy(&contents))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Remove any annotations left in a task's destination directory by an earlier attempt.
+pub(crate) async fn clear(task_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(path(task_dir)).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn parse(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.into(), value.into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_annotations() {
+        let annotations = parse("model=v2\nrows=10\n\nnot an annotation\nrows=12\nquery=a=b");
+        let expected: BTreeMap<String, String> = vec![
+            ("model".into(), "v2".into()),
+            ("rows".into(), "12".into()),
+            ("query".into(), "a=b".into()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected, annotations);
+    }
+}
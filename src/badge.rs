@@ -0,0 +1,165 @@
+//! Badges that show how a run went, for dashboards to embed.
+
+use serde_json::json;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+
+use crate::Summary;
+
+impl Summary {
+    /// Write a badge showing the pass rate and duration of this run to `path`.
+    ///
+    /// If `path` ends in `.json`, the badge is a shields.io endpoint description,
+    /// and otherwise it's an SVG image. The badge is replaced atomically, so a
+    /// dashboard never sees a half-written one.
+    pub async fn write_badge(&self, path: &Path) -> io::Result<()> {
+        let message = badge_message(self);
+        let color = badge_color(self);
+        let contents = if path.extension() == Some(OsStr::new("json")) {
+            let badge = json!({
+                "schemaVersion": 1,
+                "label": LABEL,
+                "message": message,
+                "color": color,
+            });
+            format!("{}\n", badge)
+        } else {
+            badge_svg(LABEL, &message, color)
+        };
+        let mut temp_name = OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        fs::write(&temp_path, contents).await?;
+        fs::rename(&temp_path, path).await
+    }
+}
+
+const LABEL: &str = "reach";
+
+/// Like `97% passed in 1h 5m`.
+fn badge_message(summary: &Summary) -> String {
+    if summary.run() == 0 {
+        return format!("nothing to do in {}", format_duration(summary.duration));
+    }
+    // Round down, so a run with any failures never shows as 100%.
+    let percent = summary.succeeded * 100 / summary.run();
+    format!(
+        "{}% passed in {}",
+        percent,
+        format_duration(summary.duration)
+    )
+}
+
+fn badge_color(summary: &Summary) -> &'static str {
+    if summary.all_succeeded() {
+        "brightgreen"
+    } else if summary.all_failed() {
+        "red"
+    } else {
+        "orange"
+    }
+}
+
+/// A short, human duration, to the nearest second, showing at most two units.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// A flat badge with `label` on the left and `message` on a `color` background on the right.
+fn badge_svg(label: &str, message: &str, color: &str) -> String {
+    let fill = match color {
+        "brightgreen" => "#4c1",
+        "orange" => "#fe7d37",
+        _ => "#e05d44",
+    };
+    // Verdana at 11px averages about 7px a character. Close enough for a badge.
+    let text_width = |text: &str| text.chars().count() * 7 + 10;
+    let (label_width, message_width) = (text_width(label), text_width(message));
+    let width = label_width + message_width;
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}">
+  <title>{label}: {message}</title>
+  <rect width="{label_width}" height="20" fill="#555"/>
+  <rect x="{label_width}" width="{message_width}" height="20" fill="{fill}"/>
+  <g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+    <text x="{label_x}" y="14">{label}</text>
+    <text x="{message_x}" y="14">{message}</text>
+  </g>
+</svg>
+"##,
+        width = width,
+        label_width = label_width,
+        message_width = message_width,
+        fill = fill,
+        label = label,
+        message = message,
+        label_x = label_width / 2,
+        message_x = label_width + message_width / 2,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(succeeded: usize, failed: usize, seconds: u64) -> Summary {
+        Summary {
+            succeeded,
+            failed,
+            duration: Duration::from_secs(seconds),
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn test_badge_message() {
+        assert_eq!("100% passed in 5s", badge_message(&summary(3, 0, 5)));
+        assert_eq!("99% passed in 2m 5s", badge_message(&summary(199, 1, 125)));
+        assert_eq!("0% passed in 1h 1m", badge_message(&summary(0, 4, 3700)));
+        assert_eq!("nothing to do in 0s", badge_message(&summary(0, 0, 0)));
+    }
+
+    #[test]
+    fn test_badge_color() {
+        assert_eq!("brightgreen", badge_color(&summary(3, 0, 5)));
+        assert_eq!("orange", badge_color(&summary(3, 1, 5)));
+        assert_eq!("red", badge_color(&summary(0, 1, 5)));
+    }
+
+    #[tokio::test]
+    async fn test_write_badge() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let summary = summary(1, 1, 90);
+
+        let json_path = dir.path().join("badge.json");
+        summary.write_badge(&json_path).await?;
+        let badge: serde_json::Value = serde_json::from_slice(&fs::read(&json_path).await?)?;
+        assert_eq!("50% passed in 1m 30s", badge["message"]);
+        assert_eq!("orange", badge["color"]);
+
+        let svg_path = dir.path().join("badge.svg");
+        summary.write_badge(&svg_path).await?;
+        let svg = fs::read_to_string(&svg_path).await?;
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("50% passed in 1m 30s"));
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort();
+        assert_eq!(vec!["badge.json", "badge.svg"], names);
+        Ok(())
+    }
+}
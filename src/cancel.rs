@@ -0,0 +1,180 @@
+//! Stopping a run from elsewhere in a program that uses reach as a library, and telling
+//! tasks why their commands are being stopped.
+
+use futures::future;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::watch;
+
+/// A handle for cancelling runs, which can be cloned and handed to whatever should be able
+/// to stop them, like another task, a thread, or a signal handler.
+///
+/// Give `cancelled()` to `run_until`. Once `cancel` is called, no new tasks start,
+/// running commands are terminated, their tasks are marked as interrupted in their `status`
+/// files and the run's journal, and `run_until` returns `Error::Interrupted`.
+///
+/// ```no_run
+/// # async fn example(config: reach::Config) -> Result<(), reach::Error> {
+/// let token = reach::CancellationToken::new();
+/// let run = reach::run_until(config, (), token.cancelled());
+/// // Later, from anywhere holding a clone of `token`:
+/// token.cancel();
+/// # run.await.map(|_| ())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        CancellationToken {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Cancel every run given this token, or any clone of it, now or later.
+    pub fn cancel(&self) {
+        // Only fails if there are no receivers, but we always hold one.
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Completes once the token is cancelled, straight away if it already has been.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut receiver = self.receiver.clone();
+        async move {
+            while !*receiver.borrow() {
+                if receiver.changed().await.is_err() {
+                    // Every sender is gone, so nothing can cancel it any more.
+                    future::pending::<()>().await;
+                }
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Why reach terminated a task's command before it finished.
+///
+/// Just before the command is sent SIGTERM, the reason is written to the file named by
+/// `REACH_CANCEL_FILE`, so that a command that checks for it can save its work and exit
+/// cleanly in the grace period. It's noted in the task's `status` file as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CancelReason {
+    /// The command ran for longer than the timeout.
+    Timeout,
+    /// Enough tasks failed for `Halt::KillOnError` to halt the run.
+    Halt,
+    /// Tasks wrote more than `Config::max_total_output`, and `OutputPolicy::Kill` said to stop.
+    Budget,
+    /// The run was interrupted, like by Ctrl-C or a `CancellationToken`.
+    Shutdown,
+}
+
+impl CancelReason {
+    /// The name of the reason, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CancelReason::Timeout => "timeout",
+            CancelReason::Halt => "halt",
+            CancelReason::Budget => "budget",
+            CancelReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CancelReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "timeout" => Ok(CancelReason::Timeout),
+            "halt" => Ok(CancelReason::Halt),
+            "budget" => Ok(CancelReason::Budget),
+            "shutdown" => Ok(CancelReason::Shutdown),
+            _ => Err(format!("No such CancelReason: {}", s)),
+        }
+    }
+}
+
+/// The name of the file in each task's destination directory that says why its command
+/// is being terminated, once it is.
+pub(crate) const CANCEL_FILE: &str = "cancelled";
+
+pub(crate) fn path(task_dir: &Path) -> PathBuf {
+    task_dir.join(CANCEL_FILE)
+}
+
+/// Tell the commands of the task with destination directory `task_dir` that they're about
+/// to be terminated, and why.
+pub(crate) async fn announce(task_dir: &Path, reason: CancelReason) -> io::Result<()> {
+    fs::write(path(task_dir), format!("{}\n", reason)).await
+}
+
+/// Remove any reason left from an earlier attempt at a task.
+pub(crate) async fn clear(task_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(path(task_dir)).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let cancelled = token.cancelled();
+        assert!(!token.is_cancelled());
+        assert!(time::timeout(Duration::from_millis(10), token.cancelled())
+            .await
+            .is_err());
+
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        cancelled.await;
+        // Cancelling is for good, so later waits finish at once as well.
+        token.cancelled().await;
+    }
+
+    #[test]
+    fn test_cancel_reason_parse() {
+        for reason in &[
+            CancelReason::Timeout,
+            CancelReason::Halt,
+            CancelReason::Budget,
+            CancelReason::Shutdown,
+        ] {
+            assert_eq!(Ok(*reason), reason.name().parse());
+        }
+        assert!("boredom".parse::<CancelReason>().is_err());
+    }
+}
@@ -0,0 +1,558 @@
+//! Where each task's output goes: the files in its destination directory, and maybe the terminal.
+
+use async_trait::async_trait;
+use futures::future;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::annotations::ANNOTATIONS_FILE;
+use crate::cancel::CANCEL_FILE;
+use crate::error;
+use crate::input_hash::INPUT_HASH_FILE;
+use crate::landlock::{Restriction, Scratch};
+use crate::limits::{CpuClaim, Limits};
+use crate::status::STATUS_FILE;
+use crate::{Capture, Chunk, Message, Process, Pump, Refusal, RunAs};
+
+/// How a run captures its tasks' output, and the names of the files it goes to.
+#[derive(Debug, Clone)]
+pub(crate) struct Outputs {
+    capture: Capture,
+    stdout: String,
+    stderr: String,
+    /// The most of each output file to keep, if there's a limit.
+    max_size: Option<u64>,
+    /// Who commands run as, if not reach's own user.
+    run_as: Option<RunAs>,
+    /// The limits on the resources each command can use.
+    limits: Limits,
+    /// What each command is confined to, if it's confined.
+    restriction: Option<Restriction>,
+}
+
+impl Outputs {
+    /// Capture output as `capture` says, in files called `stdout` and `stderr`
+    /// in each task's destination directory.
+    pub(crate) fn new(capture: Capture, stdout: &str, stderr: &str) -> io::Result<Self> {
+        for name in &[stdout, stderr] {
+            if !is_plain_file_name(name) {
+                return Err(error::new(
+                    io::ErrorKind::InvalidInput,
+                    Message::OutputName(name),
+                ));
+            }
+        }
+        if stdout == stderr && capture.separates() {
+            return Err(error::refused(Refusal::SharedOutputFile));
+        }
+        Ok(Outputs {
+            capture,
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+            max_size: None,
+            run_as: None,
+            limits: Limits::default(),
+            restriction: None,
+        })
+    }
+
+    /// Keep no more than `max_size` bytes of each output file, if there's a limit,
+    /// keeping the beginning and the end of the output and clipping the middle.
+    pub(crate) fn clipped_to(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Spawn commands as `run_as`, if it's given, rather than as reach's own user.
+    pub(crate) fn running_as(mut self, run_as: Option<RunAs>) -> Self {
+        self.run_as = run_as;
+        self
+    }
+
+    pub(crate) fn run_as(&self) -> Option<RunAs> {
+        self.run_as
+    }
+
+    /// Spawn commands within `limits`.
+    pub(crate) fn limited_by(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub(crate) fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Confine commands as `restriction` says, if there is one.
+    pub(crate) fn restricted_by(mut self, restriction: Option<Restriction>) -> Self {
+        self.restriction = restriction;
+        self
+    }
+
+    pub(crate) fn capture(&self) -> Capture {
+        self.capture
+    }
+
+    pub(crate) fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// The file for the standard output of the task with destination directory `task_dir`,
+    /// or `None` if it's discarded.
+    pub(crate) fn stdout_path(&self, task_dir: &Path) -> Option<PathBuf> {
+        match self.capture {
+            Capture::Discard => None,
+            _ => Some(task_dir.join(&self.stdout)),
+        }
+    }
+
+    /// The file for the standard error of the task with destination directory `task_dir`,
+    /// or `None` if it goes wherever standard output goes.
+    pub(crate) fn stderr_path(&self, task_dir: &Path) -> Option<PathBuf> {
+        if self.capture.separates() {
+            Some(task_dir.join(&self.stderr))
+        } else {
+            None
+        }
+    }
+
+    /// Spawn `command` for the task named `name`, with `inputs` and destination directory
+    /// `task_dir`, running in `workdir` if it has one of its own, with its output going
+    /// where it should.
+    pub(crate) async fn spawn(
+        &self,
+        mut command: Command,
+        name: &OsStr,
+        inputs: &[PathBuf],
+        task_dir: &Path,
+        workdir: Option<&Path>,
+    ) -> io::Result<Captured> {
+        // So that a run that's dropped part way through doesn't leave commands running.
+        command.kill_on_drop(true);
+        crate::in_own_process_group(&mut command);
+        if let Some(run_as) = self.run_as {
+            run_as.apply(&mut command);
+        }
+        let cpus = self.limits.apply(&mut command);
+        // After the limits, which may need to join a cgroup that commands can't write to.
+        let (_ruleset, scratch) = match &self.restriction {
+            Some(restriction) => {
+                let scratch = Scratch::new(self.run_as)?;
+                let ruleset =
+                    restriction.apply(&mut command, inputs, task_dir, workdir, &scratch)?;
+                (Some(ruleset), Some(scratch))
+            }
+            None => (None, None),
+        };
+        let stdout = match self.stdout_path(task_dir) {
+            Some(path) => Some(create(&path).await?.into_std().await),
+            None => None,
+        };
+        let stderr = match self.stderr_path(task_dir) {
+            Some(path) => Some(create(&path).await?.into_std().await),
+            None => None,
+        };
+        // Output only needs to pass through reach to be tagged or clipped.
+        let stdout = match stdout {
+            Some(stdout) if self.capture == Capture::Tag || self.max_size.is_some() => stdout,
+            stdout => {
+                let (stdout, stderr) = match (stdout, stderr) {
+                    (Some(stdout), Some(stderr)) => (stdout.into(), stderr.into()),
+                    (Some(stdout), None) => (stdout.try_clone()?.into(), stdout.into()),
+                    _ => (Stdio::null(), Stdio::null()),
+                };
+                let child = command.stdout(stdout).stderr(stderr).spawn()?;
+                return Ok(Captured {
+                    #[cfg(windows)]
+                    job: crate::job::Job::for_child(&child).ok(),
+                    child,
+                    copies: Vec::new(),
+                    files: Vec::new(),
+                    clipped: Vec::new(),
+                    _cpus: cpus,
+                    _scratch: scratch,
+                });
+            }
+        };
+
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = Arc::new(Mutex::new(OutputFile::new(
+            stdout,
+            &self.stdout,
+            self.max_size,
+        )));
+        let mut files = vec![stdout.clone()];
+        // Merged output shares one file, and one limit.
+        let stderr = match stderr {
+            Some(stderr) => {
+                let stderr = Arc::new(Mutex::new(OutputFile::new(
+                    stderr,
+                    &self.stderr,
+                    self.max_size,
+                )));
+                files.push(stderr.clone());
+                stderr
+            }
+            None => stdout.clone(),
+        };
+        let tag = match self.capture {
+            Capture::Tag => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        };
+        let copies = vec![
+            tokio::spawn(copy_output(
+                child.stdout.take().expect("Stdout is piped"),
+                stdout,
+                tag.clone().map(|tag| (tag, Stream::Stdout)),
+            )),
+            tokio::spawn(copy_output(
+                child.stderr.take().expect("Stderr is piped"),
+                stderr,
+                tag.map(|tag| (tag, Stream::Stderr)),
+            )),
+        ];
+        Ok(Captured {
+            #[cfg(windows)]
+            job: crate::job::Job::for_child(&child).ok(),
+            child,
+            copies,
+            files,
+            clipped: Vec::new(),
+            _cpus: cpus,
+            _scratch: scratch,
+        })
+    }
+}
+
+/// Create the output file at `path`, failing the task, but not the run, if it can't be.
+pub(crate) async fn create(path: &Path) -> io::Result<fs::File> {
+    fs::File::create(path).await.map_err(error::in_output(path))
+}
+
+impl Capture {
+    /// Whether standard error goes to a file of its own.
+    fn separates(self) -> bool {
+        matches!(self, Capture::Separate | Capture::Tag)
+    }
+}
+
+/// A task's process, along with anything still copying its input or output.
+pub(crate) struct Captured {
+    child: Child,
+    copies: Vec<JoinHandle<io::Result<()>>>,
+    /// The output files that reach writes itself, rather than the command.
+    files: Vec<Arc<Mutex<OutputFile>>>,
+    /// The names of the output files that had to be clipped, once the command has finished.
+    clipped: Vec<String>,
+    /// The job that the command and everything it starts are in, if it could be put in one.
+    #[cfg(windows)]
+    job: Option<crate::job::Job>,
+    /// The CPUs the command has to itself, until it's dropped.
+    _cpus: Option<CpuClaim>,
+    /// The confined command's scratch directory, until it's dropped.
+    _scratch: Option<Scratch>,
+}
+
+impl Captured {
+    /// Send the contents of `inputs` to the command's standard input, one after another,
+    /// or only `chunk` of its one input, if its standard input is piped.
+    pub(crate) fn feed(&mut self, inputs: &[PathBuf], chunk: Option<Chunk>) {
+        let stdin = match self.child.stdin.take() {
+            Some(stdin) => stdin,
+            None => return,
+        };
+        let inputs = inputs.to_vec();
+        self.copies.push(tokio::spawn(async move {
+            let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(tokio::io::empty());
+            for input in inputs {
+                let input: Box<dyn AsyncRead + Unpin + Send> = match chunk {
+                    Some(chunk) => Box::new(chunk.open(&input).await?),
+                    None => Box::new(fs::File::open(input).await?),
+                };
+                reader = Box::new(reader.chain(input));
+            }
+            Pump::default().feed(&mut reader, stdin).await
+        }));
+    }
+
+    /// Finish writing the output files that reach writes itself, noting which were clipped.
+    async fn finish_files(&mut self) -> io::Result<()> {
+        for file in self.files.drain(..) {
+            let mut file = file.lock().await;
+            if file.finish().await? {
+                self.clipped.push(file.name.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait for the output of a command that's been stopped, in case something
+/// it started still has its output open.
+const COPY_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+#[async_trait]
+impl Process for Captured {
+    /// Wait for the command to exit, and for all of its output to be copied.
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait().await?;
+        for copy in self.copies.drain(..) {
+            copy.await.map_err(io::Error::other)??;
+        }
+        self.finish_files().await?;
+        Ok(status)
+    }
+
+    async fn terminate(&mut self) -> io::Result<()> {
+        // Killing the command on Windows would leave whatever it started running.
+        #[cfg(windows)]
+        match &self.job {
+            Some(job) => {
+                job.terminate()?;
+                self.child.wait().await?;
+            }
+            None => crate::terminate(&mut self.child).await?,
+        }
+        #[cfg(not(windows))]
+        crate::terminate(&mut self.child).await?;
+        let copies = future::join_all(self.copies.iter_mut());
+        if time::timeout(COPY_GRACE_PERIOD, copies).await.is_err() {
+            for copy in &self.copies {
+                copy.abort();
+            }
+        }
+        self.copies.clear();
+        self.finish_files().await
+    }
+
+    fn clipped(&self) -> Vec<String> {
+        self.clipped.clone()
+    }
+}
+
+/// An output file that, if it has a limit, keeps only the beginning and the end of
+/// everything written to it, so that a runaway command can't fill the disk.
+#[derive(Debug)]
+struct OutputFile {
+    file: fs::File,
+    name: String,
+    /// How much more can be written straight to the file.
+    head: u64,
+    /// The latest output after the head, which is written once the command has finished.
+    tail: VecDeque<u8>,
+    tail_size: usize,
+    /// How many bytes were dropped from between the head and the tail.
+    clipped: u64,
+}
+
+impl OutputFile {
+    fn new(file: std::fs::File, name: &str, max_size: Option<u64>) -> Self {
+        let (head, tail_size) = match max_size {
+            Some(max_size) => (max_size / 2, (max_size - max_size / 2) as usize),
+            None => (u64::MAX, 0),
+        };
+        OutputFile {
+            file: fs::File::from_std(file),
+            name: name.into(),
+            head,
+            tail: VecDeque::new(),
+            tail_size,
+            clipped: 0,
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let direct = data
+            .len()
+            .min(usize::try_from(self.head).unwrap_or(usize::MAX));
+        self.file.write_all(&data[..direct]).await?;
+        self.head -= direct as u64;
+        self.tail.extend(&data[direct..]);
+        let excess = self.tail.len().saturating_sub(self.tail_size);
+        self.tail.drain(..excess);
+        self.clipped += excess as u64;
+        Ok(())
+    }
+
+    /// Write out the tail, after a note of how much was clipped before it if anything was,
+    /// and say whether anything was.
+    async fn finish(&mut self) -> io::Result<bool> {
+        if self.clipped > 0 {
+            let note = format!("\n[... {} bytes clipped by reach ...]\n", self.clipped);
+            self.file.write_all(note.as_bytes()).await?;
+        }
+        let (front, back) = self.tail.as_slices();
+        self.file.write_all(front).await?;
+        self.file.write_all(back).await?;
+        self.tail.clear();
+        self.file.flush().await?;
+        Ok(self.clipped > 0)
+    }
+}
+
+/// One of reach's own output streams.
+#[derive(Debug, Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Copy everything from `reader` to `file`, and if there's a tag, each line of it to
+/// one of reach's own streams too, tagged with it.
+async fn copy_output(
+    reader: impl AsyncRead + Unpin,
+    file: Arc<Mutex<OutputFile>>,
+    echo: Option<(String, Stream)>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let (tag, stream) = match echo {
+        Some(echo) => echo,
+        None => {
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let read = reader.read(&mut buffer).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                file.lock().await.write(&buffer[..read]).await?;
+            }
+        }
+    };
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+        file.lock().await.write(&line).await?;
+        let tagged = tag_line(&tag, &line);
+        // In one write, so that lines from tasks running at the same time don't get mixed up.
+        match stream {
+            Stream::Stdout => io::stdout().lock().write_all(&tagged)?,
+            Stream::Stderr => io::stderr().lock().write_all(&tagged)?,
+        }
+    }
+}
+
+/// `line`, with `tag` and a tab in front of it, and ending in a newline even if it didn't.
+fn tag_line(tag: &str, line: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(tag.len() + line.len() + 2);
+    tagged.extend_from_slice(tag.as_bytes());
+    tagged.push(b'\t');
+    tagged.extend_from_slice(line);
+    if tagged.last() != Some(&b'\n') {
+        tagged.push(b'\n');
+    }
+    tagged
+}
+
+/// Whether `name` is a file name, not a path, and not the name of a file reach keeps itself.
+fn is_plain_file_name(name: &str) -> bool {
+    ![
+        "",
+        ".",
+        "..",
+        STATUS_FILE,
+        ANNOTATIONS_FILE,
+        INPUT_HASH_FILE,
+        CANCEL_FILE,
+    ]
+    .contains(&name)
+        && !name.contains('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_names() {
+        assert!(Outputs::new(Capture::Separate, "out", "err").is_ok());
+        assert!(Outputs::new(Capture::Separate, "log", "log").is_err());
+        assert!(Outputs::new(Capture::Merge, "log", "log").is_ok());
+        for name in &[
+            "",
+            ".",
+            "..",
+            "a/b",
+            "/out",
+            "out/",
+            STATUS_FILE,
+            ANNOTATIONS_FILE,
+            INPUT_HASH_FILE,
+            CANCEL_FILE,
+        ] {
+            assert!(
+                Outputs::new(Capture::Merge, name, "err").is_err(),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_output_paths() -> io::Result<()> {
+        let dest = Path::new("/dest");
+        let outputs = Outputs::new(Capture::Merge, "stdout.txt", "stderr.txt")?;
+        assert_eq!(Some(dest.join("stdout.txt")), outputs.stdout_path(dest));
+        assert_eq!(None, outputs.stderr_path(dest));
+        let outputs = Outputs::new(Capture::Discard, "out", "err")?;
+        assert_eq!(None, outputs.stdout_path(dest));
+        assert_eq!(None, outputs.stderr_path(dest));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_file_clipping() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        for (max_size, chunks, expected, clipped) in &[
+            (None, &["abc", "def"][..], "abcdef", false),
+            (Some(6), &["abc", "def"][..], "abcdef", false),
+            (
+                Some(4),
+                &["abc", "def", "g"][..],
+                "ab\n[... 3 bytes clipped by reach ...]\nfg",
+                true,
+            ),
+            (
+                Some(3),
+                &["abcdefg"][..],
+                "a\n[... 4 bytes clipped by reach ...]\nfg",
+                true,
+            ),
+        ] {
+            let mut file = OutputFile::new(std::fs::File::create(&path)?, "out", *max_size);
+            for chunk in *chunks {
+                file.write(chunk.as_bytes()).await?;
+            }
+            assert_eq!(*clipped, file.finish().await?, "{:?}", max_size);
+            assert_eq!(*expected, std::fs::read_to_string(&path)?, "{:?}", max_size);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_line() {
+        assert_eq!(b"a.txt\thello\n".to_vec(), tag_line("a.txt", b"hello\n"));
+        assert_eq!(
+            b"a.txt\tno newline\n".to_vec(),
+            tag_line("a.txt", b"no newline")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! The `checksums` file, which records the size and hash of every file a task left in its
+//! destination directory when it finished, so that `verify` can tell if any has changed since.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::input_hash::{self, INPUT_HASH_FILE};
+use crate::status::STATUS_FILE;
+use crate::{error, Message};
+
+/// The name of the file in each task's destination directory that holds its files' checksums.
+pub(crate) const CHECKSUMS_FILE: &str = "checksums";
+
+/// The size and hash of each file in a task's destination directory, by its path there.
+pub(crate) type Checksums = BTreeMap<String, (u64, String)>;
+
+/// Files that reach writes after the checksums, or that say what they are themselves.
+fn is_bookkeeping(name: &str) -> bool {
+    [CHECKSUMS_FILE, STATUS_FILE, INPUT_HASH_FILE].contains(&name) || name.ends_with(".tmp")
+}
+
+/// The checksums of the files in the task directory `task_dir` as they are now.
+pub(crate) async fn compute(task_dir: &Path) -> io::Result<Checksums> {
+    let mut checksums = Checksums::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        let mut entries = fs::read_dir(task_dir.join(&relative)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let name = path.to_string_lossy().into_owned();
+            if !file_type.is_file() || (relative.as_os_str().is_empty() && is_bookkeeping(&name)) {
+                continue;
+            }
+            let size = entry.metadata().await?.len();
+            let hash = input_hash::hash_file(&entry.path()).await?;
+            checksums.insert(name, (size, hash));
+        }
+    }
+    Ok(checksums)
+}
+
+/// Record the checksums of the files in the task directory `task_dir`.
+pub(crate) async fn record(task_dir: &Path) -> io::Result<()> {
+    let contents: String = compute(task_dir)
+        .await?
+        .iter()
+        .map(|(name, (size, hash))| format!("{} {} {}\n", size, hash, name))
+        .collect();
+    let temp_path = task_dir.join(format!("{}.tmp", CHECKSUMS_FILE));
+    fs::write(&temp_path, contents).await?;
+    fs::rename(&temp_path, task_dir.join(CHECKSUMS_FILE)).await
+}
+
+/// Remove any checksums recorded in the task directory `task_dir`, which a new run of its
+/// task would make wrong.
+pub(crate) async fn clear(task_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(task_dir.join(CHECKSUMS_FILE)).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// The checksums recorded in the task directory `task_dir`, if there are any.
+pub(crate) async fn read(task_dir: &Path) -> io::Result<Option<Checksums>> {
+    let contents = match fs::read_to_string(task_dir.join(CHECKSUMS_FILE)).await {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let mut checksums = Checksums::new();
+    for (number, line) in contents.lines().enumerate() {
+        let mut parts = line.splitn(3, ' ');
+        let parsed = match (parts.next(), parts.next(), parts.next()) {
+            (Some(size), Some(hash), Some(name)) => size
+                .parse()
+                .ok()
+                .map(|size| (name.to_owned(), (size, hash.to_owned()))),
+            _ => None,
+        };
+        let (name, checksum) = parsed.ok_or_else(|| {
+            error::new(
+                io::ErrorKind::InvalidData,
+                Message::ChecksumsLine(number + 1),
+            )
+        })?;
+        checksums.insert(name, checksum);
+    }
+    Ok(Some(checksums))
+}
+
+/// What's different about the files in a task directory, as `now`, from when its checksums
+/// were `recorded`, for people to read.
+pub(crate) fn changes(recorded: &Checksums, now: &Checksums) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (name, (size, hash)) in recorded {
+        match now.get(name) {
+            None => changes.push(format!("{} is missing", name)),
+            Some((now_size, _)) if now_size < size => changes.push(format!(
+                "{} has been truncated to {} bytes from {}",
+                name, now_size, size
+            )),
+            Some((now_size, _)) if now_size > size => changes.push(format!(
+                "{} has grown to {} bytes from {}",
+                name, now_size, size
+            )),
+            Some((_, now_hash)) if now_hash != hash => {
+                changes.push(format!("{} has changed since its task finished", name))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in now.keys().filter(|name| !recorded.contains_key(*name)) {
+        changes.push(format!("{} wasn't there when its task finished", name));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_checksums() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(None, read(dir.path()).await?);
+        fs::write(dir.path().join("out"), "hello\n").await?;
+        fs::write(dir.path().join("err"), "").await?;
+        fs::create_dir(dir.path().join("then-1")).await?;
+        fs::write(dir.path().join("then-1/out"), "5\n").await?;
+        fs::write(dir.path().join(STATUS_FILE), "0\n").await?;
+        record(dir.path()).await?;
+        let recorded = read(dir.path()).await?.expect("They were just recorded");
+        let names: Vec<_> = recorded.keys().map(String::as_str).collect();
+        assert_eq!(vec!["err", "out", "then-1/out"], names);
+        assert_eq!(6, recorded["out"].0);
+        assert!(changes(&recorded, &compute(dir.path()).await?).is_empty());
+
+        fs::write(dir.path().join("out"), "hel").await?;
+        fs::write(dir.path().join("err"), "oops").await?;
+        fs::remove_file(dir.path().join("then-1/out")).await?;
+        fs::write(dir.path().join("extra"), "").await?;
+        fs::write(dir.path().join(STATUS_FILE), "1\n").await?;
+        assert_eq!(
+            vec![
+                "err has grown to 4 bytes from 0",
+                "out has been truncated to 3 bytes from 6",
+                "then-1/out is missing",
+                "extra wasn't there when its task finished",
+            ],
+            changes(&recorded, &compute(dir.path()).await?)
+        );
+        fs::write(dir.path().join(CHECKSUMS_FILE), "six abc out\n").await?;
+        assert!(read(dir.path()).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+//! Shell completion for reach's options, written out from the same definitions clap parses.
+//!
+//! The scripts complete option names and the values clap knows about statically, and ask
+//! reach itself, as `reach --complete KIND PREFIX`, for values that depend on what's on disk:
+//! the destination directories of earlier runs, and the config files reach can read.
+
+use clap::{App, Arg, ArgSettings, ValueHint};
+use reach::{ConfigFile, STATE_DIR};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::fs;
+
+/// The first argument that makes reach print completion candidates instead of running.
+pub(crate) const HELPER: &str = "--complete";
+
+/// The shells there are completion scripts for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("No such Shell: {}", s)),
+        }
+    }
+}
+
+/// What can be completed for an option or positional argument's value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Values<'a> {
+    /// Nothing in particular.
+    Anything,
+    /// One of these.
+    OneOf(&'a [&'a str]),
+    Files,
+    Directories,
+    Commands,
+    /// Whatever reach says, as `reach --complete KIND`, for this kind.
+    Dynamic(&'static str),
+}
+
+/// Kinds of values that reach lists for the scripts.
+const DESTINATIONS: &str = "destinations";
+const CONFIG_FILES: &str = "config-files";
+
+/// What can be completed for `arg`'s value.
+fn values<'a>(arg: &'a Arg<'_>) -> Values<'a> {
+    match arg.get_name() {
+        "destination" => return Values::Dynamic(DESTINATIONS),
+        "config" => return Values::Dynamic(CONFIG_FILES),
+        _ => {}
+    }
+    if let Some(possible) = arg.get_possible_values() {
+        return Values::OneOf(possible);
+    }
+    match arg.get_value_hint() {
+        // As clap has it, unhinted values are whatever the shell completes by default.
+        ValueHint::Unknown | ValueHint::AnyPath | ValueHint::FilePath => Values::Files,
+        ValueHint::DirPath => Values::Directories,
+        ValueHint::CommandName | ValueHint::CommandString | ValueHint::ExecutablePath => {
+            Values::Commands
+        }
+        _ => Values::Anything,
+    }
+}
+
+/// The options `app` has, leaving out hidden ones.
+fn options<'a, 'help>(app: &'a App<'help>) -> Vec<&'a Arg<'help>> {
+    app.get_arguments()
+        .filter(|arg| !spellings(arg).is_empty() && !arg.is_set(ArgSettings::Hidden))
+        .collect()
+}
+
+/// The positional arguments `app` has, in order.
+fn positionals<'a, 'help>(app: &'a App<'help>) -> Vec<&'a Arg<'help>> {
+    let mut positionals: Vec<_> = app.get_positionals().collect();
+    positionals.sort_by_key(|arg| arg.get_index());
+    positionals
+}
+
+/// Every way to write `option`, like `-j` and `--processes`.
+fn spellings(option: &Arg<'_>) -> Vec<String> {
+    let short = option.get_short().map(|short| format!("-{}", short));
+    let long = option.get_long().map(|long| format!("--{}", long));
+    short.into_iter().chain(long).collect()
+}
+
+/// The first sentence of `arg`'s help, to describe it in a list of candidates.
+///
+/// A sentence ends at a full stop and a space, unless it's in 'e.g. ', or a command like
+/// 'find . -name'.
+fn summary(arg: &Arg<'_>) -> String {
+    let about = arg.get_about().unwrap_or_default();
+    let end = about
+        .match_indices(". ")
+        .map(|(at, _)| at)
+        .find(|&at| {
+            let abbreviated = about[..at].ends_with("e.g") || about[..at].ends_with("i.e");
+            let continues = about[at + 2..].starts_with(|c: char| c.is_lowercase() || c == '-');
+            !abbreviated && !continues
+        })
+        .unwrap_or(about.len());
+    about[..end].trim_end_matches('.').to_owned()
+}
+
+/// The completion script for `app` in `shell`.
+pub(crate) fn script(shell: Shell, app: &App<'_>) -> String {
+    match shell {
+        Shell::Bash => bash(app),
+        Shell::Zsh => zsh(app),
+        Shell::Fish => fish(app),
+    }
+}
+
+fn bash(app: &App<'_>) -> String {
+    let name = app.get_name();
+    let options = options(app);
+    let mut all = vec!["--help".to_owned(), "--version".to_owned()];
+    let mut cases = String::new();
+    let mut takes_value = Vec::new();
+    for option in &options {
+        all.extend(spellings(option));
+        if !option.is_set(ArgSettings::TakesValue) {
+            continue;
+        }
+        let pattern = spellings(option).join("|");
+        cases += &format!(
+            "        {})\n            {}\n            return ;;\n",
+            pattern,
+            bash_reply(values(option))
+        );
+        takes_value.push(pattern);
+    }
+    let mut positional_cases = String::new();
+    for (i, arg) in positionals(app).iter().enumerate() {
+        positional_cases += &format!(
+            "        {})\n            {}\n            ;;\n",
+            i,
+            bash_reply(values(arg))
+        );
+    }
+    format!(
+        r#"_{name}() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local reach="${{COMP_WORDS[0]}}"
+    case "$prev" in
+{cases}    esac
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{all}" -- "$cur"))
+        return
+    fi
+    local i positional=0
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${{COMP_WORDS[i]}}" in
+            {takes_value}) ((i++)) ;;
+            -*) ;;
+            *) ((positional++)) ;;
+        esac
+    done
+    case "$positional" in
+{positional_cases}    esac
+}}
+complete -o filenames -F _{name} {name}
+"#,
+        name = name,
+        cases = cases,
+        all = all.join(" "),
+        takes_value = takes_value.join("|"),
+        positional_cases = positional_cases,
+    )
+}
+
+/// The bash statement that completes `values` for the word being typed.
+fn bash_reply(values: Values<'_>) -> String {
+    match values {
+        Values::Anything => "COMPREPLY=()".into(),
+        Values::OneOf(possible) => format!(
+            r#"COMPREPLY=($(compgen -W "{}" -- "$cur"))"#,
+            possible.join(" ")
+        ),
+        Values::Files => r#"COMPREPLY=($(compgen -f -- "$cur"))"#.into(),
+        Values::Directories => r#"COMPREPLY=($(compgen -d -- "$cur"))"#.into(),
+        Values::Commands => r#"COMPREPLY=($(compgen -c -- "$cur"))"#.into(),
+        // Any directory will do if reach can't suggest one.
+        Values::Dynamic(DESTINATIONS) => format!(
+            r#"COMPREPLY=($("$reach" {} {} "$cur" 2>/dev/null))
+            [[ ${{#COMPREPLY[@]}} -gt 0 ]] || COMPREPLY=($(compgen -d -- "$cur"))"#,
+            HELPER, DESTINATIONS
+        ),
+        Values::Dynamic(kind) => format!(
+            r#"COMPREPLY=($("$reach" {} {} "$cur" 2>/dev/null))"#,
+            HELPER, kind
+        ),
+    }
+}
+
+fn zsh(app: &App<'_>) -> String {
+    let name = app.get_name();
+    let mut specs = vec![
+        "'--help[Prints help information]'".to_owned(),
+        "'--version[Prints version information]'".to_owned(),
+    ];
+    for option in options(app) {
+        let repeat = if option.is_set(ArgSettings::MultipleOccurrences) {
+            "*"
+        } else {
+            ""
+        };
+        let takes_value = option.is_set(ArgSettings::TakesValue);
+        let description = zsh_escape(&summary(option));
+        let value = match takes_value {
+            true => format!(
+                ":{}:{}",
+                option.get_name(),
+                zsh_action(name, values(option))
+            ),
+            false => String::new(),
+        };
+        if let Some(short) = option.get_short() {
+            let suffix = if takes_value { "+" } else { "" };
+            specs.push(format!(
+                "'{}-{}{}[{}]{}'",
+                repeat, short, suffix, description, value
+            ));
+        }
+        if let Some(long) = option.get_long() {
+            let suffix = if takes_value { "=" } else { "" };
+            specs.push(format!(
+                "'{}--{}{}[{}]{}'",
+                repeat, long, suffix, description, value
+            ));
+        }
+    }
+    for (i, arg) in positionals(app).iter().enumerate() {
+        specs.push(format!(
+            "'{}:{}:{}'",
+            i + 1,
+            arg.get_name(),
+            zsh_action(name, values(arg))
+        ));
+    }
+    format!(
+        r#"#compdef {name}
+
+_{name}_complete() {{
+    local -a candidates
+    candidates=(${{(f)"$("${{words[1]}}" {helper} "$1" "$PREFIX" 2>/dev/null)"}})
+    (( $#candidates )) && compadd -f -a candidates
+}}
+
+_{name}_{destinations_function}() {{
+    _{name}_complete {destinations} || _files -/
+}}
+
+_{name}_{config_files_function}() {{
+    _{name}_complete {config_files}
+}}
+
+_{name}() {{
+    _arguments -s -S \
+        {specs}
+}}
+
+if [ "$funcstack[1]" = "_{name}" ]; then
+    _{name} "$@"
+else
+    compdef _{name} {name}
+fi
+"#,
+        name = name,
+        helper = HELPER,
+        destinations = DESTINATIONS,
+        destinations_function = DESTINATIONS.replace('-', "_"),
+        config_files = CONFIG_FILES,
+        config_files_function = CONFIG_FILES.replace('-', "_"),
+        specs = specs.join(" \\\n        "),
+    )
+}
+
+/// The `_arguments` action that completes `values`.
+fn zsh_action(name: &str, values: Values<'_>) -> String {
+    match values {
+        Values::Anything => " ".into(),
+        Values::OneOf(possible) => format!("({})", possible.join(" ")),
+        Values::Files => "_files".into(),
+        Values::Directories => "_files -/".into(),
+        Values::Commands => "_command_names -e".into(),
+        Values::Dynamic(kind) => format!("_{}_{}", name, kind.replace('-', "_")),
+    }
+}
+
+/// `text` as it can go in brackets inside a single-quoted `_arguments` spec.
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', r"'\''")
+        .replace('[', r"\[")
+        .replace(']', r"\]")
+        .replace(':', r"\:")
+}
+
+fn fish(app: &App<'_>) -> String {
+    let name = app.get_name();
+    let options = options(app);
+    let takes_value: Vec<String> = options
+        .iter()
+        .filter(|option| option.is_set(ArgSettings::TakesValue))
+        .flat_map(|option| spellings(option))
+        .collect();
+    let mut lines = Vec::new();
+    for option in &options {
+        let mut line = format!("complete -c {}", name);
+        if let Some(short) = option.get_short() {
+            line += &format!(" -s {}", short);
+        }
+        if let Some(long) = option.get_long() {
+            line += &format!(" -l {}", long);
+        }
+        line += &format!(" -d '{}'", fish_escape(&summary(option)));
+        if option.is_set(ArgSettings::TakesValue) {
+            line += " -r";
+            line += &fish_arguments(name, values(option));
+        }
+        lines.push(line);
+    }
+    for (i, arg) in positionals(app).iter().enumerate() {
+        lines.push(format!(
+            "complete -c {} -n 'test (__{}_positionals) = {}'{}",
+            name,
+            name,
+            i,
+            fish_arguments(name, values(arg))
+        ));
+    }
+    format!(
+        r#"function __{name}_complete
+    set -l reach (commandline -opc)[1]
+    $reach {helper} $argv (commandline -ct) 2>/dev/null
+end
+
+function __{name}_{destinations}
+    set -l found (__{name}_complete {destinations})
+    if set -q found[1]
+        printf '%s\n' $found
+    else
+        __fish_complete_directories (commandline -ct)
+    end
+end
+
+# How many positional arguments come before the one being typed.
+function __{name}_positionals
+    set -l words (commandline -opc)
+    set -e words[1]
+    set -l count 0
+    set -l skip 0
+    for word in $words
+        if test $skip = 1
+            set skip 0
+        else if contains -- $word {takes_value}
+            set skip 1
+        else if not string match -q -- '-*' $word
+            set count (math $count + 1)
+        end
+    end
+    echo $count
+end
+
+{lines}
+"#,
+        name = name,
+        helper = HELPER,
+        destinations = DESTINATIONS,
+        takes_value = takes_value.join(" "),
+        lines = lines.join("\n"),
+    )
+}
+
+/// The arguments to `complete` that complete `values`.
+fn fish_arguments(name: &str, values: Values<'_>) -> String {
+    match values {
+        Values::Anything => " -f".into(),
+        Values::OneOf(possible) => format!(" -f -a '{}'", possible.join(" ")),
+        Values::Files => " -F".into(),
+        Values::Directories => " -f -a '(__fish_complete_directories (commandline -ct))'".into(),
+        Values::Commands => " -f -a '(__fish_complete_command)'".into(),
+        Values::Dynamic(DESTINATIONS) => format!(" -f -a '(__{}_{})'", name, DESTINATIONS),
+        Values::Dynamic(kind) => format!(" -f -a '(__{}_complete {})'", name, kind),
+    }
+}
+
+/// `text` as it can go in single quotes in fish.
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', r"\\").replace('\'', r"\'")
+}
+
+/// The candidates of `kind` for a word starting with `prefix`, for the completion scripts.
+pub(crate) async fn candidates(app: &App<'_>, kind: &str, prefix: &str) -> io::Result<Vec<String>> {
+    // Only what's in the directory the word is in so far.
+    let (dir, partial) = match prefix.rfind('/') {
+        Some(slash) => prefix.split_at(slash + 1),
+        None => ("", prefix),
+    };
+    let mut entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(partial) || (name.starts_with('.') && !partial.starts_with('.')) {
+            continue;
+        }
+        let wanted = match kind {
+            DESTINATIONS => is_destination(&entry.path()),
+            CONFIG_FILES => is_config_file(app, &entry.path()).await,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Nothing to complete for {}", kind),
+                ))
+            }
+        };
+        if wanted {
+            found.push(format!("{}{}", dir, name));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Whether `path` is the destination directory of an earlier run, with its journal in it.
+fn is_destination(path: &Path) -> bool {
+    path.join(STATE_DIR).join("journal").is_file()
+}
+
+/// Whether `path` is a config file that only sets options `app` has.
+async fn is_config_file(app: &App<'_>, path: &Path) -> bool {
+    if path.extension().is_none_or(|extension| extension != "toml") {
+        return false;
+    }
+    let file = match ConfigFile::read(path).await {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    file.settings().iter().all(|(name, _)| {
+        let long = name.replace('_', "-");
+        long == "command" || app.get_arguments().any(|arg| arg.get_long() == Some(&long))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use clap::IntoApp;
+
+    #[test]
+    fn test_script() {
+        let app = Opts::into_app();
+        let bash = script(Shell::Bash, &app);
+        assert!(bash.contains("complete -o filenames -F _reach reach"));
+        assert!(bash.contains("--processes|-j") || bash.contains("-j|--processes"));
+        assert!(bash.contains(r#"compgen -W "none name size mtime random""#));
+        assert!(bash.contains(r#""$reach" --complete destinations "$cur""#));
+        assert!(bash.contains(r#""$reach" --complete config-files "$cur""#));
+
+        let zsh = script(Shell::Zsh, &app);
+        assert!(zsh.starts_with("#compdef reach\n"));
+        assert!(zsh.contains("'--order=[The order to process the source files in]:order:(none name size mtime random)'"));
+        assert!(zsh.contains("'*--include=["));
+        assert!(zsh.contains("'3:destination:_reach_destinations'"));
+        assert!(zsh.contains("'--config=["));
+
+        let fish = script(Shell::Fish, &app);
+        assert!(fish.contains("complete -c reach -s j -l processes"));
+        assert!(fish.contains("-l config -d"));
+        assert!(fish.contains("'(__reach_complete config-files)'"));
+        assert!(fish.contains("-n 'test (__reach_positionals) = 2' -f -a '(__reach_destinations)'"));
+
+        assert_eq!(Ok(Shell::Fish), "FISH".parse());
+        assert!("csh".parse::<Shell>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_candidates() -> io::Result<()> {
+        let app = Opts::into_app();
+        let dir = tempfile::tempdir()?;
+        let root = dir
+            .path()
+            .to_str()
+            .expect("Temporary directories have unicode paths");
+        for run in &["a-results", "b-results"] {
+            std::fs::create_dir_all(dir.path().join(run).join(STATE_DIR))?;
+            std::fs::write(dir.path().join(run).join(".reach/journal"), "")?;
+        }
+        std::fs::create_dir(dir.path().join("a-source"))?;
+        std::fs::write(
+            dir.path().join("reach.toml"),
+            "processes = 8\ncommand = 'gzip'\n",
+        )?;
+        std::fs::write(dir.path().join("other.toml"), "name = 'not reach'\n")?;
+        std::fs::write(dir.path().join("broken.toml"), "[table]\n")?;
+
+        let prefix = format!("{}/", root);
+        assert_eq!(
+            vec![
+                format!("{}a-results", prefix),
+                format!("{}b-results", prefix)
+            ],
+            candidates(&app, DESTINATIONS, &prefix).await?
+        );
+        assert_eq!(
+            vec![format!("{}a-results", prefix)],
+            candidates(&app, DESTINATIONS, &format!("{}a", prefix)).await?
+        );
+        assert_eq!(
+            vec![format!("{}reach.toml", prefix)],
+            candidates(&app, CONFIG_FILES, &prefix).await?
+        );
+        assert!(
+            candidates(&app, DESTINATIONS, &format!("{}missing/", prefix))
+                .await?
+                .is_empty()
+        );
+        assert!(candidates(&app, "users", &prefix).await.is_err());
+        Ok(())
+    }
+}
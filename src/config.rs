@@ -0,0 +1,1124 @@
+//! Configuration for a run, and the defaults that fill it in.
+
+use serde_json::json;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error;
+use crate::shell;
+use crate::task_list::STDIN_SOURCE;
+use crate::{
+    Capture, Framing, Halt, InputMode, Message, Naming, Order, OutputPolicy, PauseToken, RunAs,
+    Worker,
+};
+
+/// Configuration for Each.
+///
+/// `Config::builder` fills in the same defaults as the command line.
+pub struct Config {
+    pub command: String,
+    pub shell: String,
+    /// The directory containing the inputs, or a single input file.
+    ///
+    /// `-` means the inputs' paths are read from standard input instead, like the output of
+    /// `find`, one per line, or separated by NULs with `null_separated`. Tasks start as their
+    /// paths are read, before the list is finished. An input that would share a destination
+    /// with one before it is left out.
+    /// Inputs can't be ordered or watched for, and there can be no other sources.
+    pub source_dir: PathBuf,
+    /// Whether the inputs' paths are read from standard input, as `source_dir` is `-`.
+    pub from_stdin: bool,
+    /// Read the paths of inputs from standard input separated by NULs, as `find -print0` writes
+    /// them, rather than one per line.
+    pub null_separated: bool,
+    /// More source directories, or single source files, whose files are processed along with
+    /// `source_dir`'s into the same destination directory.
+    ///
+    /// Inputs from different sources can't share a destination, as they would if they had the
+    /// same name and were named by it.
+    pub more_sources: Vec<PathBuf>,
+    /// Only take the files whose names match one of these shell-style patterns, like `*.csv`,
+    /// as inputs, if there are any.
+    pub include: Vec<String>,
+    /// Never take the files whose names match any of these shell-style patterns as inputs,
+    /// even if they're included.
+    pub exclude: Vec<String>,
+    /// In filename mode, leave out the files whose names aren't unicode if the shell can't be
+    /// given them, as PowerShell and cmd can't, rather than failing their tasks.
+    ///
+    /// POSIX shells on Unix take any name, so nothing is left out for them.
+    pub skip_invalid_names: bool,
+    pub destination_dir: PathBuf,
+    /// Allow the destination directory to be inside a source directory, which is otherwise refused,
+    /// as the run would be writing inside its sources.
+    ///
+    /// Nothing inside the destination directory or `state_dir` is ever taken as an input.
+    pub nested_destination: bool,
+    /// The order to process the source files in.
+    pub order: Order,
+    /// Shuffle the inputs the same way every run in `Order::Random`, as long as they're the same
+    /// inputs and it's the same version of reach, rather than differently every time.
+    pub seed: Option<u64>,
+    /// How to name each input's destination directory.
+    ///
+    /// Inputs named by `Naming::Index` are only numbered the same way every run if they're
+    /// ordered, and can only be shuffled with a `seed`.
+    pub naming: Naming,
+    /// Write `manifest.json` in the destination directory once the run has finished,
+    /// with every input the run found and the directory its results are in.
+    ///
+    /// It's first written as the run starts, with the inputs it found then, in the order
+    /// they're processed, so that even a run that never finishes says what it set out to do.
+    ///
+    /// It's always written when inputs aren't named by `Naming::Name`, as there'd be no other
+    /// way to tell which directory is whose.
+    pub manifest: bool,
+    /// Process exactly the inputs listed in this manifest, or in the `manifest.json` in this
+    /// destination directory, in its order and into the same destinations, rather than those
+    /// in the sources, so that a run can be repeated even once its sources have changed.
+    ///
+    /// Inputs that are gone fail their tasks. Inputs can't also be read from standard input,
+    /// ordered, watched for or streamed in low-memory mode.
+    pub from_manifest: Option<PathBuf>,
+    /// Where reach keeps its own bookkeeping. Never written inside `source_dir`.
+    pub state_dir: PathBuf,
+    pub num_processes: usize,
+    /// Keep reach's own memory use the same however many inputs there are, for runs with
+    /// millions of them.
+    ///
+    /// The source directory is read twice, once to count its inputs and again as they're
+    /// needed, rather than held in memory, and the summary only lists the first
+    /// `SUMMARY_LIMIT` failures and groups, with the rest in the run's journal.
+    /// Inputs can't be ordered or watched for, and there can only be one source.
+    ///
+    /// Otherwise, each input costs reach around 160 bytes for the whole run, so 10 million
+    /// of them take well over a gigabyte. With 50,000 failing inputs, reach peaked at 22MB
+    /// without this and 14MB with it, the same as for a handful of inputs.
+    ///
+    /// What reach holds at once, in this mode, is bounded by:
+    ///
+    /// - Inputs: `prefetch` (at least one) read ahead of their tasks, `batch` in each task,
+    ///   and `num_processes` tasks at a time. The names of their destination directories
+    ///   aren't kept, so two inputs that would share one aren't refused.
+    /// - The summary: `SUMMARY_LIMIT` failures and `SUMMARY_LIMIT` groups.
+    /// - The journal and manifest: written as each task finishes, and the last run's
+    ///   journal is read a line at a time.
+    /// - Each running task's output: 64KiB for each stream it's copied from, half of
+    ///   `max_output_size` kept for its end, and, if it's echoed, its longest line.
+    /// - The `tui` dashboard: the tasks that are running and the last few that finished.
+    ///
+    /// The exception is `retry_failed`, which keeps the inputs that failed in the last run.
+    pub low_memory: bool,
+    /// Split the source, a single file rather than a directory, into chunks of this many bytes,
+    /// each the input of a task of its own, so that one huge file can be processed in parallel.
+    ///
+    /// In `Stdin` mode, each command reads only its chunk. In every mode, its command gets
+    /// `REACH_OFFSET` and `REACH_LENGTH`, the chunk's place in the file in bytes.
+    /// Chunk `i` of a file called `big.bin` has a destination directory called `big.bin.i`,
+    /// padded with zeros so that the directories sort in the chunks' order.
+    pub split_bytes: Option<u64>,
+    /// Give each command up to this many inputs at once, rather than one.
+    ///
+    /// With `{+}` in the command, it becomes all of the batch's inputs; in `Stdin` mode, the
+    /// command reads them one after another. A batch's output goes in the destination directory
+    /// of its first input, and every input in it gets the same status.
+    pub batch: usize,
+    /// How many tasks may be opening or creating files at once.
+    ///
+    /// Independent of `num_processes`, because opening hundreds of files at once
+    /// can be pathologically slow on network filesystems.
+    pub io_concurrency: usize,
+    /// How many inputs waiting to be processed to ask the kernel to start reading ahead of time.
+    pub prefetch: usize,
+    pub input_mode: InputMode,
+    /// How inputs and outputs are delimited in `InputMode::Coprocess`.
+    pub framing: Framing,
+    pub recreate: bool,
+    /// Run even if the command, or anything else that decides what tasks produce, has changed
+    /// since the last run in the same destination, mixing results from both.
+    /// Without this, or `recreate`, such a run is refused.
+    pub allow_command_change: bool,
+    /// Record a hash of each task's input with its results, and run the task again if its
+    /// input has changed since, even if it succeeded.
+    pub hash_inputs: bool,
+    /// Record the size and checksum of every file each task leaves in its destination
+    /// directory, so that `verify` can tell if any has changed since.
+    pub checksums: bool,
+    /// Once every file in the source directory has been processed, keep looking for new
+    /// files this often, and process them too, until the run is interrupted.
+    pub watch: Option<Duration>,
+    /// Tell systemd how a run that watches for inputs is doing, when it runs as a service of
+    /// `Type=notify`: that it's ready, that it's stopping, and that it's alive, for its
+    /// watchdog. Off by default, as the notifications go to whatever `NOTIFY_SOCKET` names,
+    /// which belongs to the program that embeds reach as much as to reach.
+    pub notify_systemd: bool,
+    /// Only run the tasks that failed in the last run, as recorded in its journal.
+    pub retry_failed: bool,
+    /// Only run the tasks whose input's file name matches this shell-style pattern, like
+    /// `customer-42-*`, whether or not they succeeded before.
+    pub rerun_matching: Option<String>,
+    pub retries: u32,
+    /// Signals that commands are sometimes killed by through no fault of their own, like
+    /// `SIGKILL` from the kernel's out-of-memory killer.
+    ///
+    /// A task whose command is killed by one of these is tried again without counting
+    /// against `retries`, up to three times.
+    pub retry_signals: Vec<i32>,
+    /// Kill any command that runs for longer than this.
+    pub timeout: Option<Duration>,
+    /// When to give up early because tasks are failing.
+    pub halt: Halt,
+    /// Stop the run once tasks have left more than this many bytes in their destination directories.
+    ///
+    /// A soft limit: each task's output is measured once it has finished,
+    /// so running tasks can take the total past it.
+    pub max_total_output: Option<u64>,
+    /// What to do once the output limit is passed.
+    pub output_policy: OutputPolicy,
+    /// What happens to each task's standard output and standard error.
+    pub capture: Capture,
+    /// The name of the file in each task's destination directory for its standard output.
+    pub stdout_name: String,
+    /// The name of the file in each task's destination directory for its standard error.
+    pub stderr_name: String,
+    /// Keep at most this many bytes of each output file, keeping the beginning and the end,
+    /// and noting in the task's status file that the middle was clipped.
+    ///
+    /// Not supported with shell sessions or coprocesses, which write their output themselves.
+    pub max_output_size: Option<u64>,
+    /// A template like `{dir}` for the groups that the summary breaks tasks down into.
+    ///
+    /// Defaults to `{ext}`, so that inputs of each type get their own statistics.
+    pub group_by: Option<String>,
+    /// Start at most this many tasks a second. Retries aren't held back.
+    pub max_rate: Option<f64>,
+    /// Don't start any tasks while the system's load average over the last minute is above this.
+    ///
+    /// The load average is slow to catch up, so this works best alongside `max_rate`.
+    pub max_load: Option<f64>,
+    /// Only start tasks during this window of local time each day, like `22:00-06:00`.
+    ///
+    /// Outside it, tasks that are running carry on, and the run waits for it to open again.
+    pub window: Option<String>,
+    /// Pause the run while this token is paused: no new tasks start, and running commands are
+    /// frozen, with their timeouts stopped, where reach can put them in a cgroup of their own
+    /// to freeze. Otherwise they carry on.
+    ///
+    /// Commands on workers, or in systemd scopes, are never frozen.
+    pub pause: Option<PauseToken>,
+    /// Run tasks in long-lived shells, one per process slot, rather than starting a shell for each.
+    ///
+    /// Each task still runs in a subshell of its own, so tasks can't affect each other.
+    /// In `Filename` and `Exec` modes, tasks get `/dev/null` as their standard input.
+    pub shell_sessions: bool,
+    /// A template like `{dir}`, so that tasks whose inputs give the same key go to the same worker.
+    ///
+    /// Only for coprocesses and shell sessions, whose workers outlive each task,
+    /// so that whatever a worker caches for one key is there for the next task with it.
+    /// A task waits for its worker if it's busy, even if others are idle.
+    pub affinity: Option<String>,
+    /// A command to run before each task's command, with the same placeholders and environment.
+    ///
+    /// If it fails, so does the task, and its command isn't run.
+    pub setup: Option<String>,
+    /// A command to run after each task's command, however it ended, unless the run was interrupted.
+    ///
+    /// It gets `REACH_STATUS`, the task's status, as well as the task's environment.
+    /// If it fails, so does the task.
+    pub teardown: Option<String>,
+    /// A command to run once before any tasks start, with `REACH_SOURCE_DIR` and `REACH_DEST_DIR`.
+    ///
+    /// If it fails, no tasks are run.
+    pub before_all: Option<String>,
+    /// A command to run once every task has finished, with `REACH_SUCCEEDED` and `REACH_FAILED`
+    /// as well as the same environment as `before_all`.
+    pub after_all: Option<String>,
+    /// The directory to run each task's command in, like `{dest}`, with the same placeholders
+    /// as `InputMode::Filename`. It's created if it doesn't exist.
+    ///
+    /// Without one, commands run in reach's own working directory.
+    pub workdir: Option<String>,
+    /// Commands to run after each task's command, one after another, each reading the
+    /// standard output of the one before, with the same placeholders as `InputMode::Filename`.
+    ///
+    /// Each gets a directory of its own in the task's destination directory, `then-1` and so on,
+    /// for its output and status. The task only succeeds if they all do, and a task that failed
+    /// partway through picks up from the stage that failed.
+    pub then: Vec<String>,
+    /// A command like `nice -n19 {cmd}` to run every task's command inside.
+    ///
+    /// The `{cmd}` word is replaced by the task's command, however it is run.
+    pub wrap: Option<String>,
+    /// Run every task's command in a transient systemd scope of its own, using `systemd-run`.
+    ///
+    /// The scope goes around `wrap`, if there is one.
+    pub systemd_scope: bool,
+    /// Properties like `MemoryMax=2G` or `CPUWeight=20` for each task's systemd scope.
+    pub systemd_properties: Vec<String>,
+    /// Run every command as this user and group, rather than as reach's own, which needs
+    /// reach to run as root.
+    ///
+    /// Each task's destination directories, and its working directory, are given to them,
+    /// so its commands can write there.
+    pub run_as: Option<RunAs>,
+    /// Run every task's command at this niceness, from -20, the highest priority, to 19,
+    /// the lowest. Only root can give commands a higher priority than reach's own, and not
+    /// with `run_as`, as commands give up root before their niceness is set.
+    pub nice: Option<i32>,
+    /// The most address space, in bytes, that any one process of a task's command can have,
+    /// beyond which allocations fail.
+    pub memory_limit: Option<u64>,
+    /// Run every task's command on this many CPUs of its own, out of those reach can run on.
+    ///
+    /// Tasks only share CPUs when there are too few for every task running at once.
+    pub cpus_per_task: Option<usize>,
+    /// The most files that any one process of a task's command can have open at once,
+    /// beyond which opening more fails.
+    pub task_max_fds: Option<u64>,
+    /// The most processes that the user running a task's command can have, beyond which
+    /// forking fails, so that a command that forks without end can't exhaust the machine.
+    ///
+    /// The kernel counts every process of the user, including those of other tasks,
+    /// so this is most useful with `run_as` a user of its own.
+    pub task_max_procs: Option<u64>,
+    /// Confine each task's command, and whatever it starts, with Landlock, so that it can
+    /// only read its inputs, the system's own directories, like `/usr` and `/etc`, and
+    /// `restrict_fs_read`, and only write in its destination directory, its `workdir`, if it
+    /// has one, devices like `/dev/null`, and a scratch directory of its own, which is its
+    /// `TMPDIR`, and is removed once it finishes. Only on Linux 5.13 and later.
+    ///
+    /// Commands can't gain privileges, so setuid programs like `sudo` don't work for them.
+    /// Shell sessions, coprocesses, workers, and systemd scopes can't be confined.
+    pub restrict_fs: bool,
+    /// More directories or files that commands confined by `restrict_fs` can read,
+    /// like one with the programs they run.
+    pub restrict_fs_read: Vec<PathBuf>,
+    /// Machines to run the tasks on, over SSH, instead of this one.
+    ///
+    /// As many tasks run at once as the workers have slots between them, whatever
+    /// `num_processes` is. Only a task's standard output, standard error, and exit status
+    /// come back from its worker.
+    pub workers: Vec<Worker>,
+    /// The program to connect to workers with, which takes the same arguments as `ssh`.
+    pub ssh: String,
+}
+
+impl Config {
+    /// The configuration as JSON, with every default filled in, such as for checking what a
+    /// run will do before starting it.
+    ///
+    /// Values are written the way the command line takes them, and durations in seconds.
+    pub fn to_json(&self) -> serde_json::Value {
+        let path = |path: &Path| path.to_string_lossy().into_owned();
+        let secs = |duration: Option<Duration>| duration.map(|duration| duration.as_secs_f64());
+        json!({
+            "command": self.command,
+            "shell": self.shell,
+            "source_dir": path(&self.source_dir),
+            "from_stdin": self.from_stdin,
+            "null_separated": self.null_separated,
+            "more_sources": self.more_sources.iter().map(|source| path(source)).collect::<Vec<_>>(),
+            "include": self.include,
+            "exclude": self.exclude,
+            "skip_invalid_names": self.skip_invalid_names,
+            "destination_dir": path(&self.destination_dir),
+            "nested_destination": self.nested_destination,
+            "order": self.order.name(),
+            "seed": self.seed,
+            "naming": self.naming.name(),
+            "manifest": self.manifest,
+            "from_manifest": self.from_manifest.as_deref().map(path),
+            "state_dir": path(&self.state_dir),
+            "num_processes": self.num_processes,
+            "low_memory": self.low_memory,
+            "split_bytes": self.split_bytes,
+            "batch": self.batch,
+            "io_concurrency": self.io_concurrency,
+            "prefetch": self.prefetch,
+            "input_mode": self.input_mode.name(),
+            "framing": self.framing.name(),
+            "recreate": self.recreate,
+            "allow_command_change": self.allow_command_change,
+            "hash_inputs": self.hash_inputs,
+            "checksums": self.checksums,
+            "watch_secs": secs(self.watch),
+            "notify_systemd": self.notify_systemd,
+            "retry_failed": self.retry_failed,
+            "rerun_matching": self.rerun_matching,
+            "retries": self.retries,
+            "retry_signals": self.retry_signals,
+            "timeout_secs": secs(self.timeout),
+            "halt": self.halt.to_string(),
+            "max_total_output": self.max_total_output,
+            "output_policy": self.output_policy.name(),
+            "capture": self.capture.name(),
+            "stdout_name": self.stdout_name,
+            "stderr_name": self.stderr_name,
+            "max_output_size": self.max_output_size,
+            "group_by": self.group_by,
+            "max_rate": self.max_rate,
+            "max_load": self.max_load,
+            "window": self.window,
+            "pause": self.pause.is_some(),
+            "shell_sessions": self.shell_sessions,
+            "affinity": self.affinity,
+            "setup": self.setup,
+            "teardown": self.teardown,
+            "before_all": self.before_all,
+            "after_all": self.after_all,
+            "workdir": self.workdir,
+            "then": self.then,
+            "wrap": self.wrap,
+            "systemd_scope": self.systemd_scope,
+            "systemd_properties": self.systemd_properties,
+            "run_as": self.run_as.map(|run_as| run_as.to_string()),
+            "nice": self.nice,
+            "memory_limit": self.memory_limit,
+            "cpus_per_task": self.cpus_per_task,
+            "task_max_fds": self.task_max_fds,
+            "task_max_procs": self.task_max_procs,
+            "restrict_fs": self.restrict_fs,
+            "restrict_fs_read": self.restrict_fs_read.iter().map(|dir| path(dir)).collect::<Vec<_>>(),
+            "workers": self.workers.iter().map(Worker::to_string).collect::<Vec<_>>(),
+            "ssh": self.ssh,
+        })
+    }
+
+    /// Start building a configuration for running `command` on every file in `source_dir`,
+    /// or on every file named in standard input if it's `-`.
+    ///
+    /// Anything not set on the builder gets the same default as it does on the command line.
+    pub fn builder(command: impl Into<String>, source_dir: impl Into<PathBuf>) -> ConfigBuilder {
+        ConfigBuilder {
+            command: command.into(),
+            source_dir: source_dir.into(),
+            null_separated: false,
+            more_sources: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            skip_invalid_names: false,
+            destination_dir: None,
+            nested_destination: false,
+            order: Order::Unordered,
+            seed: None,
+            naming: Naming::Name,
+            manifest: false,
+            from_manifest: None,
+            state_dir: None,
+            shell: None,
+            num_processes: None,
+            low_memory: false,
+            split_bytes: None,
+            batch: 1,
+            io_concurrency: DEFAULT_IO_CONCURRENCY,
+            prefetch: 0,
+            input_mode: None,
+            framing: Framing::Length,
+            recreate: false,
+            allow_command_change: false,
+            hash_inputs: false,
+            checksums: false,
+            watch: None,
+            notify_systemd: false,
+            retry_failed: false,
+            rerun_matching: None,
+            retries: 0,
+            retry_signals: Vec::new(),
+            timeout: None,
+            halt: Halt::Never,
+            max_total_output: None,
+            output_policy: OutputPolicy::Stop,
+            capture: Capture::Separate,
+            stdout_name: DEFAULT_STDOUT_NAME.into(),
+            stderr_name: DEFAULT_STDERR_NAME.into(),
+            max_output_size: None,
+            group_by: None,
+            max_rate: None,
+            max_load: None,
+            window: None,
+            pause: None,
+            shell_sessions: false,
+            affinity: None,
+            setup: None,
+            teardown: None,
+            before_all: None,
+            after_all: None,
+            workdir: None,
+            then: Vec::new(),
+            wrap: None,
+            systemd_scope: false,
+            systemd_properties: Vec::new(),
+            run_as: None,
+            nice: None,
+            memory_limit: None,
+            cpus_per_task: None,
+            task_max_fds: None,
+            task_max_procs: None,
+            restrict_fs: false,
+            restrict_fs_read: Vec::new(),
+            workers: Vec::new(),
+            ssh: DEFAULT_SSH.into(),
+        }
+    }
+}
+
+/// The default for `Config::io_concurrency`.
+const DEFAULT_IO_CONCURRENCY: usize = 64;
+
+/// The state directory's name inside the destination directory, unless it's given another
+/// with `ConfigBuilder::state_dir`.
+pub const STATE_DIR: &str = ".reach";
+
+/// The destination directory for inputs read from standard input, in the current directory,
+/// unless there's another.
+const STDIN_DESTINATION_DIR: &str = "stdin-results";
+
+/// The default for `Config::stdout_name`.
+const DEFAULT_STDOUT_NAME: &str = "out";
+
+/// The default for `Config::stderr_name`.
+const DEFAULT_STDERR_NAME: &str = "err";
+
+/// The default for `Config::ssh`.
+const DEFAULT_SSH: &str = "ssh";
+
+/// Builds a `Config`, filling in defaults for anything that isn't set. See `Config::builder`.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    command: String,
+    source_dir: PathBuf,
+    null_separated: bool,
+    more_sources: Vec<PathBuf>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    skip_invalid_names: bool,
+    destination_dir: Option<PathBuf>,
+    nested_destination: bool,
+    order: Order,
+    seed: Option<u64>,
+    naming: Naming,
+    manifest: bool,
+    from_manifest: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+    shell: Option<String>,
+    num_processes: Option<usize>,
+    low_memory: bool,
+    split_bytes: Option<u64>,
+    batch: usize,
+    io_concurrency: usize,
+    prefetch: usize,
+    input_mode: Option<InputMode>,
+    framing: Framing,
+    recreate: bool,
+    allow_command_change: bool,
+    hash_inputs: bool,
+    checksums: bool,
+    watch: Option<Duration>,
+    notify_systemd: bool,
+    retry_failed: bool,
+    rerun_matching: Option<String>,
+    retries: u32,
+    retry_signals: Vec<i32>,
+    timeout: Option<Duration>,
+    halt: Halt,
+    max_total_output: Option<u64>,
+    output_policy: OutputPolicy,
+    capture: Capture,
+    stdout_name: String,
+    stderr_name: String,
+    max_output_size: Option<u64>,
+    group_by: Option<String>,
+    max_rate: Option<f64>,
+    max_load: Option<f64>,
+    window: Option<String>,
+    pause: Option<PauseToken>,
+    shell_sessions: bool,
+    affinity: Option<String>,
+    setup: Option<String>,
+    teardown: Option<String>,
+    before_all: Option<String>,
+    after_all: Option<String>,
+    workdir: Option<String>,
+    then: Vec<String>,
+    wrap: Option<String>,
+    systemd_scope: bool,
+    systemd_properties: Vec<String>,
+    run_as: Option<RunAs>,
+    nice: Option<i32>,
+    memory_limit: Option<u64>,
+    cpus_per_task: Option<usize>,
+    task_max_fds: Option<u64>,
+    task_max_procs: Option<u64>,
+    restrict_fs: bool,
+    restrict_fs_read: Vec<PathBuf>,
+    workers: Vec<Worker>,
+    ssh: String,
+}
+
+impl ConfigBuilder {
+    /// Process the files in these directories, or these files, too.
+    pub fn more_sources(mut self, more_sources: Vec<PathBuf>) -> Self {
+        self.more_sources = more_sources;
+        self
+    }
+
+    /// Defaults to false, so paths read from standard input are one per line.
+    pub fn null_separated(mut self, null_separated: bool) -> Self {
+        self.null_separated = null_separated;
+        self
+    }
+
+    /// Defaults to none, which includes every file.
+    pub fn include(mut self, include: Vec<String>) -> Self {
+        self.include = include;
+        self
+    }
+
+    /// Defaults to none.
+    pub fn exclude(mut self, exclude: Vec<String>) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Defaults to `false`, which fails the tasks for names the shell can't be given.
+    pub fn skip_invalid_names(mut self, skip_invalid_names: bool) -> Self {
+        self.skip_invalid_names = skip_invalid_names;
+        self
+    }
+
+    /// Defaults to the source directory's name with `-results` appended, next to it,
+    /// or `stdin-results` in the current directory for inputs read from standard input.
+    pub fn destination_dir(mut self, destination_dir: impl Into<PathBuf>) -> Self {
+        self.destination_dir = Some(destination_dir.into());
+        self
+    }
+
+    /// Defaults to false.
+    pub fn nested_destination(mut self, nested_destination: bool) -> Self {
+        self.nested_destination = nested_destination;
+        self
+    }
+
+    /// Defaults to `Order::Unordered`.
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Defaults to `None`.
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Defaults to `Naming::Name`.
+    pub fn naming(mut self, naming: Naming) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    /// Defaults to false.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Defaults to the inputs in the sources.
+    pub fn from_manifest(mut self, from_manifest: Option<PathBuf>) -> Self {
+        self.from_manifest = from_manifest;
+        self
+    }
+
+    /// Defaults to `.reach` inside the destination directory.
+    pub fn state_dir(mut self, state_dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(state_dir.into());
+        self
+    }
+
+    /// Defaults to `$SHELL`, or `/bin/sh` if that isn't set. On Windows, defaults to
+    /// `%ComSpec%`, or `cmd.exe` if that isn't set.
+    pub fn shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = Some(shell.into());
+        self
+    }
+
+    /// Defaults to the number of CPUs.
+    pub fn num_processes(mut self, num_processes: usize) -> Self {
+        self.num_processes = Some(num_processes);
+        self
+    }
+
+    /// Defaults to false.
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.low_memory = low_memory;
+        self
+    }
+
+    /// Defaults to `None`, so every file in the sources is an input of its own.
+    pub fn split_bytes(mut self, split_bytes: Option<u64>) -> Self {
+        self.split_bytes = split_bytes;
+        self
+    }
+
+    /// Defaults to 1, so every input gets a command of its own.
+    pub fn batch(mut self, batch: usize) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// Defaults to 64.
+    pub fn io_concurrency(mut self, io_concurrency: usize) -> Self {
+        self.io_concurrency = io_concurrency;
+        self
+    }
+
+    pub fn prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Defaults to `InputMode::detect` of the command.
+    pub fn input_mode(mut self, input_mode: InputMode) -> Self {
+        self.input_mode = Some(input_mode);
+        self
+    }
+
+    /// Defaults to `Framing::Length`.
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn recreate(mut self, recreate: bool) -> Self {
+        self.recreate = recreate;
+        self
+    }
+
+    pub fn allow_command_change(mut self, allow_command_change: bool) -> Self {
+        self.allow_command_change = allow_command_change;
+        self
+    }
+
+    pub fn hash_inputs(mut self, hash_inputs: bool) -> Self {
+        self.hash_inputs = hash_inputs;
+        self
+    }
+
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    pub fn watch(mut self, watch: Option<Duration>) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    pub fn notify_systemd(mut self, notify_systemd: bool) -> Self {
+        self.notify_systemd = notify_systemd;
+        self
+    }
+
+    pub fn retry_failed(mut self, retry_failed: bool) -> Self {
+        self.retry_failed = retry_failed;
+        self
+    }
+
+    pub fn rerun_matching(mut self, rerun_matching: Option<String>) -> Self {
+        self.rerun_matching = rerun_matching;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Defaults to none.
+    pub fn retry_signals(mut self, retry_signals: Vec<i32>) -> Self {
+        self.retry_signals = retry_signals;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn halt(mut self, halt: Halt) -> Self {
+        self.halt = halt;
+        self
+    }
+
+    pub fn max_total_output(mut self, max_total_output: Option<u64>) -> Self {
+        self.max_total_output = max_total_output;
+        self
+    }
+
+    /// Defaults to `OutputPolicy::Stop`.
+    pub fn output_policy(mut self, output_policy: OutputPolicy) -> Self {
+        self.output_policy = output_policy;
+        self
+    }
+
+    /// Defaults to `Capture::Separate`.
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Defaults to `out`.
+    pub fn stdout_name(mut self, stdout_name: impl Into<String>) -> Self {
+        self.stdout_name = stdout_name.into();
+        self
+    }
+
+    /// Defaults to `err`.
+    pub fn stderr_name(mut self, stderr_name: impl Into<String>) -> Self {
+        self.stderr_name = stderr_name.into();
+        self
+    }
+
+    pub fn max_output_size(mut self, max_output_size: Option<u64>) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    /// Defaults to grouping tasks by their input's extension.
+    pub fn group_by(mut self, group_by: Option<String>) -> Self {
+        self.group_by = group_by;
+        self
+    }
+
+    pub fn max_rate(mut self, max_rate: Option<f64>) -> Self {
+        self.max_rate = max_rate;
+        self
+    }
+
+    pub fn max_load(mut self, max_load: Option<f64>) -> Self {
+        self.max_load = max_load;
+        self
+    }
+
+    pub fn window(mut self, window: Option<String>) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn pause(mut self, pause: Option<PauseToken>) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    pub fn shell_sessions(mut self, shell_sessions: bool) -> Self {
+        self.shell_sessions = shell_sessions;
+        self
+    }
+
+    pub fn affinity(mut self, affinity: Option<String>) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    pub fn setup(mut self, setup: Option<String>) -> Self {
+        self.setup = setup;
+        self
+    }
+
+    pub fn teardown(mut self, teardown: Option<String>) -> Self {
+        self.teardown = teardown;
+        self
+    }
+
+    pub fn before_all(mut self, before_all: Option<String>) -> Self {
+        self.before_all = before_all;
+        self
+    }
+
+    pub fn after_all(mut self, after_all: Option<String>) -> Self {
+        self.after_all = after_all;
+        self
+    }
+
+    pub fn workdir(mut self, workdir: Option<String>) -> Self {
+        self.workdir = workdir;
+        self
+    }
+
+    pub fn then(mut self, then: Vec<String>) -> Self {
+        self.then = then;
+        self
+    }
+
+    pub fn wrap(mut self, wrap: Option<String>) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn systemd_scope(mut self, systemd_scope: bool) -> Self {
+        self.systemd_scope = systemd_scope;
+        self
+    }
+
+    pub fn systemd_properties(mut self, systemd_properties: Vec<String>) -> Self {
+        self.systemd_properties = systemd_properties;
+        self
+    }
+
+    /// Defaults to reach's own user and group.
+    pub fn run_as(mut self, run_as: Option<RunAs>) -> Self {
+        self.run_as = run_as;
+        self
+    }
+
+    /// Defaults to reach's own niceness.
+    pub fn nice(mut self, nice: Option<i32>) -> Self {
+        self.nice = nice;
+        self
+    }
+
+    /// Defaults to `None`, for no limit.
+    pub fn memory_limit(mut self, memory_limit: Option<u64>) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Defaults to `None`, which lets every command run on any of the CPUs.
+    pub fn cpus_per_task(mut self, cpus_per_task: Option<usize>) -> Self {
+        self.cpus_per_task = cpus_per_task;
+        self
+    }
+
+    pub fn task_max_fds(mut self, task_max_fds: Option<u64>) -> Self {
+        self.task_max_fds = task_max_fds;
+        self
+    }
+
+    pub fn task_max_procs(mut self, task_max_procs: Option<u64>) -> Self {
+        self.task_max_procs = task_max_procs;
+        self
+    }
+
+    /// Defaults to false.
+    pub fn restrict_fs(mut self, restrict_fs: bool) -> Self {
+        self.restrict_fs = restrict_fs;
+        self
+    }
+
+    pub fn restrict_fs_read(mut self, restrict_fs_read: Vec<PathBuf>) -> Self {
+        self.restrict_fs_read = restrict_fs_read;
+        self
+    }
+
+    pub fn workers(mut self, workers: Vec<Worker>) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn ssh(mut self, ssh: impl Into<String>) -> Self {
+        self.ssh = ssh.into();
+        self
+    }
+
+    /// Fill in the defaults. The destination directory isn't created until the run starts.
+    pub fn build(self) -> io::Result<Config> {
+        let from_stdin = self.source_dir == Path::new(STDIN_SOURCE);
+        let source_dir = if from_stdin {
+            self.source_dir.clone()
+        } else {
+            self.source_dir.canonicalize().map_err(|error| {
+                error::new(
+                    error.kind(),
+                    Message::InvalidSourceDir {
+                        path: &self.source_dir,
+                        error: &error,
+                    },
+                )
+            })?
+        };
+        let more_sources = self
+            .more_sources
+            .iter()
+            .map(|source| {
+                source.canonicalize().map_err(|error| {
+                    error::new(
+                        error.kind(),
+                        Message::InvalidSource {
+                            path: source,
+                            error: &error,
+                        },
+                    )
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let destination_dir = match self.destination_dir {
+            Some(destination_dir) => destination_dir,
+            None if from_stdin => std::env::current_dir()?.join(STDIN_DESTINATION_DIR),
+            None => default_destination_dir(&source_dir)?,
+        };
+        let destination_dir = resolve_destination_directory(destination_dir)?;
+        let state_dir = self
+            .state_dir
+            .unwrap_or_else(|| destination_dir.join(STATE_DIR));
+        let command = &self.command;
+        let input_mode = self
+            .input_mode
+            .unwrap_or_else(|| InputMode::detect(command));
+        Ok(Config {
+            command: self.command,
+            shell: self.shell.unwrap_or_else(shell::default_shell),
+            source_dir,
+            from_stdin,
+            null_separated: self.null_separated,
+            more_sources,
+            include: self.include,
+            exclude: self.exclude,
+            skip_invalid_names: self.skip_invalid_names,
+            destination_dir,
+            nested_destination: self.nested_destination,
+            order: self.order,
+            seed: self.seed,
+            naming: self.naming,
+            manifest: self.manifest,
+            from_manifest: self.from_manifest,
+            state_dir,
+            num_processes: self.num_processes.unwrap_or_else(num_cpus::get),
+            low_memory: self.low_memory,
+            split_bytes: self.split_bytes,
+            batch: self.batch,
+            io_concurrency: self.io_concurrency,
+            prefetch: self.prefetch,
+            input_mode,
+            framing: self.framing,
+            recreate: self.recreate,
+            allow_command_change: self.allow_command_change,
+            hash_inputs: self.hash_inputs,
+            checksums: self.checksums,
+            watch: self.watch,
+            notify_systemd: self.notify_systemd,
+            retry_failed: self.retry_failed,
+            rerun_matching: self.rerun_matching,
+            retries: self.retries,
+            retry_signals: self.retry_signals,
+            timeout: self.timeout,
+            halt: self.halt,
+            max_total_output: self.max_total_output,
+            output_policy: self.output_policy,
+            capture: self.capture,
+            stdout_name: self.stdout_name,
+            stderr_name: self.stderr_name,
+            max_output_size: self.max_output_size,
+            group_by: self.group_by,
+            max_rate: self.max_rate,
+            max_load: self.max_load,
+            window: self.window,
+            pause: self.pause,
+            shell_sessions: self.shell_sessions,
+            affinity: self.affinity,
+            setup: self.setup,
+            teardown: self.teardown,
+            before_all: self.before_all,
+            after_all: self.after_all,
+            workdir: self.workdir,
+            then: self.then,
+            wrap: self.wrap,
+            systemd_scope: self.systemd_scope,
+            systemd_properties: self.systemd_properties,
+            run_as: self.run_as,
+            nice: self.nice,
+            memory_limit: self.memory_limit,
+            cpus_per_task: self.cpus_per_task,
+            task_max_fds: self.task_max_fds,
+            task_max_procs: self.task_max_procs,
+            restrict_fs: self.restrict_fs,
+            restrict_fs_read: self.restrict_fs_read,
+            workers: self.workers,
+            ssh: self.ssh,
+        })
+    }
+}
+
+/// Make up a path to the destination directory from the source directory.
+/// `foo` becomes `foo-results`
+fn default_destination_dir(source_dir: &Path) -> io::Result<PathBuf> {
+    let mut file_name = source_dir
+        .file_name()
+        .ok_or_else(|| {
+            error::new(
+                io::ErrorKind::InvalidInput,
+                Message::NoDefaultDestination(source_dir),
+            )
+        })?
+        .to_owned();
+    file_name.push("-results");
+    let mut dest = source_dir.to_path_buf();
+    dest.set_file_name(file_name);
+    Ok(dest)
+}
+
+/// The destination directory, with any links resolved if it exists already.
+fn resolve_destination_directory(destination: PathBuf) -> io::Result<PathBuf> {
+    match destination.canonicalize() {
+        Ok(destination) => Ok(destination),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(destination),
+        Err(error) => Err(error::new(
+            error.kind(),
+            Message::InvalidDestination {
+                path: &destination,
+                error: &error,
+            },
+        )),
+    }
+}
+
+/// Create the destination directory if it doesn't exist, once the run is starting.
+pub(crate) fn ensure_destination_directory(destination: &Path) -> io::Result<()> {
+    fs::create_dir_all(destination).map_err(|error| {
+        error::new(
+            error.kind(),
+            Message::DestinationUncreatable {
+                path: destination,
+                error: &error,
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_destination_dir() {
+        assert_eq!(
+            PathBuf::from("/data/photos-results"),
+            default_destination_dir(Path::new("/data/photos")).unwrap()
+        );
+        assert!(default_destination_dir(Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_to_json() -> io::Result<()> {
+        let source = tempfile::tempdir()?;
+        let destination = tempfile::tempdir()?;
+        let config = Config::builder("wc -l {}", source.path())
+            .destination_dir(destination.path())
+            .shell("/bin/sh")
+            .halt(Halt::OnError(3))
+            .timeout(Some(Duration::from_millis(1500)))
+            .build()?;
+        let json = config.to_json();
+        assert_eq!("wc -l {}", json["command"]);
+        assert_eq!("filename", json["input_mode"]);
+        assert_eq!("on-error:3", json["halt"]);
+        assert_eq!(1.5, json["timeout_secs"]);
+        assert_eq!(serde_json::Value::Null, json["watch_secs"]);
+        assert_eq!(
+            *destination.path().join(STATE_DIR).to_string_lossy(),
+            json["state_dir"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Settings read from a config file like `reach.toml`, so that a long command line can be
+//! kept next to the data it runs on.
+//!
+//! The file is TOML, with `name = value` settings and no tables. The values are strings,
+//! integers, floats, booleans, and arrays of those. What each setting means is up to
+//! whoever reads the file; `reach` takes them as its command-line options.
+
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::fs;
+
+use crate::{error, Message};
+
+/// The settings in a config file, in the order they're given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigFile {
+    settings: Vec<(String, ConfigValue)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<ConfigValue>),
+}
+
+impl ConfigFile {
+    /// Read the settings in the file at `path`.
+    pub async fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path).await.map_err(|error| {
+            error::new(
+                error.kind(),
+                Message::ConfigFileUnreadable {
+                    path,
+                    error: &error,
+                },
+            )
+        })?;
+        text.parse().map_err(|problem: String| {
+            error::new(
+                io::ErrorKind::InvalidInput,
+                Message::InvalidConfigFile {
+                    path,
+                    problem: &problem,
+                },
+            )
+        })
+    }
+
+    pub fn settings(&self) -> &[(String, ConfigValue)] {
+        &self.settings
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let table: toml::Table =
+            toml::from_str(s).map_err(|error| error.to_string().trim_end().to_owned())?;
+        let settings = table
+            .into_iter()
+            .map(|(name, value)| {
+                let value = ConfigValue::try_from(value)
+                    .map_err(|problem| format!("{}: {}", name, problem))?;
+                Ok((name, value))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(ConfigFile { settings })
+    }
+}
+
+impl TryFrom<toml::Value> for ConfigValue {
+    type Error = &'static str;
+
+    fn try_from(value: toml::Value) -> Result<Self, &'static str> {
+        match value {
+            toml::Value::String(value) => Ok(ConfigValue::String(value)),
+            toml::Value::Integer(value) => Ok(ConfigValue::Integer(value)),
+            toml::Value::Float(value) => Ok(ConfigValue::Float(value)),
+            toml::Value::Boolean(value) => Ok(ConfigValue::Boolean(value)),
+            toml::Value::Array(values) => values
+                .into_iter()
+                .map(ConfigValue::try_from)
+                .collect::<Result<_, _>>()
+                .map(ConfigValue::Array),
+            toml::Value::Datetime(_) => Err("Dates and times aren't supported"),
+            toml::Value::Table(_) => Err("Tables aren't supported"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Vec<(String, ConfigValue)>, String> {
+        s.parse::<ConfigFile>().map(|file| file.settings)
+    }
+
+    #[test]
+    fn test_parse() {
+        use ConfigValue::*;
+        let file = r#"
+# The pipeline.
+command = "gzip -c > \"$REACH_DEST_DIR/out.gz\""  # Compress each input.
+processes = 8
+max-rate = 2.5
+retry_failed = true
+'stdout-name' = 'C:\out'
+then = [
+    "sha256sum",  # Checksums, too.
+    'wc -c',
+]
+setup = """
+set -e
+echo \
+  hi"""
+"#;
+        assert_eq!(
+            Ok(vec![
+                (
+                    "command".into(),
+                    String("gzip -c > \"$REACH_DEST_DIR/out.gz\"".into())
+                ),
+                ("processes".into(), Integer(8)),
+                ("max-rate".into(), Float(2.5)),
+                ("retry_failed".into(), Boolean(true)),
+                ("stdout-name".into(), String("C:\\out".into())),
+                (
+                    "then".into(),
+                    Array(vec![String("sha256sum".into()), String("wc -c".into())])
+                ),
+                ("setup".into(), String("set -e\necho hi".into())),
+            ]),
+            parse(file)
+        );
+        assert_eq!(Ok(vec![]), parse(""));
+        assert_eq!(
+            Ok(vec![("a".into(), String("\"x\"".into()))]),
+            parse("a = '''\"x\"'''")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = parse("a = 1\na = 2").unwrap_err();
+        assert!(error.starts_with("TOML parse error at line 2"), "{}", error);
+        assert!(
+            error.ends_with("duplicate key `a` in document root"),
+            "{}",
+            error
+        );
+        assert_eq!(
+            Err("hooks: Tables aren't supported".into()),
+            parse("[hooks]\n")
+        );
+        assert_eq!(Err("a: Tables aren't supported".into()), parse("a.b = 1"));
+        assert_eq!(
+            Err("a: Tables aren't supported".into()),
+            parse("a = [1, { b = 2 }]")
+        );
+        assert_eq!(
+            Err("a: Dates and times aren't supported".into()),
+            parse("a = 1979-05-27")
+        );
+        assert!(parse("a = \"b\nc").is_err());
+        assert!(parse("a = 1 b = 2").is_err());
+        assert!(parse("a").is_err());
+        assert!(parse("a =").is_err());
+        assert!(parse("a = [1, 2").is_err());
+        assert!(parse("a = \"\\q\"").is_err());
+    }
+}
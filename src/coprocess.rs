@@ -0,0 +1,275 @@
+//! Coprocesses: long-lived workers that reach streams inputs to, one after another.
+//!
+//! Rather than running the command once for each task, reach starts it once for each
+//! process slot and keeps it running. Each task writes its input to an idle worker's
+//! standard input as one framed request, and the worker's framed response on its
+//! standard output becomes the task's `out`. Workers that are slow to start, because
+//! they load a model or warm up a JIT, only pay for it once.
+//!
+//! A worker should read the whole of a request before responding to it, as reach
+//! only starts reading the response once the request has been written.
+
+use async_trait::async_trait;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::capture::{self, Outputs};
+use crate::error;
+use crate::limits::CpuClaim;
+use crate::pool::{Lease, Pool, Reservation};
+use crate::{Framing, Launcher, Message, Process, Task};
+
+/// Launches each task by sending its input to a worker running `command`.
+pub(crate) struct Coprocesses {
+    shell: String,
+    command: String,
+    framing: Framing,
+    /// Where every worker's standard error goes, as it isn't any one task's.
+    log: PathBuf,
+    outputs: Outputs,
+    workers: Pool<Coprocess>,
+}
+
+impl Coprocesses {
+    /// Send tasks to workers taken from `workers`, which run `command` with `shell`,
+    /// and write their responses to `outputs`.
+    pub(crate) fn new(
+        shell: String,
+        command: String,
+        framing: Framing,
+        state_dir: &Path,
+        outputs: Outputs,
+        workers: Pool<Coprocess>,
+    ) -> Self {
+        Coprocesses {
+            shell,
+            command,
+            framing,
+            log: state_dir.join("coprocess.err"),
+            outputs,
+            workers,
+        }
+    }
+
+    fn start(&self) -> io::Result<Coprocess> {
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log)?;
+        let mut command = Command::new(&self.shell);
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(log)
+            .kill_on_drop(true);
+        crate::in_own_process_group(&mut command);
+        if let Some(run_as) = self.outputs.run_as() {
+            run_as.apply(&mut command);
+        }
+        let cpus = self.outputs.limits().apply(&mut command);
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().expect("Coprocess stdin is piped");
+        let stdout = child.stdout.take().expect("Coprocess stdout is piped");
+        Ok(Coprocess {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            framing: self.framing,
+            out: None,
+            broken: false,
+            _cpus: cpus,
+        })
+    }
+}
+
+#[async_trait]
+impl Launcher for Coprocesses {
+    type Process = Lease<Coprocess>;
+
+    async fn reserve(&self, task: &Task<'_>) -> Reservation {
+        self.workers.reserve(task.inputs, task.dir()).await
+    }
+
+    /// Workers outlive their tasks, so unlike other commands, they don't get told about each
+    /// one in environment variables.
+    async fn launch(
+        &self,
+        task: &Task<'_>,
+        reservation: Reservation,
+    ) -> io::Result<Lease<Coprocess>> {
+        let input = fs::File::open(task.input()).await?;
+        // A discarded response still has to be read, to keep the worker in step.
+        let out = match self.outputs.stdout_path(task.dir()) {
+            Some(stdout) => capture::create(&stdout).await?,
+            None => fs::OpenOptions::new().write(true).open("/dev/null").await?,
+        };
+        // Workers' complaints go to the log, but every task has an `err`, even if it's empty.
+        if let Some(stderr) = self.outputs.stderr_path(task.dir()) {
+            capture::create(&stderr).await?;
+        }
+        let mut worker = self.workers.take(reservation, || self.start())?;
+        match worker.send(input).await {
+            Ok(()) => {
+                worker.out = Some(out);
+                Ok(worker)
+            }
+            Err(error) => {
+                self.finished(worker);
+                Err(error)
+            }
+        }
+    }
+
+    /// Give back a worker once it has responded, unless it's no longer usable.
+    fn finished(&self, worker: Lease<Coprocess>) {
+        if !worker.broken {
+            self.workers.put(worker);
+        }
+    }
+}
+
+/// A running worker, handling one request at a time.
+pub(crate) struct Coprocess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    framing: Framing,
+    /// The `out` of the task whose request the worker is handling.
+    out: Option<fs::File>,
+    /// Whether the worker has died, or got out of step with its protocol.
+    ///
+    /// Set while a request or response is only partly sent, so that a task that
+    /// fails or is cancelled halfway through one never hands its worker on.
+    broken: bool,
+    /// The CPUs the worker has to itself, for as long as it runs.
+    _cpus: Option<CpuClaim>,
+}
+
+impl Coprocess {
+    /// Send the contents of `input` to the worker as a request.
+    async fn send(&mut self, mut input: fs::File) -> io::Result<()> {
+        match self.framing {
+            Framing::Length => {
+                let size = input.metadata().await?.len();
+                self.broken = true;
+                self.stdin
+                    .write_all(format!("{}\n", size).as_bytes())
+                    .await?;
+                let sent = tokio::io::copy(&mut input.take(size), &mut self.stdin).await?;
+                if sent < size {
+                    return Err(error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        Message::InputShrank,
+                    ));
+                }
+            }
+            Framing::Line => {
+                let mut line = Vec::new();
+                input.read_to_end(&mut line).await?;
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                if line.contains(&b'\n') {
+                    return Err(error::new(
+                        io::ErrorKind::InvalidData,
+                        Message::InputNotOneLine,
+                    ));
+                }
+                line.push(b'\n');
+                self.broken = true;
+                self.stdin.write_all(&line).await?;
+            }
+        }
+        self.stdin.flush().await?;
+        self.broken = false;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Process for Coprocess {
+    /// Wait for the worker's response, and write it to the task's `out`.
+    ///
+    /// A response always counts as success, as the protocol has no other kind.
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.broken = true;
+        let out = self
+            .out
+            .as_mut()
+            .expect("Coprocess has a request to respond to");
+        let complete = match self.framing {
+            Framing::Length => {
+                let mut header = Vec::new();
+                read_line(&mut self.stdout, &mut header).await?;
+                let size = parse_length(&header)?;
+                tokio::io::copy(&mut (&mut self.stdout).take(size), out).await? == size
+            }
+            Framing::Line => {
+                let mut line = Vec::new();
+                read_line(&mut self.stdout, &mut line).await?;
+                out.write_all(&line).await?;
+                true
+            }
+        };
+        if !complete {
+            return Err(exited_error());
+        }
+        out.flush().await?;
+        self.out = None;
+        self.broken = false;
+        Ok(ExitStatus::from_raw(0))
+    }
+
+    /// Terminate the worker, which can't be trusted with another request.
+    async fn terminate(&mut self) -> io::Result<()> {
+        self.broken = true;
+        crate::terminate(&mut self.child).await
+    }
+}
+
+/// Read one whole line, newline and all, failing if the worker exits first.
+async fn read_line(reader: &mut BufReader<ChildStdout>, line: &mut Vec<u8>) -> io::Result<()> {
+    reader.read_until(b'\n', line).await?;
+    if line.last() == Some(&b'\n') {
+        Ok(())
+    } else {
+        Err(exited_error())
+    }
+}
+
+fn exited_error() -> io::Error {
+    error::new(io::ErrorKind::UnexpectedEof, Message::CoprocessExited)
+}
+
+/// The size of a response, from the line that comes before it.
+fn parse_length(header: &[u8]) -> io::Result<u64> {
+    std::str::from_utf8(header)
+        .ok()
+        .and_then(|header| header.trim().parse().ok())
+        .ok_or_else(|| {
+            error::new(
+                io::ErrorKind::InvalidData,
+                Message::CoprocessBadLength(&String::from_utf8_lossy(header)),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_length() {
+        assert_eq!(0, parse_length(b"0\n").unwrap());
+        assert_eq!(1234, parse_length(b"1234\n").unwrap());
+        assert!(parse_length(b"-1\n").is_err());
+        assert!(parse_length(b"lots\n").is_err());
+    }
+}
@@ -0,0 +1,543 @@
+//! A dashboard for the terminal, showing what a run is doing as it goes.
+//!
+//! Unlike a progress bar, it shows each running task and how long it's been running,
+//! the tasks that finished most recently and how, and how many tasks a second the run is
+//! getting through. Pressing a running task's number shows the end of its standard error
+//! as it's written, and `0` hides it again.
+
+use console::Term;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::progress::{Progress, TaskOutcome};
+use crate::{Message, Status, TaskId};
+
+/// How often the dashboard is drawn again.
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// How many of the most recently finished tasks to show.
+const RECENT: usize = 5;
+
+/// How many running tasks can be picked with a key, `1` to `9`.
+const PICKABLE: usize = 9;
+
+/// How many lines of a task's standard error to show.
+const TAIL_LINES: usize = 10;
+
+/// How much of the end of a task's standard error to read, to find its last lines.
+const TAIL_BYTES: u64 = 8 * 1024;
+
+/// A progress reporter that draws a dashboard on standard error, redrawn a few times a second.
+///
+/// It's only drawn if standard error is a terminal, and only takes keys if standard input is one.
+pub struct Dashboard {
+    state: Arc<Mutex<State>>,
+    drawer: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// A dashboard that can't show tasks' output.
+    pub fn new() -> Self {
+        Self::start(None)
+    }
+
+    /// A dashboard that shows output from the file `file_name` in each task's directory in
+    /// `destination_dir`, like `err`.
+    pub fn showing_output(destination_dir: &Path, file_name: &str) -> Self {
+        Self::start(Some((destination_dir.to_owned(), file_name.to_owned())))
+    }
+
+    fn start(output: Option<(PathBuf, String)>) -> Self {
+        let term = Term::stderr();
+        let state = Arc::new(Mutex::new(State::new(output, Instant::now())));
+        let drawer = if term.is_term() {
+            let state = state.clone();
+            Some(thread::spawn(move || draw_until_stopped(&term, &state)))
+        } else {
+            None
+        };
+        Dashboard { state, drawer }
+    }
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().stopped = true;
+        if let Some(drawer) = self.drawer.take() {
+            let _ = drawer.join();
+        }
+    }
+}
+
+impl Progress for Dashboard {
+    fn set_num_tasks(&self, tasks: usize) {
+        self.state.lock().unwrap().num_tasks = tasks;
+    }
+
+    fn task_started(&self, id: &TaskId, input: &Path) {
+        self.state.lock().unwrap().running.push(Running {
+            id: id.clone(),
+            input: input.to_owned(),
+            started: Instant::now(),
+            attempt: 1,
+        });
+    }
+
+    fn task_retrying(
+        &self,
+        id: &TaskId,
+        input: &Path,
+        attempt: u32,
+        _result: &io::Result<ExitStatus>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        for running in &mut state.running {
+            if &running.id == id && running.input == input {
+                running.attempt = attempt;
+            }
+        }
+    }
+
+    fn task_completed(&self, id: &TaskId, outcome: &TaskOutcome<'_>) {
+        self.state
+            .lock()
+            .unwrap()
+            .finish(id, outcome.input(), outcome.result, outcome.duration());
+    }
+
+    fn warn(&self, warning: &Message<'_>) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.push(Message::Warning(&warning.text()).text());
+        if self.drawer.is_none() {
+            for line in state.pending.drain(..) {
+                eprintln!("{}", line);
+            }
+        }
+    }
+}
+
+/// What the dashboard knows about the run.
+#[derive(Debug)]
+struct State {
+    num_tasks: usize,
+    succeeded: usize,
+    failed: usize,
+    started: Instant,
+    /// In the order they started.
+    running: Vec<Running>,
+    /// The most recent last.
+    recent: VecDeque<Finished>,
+    /// The running task whose output is being shown.
+    showing: Option<TaskId>,
+    /// The destination directory and the name of the file in each task's directory to show.
+    output: Option<(PathBuf, String)>,
+    /// Lines to print above the dashboard when it's next drawn, like warnings.
+    pending: Vec<String>,
+    stopped: bool,
+}
+
+#[derive(Debug)]
+struct Running {
+    id: TaskId,
+    input: PathBuf,
+    started: Instant,
+    /// Which attempt it's on, counting from one.
+    attempt: u32,
+}
+
+#[derive(Debug)]
+struct Finished {
+    input: PathBuf,
+    /// How it finished, like `exit code 1`, or `None` if it succeeded.
+    failure: Option<String>,
+    duration: Duration,
+}
+
+impl State {
+    fn new(output: Option<(PathBuf, String)>, started: Instant) -> Self {
+        State {
+            num_tasks: 0,
+            succeeded: 0,
+            failed: 0,
+            started,
+            running: Vec::new(),
+            recent: VecDeque::new(),
+            showing: None,
+            output,
+            pending: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    fn finish(
+        &mut self,
+        id: &TaskId,
+        input: &Path,
+        result: &io::Result<ExitStatus>,
+        duration: Duration,
+    ) {
+        if let Some(i) = self
+            .running
+            .iter()
+            .position(|running| &running.id == id && running.input == input)
+        {
+            self.running.remove(i);
+        }
+        if self.showing.as_ref() == Some(id) && !self.running.iter().any(|r| &r.id == id) {
+            self.showing = None;
+        }
+        let failure = match result {
+            Ok(status) if status.success() => None,
+            Ok(status) => Some(match Status::from(*status) {
+                Status::Exited(code) => Message::ExitCode(code).text(),
+                status => status.to_string(),
+            }),
+            Err(error) => Some(error.to_string()),
+        };
+        if failure.is_some() {
+            self.failed += 1;
+        } else {
+            self.succeeded += 1;
+        }
+        if self.recent.len() == RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Finished {
+            input: input.to_owned(),
+            failure,
+            duration,
+        });
+    }
+
+    /// Act on the key `key`: show the output of the running task with that number, or stop
+    /// showing any for `0`.
+    fn press(&mut self, key: char) {
+        match key.to_digit(10) {
+            Some(0) => self.showing = None,
+            Some(n) => {
+                if let Some(running) = self.running.get(n as usize - 1) {
+                    self.showing = Some(running.id.clone());
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// The dashboard's lines at `now`, each at most `width` columns wide.
+    fn render(&self, now: Instant, width: usize, keys: bool) -> Vec<String> {
+        let done = self.succeeded + self.failed;
+        let elapsed = now.saturating_duration_since(self.started);
+        let per_sec = if elapsed.as_secs_f64() > 0.0 {
+            done as f64 / elapsed.as_secs_f64()
+        } else {
+            0.0
+        };
+        let mut lines = vec![Message::DashboardProgress {
+            done,
+            tasks: self.num_tasks,
+            failed: self.failed,
+            running: self.running.len(),
+            per_sec,
+            elapsed: &clock(elapsed),
+        }
+        .text()];
+
+        lines.push(Message::DashboardRunning.text());
+        for (i, running) in self.running.iter().enumerate().take(PICKABLE) {
+            let marker = if self.showing.as_ref() == Some(&running.id) {
+                '>'
+            } else {
+                ' '
+            };
+            let attempt = match running.attempt {
+                1 => String::new(),
+                attempt => format!(" {}", Message::Attempt(attempt).text()),
+            };
+            lines.push(format!(
+                "{}{} {:>8} {}{}",
+                marker,
+                i + 1,
+                clock(now.saturating_duration_since(running.started)),
+                running.input.display(),
+                attempt,
+            ));
+        }
+        if self.running.len() > PICKABLE {
+            let more = self.running.len() - PICKABLE;
+            lines.push(format!("   {}", Message::DashboardMore { more }.text()));
+        }
+
+        if !self.recent.is_empty() {
+            lines.push(Message::DashboardRecent.text());
+            for finished in self.recent.iter().rev() {
+                let failed = finished.failure.is_some();
+                let mark = Message::DashboardFinished { failed }.text();
+                let how = match &finished.failure {
+                    None => String::new(),
+                    Some(failure) => format!(": {}", failure),
+                };
+                lines.push(format!(
+                    "  {:<6} {:>8} {}{}",
+                    mark,
+                    clock(finished.duration),
+                    finished.input.display(),
+                    how,
+                ));
+            }
+        }
+
+        if let (Some(id), Some((destination_dir, file_name))) = (&self.showing, &self.output) {
+            if let Some(running) = self.running.iter().find(|running| &running.id == id) {
+                lines.push(
+                    Message::DashboardOutput {
+                        name: file_name,
+                        input: &running.input,
+                    }
+                    .text(),
+                );
+                let path = destination_dir
+                    .join(running.input.file_name().unwrap_or_default())
+                    .join(file_name);
+                for line in tail(&path, TAIL_LINES) {
+                    lines.push(format!("  {}", line));
+                }
+            }
+        }
+        if keys {
+            let output_kept = self.output.is_some();
+            lines.push(Message::DashboardKeys { output_kept }.text());
+        }
+        lines
+            .into_iter()
+            .map(|line| console::truncate_str(&line, width, "…").into_owned())
+            .collect()
+    }
+}
+
+/// Draw the dashboard on `term` every `REFRESH`, taking keys from standard input if it's a
+/// terminal, until it's stopped, and then draw it once more without them.
+fn draw_until_stopped(term: &Term, state: &Mutex<State>) {
+    let keys = Keys::new();
+    let mut drawn = 0;
+    loop {
+        let key = match &keys {
+            Some(keys) => keys.next(REFRESH),
+            None => {
+                thread::sleep(REFRESH);
+                None
+            }
+        };
+        let mut state = state.lock().unwrap();
+        if let Some(key) = key {
+            state.press(key);
+        }
+        let stopped = state.stopped;
+        let width = term.size().1 as usize;
+        let lines = state.render(Instant::now(), width, keys.is_some() && !stopped);
+        let _ = term.clear_last_lines(drawn);
+        for line in state.pending.drain(..) {
+            let _ = term.write_line(&line);
+        }
+        for line in &lines {
+            let _ = term.write_line(line);
+        }
+        drawn = lines.len();
+        if stopped {
+            return;
+        }
+    }
+}
+
+/// A duration as `m:ss`, or `h:mm:ss` if it's an hour or more.
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 * 60 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// The last `n` lines of the file at `path`, or none if it can't be read.
+fn tail(path: &Path, n: usize) -> Vec<String> {
+    let read = || -> io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(TAIL_BYTES)))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    };
+    let bytes = read().unwrap_or_default();
+    let text = String::from_utf8_lossy(&bytes);
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(n)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Keys pressed on the terminal that's standard input, read a byte at a time without waiting
+/// for a whole line.
+///
+/// The terminal is put back the way it was when this is dropped. Ctrl-C still interrupts the run.
+#[cfg(unix)]
+struct Keys {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl Keys {
+    fn new() -> Option<Self> {
+        // SAFETY: `termios` is plain data, and these only read and write the one given.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            Some(Keys { original })
+        }
+    }
+
+    /// The next key pressed, waiting no longer than `timeout` for one.
+    fn next(&self, timeout: Duration) -> Option<char> {
+        let mut poll = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let mut byte = 0u8;
+        // SAFETY: Both only touch the one `pollfd` and byte given.
+        unsafe {
+            if libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) != 1 {
+                return None;
+            }
+            if libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) != 1 {
+                // Standard input has closed, so wait out the rest of the time instead.
+                thread::sleep(timeout);
+                return None;
+            }
+        }
+        Some(byte as char)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Keys {
+    fn drop(&mut self) {
+        // SAFETY: Puts back the settings read in `new`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Keys can't be read without waiting for a whole line here, so the dashboard doesn't take any.
+#[cfg(not(unix))]
+struct Keys;
+
+#[cfg(not(unix))]
+impl Keys {
+    fn new() -> Option<Self> {
+        None
+    }
+
+    fn next(&self, _timeout: Duration) -> Option<char> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render() -> io::Result<()> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let destination_dir = tempfile::tempdir()?;
+        std::fs::create_dir(destination_dir.path().join("b.txt"))?;
+        std::fs::write(
+            destination_dir.path().join("b.txt").join("err"),
+            "one\ntwo\nthree\n",
+        )?;
+        let start = Instant::now();
+        let mut state = State::new(
+            Some((destination_dir.path().to_owned(), "err".into())),
+            start,
+        );
+        state.num_tasks = 3;
+        let id = |name: &str| TaskId::from_parts(&[Some(name.as_bytes())]);
+        for name in &["a.txt", "b.txt"] {
+            state.running.push(Running {
+                id: id(name),
+                input: Path::new("/src").join(name),
+                started: start,
+                attempt: 2,
+            });
+        }
+        state.finish(
+            &id("a.txt"),
+            Path::new("/src/a.txt"),
+            &Ok(ExitStatus::from_raw(1 << 8)),
+            Duration::from_secs(1),
+        );
+        state.press('1');
+        assert_eq!(Some(id("b.txt")), state.showing);
+
+        let lines = state.render(start + Duration::from_secs(65), 80, true);
+        assert_eq!(
+            vec![
+                "1/3 done, 1 failed, 1 running, 0.0/s, 1:05 elapsed",
+                "Running:",
+                ">1     1:05 /src/b.txt (attempt 2)",
+                "Recent:",
+                "  failed     0:01 /src/a.txt: exit code 1",
+                "err of /src/b.txt:",
+                "  one",
+                "  two",
+                "  three",
+                "Press a running task's number to see its output, or 0 to hide it.",
+            ],
+            lines
+        );
+
+        assert!(state
+            .render(start, 20, false)
+            .iter()
+            .all(|line| console::measure_text_width(line) <= 20));
+        state.press('0');
+        assert_eq!(None, state.showing);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock() {
+        assert_eq!("0:09", clock(Duration::from_millis(9500)));
+        assert_eq!("59:59", clock(Duration::from_secs(3599)));
+        assert_eq!("1:01:40", clock(Duration::from_secs(3700)));
+    }
+}
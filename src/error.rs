@@ -0,0 +1,270 @@
+//! The errors that a run can fail with, saying what reach was doing, and with which path.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Message, Refusal, Unsupported};
+
+/// What reach was doing when it failed, with the path it was working on, if there was one.
+///
+/// Converts to and from `io::Error` with the same `kind`, so code written for `io::Error`
+/// keeps working. Errors that reach carries inside an `io::Error`, like the results of
+/// tasks that `Progress` is given, can be got at with `Error::of`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The configuration can't be run as it is.
+    Config(io::Error),
+    /// The source or input at `path` couldn't be read.
+    Source { path: PathBuf, source: io::Error },
+    /// The state directory at `path` couldn't be locked, read, or written.
+    State { path: PathBuf, source: io::Error },
+    /// One of the hooks that run once for the whole run, called `name`, failed.
+    Hook { name: String, source: io::Error },
+    /// The command for the task for the input at `path` couldn't be started.
+    Spawn { path: PathBuf, source: io::Error },
+    /// The task's destination directory at `path` couldn't be prepared or written.
+    Output { path: PathBuf, source: io::Error },
+    /// The run was interrupted before it finished.
+    Interrupted,
+    /// Anything else.
+    Io(io::Error),
+}
+
+impl Error {
+    /// The kind of the underlying error, or `Interrupted` for an interrupted run.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self.io() {
+            Some(error) => error.kind(),
+            None => io::ErrorKind::Interrupted,
+        }
+    }
+
+    /// The path reach was working on, if there was one.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Source { path, .. }
+            | Error::State { path, .. }
+            | Error::Spawn { path, .. }
+            | Error::Output { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The name of what reach was doing, like `spawn`.
+    pub fn phase(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Source { .. } => "source",
+            Error::State { .. } => "state",
+            Error::Hook { .. } => "hook",
+            Error::Spawn { .. } => "spawn",
+            Error::Output { .. } => "output",
+            Error::Interrupted => "interrupted",
+            Error::Io(_) => "io",
+        }
+    }
+
+    /// The reach error that `error` carries, if it carries one.
+    pub fn of(error: &io::Error) -> Option<&Error> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// The underlying error, unless the run was interrupted.
+    pub(crate) fn io(&self) -> Option<&io::Error> {
+        match self {
+            Error::Config(source)
+            | Error::Source { source, .. }
+            | Error::State { source, .. }
+            | Error::Hook { source, .. }
+            | Error::Spawn { source, .. }
+            | Error::Output { source, .. }
+            | Error::Io(source) => Some(source),
+            Error::Interrupted => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // These say what went wrong well enough already.
+            Error::Config(source) | Error::Hook { source, .. } | Error::Io(source) => {
+                write!(f, "{}", source)
+            }
+            Error::Source { path, source } => f.write_str(
+                &Message::Unreadable {
+                    path,
+                    error: source,
+                }
+                .text(),
+            ),
+            Error::State { path, source } => f.write_str(
+                &Message::StateDirFailed {
+                    path,
+                    error: source,
+                }
+                .text(),
+            ),
+            Error::Spawn { path, source } => f.write_str(
+                &Message::SpawnFailed {
+                    path,
+                    error: source,
+                }
+                .text(),
+            ),
+            Error::Output { path, source } => f.write_str(
+                &Message::OutputFailed {
+                    path,
+                    error: source,
+                }
+                .text(),
+            ),
+            Error::Interrupted => f.write_str(&Message::Interruption.text()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io().map(|error| error as _)
+    }
+}
+
+/// Takes back the reach error that `error` carries, if it carries one.
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        if Error::of(&error).is_none() {
+            return Error::Io(error);
+        }
+        let inner = error.into_inner().expect("It carries an error");
+        *inner.downcast().expect("It carries a reach error")
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
+
+/// `error`, saying that it happened while doing what `tag` makes of it,
+/// unless it already says what reach was doing.
+pub(crate) fn tagged(error: io::Error, tag: impl FnOnce(io::Error) -> Error) -> io::Error {
+    match Error::of(&error) {
+        Some(_) => error,
+        None => tag(error).into(),
+    }
+}
+
+/// For `map_err`: the configuration can't be run as it is.
+pub(crate) fn in_config(error: io::Error) -> io::Error {
+    tagged(error, Error::Config)
+}
+
+/// A configuration that can't be run, as `message` says.
+pub(crate) fn config_error<'a>(message: impl Into<Message<'a>>) -> io::Error {
+    Error::Config(new(io::ErrorKind::InvalidInput, message)).into()
+}
+
+/// An error of `kind` that says `message`, in the words of the installed messages.
+pub(crate) fn new<'a>(kind: io::ErrorKind, message: impl Into<Message<'a>>) -> io::Error {
+    io::Error::new(kind, message.into().text())
+}
+
+/// The error for a configuration that can't be run, before saying what reach was doing.
+pub(crate) fn refused(refusal: Refusal) -> io::Error {
+    new(io::ErrorKind::InvalidInput, refusal)
+}
+
+/// The error for something reach can't do here.
+pub(crate) fn unsupported(unsupported: Unsupported) -> io::Error {
+    new(io::ErrorKind::Unsupported, unsupported)
+}
+
+/// For `map_err`: reading the source or input at `path` failed.
+pub(crate) fn in_source(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |source| {
+        tagged(source, |source| Error::Source {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// For `map_err`: using the state directory at `path` failed.
+pub(crate) fn in_state(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |source| {
+        tagged(source, |source| Error::State {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// For `map_err`: the hook called `name` failed.
+pub(crate) fn in_hook(name: &str) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |source| {
+        tagged(source, |source| Error::Hook {
+            name: name.to_owned(),
+            source,
+        })
+    }
+}
+
+/// For `map_err`: starting the command for the input at `path` failed.
+pub(crate) fn in_spawn(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |source| {
+        tagged(source, |source| Error::Spawn {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// For `map_err`: preparing or writing the task directory at `path` failed.
+pub(crate) fn in_output(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |source| {
+        tagged(source, |source| Error::Output {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let error =
+            in_spawn(Path::new("/src/a"))(io::Error::new(io::ErrorKind::NotFound, "No shell"));
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        assert_eq!(
+            "Could not start the command for /src/a: No shell",
+            error.to_string()
+        );
+        assert_eq!(Some("spawn"), Error::of(&error).map(Error::phase));
+        // Tagged again, it still says what it said first.
+        let error = in_config(error);
+        let error = Error::from(error);
+        assert_eq!(Some(Path::new("/src/a")), error.path());
+        assert_eq!("spawn", error.phase());
+
+        let error = Error::from(io::Error::other("Anything"));
+        assert!(matches!(error, Error::Io(_)));
+        assert_eq!("Anything", io::Error::from(error).to_string());
+        assert_eq!(io::ErrorKind::Interrupted, Error::Interrupted.kind());
+
+        let error = Error::from(config_error(Refusal::NoCpus));
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert_eq!("Tasks need at least one CPU each", error.to_string());
+        assert_eq!(None, error.path());
+    }
+}
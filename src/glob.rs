@@ -0,0 +1,157 @@
+//! Shell-style patterns like `customer-42-*` for picking out inputs by name.
+
+use std::ffi::OsStr;
+
+/// A pattern that matches whole file names, with `*`, `?`, and `[...]` as in the shell.
+///
+/// `[!...]` matches any character not in the brackets, and a backslash matches the
+/// character after it literally.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    /// `?`: any one character.
+    One,
+    /// `*`: any number of characters, including none.
+    Any,
+    /// `[...]`: one character in, or with `negated`, not in, any of the ranges.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Glob {
+    pub(crate) fn parse(pattern: &str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' => Token::Any,
+                '?' => Token::One,
+                '\\' => Token::Literal(
+                    chars
+                        .next()
+                        .ok_or_else(|| format!("Glob ends with a backslash: {:?}", pattern))?,
+                ),
+                '[' => {
+                    let negated = chars.next_if(|&c| c == '!' || c == '^').is_some();
+                    let mut ranges = Vec::new();
+                    loop {
+                        let start = match chars.next() {
+                            // A ']' straight after the '[' is part of the class.
+                            Some(']') if !ranges.is_empty() => break,
+                            Some(start) => start,
+                            None => return Err(format!("Unclosed [ in glob: {:?}", pattern)),
+                        };
+                        let end = match chars.next_if_eq(&'-') {
+                            Some(_) => match chars.next_if(|&c| c != ']') {
+                                Some(end) => end,
+                                // A '-' at the end of the class is just a '-'.
+                                None => {
+                                    ranges.push(('-', '-'));
+                                    start
+                                }
+                            },
+                            None => start,
+                        };
+                        ranges.push((start, end));
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Literal(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Glob { tokens })
+    }
+
+    /// Whether `name` matches the whole pattern.
+    pub(crate) fn matches(&self, name: &OsStr) -> bool {
+        let name: Vec<char> = name.to_string_lossy().chars().collect();
+        // Where to pick up from if what follows the last `*` doesn't match: the token
+        // after the `*`, and the next character for the `*` to swallow.
+        let mut backtrack = None;
+        let (mut t, mut n) = (0, 0);
+        while n < name.len() {
+            match self.tokens.get(t) {
+                Some(Token::Any) => {
+                    backtrack = Some((t + 1, n));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token.matches(name[n]) => {
+                    t += 1;
+                    n += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((after_star, swallowed)) => {
+                    t = after_star;
+                    n = swallowed + 1;
+                    backtrack = Some((after_star, n));
+                }
+                None => return false,
+            }
+        }
+        self.tokens[t..].iter().all(|token| *token == Token::Any)
+    }
+}
+
+impl Token {
+    /// Whether this token, which isn't a `*`, matches the character `c`.
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Literal(literal) => *literal == c,
+            Token::One => true,
+            Token::Any => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|&(start, end)| start <= c && c <= end) != *negated
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        Glob::parse(pattern).unwrap().matches(OsStr::new(name))
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(matches("customer-42-*", "customer-42-jan.csv"));
+        assert!(matches("customer-42-*", "customer-42-"));
+        assert!(!matches("customer-42-*", "customer-420.csv"));
+        assert!(!matches("customer-42-*", "old-customer-42-jan.csv"));
+        assert!(matches("*.csv", "a.b.csv"));
+        assert!(!matches("*.csv", "a.csv.bak"));
+        assert!(matches("*a*b*", "xaybz"));
+        assert!(!matches("*a*b*", "xbya"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", ".txt"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "a"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn test_glob_parse_errors() {
+        assert!(Glob::parse("[abc").is_err());
+        assert!(Glob::parse(r"abc\").is_err());
+    }
+}
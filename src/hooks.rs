@@ -0,0 +1,134 @@
+//! Hooks: shell commands that run around each task's command, or around the whole run.
+//!
+//! A hook that fails makes the task, or the run, fail with an error that says so,
+//! rather than with an exit code that could have come from the command itself.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use tokio::fs;
+use tokio::process::Child;
+
+use crate::error;
+use crate::shell::Shell;
+use crate::template::Template;
+use crate::{Message, RunAs, Status};
+
+/// A shell command like `mkdir -p /scratch/{stem}`, run before or after something else.
+#[derive(Debug)]
+pub(crate) struct Hook {
+    /// What the hook is called, in errors and in the name of its log.
+    name: &'static str,
+    shell: Shell,
+    command: String,
+    template: Template,
+    run_as: Option<RunAs>,
+}
+
+impl Hook {
+    /// A hook called `name` that runs `command` with `shell`, as `run_as` if it's given.
+    pub(crate) fn new(
+        name: &'static str,
+        shell: &str,
+        command: &str,
+        run_as: Option<RunAs>,
+    ) -> Self {
+        Hook {
+            name,
+            shell: Shell::new(shell),
+            command: command.into(),
+            template: Template::parse(command),
+            run_as,
+        }
+    }
+
+    /// Start the hook for the task with `inputs` and destination directory `task_dir`,
+    /// filling in its placeholders like the task's own command, with environment `env`.
+    ///
+    /// Its output goes to a log named after it in `task_dir`.
+    pub(crate) async fn start_for_task(
+        &self,
+        inputs: &[PathBuf],
+        task_dir: &Path,
+        env: &[(&str, OsString)],
+    ) -> io::Result<Child> {
+        let script = self
+            .template
+            .render(inputs, task_dir, self.shell.quoting())?;
+        self.start(script, env, task_dir).await
+    }
+
+    /// Run the hook as it is, once for a whole run, with environment `env`, logging
+    /// its output in `log_dir`.
+    pub(crate) async fn run_once(
+        &self,
+        env: &[(&str, OsString)],
+        log_dir: &Path,
+    ) -> io::Result<()> {
+        let mut child = self
+            .start(self.command.clone().into(), env, log_dir)
+            .await?;
+        self.check(child.wait().await?)
+    }
+
+    async fn start(
+        &self,
+        script: OsString,
+        env: &[(&str, OsString)],
+        log_dir: &Path,
+    ) -> io::Result<Child> {
+        let log = fs::File::create(log_dir.join(format!("{}.log", self.name)))
+            .await?
+            .into_std()
+            .await;
+        let mut command = self.shell.command(script);
+        command
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true);
+        if let Some(run_as) = self.run_as {
+            run_as.apply(&mut command);
+        }
+        command.spawn()
+    }
+
+    /// Fail unless the hook exited with `status` successfully.
+    pub(crate) fn check(&self, status: ExitStatus) -> io::Result<()> {
+        if status.success() {
+            return Ok(());
+        }
+        Err(error::new(
+            io::ErrorKind::Other,
+            Message::HookFailed {
+                name: self.name,
+                status: &Status::from(status),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hook_for_task() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hook = Hook::new("setup", "sh", "echo {stem} $GREETING; exit 3", None);
+        let env = [("GREETING", OsString::from("hello"))];
+        let mut child = hook
+            .start_for_task(&["/src/photo.jpeg".into()], dir.path(), &env)
+            .await?;
+        let error = hook.check(child.wait().await?).unwrap_err();
+        assert_eq!("The setup hook failed with exit code 3", error.to_string());
+        assert_eq!(
+            "photo hello\n",
+            std::fs::read_to_string(dir.path().join("setup.log"))?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+//! The `input-hash` file, which records what each task's input was when it was processed,
+//! so that a task whose input has changed since can be run again.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+use crate::task_id::Fnv1a;
+
+/// The name of the file in each task's destination directory that holds its input's hash.
+pub(crate) const INPUT_HASH_FILE: &str = "input-hash";
+
+fn path(task_dir: &Path) -> PathBuf {
+    task_dir.join(INPUT_HASH_FILE)
+}
+
+/// The hash of the contents of the file at `input`.
+pub(crate) async fn hash_file(input: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(input).await?;
+    let mut hash = Fnv1a::default();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(hash.hex());
+        }
+        hash.write(&buffer[..read]);
+    }
+}
+
+/// Read the hash recorded in a task's destination directory, if there is one.
+pub(crate) async fn read(task_dir: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path(task_dir)).await {
+        Ok(contents) => Ok(Some(contents.trim().into())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Record `hash` as the hash of the input of the task with destination directory `task_dir`.
+pub(crate) async fn write(task_dir: &Path, hash: &str) -> io::Result<()> {
+    fs::write(path(task_dir), format!("{}\n", hash)).await
+}
+
+/// Whether the input at `input` is the same as when the task with destination directory
+/// `task_dir` last recorded it. False if it never did.
+pub(crate) async fn unchanged(input: &Path, task_dir: &Path) -> io::Result<bool> {
+    match read(task_dir).await? {
+        Some(recorded) => Ok(recorded == hash_file(input).await?),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_input_hash() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input");
+        std::fs::write(&input, "one")?;
+        assert!(!unchanged(&input, dir.path()).await?);
+        write(dir.path(), &hash_file(&input).await?).await?;
+        assert!(unchanged(&input, dir.path()).await?);
+        std::fs::write(&input, "two")?;
+        assert!(!unchanged(&input, dir.path()).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! Windows job objects, which keep track of every process a command starts, so that
+//! stopping the command when it times out or the run is interrupted stops all of them.
+//!
+//! On Windows, killing a process leaves its children running, and a task's command is
+//! usually a shell that runs the real work in a child.
+
+use std::ffi::c_void;
+use std::io;
+use tokio::process::Child;
+
+use crate::{error, Message};
+
+type Handle = *mut c_void;
+
+const PROCESS_TERMINATE: u32 = 0x0001;
+const PROCESS_SET_QUOTA: u32 = 0x0100;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> Handle;
+    fn OpenProcess(access: u32, inherit: i32, pid: u32) -> Handle;
+    fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+    fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+    fn CloseHandle(handle: Handle) -> i32;
+}
+
+/// A job that a command's process is in, along with every process it starts after joining.
+#[derive(Debug)]
+pub(crate) struct Job(Handle);
+
+// SAFETY: Job object handles can be used from any thread.
+unsafe impl Send for Job {}
+unsafe impl Sync for Job {}
+
+impl Job {
+    /// Put `child` in a new job of its own.
+    ///
+    /// Anything it started before joining isn't in the job, but a command has barely
+    /// started by the time this is called.
+    pub(crate) fn for_child(child: &Child) -> io::Result<Self> {
+        let pid = child
+            .id()
+            .ok_or_else(|| error::new(io::ErrorKind::Other, Message::CommandFinished))?;
+        // SAFETY: A null name and attributes make an unnamed job with the default security.
+        let job = unsafe { CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+        if job.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let job = Job(job);
+        // SAFETY: `pid` is our own child's, which hasn't been waited for, so it can't
+        // belong to another process yet.
+        let process = unsafe { OpenProcess(PROCESS_TERMINATE | PROCESS_SET_QUOTA, 0, pid) };
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Both handles are open, and the process's is closed straight afterwards.
+        let assigned = unsafe { AssignProcessToJobObject(job.0, process) };
+        let error = io::Error::last_os_error();
+        unsafe { CloseHandle(process) };
+        if assigned == 0 {
+            return Err(error);
+        }
+        Ok(job)
+    }
+
+    /// Kill every process in the job, which exit with code 1, as if killed by `Child::kill`.
+    pub(crate) fn terminate(&self) -> io::Result<()> {
+        // SAFETY: The handle is open until the job is dropped.
+        if unsafe { TerminateJobObject(self.0, 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Job {
+    /// Closing the job leaves its processes running: only `terminate` stops them.
+    fn drop(&mut self) {
+        // SAFETY: The handle is open, and nothing uses it after this.
+        unsafe { CloseHandle(self.0) };
+    }
+}
@@ -0,0 +1,316 @@
+//! The run journal: what the last run did, for the next run to pick up from.
+//!
+//! The journal is a file of JSON lines in the state directory. The first line says
+//! how the run was configured, and every line after it is a task that finished.
+//! Each run reads the journal the last run left, then starts a new one.
+//! Lines are written as tasks finish, so even a run that was killed leaves a
+//! journal of everything it got done.
+
+use serde_json::json;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{CancelReason, Chunk, Config, Status, TaskId, TaskResult};
+
+/// The parts of a run's configuration that decide what its tasks produce.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Recipe {
+    command: String,
+    shell: String,
+    input_mode: String,
+    wrap: Option<String>,
+    then: Vec<String>,
+    split_bytes: Option<u64>,
+}
+
+impl Recipe {
+    pub(crate) fn new(config: &Config) -> Self {
+        Recipe {
+            command: config.command.clone(),
+            shell: config.shell.clone(),
+            input_mode: config.input_mode.name().into(),
+            wrap: config.wrap.clone(),
+            then: config.then.clone(),
+            split_bytes: config.split_bytes,
+        }
+    }
+
+    /// The ID of the task for `input`, or only `chunk` of it, in a run with this recipe.
+    pub(crate) fn task_id(&self, input: &Path, chunk: Option<Chunk>) -> TaskId {
+        let input = input.to_string_lossy();
+        let mut parts = vec![
+            Some(self.command.as_bytes()),
+            Some(self.shell.as_bytes()),
+            Some(self.input_mode.as_bytes()),
+            self.wrap.as_deref().map(str::as_bytes),
+            Some(input.as_bytes()),
+        ];
+        // After everything else, so that tasks without stages keep the IDs they always had.
+        parts.extend(self.then.iter().map(|stage| Some(stage.as_bytes())));
+        // Likewise for tasks of whole files.
+        let chunk = chunk.map(|chunk| [chunk.offset.to_le_bytes(), chunk.length.to_le_bytes()]);
+        if let Some([offset, length]) = &chunk {
+            parts.extend([Some(&offset[..]), Some(&length[..])]);
+        }
+        TaskId::from_parts(&parts)
+    }
+
+    /// A short hash of the whole recipe, which is the same for runs that would produce the
+    /// same results, and almost certainly different otherwise.
+    pub(crate) fn fingerprint(&self) -> String {
+        let split_bytes = self.split_bytes.map(u64::to_le_bytes);
+        let mut parts = vec![
+            Some(self.command.as_bytes()),
+            Some(self.shell.as_bytes()),
+            Some(self.input_mode.as_bytes()),
+            self.wrap.as_deref().map(str::as_bytes),
+            split_bytes.as_ref().map(|bytes| &bytes[..]),
+        ];
+        parts.extend(self.then.iter().map(|stage| Some(stage.as_bytes())));
+        TaskId::from_parts(&parts).as_str().to_owned()
+    }
+
+    /// The recipe as JSON, as the journal and the manifest record it.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "command": self.command,
+            "shell": self.shell,
+            "input_mode": self.input_mode,
+            "wrap": self.wrap,
+            "then": self.then,
+            "split_bytes": self.split_bytes,
+        })
+    }
+
+    /// The recipe that `to_json` gave `json`, with anything missing left empty.
+    pub(crate) fn from_json(json: &serde_json::Value) -> Self {
+        let text = |field: &str| json[field].as_str().map(String::from);
+        Recipe {
+            command: text("command").unwrap_or_default(),
+            shell: text("shell").unwrap_or_default(),
+            input_mode: text("input_mode").unwrap_or_default(),
+            wrap: text("wrap"),
+            then: json["then"]
+                .as_array()
+                .map(|stages| {
+                    stages
+                        .iter()
+                        .filter_map(|stage| stage.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            split_bytes: json["split_bytes"].as_u64(),
+        }
+    }
+
+    /// What's different about `self` from the `previous` recipe, for people to read.
+    pub(crate) fn changes_from(&self, previous: &Recipe) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, before: &dyn fmt::Debug, after: &dyn fmt::Debug| {
+            let (before, after) = (format!("{:?}", before), format!("{:?}", after));
+            if before != after {
+                changes.push(format!("{} was {}, now {}", name, before, after));
+            }
+        };
+        compare("command", &previous.command, &self.command);
+        compare("shell", &previous.shell, &self.shell);
+        compare("input mode", &previous.input_mode, &self.input_mode);
+        compare("wrap", &previous.wrap, &self.wrap);
+        compare("stages", &previous.then, &self.then);
+        compare("split size", &previous.split_bytes, &self.split_bytes);
+        changes
+    }
+}
+
+/// What the journal of an earlier run says.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Previous {
+    /// `None` if the journal was too damaged to tell.
+    pub(crate) recipe: Option<Recipe>,
+    /// The inputs whose tasks didn't succeed, as far as the run got, with where the chunk
+    /// of each one that failed starts, if it was split.
+    pub(crate) failed: HashSet<(PathBuf, Option<u64>)>,
+}
+
+impl Previous {
+    /// Read the journal at `path`, if there is one.
+    ///
+    /// Lines that can't be understood, like one cut short when reach was killed, are ignored.
+    pub(crate) fn read(path: &Path) -> io::Result<Option<Previous>> {
+        // A line at a time, as a run with millions of tasks leaves a journal of gigabytes.
+        let file = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let mut previous = Previous::default();
+        for line in file.split(b'\n') {
+            let entry: serde_json::Value = match serde_json::from_slice(&line?) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let text = |field: &str| entry[field].as_str().map(String::from);
+            match entry["event"].as_str() {
+                Some("run") => previous.recipe = Some(Recipe::from_json(&entry)),
+                Some("task") => {
+                    if let Some(input) = text("input") {
+                        if entry["succeeded"].as_bool() == Some(false) {
+                            let offset = entry["offset"].as_u64();
+                            previous.failed.insert((input.into(), offset));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Some(previous))
+    }
+
+    /// Whether the task for `input`, or for `chunk` of it, failed in the earlier run.
+    pub(crate) fn failed(&self, input: &Path, chunk: Option<Chunk>) -> bool {
+        let input = PathBuf::from(&*input.to_string_lossy());
+        self.failed
+            .contains(&(input, chunk.map(|chunk| chunk.offset)))
+    }
+}
+
+/// The journal for the current run.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Start a new journal at `path` for a run with `recipe`, replacing any old one.
+    pub(crate) fn create(path: &Path, recipe: &Recipe) -> io::Result<Self> {
+        let journal = Journal {
+            file: Mutex::new(File::create(path)?),
+        };
+        let mut entry = recipe.to_json();
+        entry["event"] = "run".into();
+        journal.write(entry)?;
+        Ok(journal)
+    }
+
+    /// Record that a task has finished.
+    pub(crate) fn record(&self, task: &TaskResult) -> io::Result<()> {
+        self.write(json!({
+            "event": "task",
+            "id": task.id.as_str(),
+            "input": task.input.to_string_lossy(),
+            "offset": task.chunk.map(|chunk| chunk.offset),
+            "length": task.chunk.map(|chunk| chunk.length),
+            "succeeded": task.succeeded(),
+            "exit_code": task.status.as_ref().and_then(Status::exit_code),
+            "status": task.status.as_ref().map(Status::to_string),
+            "signal": task.status.as_ref().and_then(Status::signal_name),
+            "error": task.error,
+            "cancelled": task.cancelled.as_ref().map(CancelReason::name),
+            "retries": task.retries,
+            "duration_secs": task.duration.as_secs_f64(),
+            "queued_secs": task.queued.as_secs_f64(),
+            "annotations": task.annotations,
+        }))
+    }
+
+    fn write(&self, entry: serde_json::Value) -> io::Result<()> {
+        // One write for the whole line, so a line is never left half-written unless reach dies.
+        let line = format!("{}\n", entry);
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn recipe(command: &str) -> Recipe {
+        Recipe {
+            command: command.into(),
+            shell: "/bin/sh".into(),
+            input_mode: "stdin".into(),
+            wrap: None,
+            then: Vec::new(),
+            split_bytes: None,
+        }
+    }
+
+    fn task(input: &str, status: Status) -> TaskResult {
+        TaskResult {
+            id: recipe("wc -l").task_id(Path::new(input), None),
+            input: input.into(),
+            destination: PathBuf::from("/dest").join(input),
+            status: Some(status),
+            error: None,
+            cancelled: None,
+            duration: Duration::from_secs(1),
+            queued: Duration::default(),
+            retries: 0,
+            chunk: None,
+            annotations: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_journal_round_trip() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("journal");
+        assert_eq!(None, Previous::read(&path)?);
+
+        let journal = Journal::create(&path, &recipe("wc -l"))?;
+        journal.record(&task("/src/a", Status::Exited(0)))?;
+        journal.record(&task("/src/b", Status::Exited(1)))?;
+        journal.record(&task("/src/c", Status::TimedOut))?;
+        let chunk = Chunk {
+            offset: 4,
+            length: 4,
+        };
+        journal.record(&TaskResult {
+            chunk: Some(chunk),
+            ..task("/src/d", Status::Exited(1))
+        })?;
+        drop(journal);
+        // As if reach was killed halfway through a line.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"event\": \"task\", \"inp")?;
+
+        let previous = Previous::read(&path)?.unwrap();
+        assert_eq!(Some(recipe("wc -l")), previous.recipe);
+        assert!(!previous.failed(Path::new("/src/a"), None));
+        assert!(previous.failed(Path::new("/src/b"), None));
+        assert!(previous.failed(Path::new("/src/c"), None));
+        assert!(previous.failed(Path::new("/src/d"), Some(chunk)));
+        assert!(!previous.failed(Path::new("/src/d"), None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipe_changes() {
+        assert!(recipe("wc -l").changes_from(&recipe("wc -l")).is_empty());
+        assert_eq!(
+            vec![r#"command was "wc -l", now "wc -c""#.to_string()],
+            recipe("wc -c").changes_from(&recipe("wc -l"))
+        );
+    }
+
+    #[test]
+    fn test_recipe_fingerprint() {
+        let wc = recipe("wc -l");
+        assert_eq!(wc.fingerprint(), recipe("wc -l").fingerprint());
+        assert_ne!(wc.fingerprint(), recipe("wc -c").fingerprint());
+        let staged = Recipe {
+            then: vec!["gzip".into()],
+            ..recipe("wc -l")
+        };
+        assert_ne!(wc.fingerprint(), staged.fingerprint());
+        assert_eq!(staged, Recipe::from_json(&staged.to_json()));
+    }
+}
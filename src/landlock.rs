@@ -0,0 +1,334 @@
+//! Confining each task's command to the files it should touch, with Landlock on Linux, so
+//! that a command that's only semi-trusted can't read or change much else, without a
+//! container.
+//!
+//! Commands can read and run what's in the system's own directories and any others they're
+//! allowed, read their inputs, and write only in their destination directories, the
+//! directory they run in, if they have one of their own, and a scratch directory of their
+//! own, which is their `TMPDIR`. Whatever they start is confined too.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::process::Command;
+
+use crate::error;
+use crate::{Message, RunAs};
+
+/// The system's own directories, which commands can read and run programs from, as far as
+/// they exist.
+const SYSTEM_DIRS: &[&str] = &[
+    "/bin", "/sbin", "/usr", "/lib", "/lib32", "/lib64", "/libx32", "/etc", "/opt", "/nix",
+    "/proc", "/sys",
+];
+
+/// Devices, which commands can write to as well, for the likes of `/dev/null`.
+const DEVICES: &str = "/dev";
+
+/// How many scratch directories this process has made, to name the next one.
+static SCRATCH_DIRS: AtomicU64 = AtomicU64::new(0);
+
+/// How commands are confined.
+#[derive(Debug, Clone)]
+pub(crate) struct Restriction {
+    /// More directories or files that commands can read, besides the system's own.
+    readable: Vec<PathBuf>,
+    /// What the kernel can restrict, which is everything that's restricted.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    handled: u64,
+}
+
+/// The rules for one command, which have to be kept until it's been spawned.
+#[derive(Debug)]
+pub(crate) struct Ruleset {
+    #[cfg(target_os = "linux")]
+    _fd: std::os::unix::io::OwnedFd,
+}
+
+/// A directory for one command to write temporary files in, since it can't write in the
+/// system's, which is removed along with everything in it when it's dropped.
+#[derive(Debug)]
+pub(crate) struct Scratch {
+    path: PathBuf,
+}
+
+impl Scratch {
+    /// Make a new, empty scratch directory, belonging to `run_as` if it's given.
+    pub(crate) fn new(run_as: Option<RunAs>) -> io::Result<Self> {
+        let name = format!(
+            "reach-{}-{}",
+            std::process::id(),
+            SCRATCH_DIRS.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&path).map_err(error::in_output(&path))?;
+        let scratch = Scratch { path };
+        if let Some(run_as) = run_as {
+            run_as.own(&scratch.path)?;
+        }
+        Ok(scratch)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    //! What the kernel's Landlock interface takes, from `linux/landlock.h`.
+
+    pub(super) const CREATE_RULESET_VERSION: u32 = 1;
+    pub(super) const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    pub(super) const EXECUTE: u64 = 1 << 0;
+    pub(super) const WRITE_FILE: u64 = 1 << 1;
+    pub(super) const READ_FILE: u64 = 1 << 2;
+    pub(super) const READ_DIR: u64 = 1 << 3;
+    /// Everything else the first version of Landlock handles: removing and making files.
+    pub(super) const CHANGE_DIR: u64 = (1 << 13) - (1 << 4);
+    /// Since version 2, linking and renaming files between directories.
+    pub(super) const REFER: u64 = 1 << 13;
+    /// Since version 3, truncating files.
+    pub(super) const TRUNCATE: u64 = 1 << 14;
+
+    pub(super) const READ: u64 = EXECUTE | READ_FILE | READ_DIR;
+    /// What can be allowed for a file, rather than everything beneath a directory.
+    pub(super) const FOR_FILES: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    #[repr(C)]
+    pub(super) struct RulesetAttr {
+        pub(super) handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    pub(super) struct PathBeneathAttr {
+        pub(super) allowed_access: u64,
+        pub(super) parent_fd: libc::c_int,
+    }
+}
+
+impl Restriction {
+    /// Confine commands, letting them read what's beneath `readable` as well as the system's
+    /// own directories. Fails if the kernel can't.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new(readable: Vec<PathBuf>) -> io::Result<Self> {
+        // SAFETY: Asking for the version takes no attributes.
+        let version = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<sys::RulesetAttr>(),
+                0,
+                sys::CREATE_RULESET_VERSION,
+            )
+        };
+        if version < 1 {
+            let error = io::Error::last_os_error();
+            return Err(error::new(
+                io::ErrorKind::Unsupported,
+                Message::LandlockUnavailable(&error),
+            ));
+        }
+        let mut handled = sys::READ | sys::WRITE_FILE | sys::CHANGE_DIR;
+        if version >= 2 {
+            handled |= sys::REFER;
+        }
+        if version >= 3 {
+            handled |= sys::TRUNCATE;
+        }
+        Ok(Restriction { readable, handled })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn new(_readable: Vec<PathBuf>) -> io::Result<Self> {
+        Err(error::unsupported(crate::Unsupported::Confinement))
+    }
+
+    /// Confine `command`, for a task with `inputs` whose results go in `task_dir`, and which
+    /// runs in `workdir`, if it has one of its own, with `scratch` as its `TMPDIR`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn apply(
+        &self,
+        command: &mut Command,
+        inputs: &[PathBuf],
+        task_dir: &Path,
+        workdir: Option<&Path>,
+        scratch: &Scratch,
+    ) -> io::Result<Ruleset> {
+        use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+        let attr = sys::RulesetAttr {
+            handled_access_fs: self.handled,
+        };
+        // SAFETY: `attr` is the size given, and lives until the call returns.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const sys::RulesetAttr,
+                std::mem::size_of::<sys::RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The kernel just opened it for us, close-on-exec.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+        let system = SYSTEM_DIRS.iter().map(Path::new);
+        for path in system.chain(self.readable.iter().map(PathBuf::as_path)) {
+            self.allow(&fd, path, sys::READ)?;
+        }
+        for input in inputs {
+            self.allow(&fd, input, sys::READ)?;
+        }
+        self.allow(&fd, Path::new(DEVICES), sys::READ | sys::WRITE_FILE)?;
+        self.allow(&fd, task_dir, self.handled)?;
+        if let Some(workdir) = workdir {
+            self.allow(&fd, workdir, self.handled)?;
+        }
+        self.allow(&fd, scratch.path(), self.handled)?;
+        command.env("TMPDIR", scratch.path());
+        let raw_fd = fd.as_raw_fd();
+        // SAFETY: `prctl` and `landlock_restrict_self` are system calls, so async-signal-safe,
+        // and the ruleset's descriptor stays open until the command has been spawned.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::syscall(libc::SYS_landlock_restrict_self, raw_fd, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(Ruleset { _fd: fd })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn apply(
+        &self,
+        _command: &mut Command,
+        _inputs: &[PathBuf],
+        _task_dir: &Path,
+        _workdir: Option<&Path>,
+        _scratch: &Scratch,
+    ) -> io::Result<Ruleset> {
+        Ok(Ruleset {})
+    }
+
+    /// Allow `access` beneath `path`, in the ruleset `fd`, as far as it can be allowed there,
+    /// if `path` exists.
+    #[cfg(target_os = "linux")]
+    fn allow(
+        &self,
+        fd: &impl std::os::unix::io::AsRawFd,
+        path: &Path,
+        access: u64,
+    ) -> io::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        let file = match std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => {
+                return Err(error::new(
+                    error.kind(),
+                    Message::ConfineFailed {
+                        path,
+                        error: &error,
+                    },
+                ))
+            }
+        };
+        let mut access = access & self.handled;
+        if !file.metadata()?.is_dir() {
+            access &= sys::FOR_FILES;
+        }
+        let attr = sys::PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: `attr` lives until the call returns, and `file` stays open until then.
+        let added = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                fd.as_raw_fd(),
+                sys::RULE_PATH_BENEATH,
+                &attr as *const sys::PathBeneathAttr,
+                0,
+            )
+        };
+        if added != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_restriction() -> io::Result<()> {
+        let restriction = match Restriction::new(Vec::new()) {
+            Ok(restriction) => restriction,
+            // Not every kernel has Landlock, and there's nothing to test without it.
+            Err(error) if error.kind() == io::ErrorKind::Unsupported => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input");
+        let secret = dir.path().join("secret");
+        let task_dir = dir.path().join("task");
+        std::fs::write(&input, "input\n")?;
+        std::fs::write(&secret, "secret\n")?;
+        std::fs::create_dir(&task_dir)?;
+        let scratch = Scratch::new(None)?;
+        let run = |script: &str| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(script)
+                .stderr(std::process::Stdio::null());
+            let ruleset = restriction.apply(
+                &mut command,
+                std::slice::from_ref(&input),
+                &task_dir,
+                None,
+                &scratch,
+            );
+            async move {
+                let _ruleset = ruleset?;
+                io::Result::Ok(command.status().await?.success())
+            }
+        };
+        let (input, secret, task_dir) = (input.display(), secret.display(), task_dir.display());
+        assert!(run(&format!("cat {} > {}/out", input, task_dir)).await?);
+        assert!(run("echo hidden > /dev/null").await?);
+        assert!(run("echo temporary > \"$TMPDIR/file\"").await?);
+        assert!(!run(&format!("cat {}", secret)).await?);
+        assert!(!run(&format!("echo changed > {}", input)).await?);
+        assert!(!run(&format!("touch {}/../other", task_dir)).await?);
+        let scratch_path = scratch.path().to_owned();
+        assert!(scratch_path.join("file").exists());
+        drop(scratch);
+        assert!(!scratch_path.exists());
+        Ok(())
+    }
+}
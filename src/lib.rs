@@ -1,55 +1,681 @@
+// For `json!` with as many fields as `Config::to_json` has.
+#![recursion_limit = "256"]
+
 use async_trait::async_trait;
-use futures::{join, stream};
+use futures::{future, stream, Future};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::process::ExitStatus;
+use std::process::{ExitStatus, Stdio};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::process::Command;
+use tokio::process::{Child, Command};
+use tokio::sync::{watch, Semaphore};
+use tokio::time;
 use tokio_stream::wrappers::ReadDirStream;
 
+use pool::Reservation;
+use shell::Shell;
+use template::{shell_words, ArgsTemplate, Quoting, Template, WrapTemplate};
+
+mod annotations;
+mod badge;
+mod bundle;
+mod cancel;
+mod capture;
+mod checksums;
+mod config;
+mod config_file;
+#[cfg(unix)]
+mod coprocess;
+#[cfg(feature = "progress-bar")]
+mod dashboard;
+mod error;
+mod glob;
+mod hooks;
+mod input_hash;
+#[cfg(windows)]
+mod job;
+mod journal;
+mod landlock;
+mod limits;
+mod messages;
+mod metrics;
+mod naming;
+mod notify;
+mod outage;
+mod pause;
+mod pool;
+mod prefetch;
 mod progress;
+#[cfg(feature = "progress-bar")]
+mod progress_bar;
+mod progress_file;
+mod pump;
+#[cfg(all(unix, feature = "remote"))]
+mod remote;
+mod run_as;
+#[cfg(unix)]
+mod session;
+mod shell;
+mod signals;
+mod split;
+mod stages;
+mod state;
+mod status;
+mod summary;
+mod task_id;
+mod task_list;
+mod template;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod throttle;
+mod units;
+mod verify;
+mod window;
 
-pub use progress::default_progress_bar;
-
-/// Configuration for Each.
-pub struct Config {
-    pub command: String,
-    pub shell: String,
-    pub source_dir: PathBuf,
-    pub destination_dir: PathBuf,
-    pub num_processes: usize,
-    pub input_mode: InputMode,
-    pub recreate: bool,
-    pub retries: u32,
-}
-
-pub async fn run(config: Config, progress_bar: impl progress::Progress) -> io::Result<()> {
-    let each = Each::new(
-        config.source_dir,
-        config.num_processes,
-        // TODO(jml): Implement recreate
-        config.recreate,
-        // TODO(jml): Implement retries
-        config.retries,
-    );
-    match config.input_mode {
-        InputMode::Stdin => {
-            let runner = StdinRunner::new(config.shell, config.command);
-            each.run(&runner, &config.destination_dir, &progress_bar)
-                .await
+pub use bundle::{is_systemic, RecentEvents, SupportBundle, SUPPORT_BUNDLE_EVENTS};
+pub use cancel::{CancelReason, CancellationToken};
+pub use config::{Config, ConfigBuilder, STATE_DIR};
+pub use config_file::{ConfigFile, ConfigValue};
+#[cfg(feature = "progress-bar")]
+pub use dashboard::Dashboard;
+pub use error::Error;
+pub use messages::{set_messages, English, Message, Messages, Refusal, Unsupported};
+pub use metrics::Metrics;
+pub use naming::Naming;
+pub use pause::PauseToken;
+pub use progress::{JsonProgress, Progress, ProgressMode, TaskOutcome};
+#[cfg(feature = "progress-bar")]
+pub use progress_bar::{default_progress_bar, progress_bar, COMPACT_TEMPLATE, DEFAULT_TEMPLATE};
+pub use progress_file::ProgressFile;
+pub use pump::Pump;
+pub use run_as::RunAs;
+pub use signals::{parse_signal, signal_name};
+pub use split::Chunk;
+pub use status::Status;
+pub use summary::{Failure, Group, Summary, TaskResult, OTHER_GROUP, SUMMARY_LIMIT};
+pub use task_id::TaskId;
+pub use units::{parse_duration, parse_size};
+pub use verify::{verify, Discrepancy, Verification};
+
+/// Run the configured command on every file in the source directory.
+///
+/// Failing commands don't make this return an error. Instead, the returned
+/// `Summary` says how many tasks failed, so callers can decide what that means.
+///
+/// Errors say what reach was doing when the run failed, and with which path.
+pub async fn run(config: Config, progress_bar: impl progress::Progress) -> Result<Summary, Error> {
+    run_until(config, progress_bar, future::pending()).await
+}
+
+/// Like `run`, but stop early once `interrupt` completes, such as `CancellationToken::cancelled`.
+///
+/// Once interrupted, no new tasks are started, running commands are terminated,
+/// and their tasks are marked as interrupted in their `status` files.
+/// Returns `Error::Interrupted` once everything has stopped.
+/// Running again without `recreate` picks up where the interrupted run left off.
+///
+/// Dropping the future instead kills any running commands, but records nothing about them.
+pub async fn run_until(
+    config: Config,
+    progress_bar: impl progress::Progress,
+    interrupt: impl Future<Output = ()>,
+) -> Result<Summary, Error> {
+    run_with(config, progress_bar, interrupt, |_| (), Launch::Command)
+        .await
+        .map_err(Error::from)
+}
+
+/// Like `run`, but return what happened to every task that was run, in the order they finished.
+///
+/// Tasks that were skipped because they succeeded in an earlier run are not included.
+pub async fn run_collect(
+    config: Config,
+    progress_bar: impl progress::Progress,
+) -> Result<Vec<TaskResult>, Error> {
+    let tasks = Mutex::new(Vec::new());
+    run_with(
+        config,
+        progress_bar,
+        future::pending(),
+        |task| tasks.lock().unwrap().push(task),
+        Launch::Command,
+    )
+    .await?;
+    Ok(tasks.into_inner().unwrap())
+}
+
+/// What runs each task.
+enum Launch {
+    /// The configured command.
+    Command,
+    /// A stand-in for the command, in tests.
+    #[cfg(feature = "testing")]
+    Simulated(testing::Simulation),
+}
+
+/// Run everything, passing each task's result to `on_task` as it finishes.
+async fn run_with(
+    mut config: Config,
+    progress_bar: impl progress::Progress,
+    interrupt: impl Future<Output = ()>,
+    on_task: impl Fn(TaskResult),
+    launch: Launch,
+) -> io::Result<Summary> {
+    if config.workdir.is_some() {
+        // Commands that run somewhere else would find relative paths somewhere else too.
+        let here = std::env::current_dir()?;
+        if !config.from_stdin {
+            config.source_dir = here.join(&config.source_dir);
         }
-        InputMode::Filename => {
-            let runner = FilenameRunner::new(config.shell, config.command);
-            each.run(&runner, &config.destination_dir, &progress_bar)
-                .await
+        for source in &mut config.more_sources {
+            *source = here.join(&source);
+        }
+        config.destination_dir = here.join(&config.destination_dir);
+    }
+    check_overlap(&config).map_err(error::in_config)?;
+    if let Some(run_as) = config.run_as {
+        run_as.check_allowed().map_err(error::in_config)?;
+        // The scope's command is `systemd-run` itself, which has to run as root.
+        if config.systemd_scope {
+            return Err(error::config_error(Refusal::SystemdScopeRunAs));
+        }
+        // Commands give up root before their limits are applied, and with it the right to
+        // raise their priority.
+        if config.nice.is_some_and(|nice| nice < 0) {
+            return Err(error::config_error(Refusal::RunAsRaisedPriority));
+        }
+    }
+    if !config.workers.is_empty() {
+        check_workers(&config).map_err(error::in_config)?;
+        // Workers decide how many tasks run at once, not the machine reach is on.
+        #[cfg(all(unix, feature = "remote"))]
+        {
+            config.num_processes = remote::total_slots(&config.workers);
+        }
+    }
+    config::ensure_destination_directory(&config.destination_dir).map_err(error::in_config)?;
+    let mut each = Each::new(&config).map_err(error::in_config)?;
+    let recipe = journal::Recipe::new(&config);
+    let state_dir = state::StateDir::new(config.state_dir);
+    let _lock = state_dir
+        .lock()
+        .await
+        .map_err(error::in_state(state_dir.path()))?;
+    let journal_path = state_dir.path().join("journal");
+    let previous =
+        journal::Previous::read(&journal_path).map_err(error::in_state(&journal_path))?;
+    // The journal knows best, but a destination can outlive its state directory, and the
+    // manifest there says what it was made with too.
+    let previous_recipe = match previous
+        .as_ref()
+        .and_then(|previous| previous.recipe.clone())
+    {
+        Some(previous_recipe) => Some(previous_recipe),
+        None => naming::Manifest::recipe(&config.destination_dir)
+            .map_err(error::in_output(&config.destination_dir))?,
+    };
+    if let Some(previous_recipe) = previous_recipe {
+        let changes = recipe.changes_from(&previous_recipe);
+        if !changes.is_empty() && !config.allow_command_change && !config.recreate {
+            return Err(error::config_error(Message::CommandChangeRefused {
+                destination: &config.destination_dir,
+                changes: &changes,
+            }));
+        }
+        // Recreated tasks are run with the new command, so there's nothing to warn about.
+        if !changes.is_empty() && !config.recreate {
+            progress_bar.warn(&Message::CommandChanged { changes: &changes });
+        }
+    }
+    if config.retry_failed {
+        each.retry_only =
+            Some(previous.ok_or_else(|| error::config_error(Message::NothingToRetry))?);
+    }
+    let journal =
+        journal::Journal::create(&journal_path, &recipe).map_err(error::in_state(&journal_path))?;
+    let on_task = |task: TaskResult| {
+        if let Err(error) = journal.record(&task) {
+            progress_bar.warn(&Message::JournalUnwritable(&error));
+        }
+        on_task(task)
+    };
+    let destination_dir = &config.destination_dir;
+    let wrap = match (config.systemd_scope, &config.wrap) {
+        (true, wrap) => Some(
+            systemd_scope_wrap(&config.systemd_properties, wrap.as_deref())
+                .map_err(error::in_config)?,
+        ),
+        (false, wrap) => wrap.clone(),
+    };
+    let wrap = match &wrap {
+        Some(wrap) => Some(WrapTemplate::parse(wrap).map_err(|error| {
+            error::in_config(io::Error::new(io::ErrorKind::InvalidInput, error))
+        })?),
+        None => None,
+    };
+    let sessions = if config.shell_sessions {
+        Some(config.shell.clone())
+    } else {
+        None
+    };
+    // Only processes that reach starts for each task can be given just a chunk of a file.
+    if config.split_bytes.is_some()
+        && (sessions.is_some()
+            || config.input_mode == InputMode::Coprocess
+            || !config.workers.is_empty())
+    {
+        return Err(error::config_error(Refusal::SplitOutsideTasks));
+    }
+    // Only processes that reach starts for each task, on this machine, can be confined to
+    // that task's files.
+    if config.restrict_fs
+        && (sessions.is_some()
+            || config.input_mode == InputMode::Coprocess
+            || !config.workers.is_empty()
+            || config.systemd_scope)
+    {
+        return Err(error::config_error(Refusal::ConfineOutsideTasks));
+    }
+    // Only workers that outlive their tasks have anything to keep warm.
+    if config.affinity.is_some() && sessions.is_none() && config.input_mode != InputMode::Coprocess
+    {
+        return Err(error::config_error(Refusal::AffinityWithoutWorkers));
+    }
+    let run_env = vec![
+        ("REACH_SOURCE_DIR", OsString::from(&config.source_dir)),
+        ("REACH_DEST_DIR", OsString::from(destination_dir)),
+    ];
+    if let Some(before_all) = &config.before_all {
+        hooks::Hook::new("before-all", &config.shell, before_all, config.run_as)
+            .run_once(&run_env, state_dir.path())
+            .await
+            .map_err(error::in_hook("before-all"))?;
+    }
+    let after_all = match &config.after_all {
+        Some(after_all) => Some(hooks::Hook::new(
+            "after-all",
+            &config.shell,
+            after_all,
+            config.run_as,
+        )),
+        None => None,
+    };
+    let run = Run {
+        each: &each,
+        destination_dir,
+        progress_bar: &progress_bar,
+        on_task: &on_task,
+        affinity: config.affinity.as_deref(),
+        outputs: each.outputs.clone(),
+        workers: config.workers,
+        ssh: config.ssh,
+    };
+    let summary = match (launch, config.input_mode) {
+        #[cfg(feature = "testing")]
+        (Launch::Simulated(simulation), _) => run.launching(&simulation, interrupt).await,
+        (_, InputMode::Stdin) => {
+            let runner = StdinRunner::new(&config.shell, config.command, wrap);
+            run.commands(runner, sessions, interrupt).await
+        }
+        (_, InputMode::Filename) => {
+            let runner = FilenameRunner::new(&config.shell, config.command, wrap);
+            run.commands(runner, sessions, interrupt).await
+        }
+        (_, InputMode::Exec) => {
+            let runner = ExecRunner::new(&config.command, wrap).map_err(error::in_config)?;
+            run.commands(runner, sessions, interrupt).await
+        }
+        (_, InputMode::Coprocess) => {
+            if wrap.is_some() || sessions.is_some() {
+                return Err(error::config_error(Refusal::CoprocessWrapped));
+            }
+            if config.batch > 1 {
+                return Err(error::config_error(Refusal::CoprocessBatched));
+            }
+            if config.max_output_size.is_some() {
+                return Err(error::config_error(Refusal::CoprocessClipped));
+            }
+            if config.workdir.is_some() {
+                return Err(error::config_error(Refusal::CoprocessWorkdir));
+            }
+            // A worker's standard error is its own, not any one task's.
+            if matches!(config.capture, Capture::Merge | Capture::Tag) {
+                return Err(error::config_error(Refusal::CoprocessMerged));
+            }
+            #[cfg(unix)]
+            {
+                let launcher = coprocess::Coprocesses::new(
+                    config.shell,
+                    config.command,
+                    config.framing,
+                    state_dir.path(),
+                    run.outputs.clone(),
+                    run.pool(),
+                );
+                run.launching(&launcher, interrupt).await
+            }
+            #[cfg(not(unix))]
+            Err(error::unsupported(Unsupported::Coprocesses))
+        }
+    }?;
+    if let Some(after_all) = after_all {
+        let mut env = run_env;
+        env.push(("REACH_SUCCEEDED", summary.succeeded.to_string().into()));
+        env.push(("REACH_FAILED", summary.failed.to_string().into()));
+        after_all
+            .run_once(&env, state_dir.path())
+            .await
+            .map_err(error::in_hook("after-all"))?;
+    }
+    Ok(summary)
+}
+
+/// Check that the destination directory doesn't overlap any source directory, where the run
+/// could end up processing its own outputs.
+///
+/// A destination inside a source is only allowed with `config.nested_destination`,
+/// and a source inside the destination never is.
+fn check_overlap(config: &Config) -> io::Result<()> {
+    let destination = resolve(&config.destination_dir)?;
+    // Inputs read from standard input are checked as they're read, as they can be anywhere.
+    let source_dir = Some(&config.source_dir).filter(|_| !config.from_stdin);
+    for source in source_dir.into_iter().chain(&config.more_sources) {
+        let source = resolve(source)?;
+        // A single source file is found in its directory's listing.
+        let dir = match source.parent() {
+            Some(parent) if !source.is_dir() => parent,
+            _ => &source,
+        };
+        let message = if dir.starts_with(&destination) {
+            Message::SourceInsideDestination {
+                source: dir,
+                destination: &destination,
+            }
+        } else if destination.starts_with(dir) && !config.nested_destination {
+            Message::DestinationInsideSource {
+                destination: &destination,
+                source: dir,
+            }
+        } else {
+            continue;
+        };
+        return Err(error::new(io::ErrorKind::InvalidInput, message));
+    }
+    Ok(())
+}
+
+/// `path` made absolute, with symbolic links resolved as far as it exists.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let path = std::env::current_dir()?.join(path);
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(mut resolved) => {
+                resolved.extend(rest.iter().rev());
+                return Ok(resolved);
+            }
+            Err(error) => match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    rest.push(name);
+                    existing = parent;
+                }
+                _ => return Err(error),
+            },
         }
     }
 }
 
+/// Check that the run can send its tasks to `config.workers`.
+fn check_workers(config: &Config) -> io::Result<()> {
+    let invalid = |refusal| Err(error::refused(refusal));
+    if cfg!(not(unix)) {
+        return Err(error::unsupported(Unsupported::Workers));
+    }
+    if cfg!(not(feature = "remote")) {
+        return Err(error::unsupported(Unsupported::WorkersWithoutRemote));
+    }
+    if Quoting::for_shell(&config.shell) != Quoting::Posix {
+        return Err(error::new(
+            io::ErrorKind::InvalidInput,
+            Message::WorkersShell(&config.shell),
+        ));
+    }
+    if config.shell_sessions || config.input_mode == InputMode::Coprocess {
+        return invalid(Refusal::WorkersInSessions);
+    }
+    if config.workdir.is_some() {
+        return invalid(Refusal::WorkersWorkdir);
+    }
+    // Only standard input can carry more than one input over a connection.
+    if config.batch > 1 && config.input_mode != InputMode::Stdin {
+        return invalid(Refusal::WorkersBatched);
+    }
+    // The limits would only apply to `ssh` itself.
+    if config.nice.is_some()
+        || config.memory_limit.is_some()
+        || config.cpus_per_task.is_some()
+        || config.task_max_fds.is_some()
+        || config.task_max_procs.is_some()
+    {
+        return invalid(Refusal::WorkersLimited);
+    }
+    Ok(())
+}
+
+/// A wrapper that runs each command in a transient systemd scope of its own, with
+/// `properties` like `MemoryMax=2G`, and inside that in `wrap`, if there is one.
+///
+/// systemd then accounts for each task separately, and keeps track of anything it
+/// leaves running. Without root, the scopes belong to the user's service manager.
+#[cfg(unix)]
+fn systemd_scope_wrap(properties: &[String], wrap: Option<&str>) -> io::Result<String> {
+    let mut words: Vec<OsString> = vec![
+        "systemd-run".into(),
+        "--scope".into(),
+        "--quiet".into(),
+        "--collect".into(),
+    ];
+    // SAFETY: `geteuid` has no requirements, and always succeeds.
+    if unsafe { libc::geteuid() } != 0 {
+        words.push("--user".into());
+    }
+    for property in properties {
+        words.push("--property".into());
+        words.push(property.into());
+    }
+    words.push("--".into());
+    let mut scope = shell_words(&words)?
+        .into_string()
+        .expect("Quoted strings are still strings");
+    scope.push(' ');
+    scope.push_str(wrap.unwrap_or("{cmd}"));
+    Ok(scope)
+}
+
+#[cfg(not(unix))]
+fn systemd_scope_wrap(_properties: &[String], _wrap: Option<&str>) -> io::Result<String> {
+    Err(error::unsupported(Unsupported::SystemdScopes))
+}
+
+/// Everything about a run but how its tasks are launched.
+struct Run<'a, P, F> {
+    each: &'a Each,
+    destination_dir: &'a Path,
+    progress_bar: &'a P,
+    on_task: &'a F,
+    /// The template for the key that decides which worker each task goes to, if it matters.
+    affinity: Option<&'a str>,
+    outputs: capture::Outputs,
+    /// The machines to run tasks on, if not this one.
+    workers: Vec<Worker>,
+    /// The program that connects to `workers`.
+    #[cfg_attr(not(all(unix, feature = "remote")), allow(dead_code))]
+    ssh: String,
+}
+
+impl<'a, P: progress::Progress, F: Fn(TaskResult)> Run<'a, P, F> {
+    /// A pool for long-lived workers, with a slot for each process if there's affinity.
+    fn pool<W>(&self) -> pool::Pool<W> {
+        match self.affinity {
+            Some(key) => pool::Pool::with_affinity(Template::parse(key), self.each.num_processes),
+            None => pool::Pool::new(),
+        }
+    }
+
+    async fn launching<L: Launcher>(
+        &self,
+        launcher: &L,
+        interrupt: impl Future<Output = ()>,
+    ) -> io::Result<Summary> {
+        self.each
+            .run_until(
+                launcher,
+                self.destination_dir,
+                self.progress_bar,
+                interrupt,
+                self.on_task,
+            )
+            .await
+    }
+
+    /// Run the commands that `runner` builds, in shell sessions if `sessions` is the shell for them,
+    /// or on the run's workers if it has any.
+    async fn commands<R: Runner + Sync>(
+        &self,
+        runner: R,
+        sessions: Option<String>,
+        interrupt: impl Future<Output = ()>,
+    ) -> io::Result<Summary> {
+        if !self.workers.is_empty() {
+            #[cfg(all(unix, feature = "remote"))]
+            {
+                let launcher = remote::OnWorkers::new(
+                    runner,
+                    self.ssh.clone(),
+                    self.workers.clone(),
+                    self.outputs.clone(),
+                );
+                return self.launching(&launcher, interrupt).await;
+            }
+        }
+        match sessions {
+            #[cfg(unix)]
+            Some(shell) => {
+                let launcher =
+                    session::InSessions::new(runner, shell, self.outputs.clone(), self.pool())?;
+                self.launching(&launcher, interrupt).await
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(error::unsupported(Unsupported::ShellSessions)),
+            None => {
+                let launcher = Spawn {
+                    runner,
+                    outputs: self.outputs.clone(),
+                };
+                self.launching(&launcher, interrupt).await
+            }
+        }
+    }
+}
+
+/// How urgently a run has been asked to stop.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Stop {
+    /// Keep going.
+    No,
+    /// Don't start any new tasks, but let running ones finish.
+    Soon,
+    /// Don't start any new tasks, and terminate running ones.
+    Now,
+}
+
 struct Each {
     source_dir: PathBuf,
+    more_sources: Vec<PathBuf>,
+    /// What separates the paths of inputs read from standard input, if that's where they are.
+    task_list: Option<u8>,
+    order: Order,
+    /// What shuffles the inputs the same way every run, if they're in a random order.
+    seed: Option<u64>,
     num_processes: usize,
+    /// Whether to read inputs as they're needed, rather than all at once.
+    low_memory: bool,
+    /// Split the source file into chunks of this many bytes, rather than taking each file whole.
+    split_bytes: Option<u64>,
+    /// The manifest, or the destination directory with one, that lists the inputs,
+    /// rather than the sources.
+    from_manifest: Option<PathBuf>,
+    /// The most failures and groups the summary keeps, if there's a limit.
+    summary_limit: Option<usize>,
+    /// The most inputs a task may have.
+    batch: usize,
+    /// The template for the name of each task's group in the summary.
+    group_by: Template,
+    /// Run before each task's command, and it only runs if this succeeds.
+    setup: Option<hooks::Hook>,
+    /// Run after each task's command, and the task only succeeds if this does too.
+    teardown: Option<hooks::Hook>,
+    /// The directory to run each task's command in, if not reach's own.
+    workdir: Option<Template>,
+    /// Run after each task's command, one after another, each on the output of the one before.
+    stages: Vec<stages::Stage>,
+    /// Where the output of each task's command and stages goes.
+    outputs: capture::Outputs,
+    io_limiter: Semaphore,
+    /// How many inputs to ask the kernel to read ahead of their tasks.
+    prefetch: usize,
+    /// Held while the destination's filesystem is failing, so that no new tasks start.
+    outage: tokio::sync::Mutex<()>,
+    /// Pauses the run, and freezes its commands, as `Config::pause` says.
+    pauser: pause::Pauser,
+    recreate: bool,
+    /// Record each input's hash, and run tasks whose input has changed again.
+    hash_inputs: bool,
+    /// Record the checksums of each task's files when it finishes, for `verify`.
+    checksums: bool,
+    retries: u32,
+    retry_signals: Vec<i32>,
+    timeout: Option<Duration>,
+    halt: Halt,
+    max_total_output: Option<u64>,
+    output_policy: OutputPolicy,
+    throttle: throttle::Throttle,
+    /// How often to look for new files once the source directory has been processed, if at all.
+    watch: Option<Duration>,
+    /// Tells systemd how a run that watches for inputs is doing, if it should.
+    service: notify::Service,
+    /// Which files in the source directories are inputs.
+    selection: Selection,
+    /// What decides the tasks' IDs.
+    recipe: journal::Recipe,
+    /// Only run the tasks that failed in this earlier run.
+    retry_only: Option<journal::Previous>,
+    /// Only run the tasks whose input's name matches this, even if they succeeded before.
+    rerun_only: Option<glob::Glob>,
+    stop_sender: watch::Sender<Stop>,
+    stop_requested: watch::Receiver<Stop>,
+    /// Why the run was first asked to stop now, once it has been.
+    cancel_reason: OnceLock<CancelReason>,
+    /// How to name each input's destination directory.
+    naming: Naming,
+    /// The names of the destination directories given so far, which no other input's can have,
+    /// except in low-memory mode, where they aren't kept.
+    names: Mutex<HashSet<OsString>>,
+    /// Whether to write a manifest of where every input's results are.
+    write_manifest: bool,
+    /// The manifest for the current run, while it's being written.
+    manifest: Mutex<Option<naming::Manifest>>,
 }
 
 // TODO: Add support for source "dir" being a filename with a bunch of lines.
@@ -57,143 +683,1826 @@ struct Each {
 // bunch of lines into a bunch of directories with the lines as contents.
 
 impl Each {
-    fn new(source_dir: PathBuf, num_processes: usize, _recreate: bool, _retries: u32) -> Self {
-        Each {
-            source_dir,
-            num_processes,
+    fn new(config: &Config) -> io::Result<Self> {
+        let (stop_sender, stop_requested) = watch::channel(Stop::No);
+        if config.low_memory {
+            let invalid = |refusal| Err(error::refused(refusal));
+            if config.order != Order::Unordered {
+                return invalid(Refusal::LowMemoryOrdered);
+            }
+            if config.watch.is_some() {
+                return invalid(Refusal::LowMemoryWatched);
+            }
+            if !config.more_sources.is_empty() {
+                return invalid(Refusal::LowMemorySources);
+            }
+            if config.naming == Naming::Index {
+                return invalid(Refusal::LowMemoryNumbered);
+            }
+        }
+        if config.from_stdin {
+            let invalid = |refusal| Err(error::refused(refusal));
+            if !config.more_sources.is_empty() {
+                return invalid(Refusal::StdinSources);
+            }
+            if config.order != Order::Unordered {
+                return invalid(Refusal::StdinOrdered);
+            }
+            if config.watch.is_some() {
+                return invalid(Refusal::StdinWatched);
+            }
+            if config.split_bytes.is_some() {
+                return invalid(Refusal::StdinSplit);
+            }
+            if config.low_memory {
+                return invalid(Refusal::StdinLowMemory);
+            }
+        }
+        if config.from_manifest.is_some() {
+            let invalid = |refusal| Err(error::refused(refusal));
+            if config.from_stdin {
+                return invalid(Refusal::ManifestStdin);
+            }
+            if config.order != Order::Unordered {
+                return invalid(Refusal::ManifestOrdered);
+            }
+            if config.watch.is_some() {
+                return invalid(Refusal::ManifestWatched);
+            }
+            if config.low_memory {
+                return invalid(Refusal::ManifestLowMemory);
+            }
+        }
+        if let Some(split_bytes) = config.split_bytes {
+            let invalid = |refusal| Err(error::refused(refusal));
+            if split_bytes == 0 {
+                return invalid(Refusal::SplitEmpty);
+            }
+            if config.batch > 1 {
+                return invalid(Refusal::SplitBatched);
+            }
+            if !config.more_sources.is_empty() {
+                return invalid(Refusal::SplitSources);
+            }
+            if config.order != Order::Unordered {
+                return invalid(Refusal::SplitOrdered);
+            }
+            if config.watch.is_some() {
+                return invalid(Refusal::SplitWatched);
+            }
+            if config.low_memory {
+                return invalid(Refusal::SplitLowMemory);
+            }
+            if config.hash_inputs {
+                return invalid(Refusal::SplitHashed);
+            }
+            if config.naming != Naming::Name {
+                return invalid(Refusal::SplitNamed);
+            }
+        }
+        if config.naming == Naming::Index && config.order == Order::Random && config.seed.is_none()
+        {
+            return Err(error::refused(Refusal::RandomNumbered));
         }
+        if !config.then.is_empty() && config.capture == Capture::Discard {
+            return Err(error::refused(Refusal::StagesDiscarded));
+        }
+        let pauser = pause::Pauser::new(
+            config.pause.clone(),
+            if !config.workers.is_empty() {
+                Err(Unsupported::FreezeOnWorkers)
+            } else if config.systemd_scope {
+                Err(Unsupported::FreezeInScopes)
+            } else {
+                Ok(())
+            },
+        );
+        Ok(Each {
+            source_dir: config.source_dir.clone(),
+            more_sources: config.more_sources.clone(),
+            task_list: match (config.from_stdin, config.null_separated) {
+                (false, _) => None,
+                (true, false) => Some(b'\n'),
+                (true, true) => Some(b'\0'),
+            },
+            order: config.order,
+            seed: config.seed,
+            num_processes: config.num_processes,
+            low_memory: config.low_memory,
+            split_bytes: config.split_bytes,
+            from_manifest: config.from_manifest.clone(),
+            summary_limit: match config.low_memory {
+                true => Some(SUMMARY_LIMIT),
+                false => None,
+            },
+            batch: config.batch.max(1),
+            group_by: Template::parse(config.group_by.as_deref().unwrap_or("{ext}")),
+            setup: config
+                .setup
+                .as_deref()
+                .map(|setup| hooks::Hook::new("setup", &config.shell, setup, config.run_as)),
+            teardown: config.teardown.as_deref().map(|teardown| {
+                hooks::Hook::new("teardown", &config.shell, teardown, config.run_as)
+            }),
+            workdir: config.workdir.as_deref().map(Template::parse),
+            stages: config
+                .then
+                .iter()
+                .map(|command| stages::Stage::new(&config.shell, command))
+                .collect(),
+            outputs: capture::Outputs::new(
+                config.capture,
+                &config.stdout_name,
+                &config.stderr_name,
+            )?
+            .clipped_to(config.max_output_size)
+            .running_as(config.run_as)
+            .limited_by(
+                limits::Limits::new(
+                    config.nice,
+                    config.memory_limit,
+                    config.cpus_per_task,
+                    config.task_max_fds,
+                    config.task_max_procs,
+                )?
+                .within(pauser.cgroup()),
+            )
+            .restricted_by(match config.restrict_fs {
+                true => Some(landlock::Restriction::new(config.restrict_fs_read.clone())?),
+                false => None,
+            }),
+            io_limiter: Semaphore::new(config.io_concurrency.max(1)),
+            prefetch: config.prefetch,
+            outage: tokio::sync::Mutex::new(()),
+            pauser,
+            recreate: config.recreate,
+            hash_inputs: config.hash_inputs,
+            checksums: config.checksums,
+            retries: config.retries,
+            retry_signals: config.retry_signals.clone(),
+            timeout: config.timeout,
+            halt: config.halt,
+            max_total_output: config.max_total_output,
+            output_policy: config.output_policy,
+            throttle: throttle::Throttle::new(
+                config.max_rate,
+                config.max_load,
+                match &config.window {
+                    Some(window) => Some(
+                        window
+                            .parse()
+                            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?,
+                    ),
+                    None => None,
+                },
+            )?,
+            watch: config.watch,
+            // A run that watches for inputs goes on until it's stopped, like a service.
+            service: notify::Service::new(config.notify_systemd && config.watch.is_some()),
+            selection: Selection {
+                excluded_dirs: vec![
+                    resolve(&config.destination_dir)?,
+                    resolve(&config.state_dir)?,
+                ],
+                include: parse_globs(&config.include)?,
+                exclude: parse_globs(&config.exclude)?,
+                unicode_only: config.skip_invalid_names
+                    && config.input_mode == InputMode::Filename
+                    && !Quoting::for_shell(&config.shell).takes_any_bytes(),
+            },
+            recipe: journal::Recipe::new(config),
+            retry_only: None,
+            rerun_only: match &config.rerun_matching {
+                Some(pattern) => Some(
+                    glob::Glob::parse(pattern)
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?,
+                ),
+                None => None,
+            },
+            stop_sender,
+            stop_requested,
+            cancel_reason: OnceLock::new(),
+            naming: config.naming,
+            names: Mutex::new(HashSet::new()),
+            write_manifest: config.manifest || config.naming != Naming::Name,
+            manifest: Mutex::new(None),
+        })
     }
 
-    async fn load_files(&self) -> io::Result<Vec<fs::DirEntry>> {
-        use stream::TryStreamExt;
-        let source_dir = fs::read_dir(&self.source_dir).await?;
-        let stream = ReadDirStream::new(source_dir);
-        stream
-            .and_then(|source_file| async move {
-                let metadata = source_file.metadata().await?;
-                Ok((source_file, metadata))
-            })
-            .try_filter_map(|(source_file, metadata)| async move {
-                Ok(if metadata.is_file() {
-                    Some(source_file)
-                } else {
-                    None
-                })
-            })
-            .try_collect()
+    /// Like `run`, but stop once `interrupt` completes.
+    ///
+    /// Waits for running commands to be terminated, then returns an `Interrupted` error.
+    async fn run_until<L: Launcher, P: progress::Progress>(
+        &self,
+        launcher: &L,
+        destination_dir: &Path,
+        progress_bar: &P,
+        interrupt: impl Future<Output = ()>,
+        on_task: &impl Fn(TaskResult),
+    ) -> io::Result<Summary> {
+        let run = self.run(launcher, destination_dir, progress_bar, on_task);
+        tokio::pin!(run);
+        tokio::select! {
+            result = &mut run => result,
+            _ = interrupt => {
+                self.stop(Stop::Now, CancelReason::Shutdown);
+                run.await?;
+                Err(interrupted_error())
+            }
+        }
+    }
+
+    /// Ask the run to stop, for `reason` if it's stopping now.
+    /// Never overrides an earlier, more urgent request.
+    fn stop(&self, stop: Stop, reason: CancelReason) {
+        if self.stop_requested() < stop {
+            if stop == Stop::Now {
+                // Set first, so that it's there for whatever sees the request.
+                let _ = self.cancel_reason.set(reason);
+                // Frozen commands can't be terminated.
+                self.pauser.thaw();
+            }
+            // Only fails if there are no receivers, but we always hold one.
+            let _ = self.stop_sender.send(stop);
+        }
+    }
+
+    fn stop_requested(&self) -> Stop {
+        *self.stop_requested.borrow()
+    }
+
+    /// Why a command that finished with `result` was terminated, if reach terminated it.
+    fn cancelled(&self, result: &io::Result<ExitStatus>) -> Option<CancelReason> {
+        match status_of(result)? {
+            Status::TimedOut => Some(CancelReason::Timeout),
+            Status::Interrupted => Some(
+                self.cancel_reason
+                    .get()
+                    .copied()
+                    .unwrap_or(CancelReason::Shutdown),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The inputs in the sources, each named, in the order they should be processed.
+    ///
+    /// All of them, even those the run isn't limited to, so that they're numbered the same
+    /// way every run.
+    async fn load_files(&self) -> io::Result<Vec<Input>> {
+        let mut inputs = match (&self.from_manifest, self.split_bytes) {
+            (Some(run), _) => naming::Manifest::inputs(run)?
+                .into_iter()
+                .map(|(path, chunk, name)| Input { path, name, chunk })
+                .collect(),
+            (None, Some(split_bytes)) => self.split_source(split_bytes).await?,
+            (None, None) => {
+                let mut files = self.list_files().await?;
+                self.order.sort(&mut files, self.seed);
+                files.into_iter().map(|(input, _)| input).collect()
+            }
+        };
+        for input in &mut inputs {
+            self.name_input(input)?;
+        }
+        Ok(inputs)
+    }
+
+    /// The files in the sources, in no particular order, not yet named.
+    async fn list_files(&self) -> io::Result<Vec<(Input, std::fs::Metadata)>> {
+        let mut files = list_source(&self.source_dir, &self.selection)
+            .await
+            .map_err(error::in_source(&self.source_dir))?;
+        for source in &self.more_sources {
+            let more = list_source(source, &self.selection)
+                .await
+                .map_err(error::in_source(source))?;
+            files.extend(more);
+        }
+        Ok(files)
+    }
+
+    /// Give `input` the name of its destination directory, as the next input found,
+    /// unless it's a chunk, which is already named for its number, or it's from a manifest,
+    /// which names it, and note it in the manifest.
+    fn name_input(&self, input: &mut Input) -> io::Result<()> {
+        let mut names = self.names.lock().unwrap();
+        if input.chunk.is_none() && self.from_manifest.is_none() {
+            input.name = self.naming.destination(&input.path, names.len())?;
+        }
+        if self.write_manifest && input.name == naming::MANIFEST_FILE {
+            return Err(error::new(
+                io::ErrorKind::InvalidInput,
+                Message::ManifestName {
+                    input: &input.path,
+                    name: &input.name,
+                },
+            ));
+        }
+        if !self.low_memory && !names.insert(input.name.clone()) {
+            return Err(error::new(
+                io::ErrorKind::InvalidInput,
+                Message::SharedDestination {
+                    input: &input.path,
+                    name: &input.name,
+                },
+            ));
+        }
+        if let Some(manifest) = &mut *self.manifest.lock().unwrap() {
+            let id = self.recipe.task_id(&input.path, input.chunk);
+            manifest.add(&id, &input.path, input.chunk, &input.name);
+        }
+        Ok(())
+    }
+
+    /// The chunks of `chunk_size` bytes that the source, a single file, splits into.
+    async fn split_source(&self, chunk_size: u64) -> io::Result<Vec<Input>> {
+        let metadata = fs::metadata(&self.source_dir)
             .await
+            .map_err(error::in_source(&self.source_dir))?;
+        if !metadata.is_file() {
+            return Err(error::new(
+                io::ErrorKind::InvalidInput,
+                Message::NotSplittable(&self.source_dir),
+            ));
+        }
+        let name = self.source_dir.file_name().unwrap_or_default();
+        let chunks = Chunk::split(metadata.len(), chunk_size);
+        let count = chunks.len();
+        Ok(chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| Input {
+                path: self.source_dir.clone(),
+                name: Chunk::name(name, index, count),
+                chunk: Some(chunk),
+            })
+            .collect())
+    }
+
+    /// Whether `input` is one the run is limited to, if it's limited to any.
+    fn wanted(&self, input: &Input) -> bool {
+        if let Some(previous) = &self.retry_only {
+            if !previous.failed(&input.path, input.chunk) {
+                return false;
+            }
+        }
+        // Matched against the input's own name, whatever its destination is called.
+        let name = match input.chunk {
+            Some(_) => &input.name,
+            None => input.path.file_name().unwrap_or_default(),
+        };
+        match &self.rerun_only {
+            Some(glob) => glob.matches(name),
+            None => true,
+        }
+    }
+
+    /// Files that turn up in the source directory after the run has started, a batch at a time.
+    ///
+    /// Looks for new files every `interval`. A new file is only taken once its size and
+    /// modification time have stayed the same for a whole interval, so that files
+    /// that are still being written aren't processed half-finished.
+    /// Ends once the run is asked to stop.
+    fn arrivals(
+        &self,
+        interval: Duration,
+        seen: HashSet<PathBuf>,
+    ) -> impl stream::Stream<Item = Vec<Input>> + '_ {
+        let state = (seen, HashMap::new(), self.stop_requested.clone());
+        stream::unfold(
+            state,
+            move |(mut seen, mut waiting, mut stop_requested)| async move {
+                loop {
+                    tokio::select! {
+                        _ = time::sleep(interval) => {}
+                        _ = wait_for_stop(&mut stop_requested, Stop::Soon) => return None,
+                    }
+                    // The source directory may be briefly unreadable, so just look again next time.
+                    let files = match self.list_files().await {
+                        Ok(files) => files,
+                        Err(_) => continue,
+                    };
+                    let mut ready = Vec::new();
+                    let mut changing = HashMap::new();
+                    for (input, metadata) in files {
+                        let path = input.path.clone();
+                        if seen.contains(&path) {
+                            continue;
+                        }
+                        let shape = (metadata.len(), metadata.modified().ok());
+                        if waiting.get(&path) == Some(&shape) {
+                            seen.insert(path);
+                            ready.push((input, metadata));
+                        } else {
+                            changing.insert(path, shape);
+                        }
+                    }
+                    waiting = changing;
+                    self.order.sort(&mut ready, self.seed);
+                    // Those whose destination clashes with an earlier input's are left out.
+                    let ready: Vec<_> = ready
+                        .into_iter()
+                        .filter_map(|(mut input, _)| {
+                            let named = self.name_input(&mut input).is_ok();
+                            (named && self.wanted(&input)).then_some(input)
+                        })
+                        .collect();
+                    if !ready.is_empty() {
+                        return Some((ready, (seen, waiting, stop_requested)));
+                    }
+                }
+            },
+        )
     }
 
-    async fn run<R: Runner, P: progress::Progress>(
+    /// Drop the source files that have already been processed successfully,
+    /// unless we are recreating everything, or re-running the ones matching a pattern.
+    ///
+    /// When hashing inputs, a file is only dropped if it's the same as when it was processed.
+    async fn skip_completed<P: progress::Progress>(
         &self,
-        runner: &R,
+        source_files: Vec<Input>,
         destination_dir: &Path,
         progress_bar: &P,
-    ) -> io::Result<()> {
+    ) -> Vec<Input> {
         use stream::StreamExt;
-        let source_files = self.load_files().await?;
-        progress_bar.set_num_tasks(source_files.len());
-        stream::iter(source_files.into_iter())
-            .for_each_concurrent(self.num_processes, |source_file| async move {
-                let result = self
-                    .run_command(runner, &source_file, destination_dir)
+        stream::iter(source_files)
+            .filter_map(|source_file| async move {
+                let skip = self
+                    .skip(&source_file, destination_dir, Some(progress_bar))
                     .await;
-                progress_bar.task_completed(result);
+                (!skip).then_some(source_file)
             })
+            .collect()
+            .await
+    }
+
+    /// Whether `source_file` has already been processed successfully, and so shouldn't be
+    /// again, saying so to `progress_bar` if there is one.
+    async fn skip<P: progress::Progress>(
+        &self,
+        source_file: &Input,
+        destination_dir: &Path,
+        progress_bar: Option<&P>,
+    ) -> bool {
+        if self.recreate || self.rerun_only.is_some() {
+            return false;
+        }
+        let task_dir = destination_dir.join(&source_file.name);
+        let input = &source_file.path;
+        let succeeded =
+            matches!(Status::read(&task_dir).await, Ok(Some(status)) if status.is_success());
+        let skip = succeeded
+            && (!self.hash_inputs
+                || input_hash::unchanged(input, &task_dir)
+                    .await
+                    .unwrap_or(false));
+        if let (true, Some(progress_bar)) = (skip, progress_bar) {
+            progress_bar.task_skipped(&self.recipe.task_id(input, source_file.chunk), input);
+        }
+        skip
+    }
+
+    /// In low-memory mode, the inputs to process, and how many were skipped, found by reading
+    /// the source directory twice: once to count them, and again as they're needed,
+    /// so that they're never all held at once.
+    async fn stream_files<'a, P: progress::Progress>(
+        &'a self,
+        destination_dir: &'a Path,
+        progress_bar: &'a P,
+    ) -> io::Result<(impl stream::Stream<Item = Input> + 'a, usize)> {
+        use stream::{StreamExt, TryStreamExt};
+        let mut skipped = 0;
+        let mut tasks = 0;
+        let in_source = || error::in_source(&self.source_dir);
+        let entries = source_entries(&self.source_dir, &self.selection)
+            .await
+            .map_err(in_source())?;
+        let mut entries = Box::pin(entries);
+        while let Some((mut source_file, _)) = entries.try_next().await.map_err(in_source())? {
+            source_file.name = self.naming.destination(&source_file.path, 0)?;
+            if !self.wanted(&source_file) {
+                continue;
+            }
+            if self
+                .skip(&source_file, destination_dir, Some(progress_bar))
+                .await
+            {
+                skipped += 1;
+            } else {
+                tasks += 1;
+            }
+        }
+        progress_bar.set_num_tasks(tasks);
+        let entries = source_entries(&self.source_dir, &self.selection)
+            .await
+            .map_err(in_source())?;
+        let files = entries.filter_map(move |entry| async move {
+            match entry {
+                Ok((mut source_file, _)) => {
+                    if let Err(error) = self.name_input(&mut source_file) {
+                        progress_bar.warn(&Message::LeftOut(&error));
+                        return None;
+                    }
+                    let skip = !self.wanted(&source_file)
+                        || self.skip(&source_file, destination_dir, None::<&P>).await;
+                    (!skip).then_some(source_file)
+                }
+                Err(error) => {
+                    progress_bar.warn(&Message::SourceEntryUnreadable(&error));
+                    None
+                }
+            }
+        });
+        Ok((files, skipped))
+    }
+
+    /// The inputs to process, each with when it was found: those in the sources, in order,
+    /// and then any that arrive later, if watching.
+    async fn load_inputs<'a, P: progress::Progress>(
+        &'a self,
+        destination_dir: &'a Path,
+        progress_bar: &'a P,
+        summary: &'a Mutex<Summary>,
+        num_tasks: &'a AtomicUsize,
+    ) -> io::Result<impl stream::Stream<Item = (Input, Instant)> + 'a> {
+        use stream::StreamExt;
+        let mut all_files = self.load_files().await?;
+        if let Some(manifest) = &mut *self.manifest.lock().unwrap() {
+            if let Err(error) = manifest.snapshot() {
+                progress_bar.warn(&Message::ManifestUnwritable(&error));
+            }
+        }
+        let seen: HashSet<_> = all_files.iter().map(|input| input.path.clone()).collect();
+        all_files.retain(|input| self.wanted(input));
+        let total = all_files.len();
+        let source_files = self
+            .skip_completed(all_files, destination_dir, progress_bar)
             .await;
-        Ok(())
+        summary.lock().unwrap().skipped = total - source_files.len();
+        num_tasks.store(source_files.len(), Ordering::SeqCst);
+        progress_bar.set_num_tasks(source_files.len());
+        let arrivals = match self.watch {
+            Some(interval) => self
+                .arrivals(interval, seen)
+                .then(move |arrived| async move {
+                    let arrived_count = arrived.len();
+                    let source_files = self
+                        .skip_completed(arrived, destination_dir, progress_bar)
+                        .await;
+                    summary.lock().unwrap().skipped += arrived_count - source_files.len();
+                    let tasks = num_tasks.fetch_add(source_files.len(), Ordering::SeqCst);
+                    progress_bar.set_num_tasks(tasks + source_files.len());
+                    let found = Instant::now();
+                    stream::iter(
+                        source_files
+                            .into_iter()
+                            .map(move |source_file| (source_file, found)),
+                    )
+                })
+                .flatten()
+                .left_stream(),
+            None => stream::empty().right_stream(),
+        };
+        if self.watch.is_some() {
+            self.service.ready();
+        }
+        // Each input goes along with when it was found, to tell how long its task was queued.
+        let found = Instant::now();
+        Ok(stream::iter(
+            source_files
+                .into_iter()
+                .map(move |source_file| (source_file, found)),
+        )
+        .chain(arrivals))
+    }
+
+    /// The inputs whose paths are read from standard input, separated by `delimiter`,
+    /// each with when it was read, as they're read.
+    ///
+    /// Inputs are left out if they aren't files, aren't selected, or would share a destination
+    /// with one before them.
+    fn listed_inputs<'a, P: progress::Progress>(
+        &'a self,
+        delimiter: u8,
+        destination_dir: &'a Path,
+        progress_bar: &'a P,
+        summary: &'a Mutex<Summary>,
+        num_tasks: &'a AtomicUsize,
+    ) -> impl stream::Stream<Item = (Input, Instant)> + 'a {
+        use stream::StreamExt;
+        let paths = Box::pin(task_list::paths(tokio::io::stdin(), delimiter));
+        stream::unfold(paths, move |mut paths| async move {
+            loop {
+                let path = match paths.next().await? {
+                    Ok(path) => path,
+                    Err(error) => {
+                        progress_bar.warn(&Message::InputListUnreadable(&error));
+                        return None;
+                    }
+                };
+                let input = match self.listed_input(path).await {
+                    Ok(Some(input)) => input,
+                    Ok(None) => continue,
+                    Err(error) => {
+                        progress_bar.warn(&Message::LeftOut(&error));
+                        continue;
+                    }
+                };
+                if !self.wanted(&input) {
+                    continue;
+                }
+                if self.skip(&input, destination_dir, Some(progress_bar)).await {
+                    summary.lock().unwrap().skipped += 1;
+                    continue;
+                }
+                let tasks = num_tasks.fetch_add(1, Ordering::SeqCst) + 1;
+                progress_bar.set_num_tasks(tasks);
+                return Some(((input, Instant::now()), paths));
+            }
+        })
+    }
+
+    /// The input at `path`, read from standard input, named,
+    /// or `None` if it isn't a file or isn't selected.
+    async fn listed_input(&self, path: PathBuf) -> io::Result<Option<Input>> {
+        // Collecting its components drops any `./` in the path, as `find` writes them.
+        let path: PathBuf = std::env::current_dir()?.join(path).components().collect();
+        let name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            None => return Ok(None),
+        };
+        let metadata = fs::metadata(&path).await.map_err(|error| {
+            error::new(
+                error.kind(),
+                Message::Unreadable {
+                    path: &path,
+                    error: &error,
+                },
+            )
+        })?;
+        if !metadata.is_file() || !self.selection.selects(&resolve(&path)?, &name) {
+            return Ok(None);
+        }
+        let mut input = Input {
+            path,
+            name,
+            chunk: None,
+        };
+        self.name_input(&mut input)?;
+        Ok(Some(input))
     }
 
-    async fn run_command<R: Runner>(
+    async fn run<L: Launcher, P: progress::Progress>(
         &self,
-        runner: &R,
-        source_file: &fs::DirEntry,
+        launcher: &L,
         destination_dir: &Path,
+        progress_bar: &P,
+        on_task: &impl Fn(TaskResult),
+    ) -> io::Result<Summary> {
+        use stream::StreamExt;
+        let start = Instant::now();
+        let summary = Mutex::new(Summary::default());
+        let num_tasks = AtomicUsize::new(0);
+        if self.write_manifest {
+            *self.manifest.lock().unwrap() =
+                Some(naming::Manifest::create(destination_dir, &self.recipe)?);
+        }
+        let inputs = if let Some(delimiter) = self.task_list {
+            self.listed_inputs(
+                delimiter,
+                destination_dir,
+                progress_bar,
+                &summary,
+                &num_tasks,
+            )
+            .left_stream()
+            .left_stream()
+        } else if self.low_memory {
+            let (files, skipped) = self.stream_files(destination_dir, progress_bar).await?;
+            summary.lock().unwrap().skipped = skipped;
+            files
+                .map(|source_file| (source_file, Instant::now()))
+                .right_stream()
+                .left_stream()
+        } else {
+            self.load_inputs(destination_dir, progress_bar, &summary, &num_tasks)
+                .await?
+                .right_stream()
+        };
+        let tasks = inputs
+        // Runs this many inputs ahead of the tasks, so each one is read before its task needs it.
+        .map(|(input, found)| async move {
+            if self.prefetch > 0 {
+                if let Err(error) = prefetch::advise(&input.path, input.chunk).await {
+                    progress_bar.warn(&Message::PrefetchFailed {
+                            path: &input.path,
+                            error: &error,
+                        });
+                }
+            }
+            (input, found)
+        })
+        .buffered(self.prefetch.max(1))
+        // Makes up batches from whatever's ready, so a batch never waits for files to arrive.
+        .ready_chunks(self.batch)
+        .enumerate()
+        // Polled only when there's a free process, so tasks are held back one at a time.
+        .then(|task| async move {
+            self.throttled(progress_bar).await;
+            task
+        })
+        .take_while(|_| future::ready(self.stop_requested() == Stop::No))
+        .for_each_concurrent(self.num_processes, |(index, batch)| {
+            let summary = &summary;
+            async move {
+                let inputs: Vec<_> = batch.iter().map(|(input, _)| input.path.clone()).collect();
+                let dirs: Vec<_> = batch
+                    .iter()
+                    .map(|(input, _)| destination_dir.join(&input.name))
+                    .collect();
+                let chunks: Vec<_> = batch.iter().map(|(input, _)| input.chunk).collect();
+                let ids: Vec<_> = inputs
+                    .iter()
+                    .zip(&chunks)
+                    .map(|(input, chunk)| self.recipe.task_id(input, *chunk))
+                    .collect();
+                // Hashed before the task runs, so that changes made while it runs are noticed next time.
+                let hashes = if self.hash_inputs {
+                    self.hash_inputs(&inputs, progress_bar).await
+                } else {
+                    vec![None; inputs.len()]
+                };
+                let started = Instant::now();
+                let queued: Vec<_> = batch
+                    .iter()
+                    .map(|(_, found)| started.duration_since(*found))
+                    .collect();
+                for (id, input) in ids.iter().zip(&inputs) {
+                    progress_bar.task_started(id, input);
+                }
+                let (result, attempts) = self
+                    .run_task(launcher, progress_bar, &inputs, &dirs, chunks[0], &ids, index)
+                    .await;
+                let duration = started.elapsed();
+                let annotations = annotations::read(&dirs[0]).await.unwrap_or_else(|error| {
+                    progress_bar.warn(&Message::AnnotationsUnreadable {
+                            dir: &dirs[0],
+                            error: &error,
+                        });
+                    BTreeMap::new()
+                });
+                // Every input in a batch shares its result, so each one is recorded as if it were a task of its own.
+                let each_input = ids
+                    .into_iter()
+                    .zip(inputs)
+                    .zip(dirs)
+                    .zip(chunks)
+                    .zip(hashes)
+                    .zip(queued);
+                for (((((id, input), dir), chunk), hash), queued) in each_input {
+                    if let Some(hash) = hash {
+                        if let Err(error) = input_hash::write(&dir, &hash).await {
+                            progress_bar.warn(&Message::HashUnrecorded {
+                                    input: &input,
+                                    error: &error,
+                                });
+                        }
+                    }
+                    let mut task = TaskResult::new(id, input, dir, &result, attempts, duration);
+                    task.chunk = chunk;
+                    task.cancelled = self.cancelled(&result);
+                    task.annotations = annotations.clone();
+                    task.queued = queued;
+                    // Only measured when there's a limit, as it means reading every task's directory.
+                    let output_bytes = match self.max_total_output {
+                        Some(_) => disk_usage(&task.destination).await.unwrap_or(0),
+                        None => 0,
+                    };
+                    let (failed, total_output_bytes) = {
+                        let mut summary = summary.lock().unwrap();
+                        summary.record(&task, self.group_of(&task), self.summary_limit);
+                        summary.output_bytes += output_bytes;
+                        // Only the failure that reached the limit halted the run.
+                        if self.halt.stop_after(summary.failed).is_some()
+                            && summary.halted_by.is_none()
+                        {
+                            summary.halted_by = Some(Failure::of(&task));
+                        }
+                        (summary.failed, summary.output_bytes)
+                    };
+                    progress_bar.task_completed(
+                        &task.id,
+                        &TaskOutcome {
+                            task: &task,
+                            result: &result,
+                        },
+                    );
+                    on_task(task);
+                    if let Some(stop) = self.halt.stop_after(failed) {
+                        self.stop(stop, CancelReason::Halt);
+                    }
+                    if matches!(self.max_total_output, Some(limit) if total_output_bytes > limit) {
+                        self.stop(self.output_policy.stop(), CancelReason::Budget);
+                    }
+                }
+            }
+        });
+        // Following pauses and feeding the watchdog never finish, so they stop when the tasks do.
+        tokio::select! {
+            _ = tasks => {}
+            _ = self.pauser.follow(|message| progress_bar.warn(message)) => {}
+            _ = self.service.feed_watchdog() => {}
+        }
+        if let Some(manifest) = self.manifest.lock().unwrap().take() {
+            if let Err(error) = manifest.finish() {
+                progress_bar.warn(&Message::ManifestUnwritable(&error));
+            }
+        }
+        let mut summary = summary.into_inner().unwrap();
+        summary.duration = start.elapsed();
+        Ok(summary)
+    }
+
+    /// The hash of each of `inputs`, or `None` for any that couldn't be read.
+    async fn hash_inputs<P: progress::Progress>(
+        &self,
+        inputs: &[PathBuf],
+        progress_bar: &P,
+    ) -> Vec<Option<String>> {
+        let mut hashes = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let _permit = self.io_permit().await;
+            hashes.push(match input_hash::hash_file(input).await {
+                Ok(hash) => Some(hash),
+                Err(error) => {
+                    progress_bar.warn(&Message::HashFailed {
+                        input,
+                        error: &error,
+                    });
+                    None
+                }
+            });
+        }
+        hashes
+    }
+
+    /// The name of the group in the summary that `task` belongs to.
+    fn group_of(&self, task: &TaskResult) -> String {
+        self.group_by
+            .render_arg(std::slice::from_ref(&task.input), &task.destination)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Wait until the throttle lets another task start, unless the run has to stop now.
+    async fn throttled<P: progress::Progress>(&self, progress_bar: &P) {
+        drop(self.outage.lock().await);
+        if let Some(window) = self.throttle.closed_window() {
+            progress_bar.warn(&Message::OutsideWindow {
+                window: &window.to_string(),
+                opens: &window.opens(),
+            });
+        }
+        let mut stop_requested = self.stop_requested.clone();
+        let ready = async {
+            self.throttle.wait().await;
+            self.pauser.resumed().await;
+        };
+        tokio::select! {
+            _ = ready => {}
+            _ = wait_for_stop(&mut stop_requested, Stop::Now) => {}
+        }
+    }
+
+    /// Run the command for a task, retrying it if it fails.
+    ///
+    /// If it fails because the destination's filesystem is failing, it's tried again once the
+    /// filesystem is back, without counting against its retries. So it is if it's killed by
+    /// one of `retry_signals`, up to `MAX_SIGNAL_RETRIES` times.
+    ///
+    /// Returns the result of the last attempt, and how many attempts there were.
+    // TODO: Count a failure in an earlier run against the retries, as `--retries` promises.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_task<L: Launcher, P: progress::Progress>(
+        &self,
+        launcher: &L,
+        progress_bar: &P,
+        inputs: &[PathBuf],
+        dirs: &[PathBuf],
+        chunk: Option<Chunk>,
+        ids: &[TaskId],
+        index: usize,
+    ) -> (io::Result<ExitStatus>, u32) {
+        let workdir = self
+            .workdir
+            .as_ref()
+            .map(|workdir| PathBuf::from(workdir.render_arg(inputs, &dirs[0])));
+        let mut attempts = 0;
+        let mut false_alarms = 0;
+        let mut signal_retries = 0;
+        loop {
+            attempts += 1;
+            let task = Task {
+                inputs,
+                dirs,
+                chunk,
+                workdir: workdir.as_deref(),
+                id: &ids[0],
+                index,
+                attempt: attempts,
+            };
+            let result = self.run_command(launcher, &task).await;
+            if let Err(error) = &result {
+                if outage::is_outage(error) && false_alarms < outage::MAX_FALSE_ALARMS {
+                    if !self.wait_out_outage(&dirs[0], error, progress_bar).await {
+                        false_alarms += 1;
+                    }
+                    if self.stop_requested() == Stop::No {
+                        attempts -= 1;
+                        continue;
+                    }
+                }
+            }
+            let retry = match &result {
+                Ok(status) => !status.success(),
+                Err(error) => error.kind() != io::ErrorKind::Interrupted,
+            };
+            let signal = match &result {
+                Ok(status) => Status::from(*status).signal(),
+                Err(_) => None,
+            };
+            if matches!(signal, Some(signal) if self.retry_signals.contains(&signal))
+                && signal_retries < MAX_SIGNAL_RETRIES
+            {
+                signal_retries += 1;
+            }
+            if !retry
+                || attempts > self.retries + signal_retries
+                || self.stop_requested() != Stop::No
+            {
+                return (result, attempts);
+            }
+            for (id, input) in ids.iter().zip(inputs) {
+                progress_bar.task_retrying(id, input, attempts + 1, &result);
+            }
+        }
+    }
+
+    /// Wait until files can be written in the destination directory that `task_dir` is in
+    /// again, after a task failed with `error`, unless the run has to stop now.
+    ///
+    /// Only one task waits at a time, and no new tasks start until it's done.
+    /// Returns whether the filesystem was found to be failing at all.
+    async fn wait_out_outage(
+        &self,
+        task_dir: &Path,
+        error: &io::Error,
+        progress_bar: &impl progress::Progress,
+    ) -> bool {
+        let _outage = self.outage.lock().await;
+        let destination_dir = task_dir.parent().unwrap_or(task_dir);
+        let mut stop_requested = self.stop_requested.clone();
+        let mut delay = outage::FIRST_DELAY;
+        let mut failing = false;
+        while outage::probe(destination_dir).await.is_err() {
+            if !failing {
+                progress_bar.warn(&Message::DestinationFailing(error));
+                failing = true;
+            }
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = wait_for_stop(&mut stop_requested, Stop::Now) => return failing,
+            }
+            delay = (delay * 2).min(outage::MAX_DELAY);
+        }
+        if failing {
+            progress_bar.warn(&Message::DestinationBack);
+        }
+        failing
+    }
+
+    /// Run `task`'s pipeline, from wherever it should start, and write its status.
+    async fn run_command<L: Launcher>(
+        &self,
+        launcher: &L,
+        task: &Task<'_>,
     ) -> io::Result<ExitStatus> {
-        let base_directory = destination_dir.join(source_file.file_name());
-        ensure_directory(&base_directory).await?;
+        // A manifest can list inputs that have gone from the sources since it was written,
+        // and they fail their tasks, whether or not the command would notice.
+        if self.from_manifest.is_some() {
+            for input in task.inputs {
+                fs::metadata(input).await.map_err(error::in_source(input))?;
+            }
+        }
+        let first_stage = self.first_stage(task).await;
+        let (result, clipped) = if first_stage == 0 {
+            stages::clear_from(task.dir(), 1, self.stages.len()).await?;
+            let (result, clipped) = self.run_own_command(launcher, task).await?;
+            match result {
+                Ok(status) if status.success() && !self.stages.is_empty() => {
+                    (self.run_stages(task, 1).await, clipped)
+                }
+                result => (result, clipped),
+            }
+        } else {
+            {
+                let _permit = self.io_permit().await;
+                prepare(task, self.outputs.run_as()).await?;
+            }
+            (self.run_stages(task, first_stage).await, Vec::new())
+        };
+        let status = match status_of(&result) {
+            Some(status) => status,
+            None => return result,
+        };
+        let cancelled = self.cancelled(&result);
+        // The rest of a batch's directories only have a status, so there's nothing to clip.
+        // Checksums come first, so that a task with a status always has them.
+        if self.checksums {
+            checksums::record(task.dir()).await?;
+        }
+        status.write(task.dir(), &clipped, cancelled).await?;
+        for dir in &task.dirs[1..] {
+            status.write(dir, &[], cancelled).await?;
+        }
+        result
+    }
+
+    /// Which stage of its pipeline `task` starts from, where 0 is its own command.
+    ///
+    /// Recreating a task starts it from scratch, but retrying it picks up where it failed.
+    async fn first_stage(&self, task: &Task<'_>) -> usize {
+        if self.stages.is_empty()
+            || (task.attempt == 1 && (self.recreate || self.rerun_only.is_some()))
+        {
+            return 0;
+        }
+        stages::resume_from(task.dir(), self.stages.len()).await
+    }
 
-        // TODO(jml): 'create' truncates. Actual desired behaviour depends on 'recreate' setting.
-        let (out_file, err_file, command) = join!(
-            fs::File::create(base_directory.join("out"))
+    /// Run `task`'s own command, with its hooks, and the names of any output files that
+    /// had to be clipped.
+    async fn run_own_command<L: Launcher>(
+        &self,
+        launcher: &L,
+        task: &Task<'_>,
+    ) -> io::Result<(io::Result<ExitStatus>, Vec<String>)> {
+        // Only this attempt's annotations count, but the hooks can annotate it too.
+        annotations::clear(task.dir()).await?;
+        if let Some(setup) = &self.setup {
+            {
+                let _permit = self.io_permit().await;
+                prepare(task, self.outputs.run_as()).await?;
+            }
+            self.run_hook(setup, task, Vec::new()).await?;
+        }
+        let mut process = self.start_command(launcher, task).await?;
+        let result = self.wait_for(&mut process, task.dir()).await;
+        let clipped = process.clipped();
+        launcher.finished(process);
+        // There's no point tidying up after an interrupted task, as there's no time for it.
+        match (&self.teardown, status_of(&result)) {
+            (Some(teardown), Some(status)) if status != Status::Interrupted => {
+                let env = vec![("REACH_STATUS", status.to_string().into())];
+                self.run_hook(teardown, task, env).await?;
+            }
+            _ => {}
+        }
+        Ok((result, clipped))
+    }
+
+    /// Run the stages after `task`'s own command, starting from stage `first`, until one fails.
+    async fn run_stages(&self, task: &Task<'_>, first: usize) -> io::Result<ExitStatus> {
+        let mut stage = first;
+        loop {
+            let result = self.run_stage(task, stage).await;
+            if stage == self.stages.len() || !matches!(&result, Ok(status) if status.success()) {
+                return result;
+            }
+            stage += 1;
+        }
+    }
+
+    /// Run the `stage`th stage after `task`'s own command, counting from one, on the output
+    /// of the one before, and write its status.
+    async fn run_stage(&self, task: &Task<'_>, stage: usize) -> io::Result<ExitStatus> {
+        let stage_dir = stages::dir(task.dir(), stage);
+        let previous_dir = match stage {
+            1 => task.dir().to_path_buf(),
+            _ => stages::dir(task.dir(), stage - 1),
+        };
+        let mut process = {
+            let _permit = self.io_permit().await;
+            stages::clear_from(task.dir(), stage, self.stages.len()).await?;
+            ensure_directory(&stage_dir).await?;
+            if let Some(run_as) = self.outputs.run_as() {
+                run_as.own(&stage_dir)?;
+            }
+            let mut env = task.env();
+            env.push(("REACH_STAGE", stage.to_string().into()));
+            env.push(("REACH_PREVIOUS_DIR", previous_dir.clone().into()));
+            let stdin = self
+                .outputs
+                .stdout_path(&previous_dir)
+                .expect("Stages never discard their output");
+            self.stages[stage - 1]
+                .start(
+                    task.inputs,
+                    task.dir(),
+                    &stage_dir,
+                    &stdin,
+                    &self.outputs,
+                    &env,
+                )
                 .await?
-                .into_std(),
-            fs::File::create(base_directory.join("err"))
+        };
+        let result = self.wait_for(&mut process, task.dir()).await;
+        if let Some(status) = status_of(&result) {
+            status
+                .write(&stage_dir, &process.clipped(), self.cancelled(&result))
+                .await?;
+        }
+        result
+    }
+
+    /// Prepare the destination directory for a task, and start its command.
+    ///
+    /// Only `io_concurrency` tasks can be doing this at any one time.
+    async fn start_command<L: Launcher>(
+        &self,
+        launcher: &L,
+        task: &Task<'_>,
+    ) -> io::Result<L::Process> {
+        let reservation = launcher.reserve(task).await;
+        // The wait may have been long enough for the run to be interrupted.
+        if self.stop_requested() == Stop::Now {
+            return Err(interrupted_error());
+        }
+        let _permit = self.io_permit().await;
+        prepare(task, self.outputs.run_as())
+            .await
+            .map_err(error::in_output(task.dir()))?;
+        launcher
+            .launch(task, reservation)
+            .await
+            .map_err(error::in_spawn(task.input()))
+    }
+
+    /// Wait for a turn to open or create files.
+    async fn io_permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.io_limiter
+            .acquire()
+            .await
+            .expect("IO limiter is never closed")
+    }
+
+    /// Run `hook` for `task`, with `env` as well as the task's own environment variables.
+    ///
+    /// The hook gets the same timeout as the task's command.
+    async fn run_hook(
+        &self,
+        hook: &hooks::Hook,
+        task: &Task<'_>,
+        env: Vec<(&'static str, OsString)>,
+    ) -> io::Result<()> {
+        let mut task_env = task.env();
+        task_env.extend(env);
+        let mut child = {
+            let _permit = self.io_permit().await;
+            hook.start_for_task(task.inputs, task.dir(), &task_env)
                 .await?
-                .into_std(),
-            runner.get_command(source_file),
-        );
-        let mut command = command?;
-        let mut child_process = command.stdout(out_file).stderr(err_file).spawn()?;
-        child_process.wait().await
+        };
+        let status = self.wait_for(&mut child, task.dir()).await?;
+        hook.check(status)
+    }
+
+    /// Wait for a running command of the task with destination directory `task_dir` to finish.
+    ///
+    /// Terminates the command if it runs past the timeout, or if the run has to stop now,
+    /// once the task's cancel file says why.
+    async fn wait_for<P: Process>(&self, child: &mut P, task_dir: &Path) -> io::Result<ExitStatus> {
+        let timeout = async {
+            match self.timeout {
+                // Time spent frozen doesn't count.
+                Some(timeout) => self.pauser.sleep(timeout).await,
+                None => future::pending().await,
+            }
+        };
+        let mut stop_requested = self.stop_requested.clone();
+        tokio::select! {
+            status = child.wait() => {
+                let status = status?;
+                // Ctrl-C also reaches children directly, so they can die before we notice.
+                if !status.success() && self.stop_requested() == Stop::Now {
+                    Err(interrupted_error())
+                } else {
+                    Ok(status)
+                }
+            }
+            _ = timeout => {
+                // The command is terminated whether or not it could be told why.
+                let _ = cancel::announce(task_dir, CancelReason::Timeout).await;
+                child.terminate().await?;
+                Err(error::new(
+                    io::ErrorKind::TimedOut,
+                    Message::TimedOut(self.timeout.unwrap_or_default()),
+                ))
+            }
+            _ = wait_for_stop(&mut stop_requested, Stop::Now) => {
+                let reason = self.cancel_reason.get().copied().unwrap_or(CancelReason::Shutdown);
+                let _ = cancel::announce(task_dir, reason).await;
+                child.terminate().await?;
+                Err(interrupted_error())
+            }
+        }
+    }
+}
+
+/// An input to the run: a file in one of the sources, or a chunk of the one that's split.
+#[derive(Debug)]
+struct Input {
+    path: PathBuf,
+    /// The name of the input's destination directory, as the run's naming has it,
+    /// or the file's own name until it's been named. A chunk is always named for the file,
+    /// with its number after it.
+    name: OsString,
+    /// The part of the file that's the input, if it's split.
+    chunk: Option<Chunk>,
+}
+
+/// Which files in a source directory are inputs.
+#[derive(Debug)]
+struct Selection {
+    /// The destination and state directories, resolved, which no input is ever taken from.
+    excluded_dirs: Vec<PathBuf>,
+    /// Only files whose names match one of these are inputs, unless there are none.
+    include: Vec<glob::Glob>,
+    /// No file whose name matches one of these is an input.
+    exclude: Vec<glob::Glob>,
+    /// Only files whose names are unicode are inputs.
+    unicode_only: bool,
+}
+
+impl Selection {
+    /// Whether the file called `name`, at the resolved `path`, is an input.
+    fn selects(&self, path: &Path, name: &OsStr) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(name)))
+            && !self.exclude.iter().any(|glob| glob.matches(name))
+            && !self.excluded_dirs.iter().any(|dir| path.starts_with(dir))
+            && !(self.unicode_only && name.to_str().is_none())
+    }
+}
+
+fn parse_globs(patterns: &[String]) -> io::Result<Vec<glob::Glob>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            glob::Glob::parse(pattern)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+        })
+        .collect()
+}
+
+/// The files in `source`, if it's a directory, or `source` itself, if it's a file,
+/// leaving out any that `selection` doesn't select.
+async fn list_source(
+    source: &Path,
+    selection: &Selection,
+) -> io::Result<Vec<(Input, std::fs::Metadata)>> {
+    use stream::TryStreamExt;
+    source_entries(source, selection).await?.try_collect().await
+}
+
+/// Like `list_source`, but as the directory is read, rather than all at once.
+async fn source_entries<'a>(
+    source: &Path,
+    selection: &'a Selection,
+) -> io::Result<impl stream::Stream<Item = io::Result<(Input, std::fs::Metadata)>> + 'a> {
+    use stream::TryStreamExt;
+    // Only a directory listing has entries, so a single file is found in its directory's.
+    let (dir, only) = if fs::metadata(source).await?.is_dir() {
+        (source, None)
+    } else {
+        (
+            source.parent().unwrap_or(Path::new(".")),
+            source.file_name().map(OsStr::to_owned),
+        )
+    };
+    let resolved_dir = resolve(dir)?;
+    let stream = ReadDirStream::new(fs::read_dir(dir).await?);
+    Ok(stream
+        .try_filter(move |source_file| {
+            let name = source_file.file_name();
+            let path = resolved_dir.join(&name);
+            future::ready(
+                (only.is_none() || only.as_deref() == Some(&*name))
+                    && selection.selects(&path, &name),
+            )
+        })
+        .and_then(|source_file| async move {
+            let metadata = source_file.metadata().await?;
+            let input = Input {
+                path: source_file.path(),
+                name: source_file.file_name(),
+                chunk: None,
+            };
+            Ok((input, metadata))
+        })
+        .try_filter(|(_, metadata)| future::ready(metadata.is_file())))
+}
+
+/// Make sure each of a task's destination directories exists, with no status,
+/// and so does its working directory, belonging to `run_as` if it's given.
+async fn prepare(task: &Task<'_>, run_as: Option<RunAs>) -> io::Result<()> {
+    for dir in task.dirs {
+        ensure_directory(dir).await?;
+        Status::clear(dir).await?;
+        cancel::clear(dir).await?;
+        checksums::clear(dir).await?;
+    }
+    if let Some(workdir) = task.workdir {
+        ensure_directory(workdir).await?;
+    }
+    if let Some(run_as) = run_as {
+        for dir in task.dirs.iter().map(PathBuf::as_path).chain(task.workdir) {
+            run_as.own(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// The status to record for a command that finished with `result`, or `None` if it
+/// couldn't be run at all.
+pub(crate) fn status_of(result: &io::Result<ExitStatus>) -> Option<Status> {
+    match result {
+        Ok(exit_status) => Some(Status::from(*exit_status)),
+        Err(error) => Status::from_error(error),
+    }
+}
+
+fn interrupted_error() -> io::Error {
+    Error::Interrupted.into()
+}
+
+/// Completes once the run has been asked to stop at least as urgently as `stop`.
+async fn wait_for_stop(stop_requested: &mut watch::Receiver<Stop>, stop: Stop) {
+    while *stop_requested.borrow() < stop {
+        if stop_requested.changed().await.is_err() {
+            // Nothing can stop the run any more.
+            future::pending::<()>().await;
+        }
+    }
+}
+
+/// The most times a task is tried again because its command was killed by one of `Config::retry_signals`.
+const MAX_SIGNAL_RETRIES: u32 = 3;
+
+/// How long a child process gets to exit after SIGTERM before we send SIGKILL.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Give the command a process group of its own, which whatever it starts joins too,
+/// so that `terminate` can stop all of them, not just the command's own process.
+#[cfg(unix)]
+pub(crate) fn in_own_process_group(command: &mut Command) {
+    // SAFETY: `setpgid` is async-signal-safe, and we only call it between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn in_own_process_group(_command: &mut Command) {}
+
+/// Stop a child process, and everything it started, politely at first.
+///
+/// On Unix, sends SIGTERM to the child's process group and waits up to `KILL_GRACE_PERIOD`
+/// for the child to exit, then kills whatever's left of the group outright, as anything it
+/// started may have ignored SIGTERM or outlived it. A child without a group of its own, from
+/// `in_own_process_group`, is signalled on its own.
+async fn terminate(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        let pid = pid as libc::pid_t;
+        // SAFETY: `kill` has no memory safety requirements. `pid` is our own child, which
+        // has not been reaped yet, so neither it nor the group it leads can be reused.
+        unsafe {
+            if libc::kill(-pid, libc::SIGTERM) != 0 {
+                libc::kill(pid, libc::SIGTERM);
+            }
+        }
+        let exited = time::timeout(KILL_GRACE_PERIOD, child.wait()).await;
+        // SAFETY: As above, except that the child may have been reaped, but the group
+        // lasts as long as anything it started, and pids are only reused long after.
+        unsafe { libc::kill(-pid, libc::SIGKILL) };
+        if let Ok(status) = exited {
+            return status.map(|_| ());
+        }
+    }
+    child.kill().await
+}
+
+/// A running task's command, whether it's a process of its own or running in a shell session.
+#[async_trait]
+trait Process {
+    /// Wait for the command to finish.
+    async fn wait(&mut self) -> io::Result<ExitStatus>;
+
+    /// Stop the command, politely at first.
+    async fn terminate(&mut self) -> io::Result<()>;
+
+    /// The names of the output files that had to be clipped to `Config::max_output_size`,
+    /// once the command has finished.
+    fn clipped(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[async_trait]
+impl Process for Child {
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        Child::wait(self).await
+    }
+
+    async fn terminate(&mut self) -> io::Result<()> {
+        terminate(self).await
+    }
+}
+
+/// One attempt at a task: processing a source file, or a batch of them, into its destination directory.
+struct Task<'a> {
+    /// Never empty, and only more than one file in a batch.
+    inputs: &'a [PathBuf],
+    /// The destination directory for each input, in the same order.
+    ///
+    /// The first input's is the task's, and the others only get a status.
+    dirs: &'a [PathBuf],
+    /// The part of the input that's the task's, if the input is split.
+    chunk: Option<Chunk>,
+    /// The directory to run the task's command in, if not reach's own.
+    workdir: Option<&'a Path>,
+    /// The task's ID, or the first input's in a batch.
+    id: &'a TaskId,
+    /// Where the task comes in the run, counting from zero.
+    index: usize,
+    /// Which attempt at the task this is, counting from one.
+    attempt: u32,
+}
+
+impl Task<'_> {
+    /// The task's destination directory.
+    fn dir(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    /// The task's only input, or the first in its batch.
+    fn input(&self) -> &Path {
+        &self.inputs[0]
+    }
+
+    /// The name the task goes by: its input's file name.
+    fn name(&self) -> &OsStr {
+        self.input().file_name().unwrap_or_default()
+    }
+
+    /// The environment variables that tell the task's command about the task.
+    fn env(&self) -> Vec<(&'static str, OsString)> {
+        let mut env = vec![
+            ("REACH_INPUT", self.input().into()),
+            ("REACH_INPUT_NAME", self.name().into()),
+            ("REACH_DEST_DIR", self.dir().into()),
+            ("REACH_TASK_ID", self.id.as_str().into()),
+            ("REACH_TASK_INDEX", self.index.to_string().into()),
+            ("REACH_ATTEMPT", self.attempt.to_string().into()),
+            ("REACH_ANNOTATIONS", annotations::path(self.dir()).into()),
+            ("REACH_CANCEL_FILE", cancel::path(self.dir()).into()),
+        ];
+        if let Some(chunk) = self.chunk {
+            env.push(("REACH_OFFSET", chunk.offset.to_string().into()));
+            env.push(("REACH_LENGTH", chunk.length.to_string().into()));
+        }
+        env
+    }
+}
+
+/// Starts the command for each task, one way or another.
+#[async_trait]
+trait Launcher: Sync {
+    type Process: Process + Send;
+
+    /// Wait for whatever `task` needs to itself, like a particular worker,
+    /// before it takes its turn to open files.
+    async fn reserve(&self, _task: &Task<'_>) -> Reservation {
+        Reservation::any()
+    }
+
+    /// Start the command for `task`, whose destination directory exists and has no status.
+    async fn launch(&self, task: &Task<'_>, reservation: Reservation) -> io::Result<Self::Process>;
+
+    /// Take back a task's process once its command has finished.
+    fn finished(&self, _process: Self::Process) {}
+}
+
+/// Launches the command that a `Runner` builds for each task as a process of its own.
+struct Spawn<R> {
+    runner: R,
+    outputs: capture::Outputs,
+}
+
+#[async_trait]
+impl<R: Runner + Sync> Launcher for Spawn<R> {
+    type Process = capture::Captured;
+
+    async fn launch(
+        &self,
+        task: &Task<'_>,
+        _reservation: Reservation,
+    ) -> io::Result<capture::Captured> {
+        let mut command = self
+            .runner
+            .get_command(task.inputs, task.chunk, task.dir())
+            .await?;
+        command.envs(task.env());
+        if let Some(workdir) = task.workdir {
+            command.current_dir(workdir);
+        }
+        let mut process = self
+            .outputs
+            .spawn(command, task.name(), task.inputs, task.dir(), task.workdir)
+            .await?;
+        process.feed(task.inputs, task.chunk);
+        Ok(process)
     }
 }
 
 #[async_trait]
 trait Runner {
-    async fn get_command(&self, source_file: &fs::DirEntry) -> io::Result<Command>;
+    /// Build the command for the task that processes `inputs`, or only `chunk` of its one
+    /// input if that's given, into `task_dir`.
+    ///
+    /// If the command's standard input is piped, it gets the contents of every input in turn,
+    /// or the chunk's part of its input.
+    ///
+    /// Errors say which input, if it was one of them that couldn't be read.
+    async fn get_command(
+        &self,
+        inputs: &[PathBuf],
+        chunk: Option<Chunk>,
+        task_dir: &Path,
+    ) -> Result<Command, Error>;
+
+    /// The POSIX shell script for the task that processes `inputs` into `task_dir`,
+    /// for running in a shell session.
+    #[cfg(unix)]
+    fn script(&self, inputs: &[PathBuf], task_dir: &Path) -> Result<session::Script, Error>;
+}
+
+/// A POSIX shell script that runs `command`, a program followed by its arguments,
+/// inside `wrap` if there is one.
+#[cfg(unix)]
+fn wrapped_script(
+    wrap: Option<&WrapTemplate>,
+    command: Vec<OsString>,
+    inputs: &[PathBuf],
+    task_dir: &Path,
+) -> io::Result<OsString> {
+    match wrap {
+        Some(wrap) => shell_words(&wrap.wrap(command, inputs, task_dir)),
+        None => shell_words(&command),
+    }
+}
+
+/// Build a command from a program and its arguments, inside `wrap` if there is one.
+///
+/// Its standard input is `/dev/null`, as reach's own could be the list of inputs,
+/// and one task at a time can't have it anyway.
+fn new_command(
+    wrap: Option<&WrapTemplate>,
+    command: Vec<OsString>,
+    inputs: &[PathBuf],
+    task_dir: &Path,
+) -> Command {
+    let command = match wrap {
+        Some(wrap) => wrap.wrap(command, inputs, task_dir),
+        None => command,
+    };
+    let mut command = shell::command(command);
+    command.stdin(Stdio::null());
+    command
 }
 
 #[derive(Debug)]
 struct StdinRunner {
-    shell: String,
+    shell: Shell,
     command: String,
+    wrap: Option<WrapTemplate>,
 }
 
 impl StdinRunner {
-    fn new(shell: String, command: String) -> Self {
-        StdinRunner { shell, command }
+    fn new(shell: &str, command: String, wrap: Option<WrapTemplate>) -> Self {
+        StdinRunner {
+            shell: Shell::new(shell),
+            command,
+            wrap,
+        }
     }
 }
 
 #[async_trait]
 impl Runner for StdinRunner {
-    async fn get_command(&self, source_file: &fs::DirEntry) -> io::Result<Command> {
-        let source_path = source_file.path();
+    async fn get_command(
+        &self,
+        inputs: &[PathBuf],
+        chunk: Option<Chunk>,
+        task_dir: &Path,
+    ) -> Result<Command, Error> {
+        // The child gets the input file itself as its stdin, rather than a pipe we copy into,
+        // so its contents never pass through reach, and the child can seek or mmap it.
+        // A batch has no one file to give it, and a chunk would be read past its end,
+        // so they get a pipe after all.
         // TODO(jml): Understand whether this actually has any benefit over directly opening the standard file.
-        let in_file = fs::File::open(source_path).await?.into_std().await;
-        let mut command = Command::new(&self.shell);
-        command.arg("-c").arg(&self.command).stdin(in_file);
+        let stdin: Stdio = match (inputs, chunk) {
+            ([input], None) => {
+                let file = fs::File::open(input)
+                    .await
+                    .map_err(|source| Error::Source {
+                        path: input.clone(),
+                        source,
+                    })?;
+                file.into_std().await.into()
+            }
+            _ => Stdio::piped(),
+        };
+        let argv = self.shell.argv(&self.command);
+        let mut command = new_command(self.wrap.as_ref(), argv, inputs, task_dir);
+        command.stdin(stdin);
         Ok(command)
     }
+
+    #[cfg(unix)]
+    fn script(&self, inputs: &[PathBuf], task_dir: &Path) -> Result<session::Script, Error> {
+        let text = match &self.wrap {
+            Some(_) => {
+                let argv = self.shell.argv(&self.command);
+                wrapped_script(self.wrap.as_ref(), argv, inputs, task_dir)?
+            }
+            None => self.command.clone().into(),
+        };
+        Ok(session::Script {
+            text,
+            stdin: inputs.to_vec(),
+        })
+    }
 }
 
 struct FilenameRunner {
-    shell: String,
-    command: String,
+    shell: Shell,
+    command: Template,
+    wrap: Option<WrapTemplate>,
 }
 
 impl FilenameRunner {
-    fn new(shell: String, command: String) -> Self {
-        FilenameRunner { shell, command }
+    fn new(shell: &str, command: String, wrap: Option<WrapTemplate>) -> Self {
+        FilenameRunner {
+            shell: Shell::new(shell),
+            command: Template::parse(&command),
+            wrap,
+        }
     }
 }
 
 #[async_trait]
 impl Runner for FilenameRunner {
-    async fn get_command(&self, source_file: &fs::DirEntry) -> io::Result<Command> {
-        let source_path = source_file.path();
-        let source_path = source_path.to_str().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::Unsupported,
-                format!("Non-unicode filename: {:?}", source_path),
-            )
-        })?;
-        let mut command = Command::new(&self.shell);
-        command
-            .arg("-c")
-            .arg(self.command.replace("{}", source_path));
-        Ok(command)
+    async fn get_command(
+        &self,
+        inputs: &[PathBuf],
+        _chunk: Option<Chunk>,
+        task_dir: &Path,
+    ) -> Result<Command, Error> {
+        let rendered = self
+            .command
+            .render(inputs, task_dir, self.shell.quoting())?;
+        let argv = self.shell.argv(rendered);
+        Ok(new_command(self.wrap.as_ref(), argv, inputs, task_dir))
+    }
+
+    #[cfg(unix)]
+    fn script(&self, inputs: &[PathBuf], task_dir: &Path) -> Result<session::Script, Error> {
+        let rendered = self
+            .command
+            .render(inputs, task_dir, self.shell.quoting())?;
+        let text = match &self.wrap {
+            Some(_) => {
+                let argv = self.shell.argv(rendered);
+                wrapped_script(self.wrap.as_ref(), argv, inputs, task_dir)?
+            }
+            None => rendered,
+        };
+        Ok(session::Script {
+            text,
+            stdin: Vec::new(),
+        })
+    }
+}
+
+/// Runs the command directly, without a shell, substituting placeholders into its arguments.
+struct ExecRunner {
+    command: ArgsTemplate,
+    wrap: Option<WrapTemplate>,
+}
+
+impl ExecRunner {
+    fn new(command: &str, wrap: Option<WrapTemplate>) -> io::Result<Self> {
+        let command = ArgsTemplate::parse(command)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        Ok(ExecRunner { command, wrap })
+    }
+}
+
+#[async_trait]
+impl Runner for ExecRunner {
+    async fn get_command(
+        &self,
+        inputs: &[PathBuf],
+        _chunk: Option<Chunk>,
+        task_dir: &Path,
+    ) -> Result<Command, Error> {
+        let mut argv = vec![self.command.program(inputs, task_dir)];
+        argv.extend(self.command.args(inputs, task_dir));
+        Ok(new_command(self.wrap.as_ref(), argv, inputs, task_dir))
+    }
+
+    #[cfg(unix)]
+    fn script(&self, inputs: &[PathBuf], task_dir: &Path) -> Result<session::Script, Error> {
+        let mut argv = vec![self.command.program(inputs, task_dir)];
+        argv.extend(self.command.args(inputs, task_dir));
+        let text = wrapped_script(self.wrap.as_ref(), argv, inputs, task_dir)?;
+        Ok(session::Script {
+            text,
+            stdin: Vec::new(),
+        })
     }
 }
 
 /// How the command given to `reach` gets at its input.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InputMode {
     /// The contents of the input file are sent to standard input.
+    ///
+    /// The input file is opened and handed to the command as its standard input,
+    /// so reach never copies its contents, however big it is.
+    /// Commands that want a path rather than a stream can open `/dev/stdin`,
+    /// which on Linux is the input file itself and can be seeked or mmapped.
     Stdin,
-    /// The name of the input file is passed as a command-line argument.
+    /// The name of the input file is substituted into the command.
+    ///
+    /// Besides `{}` for the input's path, the command can use `{basename}`,
+    /// `{stem}`, `{ext}`, and `{dir}` for parts of that path,
+    /// and `{dest}` for the task's destination directory.
+    /// With batches, `{+}` is the paths of all of a batch's inputs, and the other
+    /// placeholders are about the first of them.
     Filename,
+    /// Like `Filename`, but the command is run directly rather than by the shell.
+    ///
+    /// The command is split into words using shell quoting rules, and placeholders
+    /// are substituted into each word exactly, with no quoting needed.
+    /// Nothing else the shell does, like pipes or variables, is available.
+    Exec,
+    /// The command is started once for each process slot, and every input is sent
+    /// to one of these long-lived workers as a request on its standard input.
+    ///
+    /// The worker's response to the request, framed the same way, becomes the task's `out`.
+    /// Workers' standard error goes to `coprocess.err` in the state directory.
+    Coprocess,
+}
+
+impl InputMode {
+    /// The name of the input mode, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputMode::Stdin => "stdin",
+            InputMode::Filename => "filename",
+            InputMode::Exec => "exec",
+            InputMode::Coprocess => "coprocess",
+        }
+    }
+
+    /// The input mode `command` most likely wants: `Filename` if it has a `{}` or `{+}`
+    /// placeholder, otherwise `Stdin`.
+    pub fn detect(command: &str) -> Self {
+        if Template::parse(command).has_input() {
+            InputMode::Filename
+        } else {
+            InputMode::Stdin
+        }
+    }
 }
 
 impl FromStr for InputMode {
@@ -203,11 +2512,336 @@ impl FromStr for InputMode {
         match s.to_lowercase().as_str() {
             "stdin" => Ok(InputMode::Stdin),
             "filename" => Ok(InputMode::Filename),
+            "exec" => Ok(InputMode::Exec),
+            "coprocess" => Ok(InputMode::Coprocess),
             _ => Err(format!("No such InputMode: {}", s)),
         }
     }
 }
 
+/// The order to process the files in the source directory in.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// Whatever order the filesystem lists them in, which costs nothing.
+    #[default]
+    Unordered,
+    /// By name.
+    Name,
+    /// Largest first, so the longest tasks don't hold up the end of the run.
+    Size,
+    /// Least recently modified first.
+    Mtime,
+    /// Shuffled.
+    Random,
+}
+
+impl Order {
+    /// Sort `files`, shuffling them the same way every time if there's a `seed`.
+    fn sort(&self, files: &mut [(Input, std::fs::Metadata)], seed: Option<u64>) {
+        match self {
+            Order::Unordered => {}
+            Order::Name => files.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name)),
+            Order::Size => files.sort_by_key(|(_, metadata)| std::cmp::Reverse(metadata.len())),
+            Order::Mtime => files.sort_by_key(|(_, metadata)| metadata.modified().ok()),
+            Order::Random => match seed {
+                Some(seed) => {
+                    // From the same order, whatever order the filesystem lists them in.
+                    files.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+                    files.shuffle(&mut StdRng::seed_from_u64(seed));
+                }
+                None => files.shuffle(&mut rand::thread_rng()),
+            },
+        }
+    }
+}
+
+impl Order {
+    /// The name of the order, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Order::Unordered => "none",
+            Order::Name => "name",
+            Order::Size => "size",
+            Order::Mtime => "mtime",
+            Order::Random => "random",
+        }
+    }
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Order::Unordered),
+            "name" => Ok(Order::Name),
+            "size" => Ok(Order::Size),
+            "mtime" => Ok(Order::Mtime),
+            "random" => Ok(Order::Random),
+            _ => Err(format!("No such Order: {}", s)),
+        }
+    }
+}
+
+/// How requests to coprocesses, and their responses, are delimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// A line with the size in bytes, in decimal, then exactly that many bytes.
+    #[default]
+    Length,
+    /// A single line. Every input must be one line, with or without a newline at the end.
+    Line,
+}
+
+impl Framing {
+    /// The name of the framing, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Framing::Length => "length",
+            Framing::Line => "line",
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "length" => Ok(Framing::Length),
+            "line" => Ok(Framing::Line),
+            _ => Err(format!("No such Framing: {}", s)),
+        }
+    }
+}
+
+/// What to do once tasks have written more than `Config::max_total_output`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputPolicy {
+    /// Don't start any more tasks, but let running ones finish.
+    #[default]
+    Stop,
+    /// Don't start any more tasks, and terminate running ones.
+    Kill,
+}
+
+impl OutputPolicy {
+    /// The name of the policy, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputPolicy::Stop => "stop",
+            OutputPolicy::Kill => "kill",
+        }
+    }
+
+    fn stop(&self) -> Stop {
+        match self {
+            OutputPolicy::Stop => Stop::Soon,
+            OutputPolicy::Kill => Stop::Now,
+        }
+    }
+}
+
+impl FromStr for OutputPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "stop" => Ok(OutputPolicy::Stop),
+            "kill" => Ok(OutputPolicy::Kill),
+            _ => Err(format!("No such OutputPolicy: {}", s)),
+        }
+    }
+}
+
+/// What happens to the output of each task's command.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Capture {
+    /// Standard output and standard error each go to a file of their own.
+    #[default]
+    Separate,
+    /// Standard error goes to the same file as standard output, interleaved as it was written.
+    Merge,
+    /// Both are thrown away, for commands whose results are the files they write.
+    Discard,
+    /// Like `Separate`, but every line is also copied to reach's own standard output or error
+    /// as it's written, tagged with the task's input file name.
+    Tag,
+}
+
+impl Capture {
+    /// The name of the capture mode, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capture::Separate => "separate",
+            Capture::Merge => "merge",
+            Capture::Discard => "discard",
+            Capture::Tag => "tag",
+        }
+    }
+}
+
+impl FromStr for Capture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "separate" => Ok(Capture::Separate),
+            "merge" => Ok(Capture::Merge),
+            "discard" => Ok(Capture::Discard),
+            "tag" => Ok(Capture::Tag),
+            _ => Err(format!("No such Capture: {}", s)),
+        }
+    }
+}
+
+/// When to give up on a run early because tasks are failing.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Halt {
+    /// Run every task, however many fail.
+    #[default]
+    Never,
+    /// Once this many tasks have failed, don't start any more, but let running ones finish.
+    OnError(usize),
+    /// Once this many tasks have failed, don't start any more, and terminate running ones.
+    KillOnError(usize),
+}
+
+impl Halt {
+    /// How the run should stop once `failed` tasks have failed, if at all.
+    fn stop_after(&self, failed: usize) -> Option<Stop> {
+        match *self {
+            Halt::OnError(limit) if failed >= limit => Some(Stop::Soon),
+            Halt::KillOnError(limit) if failed >= limit => Some(Stop::Now),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Halt {
+    /// The halt policy, as `from_str` accepts it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Halt::Never => write!(f, "never"),
+            Halt::OnError(1) => write!(f, "on-error"),
+            Halt::OnError(limit) => write!(f, "on-error:{}", limit),
+            Halt::KillOnError(1) => write!(f, "kill-on-error"),
+            Halt::KillOnError(limit) => write!(f, "kill-on-error:{}", limit),
+        }
+    }
+}
+
+impl FromStr for Halt {
+    type Err = String;
+
+    /// Parses `never`, `on-error`, or `kill-on-error`. The last two can be
+    /// followed by `:N` to halt after `N` failures rather than the first.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowered = s.to_lowercase();
+        let (policy, limit) = match lowered.split_once(':') {
+            Some((policy, limit)) => match limit.parse() {
+                Ok(limit) if limit > 0 && policy != "never" => (policy, limit),
+                _ => return Err(format!("Invalid number of failures in {:?}", s)),
+            },
+            None => (lowered.as_str(), 1),
+        };
+        match policy {
+            "never" => Ok(Halt::Never),
+            "on-error" => Ok(Halt::OnError(limit)),
+            "kill-on-error" => Ok(Halt::KillOnError(limit)),
+            _ => Err(format!("No such Halt: {}", s)),
+        }
+    }
+}
+
+/// A machine to run tasks on, reached over SSH.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Worker {
+    /// Where to connect to, like `user@host`, or a host from the SSH configuration.
+    pub destination: String,
+    /// How many tasks the worker runs at once.
+    pub slots: usize,
+}
+
+impl fmt::Display for Worker {
+    /// The worker, as `from_str` accepts it, with an IPv6 address in brackets.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (user, host) = match self.destination.rsplit_once('@') {
+            Some((user, host)) => (user, host),
+            None => ("", self.destination.as_str()),
+        };
+        if host.contains(':') && user.is_empty() {
+            write!(f, "[{}]:{}", host, self.slots)
+        } else if host.contains(':') {
+            write!(f, "{}@[{}]:{}", user, host, self.slots)
+        } else {
+            write!(f, "{}:{}", self.destination, self.slots)
+        }
+    }
+}
+
+impl FromStr for Worker {
+    type Err = String;
+
+    /// Parses `user@host` or `host`, optionally followed by `:N` to run `N` tasks at once
+    /// rather than one.
+    ///
+    /// An IPv6 address, like `fe80::1`, is taken as it is, unless it's in brackets, as in
+    /// `me@[fe80::1]:4`, which it has to be to be given a number of slots.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid worker: {:?}", s);
+        let (destination, slots) = match s.split_once('[') {
+            Some((user, rest)) => {
+                let (address, slots) = rest.split_once(']').ok_or_else(invalid)?;
+                if address.is_empty() || !(user.is_empty() || user.ends_with('@')) {
+                    return Err(invalid());
+                }
+                let slots = match slots {
+                    "" => None,
+                    slots => Some(slots.strip_prefix(':').ok_or_else(invalid)?),
+                };
+                (format!("{}{}", user, address), slots)
+            }
+            None => match s.rsplit_once(':') {
+                // Any other colon belongs to an IPv6 address.
+                Some((destination, slots)) if !destination.contains(':') => {
+                    (destination.to_owned(), Some(slots))
+                }
+                _ => (s.to_owned(), None),
+            },
+        };
+        let slots = match slots.map(str::parse) {
+            None => 1,
+            Some(Ok(slots)) if slots > 0 => slots,
+            Some(_) => return Err(format!("Invalid number of slots in {:?}", s)),
+        };
+        if destination.is_empty() || destination.starts_with('-') {
+            return Err(invalid());
+        }
+        Ok(Worker { destination, slots })
+    }
+}
+
+/// The total size of the files in a directory and all its subdirectories.
+///
+/// Symbolic links count as themselves, not what they point to.
+async fn disk_usage(path: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = fs::symlink_metadata(entry.path()).await?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                total += metadata.len();
+            }
+        }
+    }
+    Ok(total)
+}
+
 /// Asynchronously ensure a directory exists.
 async fn ensure_directory(p: &Path) -> io::Result<()> {
     let result = fs::create_dir_all(p).await;
@@ -228,5 +2862,227 @@ mod tests {
     fn test_input_mode_parse() {
         assert_eq!(Ok(InputMode::Stdin), "stdin".parse());
         assert_eq!(Ok(InputMode::Filename), "filename".parse());
+        assert_eq!(Ok(InputMode::Exec), "exec".parse());
+        assert_eq!(Ok(InputMode::Coprocess), "coprocess".parse());
+        for mode in &[
+            InputMode::Stdin,
+            InputMode::Filename,
+            InputMode::Exec,
+            InputMode::Coprocess,
+        ] {
+            assert_eq!(Ok(*mode), mode.name().parse());
+        }
+    }
+
+    #[test]
+    fn test_input_mode_detect() {
+        assert_eq!(InputMode::Filename, InputMode::detect("wc -c {}"));
+        assert_eq!(InputMode::Stdin, InputMode::detect("wc -c"));
+        assert_eq!(InputMode::Stdin, InputMode::detect("echo {{}}"));
+    }
+
+    #[test]
+    fn test_order_parse() {
+        assert_eq!(Ok(Order::Unordered), "none".parse());
+        assert_eq!(Ok(Order::Name), "name".parse());
+        assert_eq!(Ok(Order::Size), "size".parse());
+        assert_eq!(Ok(Order::Mtime), "mtime".parse());
+        assert_eq!(Ok(Order::Random), "random".parse());
+        assert!("biggest".parse::<Order>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_systemd_scope_wrap() -> io::Result<()> {
+        let wrap = systemd_scope_wrap(&[], None)?;
+        assert!(wrap.starts_with("systemd-run --scope --quiet --collect "));
+        assert!(wrap.ends_with(" -- {cmd}"));
+        let properties = ["MemoryMax=2G".to_string(), "Description=a task".to_string()];
+        let wrap = systemd_scope_wrap(&properties, Some("nice -n19 {cmd}"))?;
+        assert!(wrap.ends_with(
+            " --property MemoryMax=2G --property 'Description=a task' -- nice -n19 {cmd}"
+        ));
+        assert!(WrapTemplate::parse(&wrap).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_framing_parse() {
+        assert_eq!(Ok(Framing::Length), "length".parse());
+        assert_eq!(Ok(Framing::Line), "line".parse());
+        assert!("json".parse::<Framing>().is_err());
+    }
+
+    #[test]
+    fn test_output_policy_parse() {
+        assert_eq!(Ok(OutputPolicy::Stop), "stop".parse());
+        assert_eq!(Ok(OutputPolicy::Kill), "kill".parse());
+        assert!("pause".parse::<OutputPolicy>().is_err());
+    }
+
+    /// Fails to launch its first few tasks as if the filesystem had gone away,
+    /// taking the destination directory with it for a moment the first time.
+    #[cfg(unix)]
+    struct FlakyLauncher {
+        failures: Mutex<usize>,
+        destination_dir: PathBuf,
+    }
+
+    #[cfg(unix)]
+    #[async_trait]
+    impl Launcher for FlakyLauncher {
+        type Process = Child;
+
+        async fn launch(&self, _task: &Task<'_>, _reservation: Reservation) -> io::Result<Child> {
+            let first = {
+                let mut failures = self.failures.lock().unwrap();
+                if *failures == 0 {
+                    return Command::new("true").spawn();
+                }
+                *failures -= 1;
+                *failures == 0
+            };
+            if first {
+                fs::remove_dir_all(&self.destination_dir).await?;
+                let destination_dir = self.destination_dir.clone();
+                tokio::spawn(async move {
+                    time::sleep(Duration::from_millis(200)).await;
+                    fs::create_dir(destination_dir).await
+                });
+            }
+            Err(io::Error::from_raw_os_error(libc::ESTALE))
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_outage() -> io::Result<()> {
+        let source = tempfile::tempdir()?;
+        fs::write(source.path().join("a.txt"), b"a").await?;
+        let destination = tempfile::tempdir()?;
+        for (failures, succeeded) in &[(1, true), (outage::MAX_FALSE_ALARMS as usize + 1, false)] {
+            let config = Config::builder("true", source.path())
+                .destination_dir(destination.path().join("dest"))
+                .recreate(true)
+                .build()?;
+            let launcher = FlakyLauncher {
+                failures: Mutex::new(*failures),
+                destination_dir: config.destination_dir.clone(),
+            };
+            let summary = Each::new(&config)?
+                .run(&launcher, &config.destination_dir, &(), &|_| {})
+                .await?;
+            // Waiting out an outage doesn't use up a task's retries.
+            assert_eq!(*succeeded, summary.all_succeeded(), "{}", summary);
+            assert_eq!(0, summary.retried);
+        }
+        Ok(())
+    }
+
+    /// Fails every task, without waiting for anything.
+    #[cfg(unix)]
+    struct FailingLauncher;
+
+    #[cfg(unix)]
+    #[async_trait]
+    impl Launcher for FailingLauncher {
+        type Process = Child;
+
+        async fn launch(&self, _task: &Task<'_>, _reservation: Reservation) -> io::Result<Child> {
+            Command::new("false").spawn()
+        }
+    }
+
+    /// In low-memory mode, nothing is kept for each input, so more of them don't take more.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_low_memory_bounded() -> io::Result<()> {
+        let source = tempfile::tempdir()?;
+        let inputs = SUMMARY_LIMIT + 100;
+        for i in 0..inputs {
+            fs::write(source.path().join(format!("{}.txt", i)), b"").await?;
+        }
+        let destination = tempfile::tempdir()?;
+        for &low_memory in &[false, true] {
+            let config = Config::builder("false", source.path())
+                .destination_dir(destination.path().join("dest"))
+                .recreate(true)
+                .num_processes(16)
+                .group_by(Some("{stem}".into()))
+                .low_memory(low_memory)
+                .build()?;
+            let each = Each::new(&config)?;
+            let summary = each
+                .run(&FailingLauncher, &config.destination_dir, &(), &|_| {})
+                .await?;
+            assert_eq!(inputs, summary.failed);
+            let names = each.names.lock().unwrap().len();
+            if low_memory {
+                assert_eq!(0, names);
+                assert_eq!(SUMMARY_LIMIT, summary.failures.len());
+                assert_eq!(100, summary.failures_omitted);
+                assert_eq!(SUMMARY_LIMIT + 1, summary.groups.len());
+            } else {
+                assert_eq!(inputs, names);
+                assert_eq!(inputs, summary.failures.len());
+                assert_eq!(inputs, summary.groups.len());
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disk_usage() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("out"), vec![0; 1000]).await?;
+        fs::create_dir(dir.path().join("frames")).await?;
+        fs::write(dir.path().join("frames/1.png"), vec![0; 24]).await?;
+        assert_eq!(1024, disk_usage(dir.path()).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_parse() {
+        let worker = |destination: &str, slots| Worker {
+            destination: destination.into(),
+            slots,
+        };
+        assert_eq!(Ok(worker("me@box", 1)), "me@box".parse());
+        assert_eq!(Ok(worker("me@box", 8)), "me@box:8".parse());
+        assert_eq!(Ok(worker("box", 2)), "box:2".parse());
+        assert!("me@box:0".parse::<Worker>().is_err());
+        assert!("me@box:many".parse::<Worker>().is_err());
+        assert!(":4".parse::<Worker>().is_err());
+        assert!("-oProxyCommand=x".parse::<Worker>().is_err());
+
+        assert_eq!(Ok(worker("::1", 1)), "::1".parse());
+        assert_eq!(Ok(worker("me@fe80::1", 1)), "me@fe80::1".parse());
+        assert_eq!(Ok(worker("::1", 4)), "[::1]:4".parse());
+        assert_eq!(Ok(worker("me@fe80::1", 2)), "me@[fe80::1]:2".parse());
+        assert_eq!(Ok(worker("fe80::1", 1)), "[fe80::1]".parse());
+        assert!("[::1]4".parse::<Worker>().is_err());
+        assert!("[::1:4".parse::<Worker>().is_err());
+        assert!("[]:4".parse::<Worker>().is_err());
+        assert!("me[::1]".parse::<Worker>().is_err());
+        assert!("[::1]:0".parse::<Worker>().is_err());
+        for s in &["me@box:8", "[::1]:4", "me@[fe80::1]:2"] {
+            let worker: Worker = s.parse().unwrap();
+            assert_eq!(s, &worker.to_string());
+        }
+    }
+
+    #[test]
+    fn test_halt_parse() {
+        assert_eq!(Ok(Halt::Never), "never".parse());
+        assert_eq!(Ok(Halt::OnError(1)), "on-error".parse());
+        assert_eq!(Ok(Halt::OnError(5)), "on-error:5".parse());
+        assert_eq!(Ok(Halt::KillOnError(1)), "kill-on-error".parse());
+        assert_eq!(Ok(Halt::KillOnError(2)), "kill-on-error:2".parse());
+        assert!("on-error:0".parse::<Halt>().is_err());
+        assert!("never:3".parse::<Halt>().is_err());
+        assert!("sometimes".parse::<Halt>().is_err());
+        for halt in &[Halt::Never, Halt::OnError(1), Halt::KillOnError(3)] {
+            assert_eq!(Ok(*halt), halt.to_string().parse());
+        }
     }
 }
@@ -0,0 +1,295 @@
+//! Limits on the resources each task's command can use, so that one task can't take down the
+//! machine: how nice it is, how much memory it can have, which CPUs it can run on, and how
+//! many files and processes it can have open. Commands can be put in a cgroup as well, so
+//! that pausing the run can freeze them.
+//!
+//! The limits are set in the child between fork and exec, and whatever the command starts
+//! inherits them.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+
+use crate::error;
+use crate::pause::Cgroup;
+use crate::{Message, Refusal, Unsupported};
+
+/// Niceness goes from the highest priority, -20, to the lowest, 19.
+const NICENESS: std::ops::RangeInclusive<i32> = -20..=19;
+
+/// The limits on each command.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limits {
+    nice: Option<i32>,
+    memory: Option<u64>,
+    cpus: Option<Cpus>,
+    fds: Option<u64>,
+    procs: Option<u64>,
+    cgroup: Option<Arc<Cgroup>>,
+}
+
+/// Commands need standard input, output, and error, at the very least.
+const MIN_FDS: u64 = 3;
+
+impl Limits {
+    /// Run commands at niceness `nice`, with at most `memory` bytes of address space, on
+    /// their own `cpus_per_task` of the CPUs reach can run on, as far as there are enough,
+    /// with at most `fds` files open in each process, and at most `procs` processes.
+    ///
+    /// As the kernel counts them, `procs` is how many processes the user running the command
+    /// can have, including those that aren't the task's.
+    pub(crate) fn new(
+        nice: Option<i32>,
+        memory: Option<u64>,
+        cpus_per_task: Option<usize>,
+        fds: Option<u64>,
+        procs: Option<u64>,
+    ) -> io::Result<Self> {
+        let invalid = |message| Err(error::new(io::ErrorKind::InvalidInput, message));
+        if let Some(nice) = nice {
+            if !NICENESS.contains(&nice) {
+                return invalid(Message::Niceness(nice));
+            }
+        }
+        if memory == Some(0) {
+            return invalid(Refusal::NoMemory.into());
+        }
+        if matches!(fds, Some(fds) if fds < MIN_FDS) {
+            return invalid(Message::TooFewFds { min: MIN_FDS });
+        }
+        if procs == Some(0) {
+            return invalid(Refusal::NoProcesses.into());
+        }
+        let rlimits = memory.is_some() || fds.is_some() || procs.is_some();
+        if (nice.is_some() || rlimits) && !cfg!(unix) {
+            return Err(error::unsupported(Unsupported::Limits));
+        }
+        let cpus = match cpus_per_task {
+            Some(0) => return invalid(Refusal::NoCpus.into()),
+            Some(per_task) => {
+                let available = available_cpus()?;
+                if per_task > available.len() {
+                    return invalid(Message::TooManyCpus {
+                        per_task,
+                        available: available.len(),
+                    });
+                }
+                Some(Cpus {
+                    per_task,
+                    load: Arc::new(Mutex::new(vec![0; available.len()])),
+                    available,
+                })
+            }
+            None => None,
+        };
+        Ok(Limits {
+            nice,
+            memory,
+            cpus,
+            fds,
+            procs,
+            cgroup: None,
+        })
+    }
+
+    /// Put commands in `cgroup`, if there is one.
+    pub(crate) fn within(mut self, cgroup: Option<Arc<Cgroup>>) -> Self {
+        self.cgroup = cgroup;
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.nice.is_none()
+            && self.memory.is_none()
+            && self.cpus.is_none()
+            && self.fds.is_none()
+            && self.procs.is_none()
+            && self.cgroup.is_none()
+    }
+
+    /// Have `command` run within the limits. Its CPUs are its own for as long as the claim
+    /// on them that this returns is kept, if it has CPUs of its own.
+    pub(crate) fn apply(&self, command: &mut Command) -> Option<CpuClaim> {
+        if self.is_empty() {
+            return None;
+        }
+        let claim = self.cpus.as_ref().map(Cpus::claim);
+        #[cfg(unix)]
+        {
+            let nice = self.nice;
+            let cgroup = self.cgroup.clone();
+            let rlimits = [
+                (libc::RLIMIT_AS, self.memory),
+                (libc::RLIMIT_NOFILE, self.fds),
+                (libc::RLIMIT_NPROC, self.procs),
+            ];
+            #[cfg(target_os = "linux")]
+            let cpu_set = claim.as_ref().map(CpuClaim::cpu_set);
+            // SAFETY: `setpriority`, `setrlimit`, and `sched_setaffinity` are all
+            // async-signal-safe, as is joining the cgroup, and everything they're given
+            // was made before the fork.
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(cgroup) = &cgroup {
+                        cgroup.join()?;
+                    }
+                    if let Some(nice) = nice {
+                        if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    for &(resource, most) in &rlimits {
+                        if let Some(most) = most {
+                            let limit = libc::rlimit {
+                                rlim_cur: most as libc::rlim_t,
+                                rlim_max: most as libc::rlim_t,
+                            };
+                            if libc::setrlimit(resource, &limit) != 0 {
+                                return Err(io::Error::last_os_error());
+                            }
+                        }
+                    }
+                    #[cfg(target_os = "linux")]
+                    if let Some(cpu_set) = &cpu_set {
+                        let size = std::mem::size_of::<libc::cpu_set_t>();
+                        if libc::sched_setaffinity(0, size, cpu_set) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = command;
+        claim
+    }
+}
+
+/// The CPUs that commands are given their own of, and how many running commands have each.
+#[derive(Debug, Clone)]
+struct Cpus {
+    per_task: usize,
+    /// The numbers of the CPUs that reach can run on.
+    available: Vec<usize>,
+    /// How many commands have each of the available CPUs.
+    load: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Cpus {
+    /// Claim the CPUs that the fewest commands have, which are free ones as long as no more
+    /// tasks run at once than there are CPUs for.
+    fn claim(&self) -> CpuClaim {
+        let mut load = self.load.lock().unwrap();
+        let mut least_loaded: Vec<usize> = (0..load.len()).collect();
+        least_loaded.sort_by_key(|&i| load[i]);
+        least_loaded.truncate(self.per_task);
+        for &i in &least_loaded {
+            load[i] += 1;
+        }
+        CpuClaim {
+            cpus: least_loaded.iter().map(|&i| self.available[i]).collect(),
+            indices: least_loaded,
+            load: self.load.clone(),
+        }
+    }
+}
+
+/// The CPUs that a command runs on, which other commands are kept off of until it's dropped.
+#[derive(Debug)]
+pub(crate) struct CpuClaim {
+    /// The numbers of the CPUs.
+    cpus: Vec<usize>,
+    /// Where they are in the available CPUs.
+    indices: Vec<usize>,
+    load: Arc<Mutex<Vec<usize>>>,
+}
+
+impl CpuClaim {
+    #[cfg(target_os = "linux")]
+    fn cpu_set(&self) -> libc::cpu_set_t {
+        // SAFETY: An all-zero `cpu_set_t` is an empty set, and every CPU number came from
+        // `sched_getaffinity`, so it fits in one.
+        unsafe {
+            let mut set = std::mem::zeroed();
+            for &cpu in &self.cpus {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            set
+        }
+    }
+}
+
+impl Drop for CpuClaim {
+    fn drop(&mut self) {
+        let mut load = self.load.lock().unwrap();
+        for &i in &self.indices {
+            load[i] -= 1;
+        }
+    }
+}
+
+/// The numbers of the CPUs that reach itself is allowed to run on.
+#[cfg(target_os = "linux")]
+fn available_cpus() -> io::Result<Vec<usize>> {
+    // SAFETY: The set is as big as `sched_getaffinity` is told, and an all-zero one is empty.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_cpus() -> io::Result<Vec<usize>> {
+    Err(error::unsupported(Unsupported::CpusPerTask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert!(Limits::new(None, None, None, None, None)
+            .unwrap()
+            .is_empty());
+        assert!(Limits::new(Some(20), None, None, None, None).is_err());
+        assert!(Limits::new(Some(-21), None, None, None, None).is_err());
+        assert!(Limits::new(None, Some(0), None, None, None).is_err());
+        assert!(Limits::new(None, None, Some(0), None, None).is_err());
+        assert!(Limits::new(None, None, Some(100_000), None, None).is_err());
+        assert!(Limits::new(None, None, None, Some(2), None).is_err());
+        assert!(Limits::new(None, None, None, None, Some(0)).is_err());
+        #[cfg(unix)]
+        assert!(!Limits::new(None, None, None, Some(64), Some(100))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_claim() {
+        let cpus = Cpus {
+            per_task: 2,
+            available: vec![0, 2, 4, 6],
+            load: Arc::new(Mutex::new(vec![0; 4])),
+        };
+        let first = cpus.claim();
+        let second = cpus.claim();
+        assert_eq!(vec![0, 2], first.cpus);
+        assert_eq!(vec![4, 6], second.cpus);
+        drop(first);
+        // The free CPUs go first, and once none are free, the least loaded do.
+        let third = cpus.claim();
+        assert_eq!(vec![0, 2], third.cpus);
+        let fourth = cpus.claim();
+        assert_eq!(vec![0, 2], fourth.cpus);
+        assert_eq!(vec![2, 2, 1, 1], *cpus.load.lock().unwrap());
+        drop((second, third, fourth));
+        assert_eq!(vec![0, 0, 0, 0], *cpus.load.lock().unwrap());
+    }
+}
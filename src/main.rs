@@ -1,23 +1,189 @@
-use reach::{Config, InputMode};
+use reach::{
+    parse_duration, parse_signal, parse_size, Capture, Config, ConfigFile, ConfigValue, Dashboard,
+    Framing, Halt, InputMode, Message, Metrics, Naming, Order, OutputPolicy, PauseToken, Progress,
+    ProgressFile, ProgressMode, RecentEvents, RunAs, Status, SupportBundle, Worker, STATE_DIR,
+};
 
-use clap::Clap;
-use std::fs;
+use clap::{ArgSettings, Clap, IntoApp, ValueHint};
+use futures::future;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
 use std::io;
+use std::net::SocketAddr;
+use std::panic;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::net::TcpListener;
+use tokio::signal;
+
+mod completions;
+mod rerun;
+
+use rerun::{Invocation, RERUN_MATCHING};
 
 #[derive(Clap, Debug)]
 #[clap(version = "0.1", author = "Jonathan M. Lange <jml@mumak.net>")]
 struct Opts {
-    #[clap(about = "The command to run on those source files")]
+    #[clap(about = "The command to run on those source files. \
+                    Each task's command gets REACH_INPUT (the input's path, or the first one's in a batch), REACH_INPUT_NAME (its file name), \
+                    REACH_DEST_DIR (the task's destination directory), REACH_TASK_ID (which stays the same across attempts and runs of the same command), REACH_TASK_INDEX (counting from 0), \
+                    and REACH_ATTEMPT (counting from 1) in its environment, except in coprocess mode. \
+                    It can also note anything worth keeping about the task, like the version of a tool it used, \
+                    as 'key=value' lines in the file named by REACH_ANNOTATIONS. \
+                    They're kept in the task's destination directory and in the run's journal. \
+                    Before reach terminates a command, it writes why (timeout, halt, budget, or shutdown) to the file named by REACH_CANCEL_FILE, \
+                    so a command that checks for it can save its work and exit cleanly before it's killed.",
+            value_hint = ValueHint::CommandString)]
     command: String,
 
-    #[clap(about = "The directory containing source files")]
-    source: PathBuf,
+    #[clap(about = "The directory containing source files, \
+                    or '-' to read the paths of the inputs from standard input, as with --from-stdin",
+            value_hint = ValueHint::DirPath)]
+    source: Option<PathBuf>,
 
     #[clap(about = "The destination directory. \
-                 Defaults to the name of the input directory with '-results' appended to the end.")]
+                 Defaults to the name of the input directory with '-results' appended to the end, \
+                 or 'stdin-results' in the current directory for inputs read from standard input.",
+            value_hint = ValueHint::DirPath)]
     destination: Option<PathBuf>,
 
+    #[clap(
+        long,
+        about = "Read the paths of the inputs from standard input, one per line, rather than listing a source directory, \
+                 as in 'find . -name \"*.csv\" | reach --from-stdin \"wc -l {}\"'. The same as giving '-' as the source. \
+                 Tasks start as paths are read, before the list is finished. \
+                 Each input's results go in a directory named after its file name, \
+                 so an input with the same name as one before it is left out. \
+                 Commands get /dev/null as their standard input, except in stdin mode. \
+                 Can't be used with --order, --watch, --source, --split-bytes, or --low-memory."
+    )]
+    from_stdin: bool,
+
+    #[clap(
+        short = '0',
+        long,
+        about = "Read the paths of inputs from standard input separated by NULs rather than newlines, \
+                 as 'find -print0' writes them, so that they can contain newlines"
+    )]
+    null: bool,
+
+    #[clap(
+        long,
+        about = "Read settings from this TOML file, like 'reach.toml', with a 'name = value' line for any option, \
+                 named as it is here without its dashes, like 'processes = 8', 'retry-failed = true', or 'then = [\"gzip\"]'. \
+                 Options given on the command line override the file's. \
+                 The file can set the command, too, as 'command = \"...\"', and then the command line doesn't, \
+                 though a command on the command line still wins.",
+        value_hint = ValueHint::FilePath
+    )]
+    #[allow(dead_code)]
+    // Read before the rest of the options are parsed, by `with_config_file`.
+    config: Option<PathBuf>,
+
+    #[clap(
+        long = "source",
+        about = "Another source directory, or a single source file, to process along with the source directory, \
+                 e.g. '--source batch2/ --source single-file.csv'. May be given more than once. \
+                 Every source's results go in the same destination directory, \
+                 so inputs from different sources can't have the same name.",
+        number_of_values = 1
+    )]
+    more_sources: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        about = "Only take the files whose names match this pattern as inputs, e.g. '*.csv'. \
+                 May be given more than once, to take the files matching any of them. \
+                 The pattern can use '*', '?', and '[...]' as in the shell.",
+        number_of_values = 1
+    )]
+    include: Vec<String>,
+
+    #[clap(
+        long,
+        about = "Leave out the files whose names match this pattern, e.g. '*.tmp', even if they're included. \
+                 May be given more than once.",
+        number_of_values = 1
+    )]
+    exclude: Vec<String>,
+
+    #[clap(
+        long,
+        about = "In filename mode, leave out the files whose names aren't valid unicode when the shell \
+                 can't be given them, as PowerShell and cmd can't, rather than failing their tasks"
+    )]
+    skip_invalid_names: bool,
+
+    #[clap(
+        long,
+        about = "Allow the destination directory to be inside the source directory, which is refused otherwise. \
+                 Nothing inside the destination directory is ever taken as an input either way."
+    )]
+    nested_destination: bool,
+
+    #[clap(
+        long,
+        about = "The order to process the source files in. \
+                 'none' takes them in whatever order the filesystem lists them. \
+                 'name' sorts them by name, 'size' puts the largest first, and 'mtime' the least recently modified first. \
+                 'random' shuffles them. \
+                 Processing the largest first keeps every process busy until the end, when sizes vary a lot.",
+        possible_values = &["none", "name", "size", "mtime", "random"],
+        default_value = "none"
+    )]
+    order: Order,
+
+    #[clap(
+        long,
+        about = "With '--order random', shuffle the inputs the same way every run with the same seed, \
+                 rather than differently every time."
+    )]
+    seed: Option<u64>,
+
+    #[clap(
+        long,
+        about = "How to name each input's directory in the destination. \
+                 'name' uses the input's file name, which inputs from different directories can share. \
+                 'mirror' uses its path, relative to the current directory if it's inside it, so the destination has the same tree as the sources. \
+                 'hash' uses a hash of its absolute path. \
+                 'index' numbers the inputs in the order they're found, which is only the same every run with --order. \
+                 Anything but 'name' implies --manifest.",
+        possible_values = &["name", "mirror", "hash", "index"],
+        default_value = "name"
+    )]
+    naming: Naming,
+
+    #[clap(
+        long,
+        about = "Write 'manifest.json' in the destination directory once the run has finished, \
+                 listing every input with its task's ID and the directory its results are in. \
+                 It's first written as the run starts, with the inputs it found then, in order."
+    )]
+    manifest: bool,
+
+    #[clap(
+        long,
+        value_name = "RUN",
+        about = "Process exactly the inputs listed in this manifest, or in the one in this destination directory, \
+                 in its order and into the same destinations, rather than those in the sources, even if they've changed since. \
+                 Inputs that are gone fail their tasks.",
+        value_hint = ValueHint::AnyPath
+    )]
+    from_manifest: Option<PathBuf>,
+
+    #[clap(
+        long,
+        about = "Where reach keeps its own bookkeeping, such as locks. \
+                 Nothing is ever written to the source directory, so this only matters if the destination is unsuitable. \
+                 Defaults to '.reach' inside the destination directory.",
+        value_hint = ValueHint::DirPath
+    )]
+    state_dir: Option<PathBuf>,
+
     #[clap(
         long,
         about = "By default, reach will not attempt to recreate files that have already been successfully processed. \
@@ -25,6 +191,67 @@ struct Opts {
     )]
     recreate: bool,
 
+    #[clap(
+        long,
+        about = "Run even if the command has changed since the last run in the same destination, \
+                 keeping the results of tasks that succeeded with the old command alongside the new ones. \
+                 reach refuses such runs otherwise, unless they --recreate every task. \
+                 The command, shell, input mode, --wrap, --then, and --split-bytes all count, \
+                 as recorded in the run's journal, or in the destination's manifest.json."
+    )]
+    allow_command_change: bool,
+
+    #[clap(
+        long,
+        about = "Record a hash of each input with its task's results, \
+                 and process it again if its content has changed since, even if it succeeded."
+    )]
+    hash_inputs: bool,
+
+    #[clap(
+        long,
+        about = "Record the size and checksum of every file each task leaves, \
+                 so that --verify can tell if any has changed since."
+    )]
+    checksums: bool,
+
+    #[clap(
+        long,
+        about = "Only re-run the tasks that failed in the last run, as recorded in the journal in the state directory. \
+                 Every run keeps a journal of what it did and with which command."
+    )]
+    retry_failed: bool,
+
+    #[clap(
+        long,
+        about = "Only re-run the tasks whose input's file name matches this pattern, e.g. 'customer-42-*', \
+                 whether or not they succeeded before, leaving every other task's results as they are. \
+                 The pattern can use '*', '?', and '[...]' as in the shell, and needs quoting to keep the shell from expanding it. \
+                 Run it with the same command and destination as before; reach refuses to if the command has changed since the last run. \
+                 Or run 'reach rerun-matching DESTINATION PATTERN' to rerun them with the command, source, and options that the last run with that destination was started with, \
+                 as recorded in its state directory when it started. \
+                 If that wasn't in the destination, give it first, as in 'reach rerun-matching --state-dir DIR DESTINATION PATTERN'."
+    )]
+    rerun_matching: Option<String>,
+
+    #[clap(
+        long,
+        about = "Once every file in the source directory has been processed, keep running, \
+                 and process new files as they appear in it, until interrupted. \
+                 A new file is only processed once it has stopped changing for a whole --poll-interval, so that it isn't processed half-written. \
+                 Run as a systemd service of Type=notify, reach tells systemd it's ready once it's watching, \
+                 and feeds the service's watchdog, if it has one, for as long as it runs."
+    )]
+    watch: bool,
+
+    #[clap(
+        long,
+        about = "How often to look for new files with --watch, e.g. '500ms' or '10s'.",
+        default_value = "1s",
+        parse(try_from_str = parse_duration)
+    )]
+    poll_interval: Duration,
+
     #[clap(
         long,
         about = "How many times reach should retry a process if it fails (exits with a non-zero status). \
@@ -35,10 +262,22 @@ struct Opts {
 
     #[clap(
         long,
-        about = "The shell to use to interpret the command.",
-        env = "SHELL"
+        about = "A signal that commands are sometimes killed by through no fault of their own, \
+                 e.g. 'KILL' for the kernel's out-of-memory killer. \
+                 A task killed by it is tried again up to three times, on top of --retries. \
+                 May be given more than once.",
+        number_of_values = 1,
+        parse(try_from_str = parse_signal)
     )]
-    shell: String,
+    retry_signal: Vec<i32>,
+
+    #[clap(
+        long,
+        about = "The shell to use to interpret the command. \
+                 Filenames substituted into the command are quoted for this shell, which may be a POSIX shell, PowerShell, or cmd. \
+                 Defaults to $SHELL, or /bin/sh; on Windows, to %ComSpec%, or cmd.exe."
+    )]
+    shell: Option<String>,
 
     #[clap(
         short = 'j',
@@ -47,94 +286,1322 @@ struct Opts {
     )]
     processes: Option<usize>,
 
+    #[clap(
+        long,
+        about = "Keep reach's own memory use the same however many inputs there are, for runs with millions of them. \
+                 The source directory is read twice, once to count the inputs and again as they're needed, \
+                 and the summary only lists the first 1000 failures; the rest are in the run's journal. \
+                 Can't be used with --order, --watch, or --source."
+    )]
+    low_memory: bool,
+
+    #[clap(
+        long,
+        about = "Split the source, a single file, into chunks of this many bytes, each processed by a task of its own, \
+                 so that one huge file can be processed in parallel. \
+                 Accepts a number of bytes, or a number followed by 'K', 'M', 'G', or 'T'. \
+                 In stdin mode, each command reads only its chunk. Every command gets REACH_OFFSET and REACH_LENGTH, \
+                 where its chunk starts and how long it is in bytes, e.g. for 'tail -c +$((REACH_OFFSET + 1)) {} | head -c $REACH_LENGTH'. \
+                 Chunk 7 of big.bin goes in big.bin.007, or however many digits the last chunk's number has.",
+        parse(try_from_str = parse_size)
+    )]
+    split_bytes: Option<u64>,
+
+    #[clap(
+        long,
+        about = "Run each command on a batch of up to this many inputs, for commands that are slow to start. \
+                 In filename and exec modes, '{+}' is replaced with the paths of every input in the batch, \
+                 and the other placeholders refer to the first one. In stdin mode, the command reads every input, one after another. \
+                 A batch's output goes in the destination directory of its first input, and every input gets the batch's status.",
+        default_value = "1"
+    )]
+    batch: usize,
+
+    #[clap(
+        long,
+        about = "The number of tasks that may be opening or creating files at the same time. \
+                 Independent of the number of processes; lower it if reach is slow to start tasks on network filesystems.",
+        default_value = "64"
+    )]
+    io_concurrency: usize,
+
+    #[clap(
+        long,
+        about = "The number of inputs waiting to be processed to ask the kernel to start reading ahead of their tasks, \
+                 so that commands don't stall on cold reads from spinning disks or network filesystems.",
+        default_value = "0"
+    )]
+    prefetch: usize,
+
     #[clap(
         long,
         about = "How the input file should be passed to the command. \
                  'stdin' means the contents of the input file will be passed to the command's stdin. \
                  'filename' mean that its name will be substituted for the string '{}' in the command. \
-                 The default is to use stdin unless '{}' is present in the command.",
-        possible_values = &["stdin", "filename"],
+                 In filename mode, '{basename}', '{stem}', '{ext}', and '{dir}' are replaced with parts of the input's path, \
+                 and '{dest}' with the directory where its results go. '{+}' is every input in a batch (see --batch). \
+                 Write '{{}}' for a literal '{}'. \
+                 'exec' is like 'filename', but runs the command without a shell (see --exec). \
+                 'coprocess' starts the command once per process and keeps it running, sending it each input in turn on its stdin (see --framing). \
+                 Its response to each input is the task's output, and its stderr goes to 'coprocess.err' in the state directory. \
+                 The default is 'filename' if '{}' or '{+}' is present in the command (not counting '{{}}'), and 'stdin' otherwise.",
+        possible_values = &["stdin", "filename", "exec", "coprocess"],
     )]
     input_mode: Option<InputMode>,
+
+    #[clap(
+        long,
+        about = "How inputs are sent to coprocesses, and their outputs read back. \
+                 'length' sends a line with the input's size in bytes, then the input itself, and expects the output the same way. \
+                 'line' sends each input as a single line, and expects a single line back.",
+        possible_values = &["length", "line"],
+        default_value = "length"
+    )]
+    framing: Framing,
+
+    #[clap(
+        long,
+        about = "Run the command directly rather than with the shell. Short for '--input-mode exec'. \
+                 The command is split into words using shell quoting rules, and placeholders are substituted into each word as they are, \
+                 so filenames never need quoting. Pipes, redirections, and variables are not available.",
+        conflicts_with = "input-mode"
+    )]
+    exec: bool,
+
+    #[clap(
+        long,
+        about = "By default, reach exits with a non-zero status if any task fails. \
+                 If this is set, reach only exits with a non-zero status if every task it ran failed."
+    )]
+    ok_if_some_fail: bool,
+
+    #[clap(
+        long,
+        about = "When to give up because tasks are failing. \
+                 'on-error' stops starting new tasks after the first failure, but lets running tasks finish. \
+                 'kill-on-error' also terminates running tasks. \
+                 Add ':N' to either to give up after N failures instead, e.g. 'on-error:10'.",
+        default_value = "never"
+    )]
+    halt: Halt,
+
+    #[clap(
+        long,
+        about = "If --halt stops the run because a task's command was killed by a signal, \
+                 exit by the same signal once the run is over, rather than with a non-zero status, \
+                 so whatever runs reach can tell a crash from an ordinary failure."
+    )]
+    propagate_signal: bool,
+
+    #[clap(
+        long,
+        about = "Stop once the tasks run so far have written more than this much to their destination directories, \
+                 to protect shared storage from a misbehaving command. \
+                 Accepts a number of bytes, or a number followed by 'K', 'M', 'G', or 'T'. \
+                 Each task's output is measured when it finishes, so running tasks can go past the limit. \
+                 See --output-policy for what happens then.",
+        parse(try_from_str = parse_size)
+    )]
+    max_total_output: Option<u64>,
+
+    #[clap(
+        long,
+        about = "What to do once --max-total-output is passed. \
+                 'stop' stops starting new tasks, but lets running tasks finish. \
+                 'kill' also terminates running tasks.",
+        possible_values = &["stop", "kill"],
+        default_value = "stop"
+    )]
+    output_policy: OutputPolicy,
+
+    #[clap(
+        long,
+        about = "What to do with each task's output. \
+                 'separate' writes stdout and stderr to files of their own in the task's destination directory (see --stdout-name and --stderr-name). \
+                 'merge' writes stderr to the same file as stdout. \
+                 'discard' throws both away, for commands whose results are the files they write. \
+                 'tag' is like 'separate', but also prints every line as it's written, with the input file's name in front, \
+                 which is handy alongside '--progress quiet'. Shell sessions and coprocesses can't be tagged.",
+        possible_values = &["separate", "merge", "discard", "tag"],
+        default_value = "separate"
+    )]
+    capture: Capture,
+
+    #[clap(
+        long,
+        about = "The name of the file in each task's destination directory that its stdout goes to.",
+        default_value = "out"
+    )]
+    stdout_name: String,
+
+    #[clap(
+        long,
+        about = "The name of the file in each task's destination directory that its stderr goes to.",
+        default_value = "err"
+    )]
+    stderr_name: String,
+
+    #[clap(
+        long,
+        about = "Keep at most this much of each task's output files, e.g. '100M', \
+                 so that a single runaway task can't fill the disk. \
+                 Accepts a number of bytes, or a number followed by 'K', 'M', 'G', or 'T'. \
+                 The beginning and end of the output are kept, with a note of how much was clipped in between, \
+                 and the task's status file gets a line like 'clipped err' for each file that was clipped. \
+                 Not supported with --shell-sessions or in coprocess mode.",
+        parse(try_from_str = parse_size)
+    )]
+    max_output_size: Option<u64>,
+
+    #[clap(
+        long,
+        about = "Kill any command that runs for longer than this and count it as a failure. \
+                 Accepts a number of seconds, or numbers followed by 'ms', 's', 'm', 'h', or 'd', like '90s' or '2h30m'. \
+                 Commands are sent SIGTERM first, then SIGKILL if they do not exit promptly.",
+        parse(try_from_str = parse_duration)
+    )]
+    timeout: Option<Duration>,
+
+    #[clap(
+        long,
+        about = "Start at most this many tasks a second, e.g. '2' or '0.5'. \
+                 Retries of failed tasks start straight away.",
+        parse(try_from_str = parse_rate)
+    )]
+    max_rate: Option<f64>,
+
+    #[clap(
+        long,
+        about = "Don't start any tasks while the system's load average over the last minute is above this, \
+                 so that reach backs off when a shared machine gets busy. \
+                 The load average takes a while to catch up, so this works best alongside --max-rate."
+    )]
+    load: Option<f64>,
+
+    #[clap(
+        long,
+        about = "Only start tasks during this window of local time each day, like '22:00-06:00', \
+                 so that a run keeps to off-peak hours on a shared machine. \
+                 Outside it, running tasks carry on, and reach waits for the window to open again."
+    )]
+    window: Option<String>,
+
+    #[clap(
+        long,
+        about = "Pause the run on SIGUSR1, and resume it on SIGUSR2. \
+                 While it's paused, no new tasks start, and running tasks are frozen, timeouts and all, \
+                 where reach can make a cgroup to freeze them in; otherwise they carry on."
+    )]
+    pausable: bool,
+
+    #[clap(
+        long,
+        about = "Keep one shell running for each process slot, and run tasks in it one after another, \
+                 rather than starting a new shell for every task. \
+                 Worthwhile when tasks are so quick that starting the shell is a large part of the work. \
+                 Each task runs in its own subshell, so tasks can't affect each other. \
+                 Needs a POSIX shell. In filename and exec modes, tasks get /dev/null as their standard input."
+    )]
+    shell_sessions: bool,
+
+    #[clap(
+        long,
+        about = "Send inputs with the same key to the same coprocess or shell session, so that anything it caches for that key stays warm. \
+                 The key is this template filled in for each input, using the same placeholders as filename mode, e.g. '{dir}' or '{ext}'. \
+                 Keys are spread over one worker for each process, and an input waits for its worker if it's busy."
+    )]
+    affinity: Option<String>,
+
+    #[clap(
+        long,
+        about = "A shell command to run before each task's command, with the same placeholders and REACH_* variables, \
+                 e.g. 'mkdir -p /scratch/{stem}'. If it fails, so does the task, without its command being run. \
+                 Its output goes to 'setup.log' in the task's destination directory."
+    )]
+    setup: Option<String>,
+
+    #[clap(
+        long,
+        about = "A shell command to run after each task's command, however it ended, unless reach was interrupted. \
+                 It has the same placeholders and variables as --setup, and REACH_STATUS too, which is what the status file will say, e.g. '0' or 'timed out'. \
+                 If it fails, so does the task. Its output goes to 'teardown.log' in the task's destination directory."
+    )]
+    teardown: Option<String>,
+
+    #[clap(
+        long,
+        about = "A shell command to run once, before any task starts, with REACH_SOURCE_DIR and REACH_DEST_DIR set. \
+                 If it fails, no tasks are run. Its output goes to 'before-all.log' in the state directory."
+    )]
+    before_all: Option<String>,
+
+    #[clap(
+        long,
+        about = "A shell command to run once, after every task has finished, \
+                 with REACH_SUCCEEDED and REACH_FAILED set as well as the variables for --before-all. \
+                 If it fails, so does reach. Its output goes to 'after-all.log' in the state directory."
+    )]
+    after_all: Option<String>,
+
+    #[clap(
+        long,
+        about = "The directory to run each task's command in, e.g. '{dest}' for its destination directory, \
+                 so that scratch files that tools leave in their working directory don't collide. \
+                 It can use the same placeholders as filename mode, and is created if it doesn't exist. \
+                 Not supported in coprocess mode. Defaults to reach's own working directory."
+    )]
+    workdir: Option<String>,
+
+    #[clap(
+        long,
+        about = "Another command to run after each task's command, reading its standard output, e.g. '--then \"gzip -9\"'. \
+                 May be given more than once, to make a pipeline where each stage reads the output of the one before. \
+                 Each stage's output and status go in a directory of its own in the task's destination directory, 'then-1' and so on, \
+                 with REACH_STAGE and REACH_PREVIOUS_DIR set. \
+                 A task only succeeds if every stage does, and a task that failed partway through picks up from the stage that failed.",
+        number_of_values = 1
+    )]
+    then: Vec<String>,
+
+    #[clap(
+        long,
+        about = "Run every task's command inside this one, e.g. 'nice -n19 {cmd}'. \
+                 '{cmd}' must be a word by itself, and is replaced by the task's command, exactly as it would otherwise be run, so it never needs quoting. \
+                 The wrapper is split into words like an --exec command, and can use the same placeholders. \
+                 Prefer --timeout to wrapping with 'timeout', as reach then knows why the task failed."
+    )]
+    wrap: Option<String>,
+
+    #[clap(
+        long,
+        about = "Run every task in a transient systemd scope of its own, using 'systemd-run --scope'. \
+                 systemd then accounts for each task's resources separately, and keeps track of any processes it leaves behind. \
+                 Without root, the scopes belong to your user's service manager. The scope goes around any --wrap."
+    )]
+    systemd_scope: bool,
+
+    #[clap(
+        long,
+        about = "A property for each task's systemd scope, like 'MemoryMax=2G' or 'CPUWeight=20'. \
+                 May be given more than once. Only used with --systemd-scope.",
+        number_of_values = 1
+    )]
+    systemd_property: Vec<String>,
+
+    #[clap(
+        long,
+        about = "Run every command as this user and group, like 'nobody:nogroup' or '1000:1000', or just a user, for their own group. \
+                 Needs reach to run as root, as in a container's entrypoint. \
+                 Each task's destination directory, and its --workdir, belong to them, so its commands can write there."
+    )]
+    run_as: Option<RunAs>,
+
+    #[clap(
+        long,
+        about = "Run every command at this niceness, from -20, the highest priority, to 19, the lowest, e.g. '--nice 10'. \
+                 Only root can give commands a higher priority than reach's own, and not with --run-as, which gives up root first.",
+        allow_hyphen_values = true
+    )]
+    nice: Option<i32>,
+
+    #[clap(
+        long,
+        about = "Limit each process of every command to this much memory, e.g. '4G', and have its allocations fail beyond that. \
+                 The limit is on address space, which is more than the memory a process is actually using.",
+        parse(try_from_str = parse_size)
+    )]
+    memory_limit: Option<u64>,
+
+    #[clap(
+        long,
+        about = "Run every command on this many CPUs of its own, out of those reach can run on, e.g. '--cpus-per-task 4' for tools that start a thread per CPU. \
+                 Commands only share CPUs when there are too few for every task running at once. Linux only."
+    )]
+    cpus_per_task: Option<usize>,
+
+    #[clap(
+        long,
+        about = "Limit each process of every command to this many open files, e.g. '1024', so that one that leaks them fails rather than exhausting the machine's"
+    )]
+    task_max_fds: Option<u64>,
+
+    #[clap(
+        long,
+        about = "Limit every command to this many processes, e.g. '256', so that one that forks without end fails rather than exhausting the machine. \
+                 The kernel counts every process of the user the command runs as, not just the task's, \
+                 so this is most useful with --run-as a user of its own, and doesn't apply to root."
+    )]
+    task_max_procs: Option<u64>,
+
+    #[clap(
+        long,
+        about = "Confine every command with Landlock, so that it can only read its inputs, the system's own directories like /usr and /etc, and any given with --restrict-fs-read, \
+                 and only write in its destination directory, its --workdir, devices like /dev/null, and a scratch directory of its own, which is its TMPDIR. \
+                 Commands can't gain privileges, so sudo doesn't work for them. Needs Linux 5.13 or later. \
+                 Shell sessions, coprocesses, workers, and systemd scopes can't be confined."
+    )]
+    restrict_fs: bool,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        about = "Another directory or file that commands confined by --restrict-fs can read, e.g. one with the programs they run. May be given more than once.",
+        number_of_values = 1,
+        value_hint = ValueHint::AnyPath
+    )]
+    restrict_fs_read: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        about = "Run tasks on this machine over SSH rather than locally, e.g. 'me@build1:8' to run up to 8 tasks at once on build1, or 'me@[fe80::1]:8' for an IPv6 address. \
+                 May be given more than once, and then as many tasks run at once as the workers have slots between them, whatever -j says. \
+                 In stdin mode, inputs are streamed to the command over the connection. \
+                 Otherwise each input is copied into a temporary directory on the worker, which the command runs in. \
+                 Only each task's standard output, standard error, and exit status come back from its worker.",
+        number_of_values = 1
+    )]
+    worker: Vec<Worker>,
+
+    #[clap(
+        long,
+        about = "The program to connect to workers with, which must take the same arguments as ssh.",
+        default_value = "ssh"
+    )]
+    ssh: String,
+
+    #[clap(
+        long,
+        about = "How to report progress. \
+                 'bar' shows a progress bar, which is compact on terminals narrower than 60 columns. \
+                 'compact' always shows a compact progress bar, with just the count of tasks done. \
+                 'json' writes a JSON object to stdout for each event, one per line: \
+                 when a task is skipped as it already succeeded, when it starts, when it's retried, \
+                 and when it finishes, with its exit code and how long it took. \
+                 Nothing else is written to stdout, so it can't go with '--capture tag'. \
+                 'tui' shows a dashboard of running tasks and how long they've taken, \
+                 recently finished tasks, and how many tasks a second are finishing; \
+                 press a running task's number to see the end of its standard error as it's written. \
+                 'quiet' reports nothing until the end of the run.",
+        possible_values = &["bar", "compact", "json", "tui", "quiet"],
+        default_value = "bar"
+    )]
+    progress: ProgressMode,
+
+    #[clap(
+        long,
+        about = "The template for the progress bar, in indicatif's format, \
+                 e.g. '{wide_bar} {pos}/{len} {eta}'. \
+                 Besides those, '{elapsed}', '{per_sec}', '{percent}', and '{prefix}' (which says whether any task has failed) are useful."
+    )]
+    progress_template: Option<String>,
+
+    #[clap(
+        long,
+        about = "Also write the end-of-run summary, including every failing input, to 'report.json' in the destination directory."
+    )]
+    report: bool,
+
+    #[clap(
+        long,
+        about = "Print the configuration that reach would run with, as JSON, with every default filled in \
+                 and the input mode detected, then exit without running anything or creating any directories."
+    )]
+    print_config: bool,
+
+    #[clap(
+        long,
+        possible_values = &["bash", "zsh", "fish"],
+        about = "Print a script that completes reach's options in this shell, then exit, \
+                 as in 'source <(reach --completions bash)'. \
+                 As well as options and their values, it completes the destination directories of earlier runs, \
+                 and config files that reach can read, by asking reach as you type."
+    )]
+    #[allow(dead_code)]
+    // Handled before the rest of the options are parsed, by `main`.
+    completions: Option<String>,
+
+    #[clap(
+        long,
+        value_hint = ValueHint::DirPath,
+        about = "Check every task directory in this destination, without running anything, then exit: \
+                 that each task succeeded and none of its output was clipped, \
+                 that its files are as it left them, if it ran with --checksums, \
+                 and that they agree with the manifest, if there is one. \
+                 Exits with a failure if anything is wrong."
+    )]
+    #[allow(dead_code)]
+    // Handled before the rest of the options are parsed, by `main`.
+    verify: Option<PathBuf>,
+
+    #[clap(
+        long,
+        about = "How to group tasks for the success rates and average durations in the summary, \
+                 as a template like '{dir}' or '{stem}'. Groups tasks by their input's extension by default."
+    )]
+    group_by: Option<String>,
+
+    #[clap(
+        long,
+        about = "Write a badge showing the pass rate and duration of the run to this file at the end of the run. \
+                 A path ending in '.json' gets a shields.io endpoint description, and anything else an SVG image. \
+                 The file is replaced atomically."
+    )]
+    badge: Option<PathBuf>,
+
+    #[clap(
+        long,
+        about = "Keep this file up to date with metrics about the run in the OpenMetrics text format, \
+                 like how many tasks have started, succeeded, and failed, how many are running, and how long they took. \
+                 The file is replaced atomically whenever a task starts or finishes."
+    )]
+    metrics_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        about = "Serve the same metrics as --metrics-file over HTTP at this address, like '127.0.0.1:9090', \
+                 for Prometheus to scrape, until the run is over."
+    )]
+    metrics_listen: Option<SocketAddr>,
+
+    #[clap(
+        long,
+        about = "Keep 'progress.json' in the destination directory up to date with how far the run has got: \
+                 how many tasks are done, have failed, and are running, how many finish a second, \
+                 and an estimate of how many seconds the rest will take. \
+                 It's rewritten atomically every second, so 'watch cat' can follow a run with no terminal."
+    )]
+    progress_file: bool,
 }
 
 fn parse_options(opts: Opts) -> Result<Config, clap::Error> {
-    let source = opts.source.canonicalize().map_err(|error| {
-        clap::Error::with_description(
-            format!("Invalid source directory {:?}: {}", opts.source, error),
-            clap::ErrorKind::Io,
-        )
+    if opts.progress == ProgressMode::Json && opts.capture == Capture::Tag {
+        return Err(option_error(
+            "Tagged output would be mixed into the JSON progress on standard output; use --progress quiet with --capture tag".into(),
+            clap::ErrorKind::ArgumentConflict,
+        ));
+    }
+    let input_mode = if opts.exec {
+        Some(InputMode::Exec)
+    } else {
+        opts.input_mode
+    };
+    let source = match (opts.source, opts.from_stdin) {
+        (None, true) => PathBuf::from("-"),
+        (Some(source), false) => source,
+        (Some(source), true) if source == Path::new("-") => source,
+        (Some(_), true) => {
+            return Err(option_error(
+                "Inputs can't be read from standard input and a source directory too; give '-' as the source to name a destination".into(),
+                clap::ErrorKind::ArgumentConflict,
+            ))
+        }
+        (None, false) => {
+            return Err(option_error(
+                "A source directory is needed, unless inputs are read from standard input with --from-stdin".into(),
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    let mut builder = Config::builder(opts.command, source)
+        .null_separated(opts.null)
+        .more_sources(opts.more_sources)
+        .include(opts.include)
+        .exclude(opts.exclude)
+        .skip_invalid_names(opts.skip_invalid_names)
+        .nested_destination(opts.nested_destination)
+        .io_concurrency(opts.io_concurrency)
+        .prefetch(opts.prefetch)
+        .batch(opts.batch)
+        .order(opts.order)
+        .seed(opts.seed)
+        .naming(opts.naming)
+        .manifest(opts.manifest)
+        .from_manifest(opts.from_manifest)
+        .framing(opts.framing)
+        .recreate(opts.recreate)
+        .allow_command_change(opts.allow_command_change)
+        .hash_inputs(opts.hash_inputs)
+        .checksums(opts.checksums)
+        .retry_failed(opts.retry_failed)
+        .rerun_matching(opts.rerun_matching)
+        .watch(if opts.watch {
+            Some(opts.poll_interval)
+        } else {
+            None
+        })
+        // Run as a service, reach is the program systemd started, so it's reach's to notify.
+        .notify_systemd(true)
+        .retries(opts.retries)
+        .low_memory(opts.low_memory)
+        .split_bytes(opts.split_bytes)
+        .retry_signals(opts.retry_signal)
+        .timeout(opts.timeout)
+        .halt(opts.halt)
+        .max_total_output(opts.max_total_output)
+        .output_policy(opts.output_policy)
+        .capture(opts.capture)
+        .stdout_name(opts.stdout_name)
+        .stderr_name(opts.stderr_name)
+        .max_output_size(opts.max_output_size)
+        .group_by(opts.group_by)
+        .max_rate(opts.max_rate)
+        .max_load(opts.load)
+        .window(opts.window)
+        .pause(opts.pausable.then(PauseToken::new))
+        .shell_sessions(opts.shell_sessions)
+        .affinity(opts.affinity)
+        .setup(opts.setup)
+        .teardown(opts.teardown)
+        .before_all(opts.before_all)
+        .after_all(opts.after_all)
+        .workdir(opts.workdir)
+        .then(opts.then)
+        .wrap(opts.wrap)
+        .systemd_scope(opts.systemd_scope)
+        .systemd_properties(opts.systemd_property)
+        .run_as(opts.run_as)
+        .nice(opts.nice)
+        .memory_limit(opts.memory_limit)
+        .cpus_per_task(opts.cpus_per_task)
+        .task_max_fds(opts.task_max_fds)
+        .task_max_procs(opts.task_max_procs)
+        .restrict_fs(opts.restrict_fs)
+        .restrict_fs_read(opts.restrict_fs_read)
+        .workers(opts.worker)
+        .ssh(opts.ssh);
+    if let Some(shell) = opts.shell {
+        builder = builder.shell(shell);
+    }
+    if let Some(input_mode) = input_mode {
+        builder = builder.input_mode(input_mode);
+    }
+    if let Some(destination) = opts.destination {
+        builder = builder.destination_dir(destination);
+    }
+    if let Some(state_dir) = opts.state_dir {
+        builder = builder.state_dir(state_dir);
+    }
+    if let Some(processes) = opts.processes {
+        builder = builder.num_processes(processes);
+    }
+    builder.build().map_err(clap_error)
+}
+
+fn clap_error(error: io::Error) -> clap::Error {
+    let kind = match error.kind() {
+        io::ErrorKind::InvalidInput => clap::ErrorKind::ValueValidation,
+        _ => clap::ErrorKind::Io,
+    };
+    option_error(error.to_string(), kind)
+}
+
+/// An error with the options, which clap prints as it is, so it's given the newline that
+/// clap's own errors end with.
+fn option_error(message: String, kind: clap::ErrorKind) -> clap::Error {
+    clap::Error::with_description(format!("{}\n", message), kind)
+}
+
+/// The options for `reach rerun-matching [--state-dir DIR] DESTINATION PATTERN`, given the
+/// arguments after `rerun-matching`: those of the last run with that destination, as it
+/// recorded them in its state directory, but only rerunning the tasks whose inputs' names
+/// match the pattern. Along with how the run was
+/// started, which is where reach now runs, as the options may have relative paths.
+async fn rerun_matching(args: &[OsString]) -> io::Result<(Invocation, Opts)> {
+    let invalid =
+        |message: Message<'_>| io::Error::new(io::ErrorKind::InvalidInput, message.text());
+    let (state_dir, destination_dir, pattern) = match args {
+        [destination_dir, pattern] => (None, Path::new(destination_dir), pattern),
+        [option, state_dir, destination_dir, pattern] if option == "--state-dir" => (
+            Some(PathBuf::from(state_dir)),
+            Path::new(destination_dir),
+            pattern,
+        ),
+        _ => return Err(invalid(Message::RerunUsage)),
+    };
+    let pattern = pattern
+        .to_str()
+        .ok_or_else(|| invalid(Message::InvalidPattern(pattern)))?;
+    let destination_dir = fs::canonicalize(destination_dir).await.map_err(|error| {
+        let message = Message::InvalidDestination {
+            path: destination_dir,
+            error: &error,
+        };
+        io::Error::new(error.kind(), message.text())
     })?;
-    // TODO(jml): There feels like there should be some more idiomatic way of doing this.
-    let destination = match opts.destination {
-        Some(p) => Ok(p),
-        None => get_destination_dir(&source),
-    }?;
-    let destination = ensure_destination_directory(destination)?;
-    let num_processes = opts.processes.unwrap_or_else(num_cpus::get);
-    // TODO(jml): Automatically choose Filename input mode if {} present in command.
-    let input_mode = opts.input_mode.unwrap_or(InputMode::Stdin);
-    Ok(Config {
-        command: opts.command,
-        shell: opts.shell,
-        source_dir: source,
-        destination_dir: destination,
-        num_processes,
-        input_mode,
-        recreate: opts.recreate,
-        retries: opts.retries,
-    })
-}
-
-/// Make up a path to the destination directory from the source directory.
-/// `foo` becomes `foo-results`
-fn get_destination_dir(source_dir: &Path) -> Result<PathBuf, clap::Error> {
-    // TODO: Write a test for this bit.
-    let mut file_name = source_dir
-        .file_name()
-        .ok_or_else(|| {
-            clap::Error::with_description(
-                format!(
-                    "You must provide an explicit destination directory if source directory is {}",
-                    source_dir.to_str().unwrap()
-                ),
-                clap::ErrorKind::ValueValidation,
-            )
-        })?
-        .to_owned();
-    file_name.push("-results");
-    let mut dest = source_dir.to_path_buf();
-    dest.set_file_name(file_name);
-    Ok(dest)
-}
-
-/// Create the destination directory if it doesn't exist.
-fn ensure_destination_directory(destination: PathBuf) -> Result<PathBuf, clap::Error> {
-    destination.canonicalize().or_else(|error| {
-        if error.kind() == io::ErrorKind::NotFound {
-            fs::create_dir_all(&destination).map_err(|error| {
-                clap::Error::with_description(
-                    format!(
-                        "Could not create destination directory {:?}: {}",
-                        destination, error
-                    ),
-                    clap::ErrorKind::Io,
-                )
-            })?;
-            Ok(destination)
+    let state_dir = state_dir.unwrap_or_else(|| destination_dir.join(STATE_DIR));
+    let invocation = Invocation::read(&state_dir, &destination_dir).await?;
+    env::set_current_dir(&invocation.dir).map_err(|error| {
+        let message = Message::RunDirGone {
+            dir: &invocation.dir,
+            error: &error,
+        };
+        io::Error::new(error.kind(), message.text())
+    })?;
+    let mut opts = Opts::try_parse_from(&invocation.args)
+        .map_err(|error| invalid(Message::RunArgsInvalid(&error.to_string())))?;
+    if opts.from_stdin || opts.source.as_deref() == Some(Path::new("-")) {
+        return Err(invalid(Message::RunFromStdin {
+            destination: &destination_dir,
+        }));
+    }
+    opts.destination = Some(destination_dir);
+    opts.state_dir = invocation.state_dir.clone();
+    opts.rerun_matching = Some(pattern.to_owned());
+    // Anything that would run more than the matching tasks, or keep running.
+    opts.recreate = false;
+    opts.retry_failed = false;
+    opts.watch = false;
+    Ok((invocation, opts))
+}
+
+/// The command line `args`, with the settings from the config file named by its `--config`,
+/// if it has one, in front of the rest as the options they stand for.
+///
+/// The file's settings for options that are on the command line itself are left out, so the
+/// command line's win. A command in the file goes in front of the command line's arguments,
+/// so they start with the source directory, unless the command line gives its own command.
+async fn with_config_file(args: Vec<OsString>) -> io::Result<Vec<OsString>> {
+    let app = Opts::into_app();
+    let given = given_options(&app, &args[1..]);
+    let path = match given.config {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let invalid = |message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("In config file {:?}: {}", path, message),
+        )
+    };
+    let file = ConfigFile::read(&path).await?;
+    let mut merged = vec![args[0].clone()];
+    let mut command = None;
+    for (name, value) in file.settings() {
+        let long = name.replace('_', "-");
+        if long == "command" {
+            match value {
+                ConfigValue::String(value) => command = Some(value.into()),
+                _ => return Err(invalid("command must be a string".into())),
+            }
+            continue;
+        }
+        let arg = app
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(&long) && long != "config")
+            .ok_or_else(|| invalid(format!("No such option as {}", name)))?;
+        if given.longs.contains(&long) {
+            continue;
+        }
+        merged.extend(
+            option_args(arg, value).map_err(|error| invalid(format!("{} {}", name, error)))?,
+        );
+    }
+    if !has_command(&given.positionals).await {
+        merged.extend(command);
+    }
+    merged.extend(args.into_iter().skip(1));
+    Ok(merged)
+}
+
+/// Whether a command line with the positional arguments `positionals` gives the command
+/// itself, rather than starting with the source: if it has all three of the command, the
+/// source, and the destination, or its first isn't '-' or a directory or file that exists,
+/// so can't be a source.
+async fn has_command(positionals: &[OsString]) -> bool {
+    match positionals.first() {
+        None => false,
+        Some(_) if positionals.len() == 3 => true,
+        Some(first) if first == "-" => false,
+        Some(first) => fs::metadata(first).await.is_err(),
+    }
+}
+
+/// The options on a command line, by their long names, where its `--config` file is,
+/// which shell it wants completions for, and which destination to verify, if any,
+/// and its positional arguments.
+struct Given {
+    longs: HashSet<String>,
+    config: Option<PathBuf>,
+    completions: Option<String>,
+    verify: Option<PathBuf>,
+    positionals: Vec<OsString>,
+}
+
+/// Pick out the options in `args`, the way `app` will parse them.
+fn given_options(app: &clap::App<'_>, args: &[OsString]) -> Given {
+    let mut given = Given {
+        longs: HashSet::new(),
+        config: None,
+        completions: None,
+        verify: None,
+        positionals: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(os_arg) = args.next() {
+        let arg = os_arg.to_string_lossy();
+        if arg == "--" {
+            given.positionals.extend(args.cloned());
+            break;
+        }
+        if arg == "-" || !arg.starts_with('-') {
+            given.positionals.push(os_arg.clone());
+            continue;
+        }
+        let found = if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            app.get_arguments()
+                .find(|option| option.get_long() == Some(name))
+                .map(|option| (option, value))
+        } else if let Some(shorts) = arg.strip_prefix('-') {
+            // The first of a cluster of short options to take a value has the rest, as in '-j4'.
+            let mut found = None;
+            for (i, short) in shorts.char_indices() {
+                let option = app
+                    .get_arguments()
+                    .find(|option| option.get_short() == Some(short));
+                let option = match option {
+                    Some(option) => option,
+                    None => break,
+                };
+                given.longs.extend(option.get_long().map(str::to_owned));
+                if option.is_set(ArgSettings::TakesValue) {
+                    let rest = &shorts[i + short.len_utf8()..];
+                    found = Some((option, Some(rest).filter(|rest| !rest.is_empty())));
+                    break;
+                }
+            }
+            found
         } else {
-            Err(clap::Error::with_description(
-                format!("Invalid destination directory {:?}: {}", destination, error),
-                clap::ErrorKind::Io,
-            ))
+            None
+        };
+        let (option, value) = match found {
+            Some(found) => found,
+            None => continue,
+        };
+        given.longs.extend(option.get_long().map(str::to_owned));
+        if option.is_set(ArgSettings::TakesValue) {
+            let value = value.map(OsString::from).or_else(|| args.next().cloned());
+            match option.get_long() {
+                Some("config") => given.config = value.map(PathBuf::from),
+                Some("completions") => {
+                    given.completions = value.map(|value| value.to_string_lossy().into_owned())
+                }
+                Some("verify") => given.verify = value.map(PathBuf::from),
+                _ => {}
+            }
+        }
+    }
+    given
+}
+
+/// The command-line arguments for setting `option` to `value`.
+fn option_args(option: &clap::Arg<'_>, value: &ConfigValue) -> Result<Vec<OsString>, String> {
+    let long = option.get_long().unwrap_or_default();
+    if !option.is_set(ArgSettings::TakesValue) {
+        return match value {
+            ConfigValue::Boolean(true) => Ok(vec![format!("--{}", long).into()]),
+            ConfigValue::Boolean(false) => Ok(vec![]),
+            _ => Err("is a switch, so it's either true or false".into()),
+        };
+    }
+    let values = match value {
+        ConfigValue::Array(values) if option.is_set(ArgSettings::MultipleOccurrences) => {
+            values.iter().collect()
+        }
+        ConfigValue::Array(_) => return Err("only takes one value".into()),
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let value = match value {
+                ConfigValue::String(value) => value.clone(),
+                ConfigValue::Integer(value) => value.to_string(),
+                ConfigValue::Float(value) => value.to_string(),
+                ConfigValue::Boolean(value) => value.to_string(),
+                ConfigValue::Array(_) => return Err("can't have arrays inside arrays".into()),
+            };
+            // Given as one argument, so that a value starting with '-' isn't taken for an option.
+            Ok(format!("--{}={}", long, value).into())
+        })
+        .collect()
+}
+
+/// Parse a rate in tasks a second, which must be more than zero.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("Invalid rate: {:?}", s)),
+    }
+}
+
+/// The exit code for a run that was stopped by Ctrl-C, following the shell convention of 128 + SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// The exit code for a run where tasks failed.
+const FAILED_EXIT_CODE: i32 = 1;
+
+/// How often `--progress-file` rewrites 'progress.json'.
+const PROGRESS_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pause `token` on SIGUSR1 and resume it on SIGUSR2, for as long as the run goes on.
+#[cfg(unix)]
+fn pause_on_signals(token: PauseToken) -> io::Result<()> {
+    use signal::unix::{signal, SignalKind};
+    let mut pause = signal(SignalKind::user_defined1())?;
+    let mut resume = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = pause.recv() => token.pause(),
+                Some(()) = resume.recv() => token.resume(),
+                else => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn pause_on_signals(_token: PauseToken) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        Message::Unsupported(reach::Unsupported::PauseSignals).text(),
+    ))
+}
+
+/// Write a support bundle if reach panics, after the panic's own message.
+fn write_bundle_on_panic(bundle: Arc<SupportBundle>) {
+    let panicked = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        panicked(info);
+        report_bundle(bundle.write_panic(&info.to_string()));
+    }));
+}
+
+/// Say where a support bundle was written, or why it couldn't be.
+fn report_bundle(written: io::Result<PathBuf>) {
+    match written {
+        Ok(path) => eprintln!("{}", Message::SupportBundle(&path).text()),
+        Err(error) => {
+            let warning = Message::SupportBundleUnwritten(&error).text();
+            eprintln!("{}", Message::Warning(&warning).text());
         }
-    })
+    }
+}
+
+/// Completes when the user hits Ctrl-C.
+async fn ctrl_c() {
+    if signal::ctrl_c().await.is_err() {
+        // We couldn't listen for Ctrl-C, so it will kill us the old-fashioned way.
+        future::pending::<()>().await;
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), io::Error> {
-    let opts: Opts = Opts::parse();
+async fn main() {
+    if let Err(error) = run_main().await {
+        eprintln!("{}", Message::Error(&error).text());
+        process::exit(1);
+    }
+}
+
+async fn run_main() -> io::Result<()> {
+    let args: Vec<OsString> = env::args_os().collect();
+    let app = Opts::into_app();
+    if args.get(1).map(|arg| arg == completions::HELPER) == Some(true) {
+        // Run by a completion script, which only wants candidates, and nothing on failure.
+        let arg = |i| {
+            args.get(i)
+                .map(|arg: &OsString| arg.to_string_lossy().into_owned())
+        };
+        let (kind, prefix) = (arg(2).unwrap_or_default(), arg(3).unwrap_or_default());
+        for candidate in completions::candidates(&app, &kind, &prefix).await? {
+            println!("{}", candidate);
+        }
+        return Ok(());
+    }
+    let given = given_options(&app, &args[1..]);
+    if let Some(shell) = given.completions {
+        let shell = shell
+            .parse()
+            .unwrap_or_else(|error| option_error(error, clap::ErrorKind::InvalidValue).exit());
+        print!("{}", completions::script(shell, &app));
+        return Ok(());
+    }
+    if let Some(destination_dir) = given.verify {
+        let verification = reach::verify(&destination_dir).await?;
+        print!("{}", Message::Verification(&verification).text());
+        if !verification.is_consistent() {
+            process::exit(FAILED_EXIT_CODE);
+        }
+        return Ok(());
+    }
+    let (invocation, opts) = if args.get(1).map(|arg| arg == RERUN_MATCHING) == Some(true) {
+        rerun_matching(&args[2..])
+            .await
+            .unwrap_or_else(|error| clap_error(error).exit())
+    } else {
+        let args = with_config_file(args)
+            .await
+            .unwrap_or_else(|error| clap_error(error).exit());
+        let invocation = Invocation {
+            dir: env::current_dir()?,
+            args: args.clone(),
+            state_dir: None,
+        };
+        (invocation, Opts::parse_from(args))
+    };
+    let ok_if_some_fail = opts.ok_if_some_fail;
+    let propagate_signal = opts.propagate_signal;
+    let report = opts.report;
+    let max_total_output = opts.max_total_output;
+    let badge = opts.badge.clone();
+    let metrics_file = opts.metrics_file.clone();
+    let metrics_listen = opts.metrics_listen;
+    let write_progress = opts.progress_file;
+    let progress_mode = opts.progress;
+    let progress_template = opts.progress_template.clone();
+    let print_config = opts.print_config;
     let config = parse_options(opts).unwrap_or_else(|err| err.exit());
-    let progress_bar = reach::default_progress_bar();
-    reach::run(config, progress_bar).await
+    if print_config {
+        println!("{:#}", config.to_json());
+        return Ok(());
+    }
+    // Made only now that it's running, as files like 'progress.json' go in it before the run starts.
+    fs::create_dir_all(&config.destination_dir)
+        .await
+        .map_err(|error| {
+            let message = Message::DestinationUncreatable {
+                path: &config.destination_dir,
+                error: &error,
+            };
+            io::Error::new(error.kind(), message.text())
+        })?;
+    // Recorded before the run starts, so that even one that's killed part way can be rerun.
+    if let Err(error) = invocation.write(&config.state_dir).await {
+        let warning = Message::InvocationUnrecorded(&error).text();
+        eprintln!("{}", Message::Warning(&warning).text());
+    }
+    if let Some(token) = config.pause.clone() {
+        pause_on_signals(token)?;
+    }
+    let progress: Box<dyn Progress> = match (progress_mode, config.capture) {
+        (ProgressMode::Tui, Capture::Discard) => Box::new(Dashboard::new()),
+        (ProgressMode::Tui, Capture::Merge) => Box::new(Dashboard::showing_output(
+            &config.destination_dir,
+            &config.stdout_name,
+        )),
+        (ProgressMode::Tui, _) => Box::new(Dashboard::showing_output(
+            &config.destination_dir,
+            &config.stderr_name,
+        )),
+        (mode, _) => mode.progress_with_template(progress_template.as_deref()),
+    };
+    let metrics = Metrics::new();
+    if let Some(address) = metrics_listen {
+        let listener = TcpListener::bind(address).await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(error) = metrics.serve(listener).await {
+                let warning = Message::MetricsServingStopped(&error).text();
+                eprintln!("{}", Message::Warning(&warning).text());
+            }
+        });
+    }
+    if let Some(path) = metrics_file.clone() {
+        // Write it once first, so a path that can't be written fails the run before it starts.
+        metrics.write(&path).await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(error) = metrics.keep_written(&path).await {
+                let warning = Message::MetricsWritingStopped(&error).text();
+                eprintln!("{}", Message::Warning(&warning).text());
+            }
+        });
+    }
+    let progress: Box<dyn Progress> = match (metrics_listen, &metrics_file) {
+        (None, None) => progress,
+        _ => Box::new((progress, metrics.clone())),
+    };
+    let progress_file = ProgressFile::new();
+    let progress_path = config.destination_dir.join("progress.json");
+    let progress: Box<dyn Progress> = if write_progress {
+        // Written once first, so a destination that can't be written fails the run before it starts.
+        progress_file.write(&progress_path).await?;
+        let writer = progress_file.clone();
+        let path = progress_path.clone();
+        tokio::spawn(async move {
+            if let Err(error) = writer.keep_written(&path, PROGRESS_FILE_INTERVAL).await {
+                let warning = Message::ProgressWritingStopped(&error).text();
+                eprintln!("{}", Message::Warning(&warning).text());
+            }
+        });
+        Box::new((progress, progress_file.clone()))
+    } else {
+        progress
+    };
+    let events = RecentEvents::new();
+    let progress: Box<dyn Progress> = Box::new((progress, events.clone()));
+    let bundle = Arc::new(SupportBundle::new(&config, events));
+    write_bundle_on_panic(bundle.clone());
+    let report_path = config.destination_dir.join("report.json");
+    let result = reach::run_until(config, progress, ctrl_c()).await;
+    let summary = match result {
+        Err(error) if error.kind() == io::ErrorKind::Interrupted => {
+            eprintln!("{}", Message::Interrupted.text());
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        Err(error) if reach::is_systemic(&error) => {
+            let written = bundle.write_error(&error);
+            eprintln!("{}", Message::Error(&error.into()).text());
+            report_bundle(written);
+            process::exit(1);
+        }
+        result => result?,
+    };
+    eprint!("{}", Message::Summary(&summary).text());
+    let over_output = matches!(max_total_output, Some(limit) if summary.output_bytes > limit);
+    if over_output {
+        let bytes = summary.output_bytes;
+        eprintln!("{}", Message::OverOutput { bytes }.text());
+    }
+    if report {
+        summary.write_json(&report_path).await?;
+    }
+    if let Some(badge) = badge {
+        summary.write_badge(&badge).await?;
+    }
+    if let Some(path) = metrics_file {
+        metrics.write(&path).await?;
+    }
+    if write_progress {
+        progress_file.write(&progress_path).await?;
+    }
+    let halt_signal = summary
+        .halted_by
+        .as_ref()
+        .and_then(|failure| failure.status.as_ref())
+        .and_then(Status::signal);
+    if let (true, Some(signal)) = (propagate_signal, halt_signal) {
+        exit_by_signal(signal);
+    }
+    let failed = if ok_if_some_fail {
+        summary.all_failed()
+    } else {
+        !summary.all_succeeded()
+    };
+    if failed || over_output {
+        process::exit(FAILED_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// Exit by `signal`, as the command that halted the run did, if it's one that ends processes.
+fn exit_by_signal(signal: i32) {
+    #[cfg(unix)]
+    // SAFETY: Nothing else is running that `signal`'s default action could leave inconsistent,
+    // as the run is over and everything has been written.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    #[cfg(not(unix))]
+    let _ = signal;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(Ok(2.0), parse_rate("2"));
+        assert_eq!(Ok(0.5), parse_rate("0.5"));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    /// Options are only parsed, as for --print-config, without making the destination.
+    #[test]
+    fn test_parse_options() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("out");
+        let opts = Opts::try_parse_from([
+            OsStr::new("reach"),
+            OsStr::new("cat"),
+            dir.path().as_os_str(),
+            destination.as_os_str(),
+        ])
+        .unwrap();
+        let config = parse_options(opts).unwrap();
+        assert_eq!(destination, config.destination_dir);
+        assert!(!destination.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_config_file() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("reach.toml");
+        std::fs::write(
+            &path,
+            "command = 'gzip'\nprocesses = 8\nretries = 2\nretry-failed = true\n\
+             recreate = false\nthen = ['wc -c', '-x']\n",
+        )?;
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let config = path.to_str().unwrap();
+        let src = dir.path().to_str().unwrap();
+
+        assert_eq!(
+            args(&[
+                "reach",
+                "--retries=2",
+                "--retry-failed",
+                "--then=wc -c",
+                "--then=-x",
+                "gzip",
+                "-j4",
+                "--config",
+                config,
+                src,
+            ]),
+            with_config_file(args(&["reach", "-j4", "--config", config, src])).await?
+        );
+        assert_eq!(
+            args(&[
+                "reach",
+                "--processes=8",
+                "--then=wc -c",
+                "--then=-x",
+                "gzip"
+            ])[..],
+            with_config_file(args(&[
+                "reach",
+                &format!("--config={}", config),
+                "--retries",
+                "1",
+                "--retry-failed",
+                src,
+            ]))
+            .await?[..5]
+        );
+        // A command on the command line wins over the file's.
+        let from_file = [
+            "reach",
+            "--processes=8",
+            "--retries=2",
+            "--retry-failed",
+            "--then=wc -c",
+            "--then=-x",
+            "--config",
+            config,
+        ];
+        assert_eq!(
+            args(&[&from_file[..], &["cat", src]].concat()),
+            with_config_file(args(&["reach", "--config", config, "cat", src])).await?
+        );
+        assert_eq!(
+            args(&[&from_file[..], &["cat", "in", "out"]].concat()),
+            with_config_file(args(&["reach", "--config", config, "cat", "in", "out"])).await?
+        );
+        // A file is a source too, when it's split into chunks.
+        let file = dir.path().join("big.bin");
+        std::fs::write(&file, [0; 1000])?;
+        let file = file.to_str().unwrap();
+        assert_eq!(
+            args(
+                &[
+                    &from_file[..4],
+                    &["--then=wc -c", "--then=-x", "gzip", "--config", config],
+                    &["--split-bytes", "300", file, "out"],
+                ]
+                .concat()
+            ),
+            with_config_file(args(&[
+                "reach",
+                "--config",
+                config,
+                "--split-bytes",
+                "300",
+                file,
+                "out",
+            ]))
+            .await?
+        );
+        let none = args(&["reach", "cat", "src"]);
+        assert_eq!(none, with_config_file(none.clone()).await?);
+
+        std::fs::write(&path, "precesses = 8\n")?;
+        let error = with_config_file(args(&["reach", "--config", config, "src"]))
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        std::fs::write(&path, "recreate = 'yes'\n")?;
+        assert!(
+            with_config_file(args(&["reach", "--config", config, "src"]))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rerun_matching() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("out");
+        let state_dir = destination.join(STATE_DIR);
+        std::fs::create_dir_all(&destination)?;
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let invocation = Invocation {
+            dir: dir.path().to_path_buf(),
+            args: args(&[
+                "reach",
+                "--recreate",
+                "--watch",
+                "-j2",
+                "wc -l",
+                "src",
+                "out",
+            ]),
+            state_dir: None,
+        };
+        invocation
+            .write(Path::new("out").join(STATE_DIR).as_path())
+            .await?;
+
+        let pattern = OsString::from("customer-42-*");
+        let (rerun, opts) = rerun_matching(&[destination.clone().into(), pattern.clone()]).await?;
+        assert_eq!(invocation.args, rerun.args);
+        assert_eq!(Some(state_dir.clone()), rerun.state_dir);
+        assert_eq!("wc -l", opts.command);
+        assert_eq!(Some(PathBuf::from("src")), opts.source);
+        assert_eq!(Some(destination.canonicalize()?), opts.destination);
+        assert_eq!(Some(state_dir), opts.state_dir);
+        assert_eq!(Some("customer-42-*".to_string()), opts.rerun_matching);
+        assert_eq!(Some(2), opts.processes);
+        assert!(!opts.recreate && !opts.watch);
+
+        // A state directory somewhere else has to be given.
+        let elsewhere = dir.path().join("state");
+        invocation.write(&elsewhere).await?;
+        let (rerun, opts) = rerun_matching(&[
+            "--state-dir".into(),
+            elsewhere.clone().into(),
+            destination.clone().into(),
+            pattern,
+        ])
+        .await?;
+        assert_eq!(Some(elsewhere.clone()), rerun.state_dir);
+        assert_eq!(Some(elsewhere), opts.state_dir);
+
+        assert!(rerun_matching(&[destination.into()]).await.is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,1450 @@
+//! The words reach uses to tell people what's happening, in one place, so that programs using
+//! reach as a library can reword them, or translate them.
+//!
+//! Anything meant for other programs, like JSON lines, reports and manifests, is never
+//! worded here, so nothing installed here can change it.
+
+use serde_json::{json, Value};
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use crate::{RunAs, Status, Summary, Verification};
+
+/// Something reach says to people. Its `Display` is the English that reach says by default.
+///
+/// Each warning and error has a variant of its own, carrying the paths, errors, and numbers
+/// it's about, so that it can be reworded without picking its English apart.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Message<'a> {
+    /// Something about the run deserves a warning, though it carries on.
+    Warning(&'a str),
+    /// Something went wrong, with a task, or with the whole run.
+    Error(&'a io::Error),
+    /// How the run went, once it's over.
+    Summary(&'a Summary),
+    /// What `verify` found.
+    Verification(&'a Verification),
+    /// The run was interrupted, and can be resumed.
+    Interrupted,
+    /// The run was stopped early, as tasks wrote `bytes` of output, more than allowed.
+    OverOutput { bytes: u64 },
+    /// The dashboard's first line: how far the run has got.
+    DashboardProgress {
+        done: usize,
+        tasks: usize,
+        failed: usize,
+        running: usize,
+        per_sec: f64,
+        elapsed: &'a str,
+    },
+    /// The heading over the dashboard's running tasks.
+    DashboardRunning,
+    /// The dashboard shows only so many running tasks, and `more` aren't shown.
+    DashboardMore { more: usize },
+    /// The heading over the dashboard's recently finished tasks.
+    DashboardRecent,
+    /// The dashboard shows the end of the file called `name` that `input`'s task is writing.
+    DashboardOutput { name: &'a str, input: &'a Path },
+    /// What keys the dashboard takes, if its tasks' output is being kept.
+    DashboardKeys { output_kept: bool },
+    /// Whether a task on the dashboard's list of recently finished ones failed.
+    DashboardFinished { failed: bool },
+    /// A task that's taken more than one attempt is on this one.
+    Attempt(u32),
+    /// A task's command exited with this code.
+    ExitCode(i32),
+    /// Something went wrong with reach itself, and a support bundle about it was written here.
+    SupportBundle(&'a Path),
+    /// The support bundle couldn't be written.
+    SupportBundleUnwritten(&'a io::Error),
+    /// A configuration reach won't run.
+    Refused(Refusal),
+    /// Something reach can't do here.
+    Unsupported(Unsupported),
+    /// The command, or how tasks run, has changed since the last run, in these ways, and
+    /// tasks that succeeded then won't be run again.
+    CommandChanged { changes: &'a [String] },
+    /// The command has changed since the last run in `destination`, in these ways, and
+    /// results from both can't be mixed.
+    CommandChangeRefused {
+        destination: &'a Path,
+        changes: &'a [String],
+    },
+    /// The source `source` is inside the destination directory.
+    SourceInsideDestination {
+        source: &'a Path,
+        destination: &'a Path,
+    },
+    /// The destination directory is inside the source `source`, without that being allowed.
+    DestinationInsideSource {
+        destination: &'a Path,
+        source: &'a Path,
+    },
+    /// Workers were given a shell that isn't a POSIX one.
+    WorkersShell(&'a str),
+    /// Shell sessions were given a shell that isn't a POSIX one.
+    SessionsShell(&'a str),
+    /// Output files were given a name that isn't a plain one, or is one that reach uses.
+    OutputName(&'a str),
+    /// Niceness was given outside the range it goes in.
+    Niceness(i32),
+    /// Tasks were given fewer file descriptors than the `min` they need.
+    TooFewFds { min: u64 },
+    /// Tasks were given more CPUs each than the `available` ones.
+    TooManyCpus { per_task: usize, available: usize },
+    /// The maximum rate of starting tasks isn't a rate.
+    InvalidRate(f64),
+    /// Commands can only be run as another user by root.
+    RunAsNotRoot(&'a RunAs),
+    /// The source directory couldn't be used.
+    InvalidSourceDir {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// One of the other sources couldn't be used.
+    InvalidSource {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// No destination was given, and none can be made up from the source.
+    NoDefaultDestination(&'a Path),
+    /// The destination directory couldn't be created.
+    DestinationUncreatable {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The destination directory couldn't be used.
+    InvalidDestination {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The config file couldn't be read.
+    ConfigFileUnreadable {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The config file isn't valid, with this problem.
+    InvalidConfigFile { path: &'a Path, problem: &'a str },
+    /// Another reach process holds the lock on the state directory.
+    StateDirLocked(&'a Path),
+    /// There's no journal from an earlier run to retry the failures of.
+    NothingToRetry,
+    /// Only a file can be split into chunks, and the source isn't one.
+    NotSplittable(&'a Path),
+    /// The input at `input` would get the name the manifest has.
+    ManifestName { input: &'a Path, name: &'a OsStr },
+    /// The input at `input` would get the same name as another input.
+    SharedDestination { input: &'a Path, name: &'a OsStr },
+    /// Something at `path` couldn't be read.
+    Unreadable {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// Something went wrong with an input read from standard input, so it's left out.
+    LeftOut(&'a io::Error),
+    /// An entry in the source directory couldn't be read.
+    SourceEntryUnreadable(&'a io::Error),
+    /// The list of inputs on standard input couldn't be read.
+    InputListUnreadable(&'a io::Error),
+    /// The manifest at `path` isn't valid, with this problem.
+    InvalidManifest { path: &'a Path, problem: &'a str },
+    /// The manifest at `path` couldn't be read.
+    ManifestUnreadable {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The manifest couldn't be written.
+    ManifestUnwritable(&'a io::Error),
+    /// The run journal couldn't be written to.
+    JournalUnwritable(&'a io::Error),
+    /// A line of the checksums file, numbered from 1, can't be read.
+    ChecksumsLine(usize),
+    /// The state directory at `path` couldn't be used.
+    StateDirFailed {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The command for the input at `path` couldn't be started.
+    SpawnFailed {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The task directory at `path` couldn't be prepared or written to.
+    OutputFailed {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// A task, or the run, was interrupted.
+    Interruption,
+    /// The input at `path` couldn't be prefetched.
+    PrefetchFailed {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The input at `input` couldn't be hashed.
+    HashFailed {
+        input: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The hash of the input at `input` couldn't be recorded.
+    HashUnrecorded {
+        input: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The annotations in the task directory `dir` couldn't be read.
+    AnnotationsUnreadable { dir: &'a Path, error: &'a io::Error },
+    /// It's outside the window of time to start tasks in, which next opens at `opens`.
+    OutsideWindow { window: &'a str, opens: &'a str },
+    /// The load average couldn't be read.
+    LoadAverageUnreadable,
+    /// The local time couldn't be read.
+    LocalTimeUnreadable,
+    /// A task's command ran for longer than its timeout.
+    TimedOut(Duration),
+    /// The hook called `name` failed, exiting with `status`.
+    HookFailed { name: &'a str, status: &'a Status },
+    /// Commands can't be confined, as Landlock isn't available.
+    LandlockUnavailable(&'a io::Error),
+    /// Commands couldn't be confined to `path`.
+    ConfineFailed {
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+    /// The command reach was to keep track of had already finished.
+    CommandFinished,
+    /// An input file got shorter while it was being sent to a coprocess.
+    InputShrank,
+    /// Line framing was used with an input with more than one line.
+    InputNotOneLine,
+    /// A coprocess exited before it finished its response.
+    CoprocessExited,
+    /// A coprocess sent this, which isn't the length of a response.
+    CoprocessBadLength(&'a str),
+    /// A shell session exited while it was running a task.
+    SessionExited,
+    /// A shell session sent this, which isn't a task's exit status.
+    SessionBadStatus(&'a str),
+    /// A path can't be passed to the shell, as it isn't unicode.
+    NonUnicodeName(&'a OsStr),
+    /// A string can't be quoted for cmd.
+    CmdUnquotable(&'a str),
+    /// The destination's filesystem is failing, and the run is paused until it's back.
+    DestinationFailing(&'a io::Error),
+    /// The destination's filesystem is back, and the run carries on.
+    DestinationBack,
+    /// The run is paused, and running tasks are frozen.
+    PausedFrozen,
+    /// The run is paused, but running tasks can't be frozen, for this reason.
+    PausedUnfrozen(&'a io::Error),
+    /// The run has been resumed.
+    Resumed,
+    /// Running tasks couldn't be frozen or thawed.
+    FreezeFailed(&'a io::Error),
+    /// Metrics are no longer being served.
+    MetricsServingStopped(&'a io::Error),
+    /// Metrics are no longer being written.
+    MetricsWritingStopped(&'a io::Error),
+    /// Progress is no longer being written.
+    ProgressWritingStopped(&'a io::Error),
+    /// How reach was run couldn't be recorded, so it can't be rerun.
+    InvocationUnrecorded(&'a io::Error),
+    /// `reach rerun-matching` was run without a destination and a pattern, and perhaps a
+    /// state directory.
+    RerunUsage,
+    /// The pattern for the tasks to rerun isn't unicode.
+    InvalidPattern(&'a OsStr),
+    /// There's no record of a run to rerun with the destination `destination`.
+    NoRunRecorded { destination: &'a Path },
+    /// The record of a run at `path` isn't valid.
+    InvalidRunRecord(&'a Path),
+    /// The directory `dir` that the run to rerun was started in can't be gone back to.
+    RunDirGone { dir: &'a Path, error: &'a io::Error },
+    /// The arguments that the run to rerun was started with aren't valid now, with this problem.
+    RunArgsInvalid(&'a str),
+    /// The run to rerun with the destination `destination` read its inputs from standard input.
+    RunFromStdin { destination: &'a Path },
+}
+
+/// A configuration that reach won't run, as one of its settings doesn't go with another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Refusal {
+    /// Systemd scopes were to run as another user.
+    SystemdScopeRunAs,
+    /// Commands run as another user were to be given a higher priority.
+    RunAsRaisedPriority,
+    /// Chunks of a file were to go to processes that outlive their tasks, or to workers.
+    SplitOutsideTasks,
+    /// Tasks were to be confined to their files without a process of their own here.
+    ConfineOutsideTasks,
+    /// Affinity was given without anything that outlives its tasks.
+    AffinityWithoutWorkers,
+    /// Coprocesses were to be wrapped, or run in shell sessions.
+    CoprocessWrapped,
+    /// Coprocesses were to be sent batches.
+    CoprocessBatched,
+    /// Coprocesses' output was to be clipped.
+    CoprocessClipped,
+    /// Coprocesses were to run in a directory for each task.
+    CoprocessWorkdir,
+    /// Coprocesses' output was to be merged or tagged.
+    CoprocessMerged,
+    /// Workers were to run shell sessions or coprocesses.
+    WorkersInSessions,
+    /// Workers were given a directory to run tasks in.
+    WorkersWorkdir,
+    /// Workers were to be sent batches other than on standard input.
+    WorkersBatched,
+    /// Workers were to limit their tasks' resources.
+    WorkersLimited,
+    /// More than one input was to be copied to a worker for a task.
+    WorkerCopies,
+    /// Inputs were to be ordered in low-memory mode.
+    LowMemoryOrdered,
+    /// Inputs were to be watched for in low-memory mode.
+    LowMemoryWatched,
+    /// There was more than one source in low-memory mode.
+    LowMemorySources,
+    /// Inputs were to be numbered in low-memory mode.
+    LowMemoryNumbered,
+    /// Inputs read from standard input were given other sources.
+    StdinSources,
+    /// Inputs read from standard input were to be ordered.
+    StdinOrdered,
+    /// Inputs read from standard input were to be watched for.
+    StdinWatched,
+    /// Inputs read from standard input were to be split.
+    StdinSplit,
+    /// Inputs read from standard input were to be in low-memory mode.
+    StdinLowMemory,
+    /// Inputs were to be read from standard input and a manifest.
+    ManifestStdin,
+    /// Inputs from a manifest were to be ordered.
+    ManifestOrdered,
+    /// Inputs from a manifest were to be watched for.
+    ManifestWatched,
+    /// Inputs from a manifest were to be in low-memory mode.
+    ManifestLowMemory,
+    /// A file was to be split into chunks of no bytes.
+    SplitEmpty,
+    /// A file's chunks were to be batched.
+    SplitBatched,
+    /// A file was to be split, with more sources.
+    SplitSources,
+    /// A file's chunks were to be ordered.
+    SplitOrdered,
+    /// A file was to be split, and watched for more inputs.
+    SplitWatched,
+    /// A file was to be split in low-memory mode.
+    SplitLowMemory,
+    /// A file's chunks were to be hashed.
+    SplitHashed,
+    /// A file's chunks were to be named other than for their number.
+    SplitNamed,
+    /// Inputs in a random order were to be numbered, without a seed.
+    RandomNumbered,
+    /// The output of tasks with stages was to be discarded.
+    StagesDiscarded,
+    /// Standard output and standard error were to share a file, without being merged.
+    SharedOutputFile,
+    /// Shell sessions' output was to be tagged.
+    SessionsTagged,
+    /// Shell sessions' output was to be clipped.
+    SessionsClipped,
+    /// Tasks were given no memory.
+    NoMemory,
+    /// Tasks were given no processes.
+    NoProcesses,
+    /// Tasks were given no CPUs.
+    NoCpus,
+}
+
+/// Something reach can't do where it's running, or for the tasks it's running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Unsupported {
+    /// Coprocesses, other than on Unix.
+    Coprocesses,
+    /// Workers, other than on Unix.
+    Workers,
+    /// Workers, without reach's remote feature.
+    WorkersWithoutRemote,
+    /// Systemd scopes, other than on Unix.
+    SystemdScopes,
+    /// Shell sessions, other than on Unix.
+    ShellSessions,
+    /// Limits on niceness, memory, file descriptors, or processes, other than on Unix.
+    Limits,
+    /// CPUs of their own for tasks, other than on Linux.
+    CpusPerTask,
+    /// Confining tasks to their files, other than on Linux.
+    Confinement,
+    /// Limiting the load average, other than on Unix.
+    LoadAverage,
+    /// Windows of time to start tasks in, other than on Unix.
+    Windows,
+    /// Pausing on signals, other than on Unix.
+    PauseSignals,
+    /// Freezing running tasks, without a cgroup freezer.
+    Freezer,
+    /// Freezing tasks running on workers.
+    FreezeOnWorkers,
+    /// Freezing tasks running in systemd scopes.
+    FreezeInScopes,
+}
+
+impl fmt::Display for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Warning(message) => write!(f, "Warning: {}", message),
+            Message::Error(error) => write!(f, "Error: {}", error),
+            Message::Summary(summary) => write!(f, "{}", summary),
+            Message::Verification(verification) => write!(f, "{}", verification),
+            Message::Interrupted => {
+                write!(
+                    f,
+                    "Interrupted. Run reach again without --recreate to resume."
+                )
+            }
+            Message::OverOutput { bytes } => write!(
+                f,
+                "Stopped early: tasks wrote {} bytes, more than --max-total-output.",
+                bytes
+            ),
+            Message::DashboardProgress {
+                done,
+                tasks,
+                failed,
+                running,
+                per_sec,
+                elapsed,
+            } => write!(
+                f,
+                "{}/{} done, {} failed, {} running, {:.1}/s, {} elapsed",
+                done, tasks, failed, running, per_sec, elapsed
+            ),
+            Message::DashboardRunning => write!(f, "Running:"),
+            Message::DashboardMore { more } => write!(f, "and {} more", more),
+            Message::DashboardRecent => write!(f, "Recent:"),
+            Message::DashboardOutput { name, input } => {
+                write!(f, "{} of {}:", name, input.display())
+            }
+            Message::DashboardKeys { output_kept: true } => write!(
+                f,
+                "Press a running task's number to see its output, or 0 to hide it."
+            ),
+            Message::DashboardKeys { output_kept: false } => {
+                write!(f, "Output can't be shown, as it isn't being kept.")
+            }
+            Message::DashboardFinished { failed: false } => write!(f, "ok"),
+            Message::DashboardFinished { failed: true } => write!(f, "failed"),
+            Message::Attempt(attempt) => write!(f, "(attempt {})", attempt),
+            Message::ExitCode(code) => write!(f, "exit code {}", code),
+            Message::SupportBundle(path) => write!(
+                f,
+                "Wrote a support bundle to {}, to attach to a report of the problem.",
+                path.display()
+            ),
+            Message::SupportBundleUnwritten(error) => {
+                write!(f, "Could not write a support bundle: {}", error)
+            }
+            Message::Refused(refusal) => write!(f, "{}", refusal),
+            Message::Unsupported(unsupported) => write!(f, "{}", unsupported),
+            Message::CommandChanged { changes } => write!(
+                f,
+                "The command has changed since the last run: {}. \
+                 Tasks that succeeded before won't be run again with the new command unless they're recreated.",
+                changes.join(", ")
+            ),
+            Message::CommandChangeRefused {
+                destination,
+                changes,
+            } => write!(
+                f,
+                "The command has changed since the last run in {}: {}. \
+                 Mixing results from both in one destination is refused; \
+                 allow the command to change to keep the old results, or recreate every task with the new one.",
+                destination.display(),
+                changes.join(", ")
+            ),
+            Message::SourceInsideDestination {
+                source,
+                destination,
+            } => write!(
+                f,
+                "The source {} is inside the destination directory {}, so the run would take its own outputs as inputs",
+                source.display(),
+                destination.display()
+            ),
+            Message::DestinationInsideSource {
+                destination,
+                source,
+            } => write!(
+                f,
+                "The destination directory {} is inside the source {}; allow it explicitly if that's intended",
+                destination.display(),
+                source.display()
+            ),
+            Message::WorkersShell(shell) => {
+                write!(f, "Workers need a POSIX shell, not {}", shell)
+            }
+            Message::SessionsShell(shell) => {
+                write!(f, "Shell sessions need a POSIX shell, not {}", shell)
+            }
+            Message::OutputName(name) => write!(
+                f,
+                "Output files need plain names other than '{}', '{}', '{}' and '{}': {:?}",
+                crate::status::STATUS_FILE,
+                crate::annotations::ANNOTATIONS_FILE,
+                crate::input_hash::INPUT_HASH_FILE,
+                crate::cancel::CANCEL_FILE,
+                name
+            ),
+            Message::Niceness(nice) => write!(f, "Niceness goes from -20 to 19, not {}", nice),
+            Message::TooFewFds { min } => write!(
+                f,
+                "Tasks need at least {} file descriptors, for standard input, output, and error",
+                min
+            ),
+            Message::TooManyCpus {
+                per_task,
+                available,
+            } => write!(
+                f,
+                "Tasks can't have {} CPUs each, as there are only {}",
+                per_task, available
+            ),
+            Message::InvalidRate(rate) => write!(f, "Invalid rate: {}", rate),
+            Message::RunAsNotRoot(run_as) => {
+                write!(f, "Only root can run commands as {}", run_as)
+            }
+            Message::InvalidSourceDir { path, error } => {
+                write!(f, "Invalid source directory {:?}: {}", path, error)
+            }
+            Message::InvalidSource { path, error } => {
+                write!(f, "Invalid source {:?}: {}", path, error)
+            }
+            Message::NoDefaultDestination(source_dir) => write!(
+                f,
+                "You must provide an explicit destination directory if source directory is {}",
+                source_dir.display()
+            ),
+            Message::DestinationUncreatable { path, error } => write!(
+                f,
+                "Could not create destination directory {:?}: {}",
+                path, error
+            ),
+            Message::InvalidDestination { path, error } => {
+                write!(f, "Invalid destination directory {:?}: {}", path, error)
+            }
+            Message::ConfigFileUnreadable { path, error } => {
+                write!(f, "Couldn't read config file {:?}: {}", path, error)
+            }
+            Message::InvalidConfigFile { path, problem } => {
+                write!(f, "Invalid config file {:?}: {}", path, problem)
+            }
+            Message::StateDirLocked(path) => write!(
+                f,
+                "Another reach process is already using state directory {:?}",
+                path
+            ),
+            Message::NothingToRetry => write!(
+                f,
+                "There's no journal from an earlier run to retry the failures of"
+            ),
+            Message::NotSplittable(path) => write!(
+                f,
+                "Only a file can be split into chunks, not {}",
+                path.display()
+            ),
+            Message::ManifestName { input, name } => write!(
+                f,
+                "{} can't have the destination {:?}, as that's where the manifest goes",
+                input.display(),
+                name
+            ),
+            Message::SharedDestination { input, name } => write!(
+                f,
+                "{} has the same destination as another input, {:?}, and they can't share it",
+                input.display(),
+                name
+            ),
+            Message::Unreadable { path, error } => {
+                write!(f, "Could not read {}: {}", path.display(), error)
+            }
+            Message::LeftOut(error) => write!(f, "{}, so it's left out", error),
+            Message::SourceEntryUnreadable(error) => write!(
+                f,
+                "Could not read an entry in the source directory: {}",
+                error
+            ),
+            Message::InputListUnreadable(error) => write!(
+                f,
+                "Could not read the list of inputs from standard input: {}",
+                error
+            ),
+            Message::InvalidManifest { path, problem } => {
+                write!(f, "Invalid manifest {}: {}", path.display(), problem)
+            }
+            Message::ManifestUnreadable { path, error } => write!(
+                f,
+                "Could not read the manifest {}: {}",
+                path.display(),
+                error
+            ),
+            Message::ManifestUnwritable(error) => {
+                write!(f, "Could not write the manifest: {}", error)
+            }
+            Message::JournalUnwritable(error) => {
+                write!(f, "Could not write to the run journal: {}", error)
+            }
+            Message::ChecksumsLine(number) => write!(
+                f,
+                "Line {} of {} can't be read",
+                number,
+                crate::checksums::CHECKSUMS_FILE
+            ),
+            Message::StateDirFailed { path, error } => write!(
+                f,
+                "Could not use the state directory {}: {}",
+                path.display(),
+                error
+            ),
+            Message::SpawnFailed { path, error } => write!(
+                f,
+                "Could not start the command for {}: {}",
+                path.display(),
+                error
+            ),
+            Message::OutputFailed { path, error } => {
+                write!(f, "Could not write to {}: {}", path.display(), error)
+            }
+            Message::Interruption => write!(f, "Interrupted"),
+            Message::PrefetchFailed { path, error } => {
+                write!(f, "Could not prefetch {}: {}", path.display(), error)
+            }
+            Message::HashFailed { input, error } => {
+                write!(f, "Could not hash {}: {}", input.display(), error)
+            }
+            Message::HashUnrecorded { input, error } => write!(
+                f,
+                "Could not record the hash of {}: {}",
+                input.display(),
+                error
+            ),
+            Message::AnnotationsUnreadable { dir, error } => write!(
+                f,
+                "Could not read the annotations in {}: {}",
+                dir.display(),
+                error
+            ),
+            Message::OutsideWindow { window, opens } => write!(
+                f,
+                "Outside the window of {}, so no more tasks will start until {}",
+                window, opens
+            ),
+            Message::LoadAverageUnreadable => write!(f, "Could not read the load average"),
+            Message::LocalTimeUnreadable => write!(f, "Could not read the local time"),
+            Message::TimedOut(timeout) => write!(f, "Command timed out after {:?}", timeout),
+            Message::HookFailed {
+                name,
+                status: Status::Exited(code),
+            } => write!(f, "The {} hook failed with exit code {}", name, code),
+            Message::HookFailed { name, status } => {
+                write!(f, "The {} hook failed with {}", name, status)
+            }
+            Message::LandlockUnavailable(error) => write!(
+                f,
+                "Commands can't be confined, as Landlock isn't available: {}",
+                error
+            ),
+            Message::ConfineFailed { path, error } => write!(
+                f,
+                "Could not confine commands to {}: {}",
+                path.display(),
+                error
+            ),
+            Message::CommandFinished => write!(f, "The command has already finished"),
+            Message::InputShrank => write!(f, "Input file shrank while it was being sent"),
+            Message::InputNotOneLine => {
+                write!(f, "Line framing needs every input to be a single line")
+            }
+            Message::CoprocessExited => {
+                write!(f, "Coprocess exited before it finished responding")
+            }
+            Message::CoprocessBadLength(header) => {
+                write!(f, "Coprocess sent a bad length: {:?}", header)
+            }
+            Message::SessionExited => write!(f, "Shell session exited unexpectedly"),
+            Message::SessionBadStatus(line) => {
+                write!(f, "Shell session sent a bad status: {:?}", line)
+            }
+            Message::NonUnicodeName(name) => write!(f, "Non-unicode filename: {:?}", name),
+            Message::CmdUnquotable(s) => write!(
+                f,
+                "Can't quote {:?} for cmd, because it contains '\"'",
+                s
+            ),
+            Message::DestinationFailing(error) => write!(
+                f,
+                "The destination's filesystem is failing ({}), so the run is paused until it's back",
+                error
+            ),
+            Message::DestinationBack => write!(
+                f,
+                "The destination's filesystem is back, so the run carries on"
+            ),
+            Message::PausedFrozen => write!(f, "Paused, with running tasks frozen"),
+            Message::PausedUnfrozen(why) => write!(
+                f,
+                "Paused, so no more tasks will start, but running ones carry on, as they can't be frozen: {}",
+                why
+            ),
+            Message::Resumed => write!(f, "Resumed"),
+            Message::FreezeFailed(error) => {
+                write!(f, "Could not freeze or thaw running tasks: {}", error)
+            }
+            Message::MetricsServingStopped(error) => {
+                write!(f, "Stopped serving metrics: {}", error)
+            }
+            Message::MetricsWritingStopped(error) => {
+                write!(f, "Stopped writing metrics: {}", error)
+            }
+            Message::ProgressWritingStopped(error) => {
+                write!(f, "Stopped writing progress: {}", error)
+            }
+            Message::InvocationUnrecorded(error) => {
+                write!(f, "Could not record how reach was run: {}", error)
+            }
+            Message::RerunUsage => write!(
+                f,
+                "Usage: reach rerun-matching [--state-dir DIR] DESTINATION PATTERN"
+            ),
+            Message::InvalidPattern(pattern) => write!(f, "Invalid pattern: {:?}", pattern),
+            Message::NoRunRecorded { destination } => write!(
+                f,
+                "There's no record of a run to rerun in {:?}. \
+                 Runs are recorded in their state directory when they start; \
+                 if it isn't in the destination, give it with --state-dir.",
+                destination
+            ),
+            Message::InvalidRunRecord(path) => write!(f, "Invalid record of a run: {:?}", path),
+            Message::RunDirGone { dir, error } => write!(
+                f,
+                "Could not return to {:?}, where the last run was: {}",
+                dir, error
+            ),
+            Message::RunArgsInvalid(problem) => {
+                write!(f, "Could not rerun the last run: {}", problem)
+            }
+            Message::RunFromStdin { destination } => write!(
+                f,
+                "The last run with destination {:?} read its inputs from standard input, so they can't be read again",
+                destination
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::SystemdScopeRunAs => write!(
+                f,
+                "Systemd scopes can't run as another user; give them User= and Group= properties instead"
+            ),
+            Refusal::RunAsRaisedPriority => write!(
+                f,
+                "Commands run as another user can't have a negative niceness, as they're no longer root by the time it's set"
+            ),
+            Refusal::SplitOutsideTasks => write!(
+                f,
+                "Chunks of a file can't be given to shell sessions, coprocesses, or workers"
+            ),
+            Refusal::ConfineOutsideTasks => write!(
+                f,
+                "Shell sessions, coprocesses, workers, and systemd scopes can't be confined to each task's files"
+            ),
+            Refusal::AffinityWithoutWorkers => {
+                write!(f, "Affinity needs coprocesses or shell sessions")
+            }
+            Refusal::CoprocessWrapped => {
+                write!(f, "Coprocesses can't be wrapped or run in shell sessions")
+            }
+            Refusal::CoprocessBatched => {
+                write!(f, "Coprocesses take one input at a time, so can't be sent batches")
+            }
+            Refusal::CoprocessClipped => write!(f, "Coprocesses' output can't be clipped"),
+            Refusal::CoprocessWorkdir => write!(
+                f,
+                "Coprocesses outlive their tasks, so can't run in a directory for each one"
+            ),
+            Refusal::CoprocessMerged => write!(f, "Coprocesses' output can't be merged or tagged"),
+            Refusal::WorkersInSessions => {
+                write!(f, "Workers can't run shell sessions or coprocesses")
+            }
+            Refusal::WorkersWorkdir => write!(
+                f,
+                "Workers run each task in a directory of their own, so can't use --workdir"
+            ),
+            Refusal::WorkersBatched => write!(f, "Workers can only be sent batches in stdin mode"),
+            Refusal::WorkersLimited => write!(
+                f,
+                "Workers can't limit their tasks' resources; give them a --wrap like 'nice -n10 {{cmd}}' instead"
+            ),
+            Refusal::WorkerCopies => {
+                write!(f, "Only one input at a time can be copied to a worker")
+            }
+            Refusal::LowMemoryOrdered => write!(
+                f,
+                "Inputs can't be ordered in low-memory mode, as that means holding them all at once"
+            ),
+            Refusal::LowMemoryWatched => write!(
+                f,
+                "Inputs can't be watched for in low-memory mode, as that means remembering every one"
+            ),
+            Refusal::LowMemorySources => write!(
+                f,
+                "There can only be one source in low-memory mode, as telling whether inputs' names clash means remembering every one"
+            ),
+            Refusal::LowMemoryNumbered => write!(
+                f,
+                "Inputs can't be numbered in low-memory mode, as the source directory is read twice, and could change in between"
+            ),
+            Refusal::StdinSources => {
+                write!(f, "Inputs read from standard input can't have other sources")
+            }
+            Refusal::StdinOrdered => {
+                write!(f, "Inputs read from standard input are processed in the order they're read")
+            }
+            Refusal::StdinWatched => write!(
+                f,
+                "Inputs read from standard input can't be watched for, as they're read until it ends"
+            ),
+            Refusal::StdinSplit => {
+                write!(f, "Only a source file can be split, not inputs read from standard input")
+            }
+            Refusal::StdinLowMemory => write!(
+                f,
+                "Inputs read from standard input can't be in low-memory mode, as telling whether their names clash means remembering every one"
+            ),
+            Refusal::ManifestStdin => {
+                write!(f, "Inputs can't be read from standard input and a manifest too")
+            }
+            Refusal::ManifestOrdered => {
+                write!(f, "Inputs from a manifest are processed in the order it lists them")
+            }
+            Refusal::ManifestWatched => {
+                write!(f, "Inputs from a manifest can't be watched for, as it lists them all")
+            }
+            Refusal::ManifestLowMemory => write!(
+                f,
+                "Inputs from a manifest can't be in low-memory mode, as it's read all at once"
+            ),
+            Refusal::SplitEmpty => write!(f, "A file can't be split into chunks of no bytes"),
+            Refusal::SplitBatched => write!(f, "Chunks of a file can't be batched"),
+            Refusal::SplitSources => {
+                write!(f, "Only one file can be split, so there can't be more sources")
+            }
+            Refusal::SplitOrdered => {
+                write!(f, "Chunks are always processed in the order they come in the file")
+            }
+            Refusal::SplitWatched => {
+                write!(f, "A file that's split can't be watched for more inputs")
+            }
+            Refusal::SplitLowMemory => write!(
+                f,
+                "A file's chunks are few enough to hold at once, so it's split without low-memory mode"
+            ),
+            Refusal::SplitHashed => write!(
+                f,
+                "Chunks' inputs can't be hashed, as each would mean reading the whole file"
+            ),
+            Refusal::SplitNamed => {
+                write!(f, "Chunks are always named for their number in the file")
+            }
+            Refusal::RandomNumbered => write!(
+                f,
+                "Inputs in a random order would be numbered differently every run, unless it's seeded"
+            ),
+            Refusal::StagesDiscarded => {
+                write!(f, "Each stage reads the output of the one before, so it can't be discarded")
+            }
+            Refusal::SharedOutputFile => write!(
+                f,
+                "Standard output and standard error need different files unless they're merged"
+            ),
+            Refusal::SessionsTagged => write!(f, "Output from shell sessions can't be tagged"),
+            Refusal::SessionsClipped => write!(f, "Output from shell sessions can't be clipped"),
+            Refusal::NoMemory => write!(f, "The memory limit has to be more than nothing"),
+            Refusal::NoProcesses => write!(f, "Tasks need at least one process"),
+            Refusal::NoCpus => write!(f, "Tasks need at least one CPU each"),
+        }
+    }
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unsupported::Coprocesses => write!(f, "Coprocesses are only supported on Unix"),
+            Unsupported::Workers => write!(f, "Workers are only supported on Unix"),
+            Unsupported::WorkersWithoutRemote => write!(f, "Workers need reach's remote feature"),
+            Unsupported::SystemdScopes => write!(f, "systemd scopes are only supported on Unix"),
+            Unsupported::ShellSessions => write!(f, "Shell sessions are only supported on Unix"),
+            Unsupported::Limits => write!(
+                f,
+                "Niceness, memory, file descriptor, and process limits are only supported on Unix"
+            ),
+            Unsupported::CpusPerTask => {
+                write!(
+                    f,
+                    "Giving tasks CPUs of their own is only supported on Linux"
+                )
+            }
+            Unsupported::Confinement => write!(f, "Commands can only be confined on Linux"),
+            Unsupported::LoadAverage => {
+                write!(f, "Limiting the load average is only supported on Unix")
+            }
+            Unsupported::Windows => {
+                write!(
+                    f,
+                    "Windows of time to run tasks in are only supported on Unix"
+                )
+            }
+            Unsupported::PauseSignals => write!(f, "Pausing on signals is only supported on Unix"),
+            Unsupported::Freezer => write!(f, "There's no cgroup freezer to use"),
+            Unsupported::FreezeOnWorkers => write!(f, "tasks on workers run on other machines"),
+            Unsupported::FreezeInScopes => {
+                write!(f, "tasks in systemd scopes leave reach's cgroup")
+            }
+        }
+    }
+}
+
+impl From<Refusal> for Message<'_> {
+    fn from(refusal: Refusal) -> Self {
+        Message::Refused(refusal)
+    }
+}
+
+impl From<Unsupported> for Message<'_> {
+    fn from(unsupported: Unsupported) -> Self {
+        Message::Unsupported(unsupported)
+    }
+}
+
+/// The wording of everything reach says to people.
+///
+/// Only `text` needs implementing, and it can leave any message it doesn't reword to
+/// `Display`, which says it in English.
+pub trait Messages: Send + Sync {
+    /// What to say for `message`. Several lines, for some messages, but with no newline at
+    /// the end unless the message has one in English.
+    fn text(&self, message: &Message<'_>) -> String {
+        message.to_string()
+    }
+}
+
+/// reach's own English.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct English;
+
+impl Messages for English {}
+
+/// The messages installed now.
+fn installed() -> &'static RwLock<Arc<dyn Messages>> {
+    static INSTALLED: OnceLock<RwLock<Arc<dyn Messages>>> = OnceLock::new();
+    INSTALLED.get_or_init(|| RwLock::new(Arc::new(English)))
+}
+
+/// Say everything with `messages` from now on, rather than in reach's own English.
+///
+/// It's for the whole program, as messages can come from anywhere in it.
+pub fn set_messages(messages: impl Messages + 'static) {
+    *installed().write().unwrap() = Arc::new(messages);
+}
+
+impl Message<'_> {
+    /// What to say, in the words of the messages installed by `set_messages`, if any.
+    pub fn text(&self) -> String {
+        let messages = installed().read().unwrap().clone();
+        messages.text(self)
+    }
+
+    /// A name for the message that never changes, like `hash_failed`, for other programs to
+    /// tell messages apart by, rather than by their wording.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Message::Warning(..) => "warning",
+            Message::Error(..) => "error",
+            Message::Summary(..) => "summary",
+            Message::Verification(..) => "verification",
+            Message::Interrupted => "interrupted",
+            Message::OverOutput { .. } => "over_output",
+            Message::DashboardProgress { .. } => "dashboard_progress",
+            Message::DashboardRunning => "dashboard_running",
+            Message::DashboardMore { .. } => "dashboard_more",
+            Message::DashboardRecent => "dashboard_recent",
+            Message::DashboardOutput { .. } => "dashboard_output",
+            Message::DashboardKeys { .. } => "dashboard_keys",
+            Message::DashboardFinished { .. } => "dashboard_finished",
+            Message::Attempt(..) => "attempt",
+            Message::ExitCode(..) => "exit_code",
+            Message::SupportBundle(..) => "support_bundle",
+            Message::SupportBundleUnwritten(..) => "support_bundle_unwritten",
+            Message::Refused(..) => "refused",
+            Message::Unsupported(..) => "unsupported",
+            Message::CommandChanged { .. } => "command_changed",
+            Message::CommandChangeRefused { .. } => "command_change_refused",
+            Message::SourceInsideDestination { .. } => "source_inside_destination",
+            Message::DestinationInsideSource { .. } => "destination_inside_source",
+            Message::WorkersShell(..) => "workers_shell",
+            Message::SessionsShell(..) => "sessions_shell",
+            Message::OutputName(..) => "output_name",
+            Message::Niceness(..) => "niceness",
+            Message::TooFewFds { .. } => "too_few_fds",
+            Message::TooManyCpus { .. } => "too_many_cpus",
+            Message::InvalidRate(..) => "invalid_rate",
+            Message::RunAsNotRoot(..) => "run_as_not_root",
+            Message::InvalidSourceDir { .. } => "invalid_source_dir",
+            Message::InvalidSource { .. } => "invalid_source",
+            Message::NoDefaultDestination(..) => "no_default_destination",
+            Message::DestinationUncreatable { .. } => "destination_uncreatable",
+            Message::InvalidDestination { .. } => "invalid_destination",
+            Message::ConfigFileUnreadable { .. } => "config_file_unreadable",
+            Message::InvalidConfigFile { .. } => "invalid_config_file",
+            Message::StateDirLocked(..) => "state_dir_locked",
+            Message::NothingToRetry => "nothing_to_retry",
+            Message::NotSplittable(..) => "not_splittable",
+            Message::ManifestName { .. } => "manifest_name",
+            Message::SharedDestination { .. } => "shared_destination",
+            Message::Unreadable { .. } => "unreadable",
+            Message::LeftOut(..) => "left_out",
+            Message::SourceEntryUnreadable(..) => "source_entry_unreadable",
+            Message::InputListUnreadable(..) => "input_list_unreadable",
+            Message::InvalidManifest { .. } => "invalid_manifest",
+            Message::ManifestUnreadable { .. } => "manifest_unreadable",
+            Message::ManifestUnwritable(..) => "manifest_unwritable",
+            Message::JournalUnwritable(..) => "journal_unwritable",
+            Message::ChecksumsLine(..) => "checksums_line",
+            Message::StateDirFailed { .. } => "state_dir_failed",
+            Message::SpawnFailed { .. } => "spawn_failed",
+            Message::OutputFailed { .. } => "output_failed",
+            Message::Interruption => "interruption",
+            Message::PrefetchFailed { .. } => "prefetch_failed",
+            Message::HashFailed { .. } => "hash_failed",
+            Message::HashUnrecorded { .. } => "hash_unrecorded",
+            Message::AnnotationsUnreadable { .. } => "annotations_unreadable",
+            Message::OutsideWindow { .. } => "outside_window",
+            Message::LoadAverageUnreadable => "load_average_unreadable",
+            Message::LocalTimeUnreadable => "local_time_unreadable",
+            Message::TimedOut(..) => "timed_out",
+            Message::HookFailed { .. } => "hook_failed",
+            Message::LandlockUnavailable(..) => "landlock_unavailable",
+            Message::ConfineFailed { .. } => "confine_failed",
+            Message::CommandFinished => "command_finished",
+            Message::InputShrank => "input_shrank",
+            Message::InputNotOneLine => "input_not_one_line",
+            Message::CoprocessExited => "coprocess_exited",
+            Message::CoprocessBadLength(..) => "coprocess_bad_length",
+            Message::SessionExited => "session_exited",
+            Message::SessionBadStatus(..) => "session_bad_status",
+            Message::NonUnicodeName(..) => "non_unicode_name",
+            Message::CmdUnquotable(..) => "cmd_unquotable",
+            Message::DestinationFailing(..) => "destination_failing",
+            Message::DestinationBack => "destination_back",
+            Message::PausedFrozen => "paused_frozen",
+            Message::PausedUnfrozen(..) => "paused_unfrozen",
+            Message::Resumed => "resumed",
+            Message::FreezeFailed(..) => "freeze_failed",
+            Message::MetricsServingStopped(..) => "metrics_serving_stopped",
+            Message::MetricsWritingStopped(..) => "metrics_writing_stopped",
+            Message::ProgressWritingStopped(..) => "progress_writing_stopped",
+            Message::InvocationUnrecorded(..) => "invocation_unrecorded",
+            Message::RerunUsage => "rerun_usage",
+            Message::InvalidPattern(..) => "invalid_pattern",
+            Message::NoRunRecorded { .. } => "no_run_recorded",
+            Message::InvalidRunRecord(..) => "invalid_run_record",
+            Message::RunDirGone { .. } => "run_dir_gone",
+            Message::RunArgsInvalid(..) => "run_args_invalid",
+            Message::RunFromStdin { .. } => "run_from_stdin",
+        }
+    }
+
+    /// What the message is about, as JSON for other programs: its paths, numbers, and
+    /// errors, but none of its wording. Errors are described by `error_json`.
+    pub fn fields(&self) -> Value {
+        match self {
+            Message::Warning(text) => json!({"text": text}),
+            Message::Error(error) => json!({"error": error_json(error)}),
+            Message::Summary(summary) => json!({
+                "succeeded": summary.succeeded,
+                "failed": summary.failed,
+                "skipped": summary.skipped,
+                "retried": summary.retried,
+                "duration_secs": summary.duration.as_secs_f64(),
+            }),
+            Message::Verification(verification) => json!({
+                "tasks": verification.tasks,
+                "checksummed": verification.checksummed,
+                "discrepancies": verification.discrepancies.len(),
+            }),
+            Message::OverOutput { bytes } => json!({"bytes": bytes}),
+            Message::DashboardProgress {
+                done,
+                tasks,
+                failed,
+                running,
+                per_sec,
+                elapsed,
+            } => json!({
+                "done": done,
+                "tasks": tasks,
+                "failed": failed,
+                "running": running,
+                "per_sec": per_sec,
+                "elapsed": elapsed,
+            }),
+            Message::DashboardMore { more } => json!({"more": more}),
+            Message::DashboardOutput { name, input } => {
+                json!({"name": name, "input": path(input)})
+            }
+            Message::DashboardKeys { output_kept } => json!({"output_kept": output_kept}),
+            Message::DashboardFinished { failed } => json!({"failed": failed}),
+            Message::Attempt(attempt) => json!({"attempt": attempt}),
+            Message::ExitCode(code) => json!({"exit_code": code}),
+            Message::SupportBundle(bundle) => json!({"path": path(bundle)}),
+            Message::Refused(refusal) => json!({"refusal": refusal.code()}),
+            Message::Unsupported(unsupported) => json!({"unsupported": unsupported.code()}),
+            Message::CommandChanged { changes } => json!({"changes": changes}),
+            Message::CommandChangeRefused {
+                destination,
+                changes,
+            } => json!({"destination": path(destination), "changes": changes}),
+            Message::SourceInsideDestination {
+                source,
+                destination,
+            }
+            | Message::DestinationInsideSource {
+                destination,
+                source,
+            } => json!({"source": path(source), "destination": path(destination)}),
+            Message::WorkersShell(shell) | Message::SessionsShell(shell) => {
+                json!({"shell": shell})
+            }
+            Message::OutputName(name) => json!({"name": name}),
+            Message::Niceness(nice) => json!({"nice": nice}),
+            Message::TooFewFds { min } => json!({"min": min}),
+            Message::TooManyCpus {
+                per_task,
+                available,
+            } => json!({"per_task": per_task, "available": available}),
+            Message::InvalidRate(rate) => json!({"rate": rate}),
+            Message::RunAsNotRoot(run_as) => json!({"uid": run_as.uid, "gid": run_as.gid}),
+            Message::InvalidSourceDir { path: at, error }
+            | Message::InvalidSource { path: at, error }
+            | Message::DestinationUncreatable { path: at, error }
+            | Message::InvalidDestination { path: at, error }
+            | Message::ConfigFileUnreadable { path: at, error }
+            | Message::Unreadable { path: at, error }
+            | Message::ManifestUnreadable { path: at, error }
+            | Message::StateDirFailed { path: at, error }
+            | Message::SpawnFailed { path: at, error }
+            | Message::OutputFailed { path: at, error }
+            | Message::PrefetchFailed { path: at, error }
+            | Message::HashFailed { input: at, error }
+            | Message::HashUnrecorded { input: at, error }
+            | Message::AnnotationsUnreadable { dir: at, error }
+            | Message::ConfineFailed { path: at, error }
+            | Message::RunDirGone { dir: at, error } => {
+                json!({"path": path(at), "error": error_json(error)})
+            }
+            Message::InvalidConfigFile { path: at, problem }
+            | Message::InvalidManifest { path: at, problem } => {
+                json!({"path": path(at), "problem": problem})
+            }
+            Message::NoDefaultDestination(at)
+            | Message::StateDirLocked(at)
+            | Message::NotSplittable(at)
+            | Message::InvalidRunRecord(at) => json!({"path": path(at)}),
+            Message::NoRunRecorded { destination } | Message::RunFromStdin { destination } => {
+                json!({"destination": path(destination)})
+            }
+            Message::InvalidPattern(pattern) => json!({"pattern": pattern.to_string_lossy()}),
+            Message::RunArgsInvalid(problem) => json!({"problem": problem}),
+            Message::ManifestName { input, name } | Message::SharedDestination { input, name } => {
+                json!({"input": path(input), "name": name.to_string_lossy()})
+            }
+            Message::SupportBundleUnwritten(error)
+            | Message::LeftOut(error)
+            | Message::SourceEntryUnreadable(error)
+            | Message::InputListUnreadable(error)
+            | Message::ManifestUnwritable(error)
+            | Message::JournalUnwritable(error)
+            | Message::LandlockUnavailable(error)
+            | Message::DestinationFailing(error)
+            | Message::PausedUnfrozen(error)
+            | Message::FreezeFailed(error)
+            | Message::MetricsServingStopped(error)
+            | Message::MetricsWritingStopped(error)
+            | Message::ProgressWritingStopped(error)
+            | Message::InvocationUnrecorded(error) => json!({"error": error_json(error)}),
+            Message::ChecksumsLine(line) => json!({"line": line}),
+            Message::OutsideWindow { window, opens } => json!({"window": window, "opens": opens}),
+            Message::TimedOut(timeout) => json!({"timeout_secs": timeout.as_secs_f64()}),
+            Message::HookFailed { name, status } => json!({
+                "name": name,
+                "exit_code": status.exit_code(),
+                "signal": status.signal_name(),
+            }),
+            Message::CoprocessBadLength(sent) | Message::SessionBadStatus(sent) => {
+                json!({"sent": sent})
+            }
+            Message::NonUnicodeName(name) => json!({"name": name.to_string_lossy()}),
+            Message::CmdUnquotable(s) => json!({"string": s}),
+            Message::Interrupted
+            | Message::DashboardRunning
+            | Message::DashboardRecent
+            | Message::NothingToRetry
+            | Message::Interruption
+            | Message::LoadAverageUnreadable
+            | Message::LocalTimeUnreadable
+            | Message::CommandFinished
+            | Message::InputShrank
+            | Message::InputNotOneLine
+            | Message::CoprocessExited
+            | Message::SessionExited
+            | Message::DestinationBack
+            | Message::PausedFrozen
+            | Message::Resumed
+            | Message::RerunUsage => json!({}),
+        }
+    }
+}
+
+impl Refusal {
+    /// A name for the refusal that never changes, like `no_cpus`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Refusal::SystemdScopeRunAs => "systemd_scope_run_as",
+            Refusal::RunAsRaisedPriority => "run_as_raised_priority",
+            Refusal::SplitOutsideTasks => "split_outside_tasks",
+            Refusal::ConfineOutsideTasks => "confine_outside_tasks",
+            Refusal::AffinityWithoutWorkers => "affinity_without_workers",
+            Refusal::CoprocessWrapped => "coprocess_wrapped",
+            Refusal::CoprocessBatched => "coprocess_batched",
+            Refusal::CoprocessClipped => "coprocess_clipped",
+            Refusal::CoprocessWorkdir => "coprocess_workdir",
+            Refusal::CoprocessMerged => "coprocess_merged",
+            Refusal::WorkersInSessions => "workers_in_sessions",
+            Refusal::WorkersWorkdir => "workers_workdir",
+            Refusal::WorkersBatched => "workers_batched",
+            Refusal::WorkersLimited => "workers_limited",
+            Refusal::WorkerCopies => "worker_copies",
+            Refusal::LowMemoryOrdered => "low_memory_ordered",
+            Refusal::LowMemoryWatched => "low_memory_watched",
+            Refusal::LowMemorySources => "low_memory_sources",
+            Refusal::LowMemoryNumbered => "low_memory_numbered",
+            Refusal::StdinSources => "stdin_sources",
+            Refusal::StdinOrdered => "stdin_ordered",
+            Refusal::StdinWatched => "stdin_watched",
+            Refusal::StdinSplit => "stdin_split",
+            Refusal::StdinLowMemory => "stdin_low_memory",
+            Refusal::ManifestStdin => "manifest_stdin",
+            Refusal::ManifestOrdered => "manifest_ordered",
+            Refusal::ManifestWatched => "manifest_watched",
+            Refusal::ManifestLowMemory => "manifest_low_memory",
+            Refusal::SplitEmpty => "split_empty",
+            Refusal::SplitBatched => "split_batched",
+            Refusal::SplitSources => "split_sources",
+            Refusal::SplitOrdered => "split_ordered",
+            Refusal::SplitWatched => "split_watched",
+            Refusal::SplitLowMemory => "split_low_memory",
+            Refusal::SplitHashed => "split_hashed",
+            Refusal::SplitNamed => "split_named",
+            Refusal::RandomNumbered => "random_numbered",
+            Refusal::StagesDiscarded => "stages_discarded",
+            Refusal::SharedOutputFile => "shared_output_file",
+            Refusal::SessionsTagged => "sessions_tagged",
+            Refusal::SessionsClipped => "sessions_clipped",
+            Refusal::NoMemory => "no_memory",
+            Refusal::NoProcesses => "no_processes",
+            Refusal::NoCpus => "no_cpus",
+        }
+    }
+}
+
+impl Unsupported {
+    /// A name for what's unsupported that never changes, like `freezer`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Unsupported::Coprocesses => "coprocesses",
+            Unsupported::Workers => "workers",
+            Unsupported::WorkersWithoutRemote => "workers_without_remote",
+            Unsupported::SystemdScopes => "systemd_scopes",
+            Unsupported::ShellSessions => "shell_sessions",
+            Unsupported::Limits => "limits",
+            Unsupported::CpusPerTask => "cpus_per_task",
+            Unsupported::Confinement => "confinement",
+            Unsupported::LoadAverage => "load_average",
+            Unsupported::Windows => "windows",
+            Unsupported::PauseSignals => "pause_signals",
+            Unsupported::Freezer => "freezer",
+            Unsupported::FreezeOnWorkers => "freeze_on_workers",
+            Unsupported::FreezeInScopes => "freeze_in_scopes",
+        }
+    }
+}
+
+fn path(path: &Path) -> Value {
+    json!(path.to_string_lossy())
+}
+
+/// What JSON says about `error`, for other programs: its kind, like `not_found`, the operating
+/// system's number for it, if it has one, and what reach was doing, and with which path, if
+/// it's one of reach's own. Never its wording.
+pub(crate) fn error_json(error: &io::Error) -> Value {
+    let reach = crate::Error::of(error);
+    let source = reach.and_then(crate::Error::io).unwrap_or(error);
+    json!({
+        "kind": snake_case(&format!("{:?}", source.kind())),
+        "os_error": source.raw_os_error(),
+        "phase": reach.map(crate::Error::phase),
+        "path": reach.and_then(crate::Error::path).map(path),
+    })
+}
+
+/// `name`, like `NotFound`, in snake case, like `not_found`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.char_indices() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Shouty;
+
+    impl Messages for Shouty {
+        fn text(&self, message: &Message<'_>) -> String {
+            match message {
+                Message::Warning(message) => format!("WARNING! {}", message.to_uppercase()),
+                Message::Refused(Refusal::NoCpus) => "TASKS NEED CPUS!".into(),
+                message => message.to_string(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(
+            "Warning: It's late",
+            English.text(&Message::Warning("It's late"))
+        );
+        assert_eq!(
+            "Error: gone",
+            English.text(&Message::Error(&io::Error::other("gone")))
+        );
+        assert_eq!(
+            "WARNING! IT'S LATE",
+            Shouty.text(&Message::Warning("It's late"))
+        );
+        assert_eq!("Running:", Shouty.text(&Message::DashboardRunning));
+        assert_eq!("TASKS NEED CPUS!", Shouty.text(&Refusal::NoCpus.into()));
+        assert_eq!(
+            "Tasks need at least one process",
+            Shouty.text(&Refusal::NoProcesses.into())
+        );
+        assert_eq!(
+            "Could not hash /src/a: gone",
+            English.text(&Message::HashFailed {
+                input: Path::new("/src/a"),
+                error: &io::Error::other("gone"),
+            })
+        );
+        assert_eq!(
+            "The destination directory /src/out is inside the source /src; allow it explicitly if that's intended",
+            English.text(&Message::DestinationInsideSource {
+                destination: Path::new("/src/out"),
+                source: Path::new("/src"),
+            })
+        );
+        assert_eq!(
+            "The after-all hook failed with exit code 3",
+            English.text(&Message::HookFailed {
+                name: "after-all",
+                status: &Status::Exited(3),
+            })
+        );
+        assert_eq!(
+            "There's no cgroup freezer to use",
+            English.text(&Unsupported::Freezer.into())
+        );
+        assert_eq!(
+            "3/10 done, 1 failed, 2 running, 0.5/s, 0:06 elapsed",
+            English.text(&Message::DashboardProgress {
+                done: 3,
+                tasks: 10,
+                failed: 1,
+                running: 2,
+                per_sec: 0.5,
+                elapsed: "0:06",
+            })
+        );
+    }
+
+    /// Other programs get a code and fields, with no wording, which installed messages can't
+    /// change either.
+    #[test]
+    fn test_code_and_fields() {
+        let error = io::Error::from_raw_os_error(2);
+        let message = Message::HashFailed {
+            input: Path::new("/src/a"),
+            error: &error,
+        };
+        assert_eq!("hash_failed", message.code());
+        assert_eq!(
+            json!({
+                "path": "/src/a",
+                "error": {"kind": "not_found", "os_error": 2, "phase": null, "path": null},
+            }),
+            message.fields()
+        );
+        let error: io::Error = crate::Error::Output {
+            path: "/dest/a".into(),
+            source: io::Error::new(io::ErrorKind::StorageFull, "full"),
+        }
+        .into();
+        assert_eq!(
+            json!({
+                "kind": "storage_full",
+                "os_error": null,
+                "phase": "output",
+                "path": "/dest/a",
+            }),
+            error_json(&error)
+        );
+        let refused = Message::from(Refusal::NoCpus);
+        assert_eq!("refused", refused.code());
+        assert_eq!(json!({"refusal": "no_cpus"}), refused.fields());
+        assert_eq!(
+            json!({"changes": ["command"]}),
+            Message::CommandChanged {
+                changes: &["command".to_owned()]
+            }
+            .fields()
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Metrics about a run in the OpenMetrics text format, for monitoring systems like Prometheus,
+//! served over HTTP or kept written to a file.
+
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::{Message, Progress, TaskId, TaskOutcome};
+
+/// The upper bounds of the task duration histogram's buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[0.1, 1.0, 10.0, 60.0, 600.0, 3600.0];
+
+/// Counts of tasks by how they've gone so far, updated from progress events.
+///
+/// Clones share the same counts, so one can be given to a run as part of its progress,
+/// as in `(progress, metrics.clone())`, while another serves or writes them.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    counts: Arc<Mutex<Counts>>,
+    changed: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    started: u64,
+    succeeded: u64,
+    failed: u64,
+    skipped: u64,
+    retries: u64,
+    /// How many finished tasks took no longer than each of `DURATION_BUCKETS`.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    duration: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Counts)) {
+        update(&mut self.counts.lock().unwrap());
+        self.changed.notify_one();
+    }
+
+    /// The metrics as an OpenMetrics text exposition.
+    pub fn render(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        let finished = counts.succeeded + counts.failed;
+        let mut text = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = write!(
+                text,
+                "# TYPE {0} counter\n# HELP {0} {1}\n{0}_total {2}\n",
+                name, help, value
+            );
+        };
+        counter("reach_tasks_started", "Tasks started.", counts.started);
+        counter(
+            "reach_tasks_succeeded",
+            "Tasks that succeeded.",
+            counts.succeeded,
+        );
+        counter("reach_tasks_failed", "Tasks that failed.", counts.failed);
+        counter(
+            "reach_tasks_skipped",
+            "Tasks left out as they succeeded in an earlier run.",
+            counts.skipped,
+        );
+        counter("reach_task_retries", "Attempts made again.", counts.retries);
+        let _ = write!(
+            text,
+            "# TYPE reach_tasks_in_flight gauge\n\
+             # HELP reach_tasks_in_flight Tasks started but not yet finished.\n\
+             reach_tasks_in_flight {}\n",
+            counts.started.saturating_sub(finished)
+        );
+        text.push_str(
+            "# TYPE reach_task_duration_seconds histogram\n\
+             # UNIT reach_task_duration_seconds seconds\n\
+             # HELP reach_task_duration_seconds How long finished tasks took, including retries.\n",
+        );
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&counts.buckets) {
+            let _ = writeln!(
+                text,
+                "reach_task_duration_seconds_bucket{{le=\"{:?}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = write!(
+            text,
+            "reach_task_duration_seconds_bucket{{le=\"+Inf\"}} {0}\n\
+             reach_task_duration_seconds_sum {1}\n\
+             reach_task_duration_seconds_count {0}\n\
+             # EOF\n",
+            finished,
+            counts.duration.as_secs_f64()
+        );
+        text
+    }
+
+    /// Write the metrics to `path`, replacing it atomically, so nothing reading it ever sees
+    /// half of them.
+    pub async fn write(&self, path: &Path) -> io::Result<()> {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        fs::write(&temp_path, self.render()).await?;
+        fs::rename(&temp_path, path).await
+    }
+
+    /// Write the metrics to `path` now, and again whenever they change, until writing fails.
+    ///
+    /// Changes made while the file is being written are all picked up by the next write.
+    pub async fn keep_written(&self, path: &Path) -> io::Result<()> {
+        loop {
+            self.write(path).await?;
+            self.changed.notified().await;
+        }
+    }
+
+    /// Answer every HTTP request that comes to `listener` with the metrics, whatever its path.
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (connection, _) = listener.accept().await?;
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                // A scraper that goes away mid-request only loses that request.
+                let _ = metrics.respond(connection).await;
+            });
+        }
+    }
+
+    async fn respond(&self, mut connection: TcpStream) -> io::Result<()> {
+        // Scrapers send a short GET with no body, so there's no need to parse more than the
+        // end of its headers, and anything longer than this isn't one.
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < 8192 {
+            let read = connection.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+        let body = self.render();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        connection.write_all(response.as_bytes()).await?;
+        connection.shutdown().await
+    }
+}
+
+impl Progress for Metrics {
+    fn set_num_tasks(&self, _tasks: usize) {}
+
+    fn task_skipped(&self, _id: &TaskId, _input: &Path) {
+        self.update(|counts| counts.skipped += 1);
+    }
+
+    fn task_started(&self, _id: &TaskId, _input: &Path) {
+        self.update(|counts| counts.started += 1);
+    }
+
+    fn task_retrying(
+        &self,
+        _id: &TaskId,
+        _input: &Path,
+        _attempt: u32,
+        _result: &io::Result<std::process::ExitStatus>,
+    ) {
+        self.update(|counts| counts.retries += 1);
+    }
+
+    fn task_completed(&self, _id: &TaskId, outcome: &TaskOutcome<'_>) {
+        let duration = outcome.duration();
+        self.update(|counts| {
+            if outcome.task.succeeded() {
+                counts.succeeded += 1;
+            } else {
+                counts.failed += 1;
+            }
+            counts.duration += duration;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(&mut counts.buckets) {
+                if duration.as_secs_f64() <= *bound {
+                    *count += 1;
+                }
+            }
+        });
+    }
+
+    /// Warnings aren't metrics, so they're left to whatever else reports progress.
+    fn warn(&self, _warning: &Message<'_>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.update(|counts| {
+            counts.started = 3;
+            counts.succeeded = 1;
+            counts.failed = 1;
+            counts.buckets = [0, 1, 2, 2, 2, 2];
+            counts.duration = Duration::from_millis(5500);
+        });
+        let text = metrics.render();
+        assert!(text.contains("\nreach_tasks_started_total 3\n"));
+        assert!(text.contains("\nreach_tasks_failed_total 1\n"));
+        assert!(text.contains("\nreach_tasks_in_flight 1\n"));
+        assert!(text.contains("\nreach_task_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(text.contains("\nreach_task_duration_seconds_bucket{le=\"10.0\"} 2\n"));
+        assert!(text.contains("\nreach_task_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("\nreach_task_duration_seconds_sum 5.5\n"));
+        assert!(text.ends_with("\n# EOF\n"));
+    }
+}
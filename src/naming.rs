@@ -0,0 +1,364 @@
+//! What each input's destination directory is called, and the manifest that says which
+//! directory belongs to which input.
+
+use serde_json::json;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error;
+use crate::journal::Recipe;
+use crate::task_id::Fnv1a;
+use crate::{Chunk, Message, TaskId};
+
+/// The name of the manifest in the destination directory.
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+/// How to name each input's destination directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Naming {
+    /// The input's file name, which inputs from different directories can share.
+    #[default]
+    Name,
+    /// The input's path, relative to the current directory if it's inside it,
+    /// so the destination has the same tree as the sources.
+    Mirror,
+    /// A hash of the input's absolute path, as 16 hex digits.
+    Hash,
+    /// The input's number, counting from 0 in the order the inputs are found,
+    /// so they're only numbered the same way every run if they're ordered.
+    Index,
+}
+
+impl Naming {
+    /// The name of the naming, as `from_str` accepts it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Naming::Name => "name",
+            Naming::Mirror => "mirror",
+            Naming::Hash => "hash",
+            Naming::Index => "index",
+        }
+    }
+
+    /// The name of the destination directory for the input at `path`,
+    /// the `index`th input found.
+    pub(crate) fn destination(&self, path: &Path, index: usize) -> io::Result<OsString> {
+        Ok(match self {
+            Naming::Name => path.file_name().unwrap_or_default().to_owned(),
+            Naming::Mirror => {
+                let here = std::env::current_dir()?;
+                let path = absolute(&here, path);
+                let relative = path.strip_prefix(&here).unwrap_or(&path);
+                // Only plain names, so that the directory is always inside the destination.
+                let mirrored: PathBuf = relative
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect();
+                mirrored.into_os_string()
+            }
+            Naming::Hash => {
+                let path = absolute(&std::env::current_dir()?, path);
+                let mut hash = Fnv1a::default();
+                hash.write(path.to_string_lossy().as_bytes());
+                hash.hex().into()
+            }
+            Naming::Index => format!("{:06}", index).into(),
+        })
+    }
+}
+
+impl FromStr for Naming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "name" => Ok(Naming::Name),
+            "mirror" => Ok(Naming::Mirror),
+            "hash" => Ok(Naming::Hash),
+            "index" => Ok(Naming::Index),
+            _ => Err(format!("No such Naming: {}", s)),
+        }
+    }
+}
+
+/// `path` made absolute, if it isn't already, with any `.` in it left out.
+fn absolute(here: &Path, path: &Path) -> PathBuf {
+    here.join(path).components().collect()
+}
+
+/// How the list of inputs in a manifest ends, and the manifest with it.
+const END: &[u8] = b"\n]}\n";
+
+/// The manifest being written for the current run: a JSON file in the destination directory
+/// with the run's recipe and its fingerprint, and every input the run found, with the
+/// directory its results are in, relative to the destination directory.
+///
+/// It's written to a temporary file as inputs are found, so that it never has to hold them
+/// all, and only replaces the manifest once the run has finished, or when it's snapshotted.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: BufWriter<File>,
+    entries: usize,
+    /// The first error writing the manifest, which stops any more being written.
+    error: Option<io::Error>,
+}
+
+impl Manifest {
+    /// Start a manifest for a run with `recipe` whose results go in `destination_dir`.
+    pub(crate) fn create(destination_dir: &Path, recipe: &Recipe) -> io::Result<Self> {
+        fs::create_dir_all(destination_dir)?;
+        let path = destination_dir.join(MANIFEST_FILE);
+        let temp_path = destination_dir.join(format!(".{}.tmp", MANIFEST_FILE));
+        let mut file = BufWriter::new(File::create(&temp_path)?);
+        write!(
+            file,
+            "{{\"recipe\": {},\n\"fingerprint\": {},\n\"inputs\": [",
+            recipe.to_json(),
+            json!(recipe.fingerprint())
+        )?;
+        Ok(Manifest {
+            path,
+            temp_path,
+            file,
+            entries: 0,
+            error: None,
+        })
+    }
+
+    /// Note that the results of the task `id`, for `input`, or only `chunk` of it,
+    /// are in the directory called `name`.
+    pub(crate) fn add(&mut self, id: &TaskId, input: &Path, chunk: Option<Chunk>, name: &OsStr) {
+        if self.error.is_some() {
+            return;
+        }
+        let entry = json!({
+            "id": id.as_str(),
+            "input": input.to_string_lossy(),
+            "offset": chunk.map(|chunk| chunk.offset),
+            "length": chunk.map(|chunk| chunk.length),
+            "destination": name.to_string_lossy(),
+        });
+        let separator = if self.entries == 0 { "" } else { "," };
+        self.entries += 1;
+        if let Err(error) = write!(self.file, "{}\n  {}", separator, entry) {
+            self.error = Some(error);
+        }
+    }
+
+    /// Replace the manifest from the last run, if there was one, with the inputs noted so far,
+    /// so that they're known even if the run never finishes. The manifest goes on being written.
+    pub(crate) fn snapshot(&mut self) -> io::Result<()> {
+        if let Some(error) = &self.error {
+            return Err(io::Error::new(error.kind(), error.to_string()));
+        }
+        self.file.flush()?;
+        let mut contents = fs::read(&self.temp_path)?;
+        contents.extend_from_slice(END);
+        let snapshot_path = self.temp_path.with_extension("snapshot.tmp");
+        fs::write(&snapshot_path, contents)?;
+        fs::rename(&snapshot_path, &self.path)
+    }
+
+    /// Finish the manifest, replacing the one from the last run, if there was one.
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.file.write_all(END)?;
+        self.file.flush()?;
+        fs::rename(&self.temp_path, &self.path)
+    }
+
+    /// The inputs listed in the manifest `run`, or in the one in the destination directory
+    /// `run`, in order, each with its chunk, if it was split, and its destination directory.
+    pub(crate) fn inputs(run: &Path) -> io::Result<Vec<(PathBuf, Option<Chunk>, OsString)>> {
+        let path = if run.is_dir() {
+            run.join(MANIFEST_FILE)
+        } else {
+            run.to_owned()
+        };
+        let invalid = |problem: String| {
+            error::new(
+                io::ErrorKind::InvalidData,
+                Message::InvalidManifest {
+                    path: &path,
+                    problem: &problem,
+                },
+            )
+        };
+        let contents = fs::read_to_string(&path).map_err(|error| {
+            error::new(
+                error.kind(),
+                Message::ManifestUnreadable {
+                    path: &path,
+                    error: &error,
+                },
+            )
+        })?;
+        let manifest: serde_json::Value =
+            serde_json::from_str(&contents).map_err(|error| invalid(error.to_string()))?;
+        let entries = manifest["inputs"]
+            .as_array()
+            .ok_or_else(|| invalid("it lists no inputs".into()))?;
+        entries
+            .iter()
+            .enumerate()
+            .map(|(number, entry)| {
+                let invalid = |what: &str| invalid(format!("input {} {}", number + 1, what));
+                let input = entry["input"]
+                    .as_str()
+                    .ok_or_else(|| invalid("has no path"))?;
+                let chunk = match (&entry["offset"], &entry["length"]) {
+                    (serde_json::Value::Null, serde_json::Value::Null) => None,
+                    (offset, length) => Some(Chunk {
+                        offset: offset.as_u64().ok_or_else(|| invalid("has a bad offset"))?,
+                        length: length.as_u64().ok_or_else(|| invalid("has a bad length"))?,
+                    }),
+                };
+                let destination = entry["destination"]
+                    .as_str()
+                    .map(Path::new)
+                    // Only plain names, so that the directory is always inside the destination.
+                    .filter(|name| {
+                        name.components().next().is_some()
+                            && name
+                                .components()
+                                .all(|component| matches!(component, Component::Normal(_)))
+                    })
+                    .ok_or_else(|| invalid("has a bad destination"))?;
+                Ok((input.into(), chunk, destination.as_os_str().to_owned()))
+            })
+            .collect()
+    }
+
+    /// The recipe of the run that left the manifest in `destination_dir`, if there is one,
+    /// and it says.
+    pub(crate) fn recipe(destination_dir: &Path) -> io::Result<Option<Recipe>> {
+        let contents = match fs::read_to_string(destination_dir.join(MANIFEST_FILE)) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        // A manifest that can't be read says nothing about the run that left it.
+        let manifest: serde_json::Value = match serde_json::from_str(&contents) {
+            Ok(manifest) => manifest,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(&manifest["recipe"])
+            .filter(|recipe| recipe.is_object())
+            .map(Recipe::from_json))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_naming_parse() {
+        for naming in &[Naming::Name, Naming::Mirror, Naming::Hash, Naming::Index] {
+            assert_eq!(Ok(*naming), naming.name().parse());
+        }
+        assert_eq!(Ok(Naming::Hash), "HASH".parse());
+        assert!("tree".parse::<Naming>().is_err());
+    }
+
+    #[test]
+    fn test_destination() -> io::Result<()> {
+        let here = std::env::current_dir()?;
+        let name = |naming: Naming, path: &Path, index| naming.destination(path, index);
+        assert_eq!("a.csv", name(Naming::Name, Path::new("/src/x/a.csv"), 3)?);
+        assert_eq!("000003", name(Naming::Index, Path::new("/src/x/a.csv"), 3)?);
+        assert_eq!(
+            "src/x/a.csv",
+            name(Naming::Mirror, Path::new("/src/x/a.csv"), 0)?
+        );
+        assert_eq!("x/a.csv", name(Naming::Mirror, &here.join("./x/a.csv"), 0)?);
+        assert_eq!(
+            name(Naming::Mirror, &here.join("x/a.csv"), 0)?,
+            name(Naming::Mirror, Path::new("x/a.csv"), 0)?
+        );
+        let hash = name(Naming::Hash, Path::new("/src/x/a.csv"), 0)?;
+        assert_eq!(16, hash.len());
+        assert_eq!(hash, name(Naming::Hash, Path::new("/src/./x/a.csv"), 1)?);
+        assert_ne!(hash, name(Naming::Hash, Path::new("/src/y/a.csv"), 0)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TaskId::from_parts(&[]);
+        let recipe = Recipe::new(&crate::Config::builder("wc -l", dir.path()).build()?);
+        assert_eq!(None, Manifest::recipe(dir.path())?);
+        let mut manifest = Manifest::create(dir.path(), &recipe)?;
+        manifest.add(&id, Path::new("/src/a.csv"), None, OsStr::new("a"));
+        let chunk = Chunk {
+            offset: 10,
+            length: 5,
+        };
+        manifest.add(&id, Path::new("/src/b.csv"), Some(chunk), OsStr::new("b"));
+        assert!(!dir.path().join(MANIFEST_FILE).exists());
+        manifest.finish()?;
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join(MANIFEST_FILE))?)?;
+        assert_eq!(
+            json!([
+                {"id": id.as_str(), "input": "/src/a.csv", "offset": null, "length": null, "destination": "a"},
+                {"id": id.as_str(), "input": "/src/b.csv", "offset": 10, "length": 5, "destination": "b"},
+            ]),
+            written["inputs"]
+        );
+        assert_eq!(json!(recipe.fingerprint()), written["fingerprint"]);
+        assert_eq!(Some(recipe), Manifest::recipe(dir.path())?);
+        assert_eq!(1, fs::read_dir(dir.path())?.count());
+        assert_eq!(
+            vec![
+                (PathBuf::from("/src/a.csv"), None, OsString::from("a")),
+                (
+                    PathBuf::from("/src/b.csv"),
+                    Some(chunk),
+                    OsString::from("b")
+                ),
+            ],
+            Manifest::inputs(dir.path())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_snapshot() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TaskId::from_parts(&[]);
+        let recipe = Recipe::new(&crate::Config::builder("wc -l", dir.path()).build()?);
+        let mut manifest = Manifest::create(dir.path(), &recipe)?;
+        manifest.snapshot()?;
+        assert!(Manifest::inputs(dir.path())?.is_empty());
+        manifest.add(&id, Path::new("/src/a.csv"), None, OsStr::new("a"));
+        manifest.snapshot()?;
+        let listed = |dir: &Path| -> io::Result<Vec<PathBuf>> {
+            Ok(Manifest::inputs(dir)?
+                .into_iter()
+                .map(|(path, ..)| path)
+                .collect())
+        };
+        assert_eq!(vec![PathBuf::from("/src/a.csv")], listed(dir.path())?);
+        assert_eq!(Some(recipe), Manifest::recipe(dir.path())?);
+        manifest.add(&id, Path::new("/src/b.csv"), None, OsStr::new("b"));
+        manifest.finish()?;
+        assert_eq!(2, listed(&dir.path().join(MANIFEST_FILE))?.len());
+        assert_eq!(1, fs::read_dir(dir.path())?.count());
+
+        fs::write(
+            dir.path().join(MANIFEST_FILE),
+            r#"{"inputs": [{"input": "/src/a.csv", "offset": null, "length": null, "destination": "../a"}]}"#,
+        )?;
+        assert!(Manifest::inputs(dir.path()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! Telling systemd how a run that watches for inputs is doing, when it runs as a service of
+//! `Type=notify`, so that a drop-folder processor can be managed like any other service.
+//!
+//! The service is ready once the inputs already there have been listed and reach is watching
+//! for more, the watchdog is fed for as long as tasks are being scheduled, if the service has
+//! one, and it's stopping once the run is over. It's all done with `sd_notify`'s datagrams,
+//! so reach doesn't need libsystemd, and it's all skipped when systemd isn't listening, or
+//! `Config::notify_systemd` isn't set.
+
+use futures::future;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time;
+
+/// The socket systemd listens for notifications on, if it's listening.
+#[cfg(unix)]
+fn socket() -> Option<OsString> {
+    std::env::var_os("NOTIFY_SOCKET").filter(|socket| !socket.is_empty())
+}
+
+#[cfg(not(unix))]
+fn socket() -> Option<OsString> {
+    None
+}
+
+/// Send `state`, like `READY=1`, to the systemd listening on `socket`: a path, or a name in
+/// Linux's abstract namespace if it starts with `@`.
+#[cfg(unix)]
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        }
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &OsStr, _state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// How often systemd wants its watchdog fed, if the service has one, and it's for this
+/// process rather than another one.
+fn watchdog_interval() -> Option<Duration> {
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid != std::process::id().to_string()) {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// A run as a service, which tells systemd it's stopping when it's dropped, if it told
+/// systemd it was ready.
+#[derive(Debug)]
+pub(crate) struct Service {
+    /// Where systemd is listening, if it is, and the run should tell it anything.
+    socket: Option<OsString>,
+    watchdog: Option<Duration>,
+    ready: AtomicBool,
+}
+
+impl Service {
+    /// A service that tells systemd how the run is doing if `enabled`, and systemd is
+    /// listening, and otherwise does nothing.
+    pub(crate) fn new(enabled: bool) -> Self {
+        let socket = socket().filter(|_| enabled);
+        let watchdog = socket.as_ref().and_then(|_| watchdog_interval());
+        Service {
+            socket,
+            watchdog,
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Tell systemd `state`, if it's listening. Failing to is no reason to stop the run,
+    /// which carries on regardless, as it would if it weren't a service.
+    fn notify(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            let _ = send(socket, state);
+        }
+    }
+
+    /// Tell systemd the service is ready.
+    pub(crate) fn ready(&self) {
+        self.notify("READY=1");
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// Feed the watchdog, twice as often as systemd wants it, so that it's never late.
+    /// Never finishes, so it's fed for as long as it's polled alongside the run's tasks,
+    /// and stops being fed if they stop being scheduled.
+    pub(crate) async fn feed_watchdog(&self) {
+        let interval = match self.watchdog {
+            Some(interval) => interval,
+            None => return future::pending().await,
+        };
+        let mut ticks = time::interval(interval / 2);
+        loop {
+            ticks.tick().await;
+            self.notify("WATCHDOG=1");
+        }
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        if *self.ready.get_mut() {
+            self.notify("STOPPING=1");
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_send() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path)?;
+        send(path.as_os_str(), "READY=1")?;
+        let mut buf = [0; 64];
+        let size = systemd.recv(&mut buf)?;
+        assert_eq!(b"READY=1", &buf[..size]);
+        assert!(send(dir.path().join("nobody").as_os_str(), "READY=1").is_err());
+        Ok(())
+    }
+
+    /// Systemd only hears the service is stopping if it heard it was ready, and the watchdog
+    /// is fed only while it's polled.
+    #[tokio::test]
+    async fn test_service() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path)?;
+        systemd.set_nonblocking(true)?;
+        let received = || {
+            let mut states = Vec::new();
+            let mut buf = [0; 64];
+            while let Ok(size) = systemd.recv(&mut buf) {
+                states.push(String::from_utf8_lossy(&buf[..size]).into_owned());
+            }
+            states
+        };
+        let service = |watchdog| Service {
+            socket: Some(path.clone().into()),
+            watchdog,
+            ready: AtomicBool::new(false),
+        };
+
+        drop(service(None));
+        assert!(received().is_empty());
+        let ready = service(None);
+        ready.ready();
+        drop(ready);
+        assert_eq!(vec!["READY=1", "STOPPING=1"], received());
+
+        let watched = service(Some(Duration::from_millis(40)));
+        let _ = time::timeout(Duration::from_millis(50), watched.feed_watchdog()).await;
+        let fed = received();
+        assert!(!fed.is_empty() && fed.iter().all(|state| state == "WATCHDOG=1"));
+        drop(watched);
+        assert!(received().is_empty());
+
+        assert!(Service::new(false).socket.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Telling when the destination's filesystem has gone away, like an NFS server that's
+//! restarting, and when it's back.
+//!
+//! Tasks that fail because of an outage would only fail again until it's over, so the
+//! run waits it out instead, and then tries them again.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+
+/// How long to wait before looking at the filesystem again, the first time.
+pub(crate) const FIRST_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest to wait between looks at the filesystem.
+pub(crate) const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How many times one task can fail with an outage error while the filesystem looks
+/// fine, before the error is taken to be the task's own.
+pub(crate) const MAX_FALSE_ALARMS: u32 = 3;
+
+/// Whether `error` means the filesystem itself is failing, rather than anything the task did.
+pub(crate) fn is_outage(error: &io::Error) -> bool {
+    // Errors that say what reach was doing carry the filesystem's error inside them.
+    if let Some(error) = crate::Error::of(error).and_then(crate::Error::io) {
+        return is_outage(error);
+    }
+    #[cfg(unix)]
+    {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::ESTALE) | Some(libc::EIO) | Some(libc::ENOTCONN)
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = error;
+        false
+    }
+}
+
+/// Check that files can be written in `dir`, and removed again.
+pub(crate) async fn probe(dir: &Path) -> io::Result<()> {
+    let path = dir.join(".reach-probe");
+    fs::write(&path, b"").await?;
+    fs::remove_file(&path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_outage() {
+        assert!(is_outage(&io::Error::from_raw_os_error(libc::ESTALE)));
+        assert!(is_outage(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(!is_outage(&io::Error::from_raw_os_error(libc::ENOENT)));
+        assert!(!is_outage(&io::Error::other("No shell")));
+        let error = crate::Error::Output {
+            path: "/dest/a".into(),
+            source: io::Error::from_raw_os_error(libc::ESTALE),
+        };
+        assert!(is_outage(&error.into()));
+    }
+
+    #[tokio::test]
+    async fn test_probe() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        probe(dir.path()).await?;
+        assert_eq!(0, std::fs::read_dir(dir.path())?.count());
+        assert!(probe(&dir.path().join("missing")).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+//! Pausing a run, and everything it's running, until it's resumed.
+//!
+//! Running commands are frozen by putting every command the run starts in a cgroup of its
+//! own, and freezing that, so pausing takes effect straight away, for whatever the commands
+//! have started as well. Where there's no cgroup freezer that reach can use, pausing only
+//! holds back new tasks.
+
+use futures::future;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+use crate::error;
+use crate::{Message, Unsupported};
+
+/// A handle for pausing and resuming runs, which can be cloned and handed to whatever should
+/// be able to pause them, like a signal handler.
+///
+/// Give it to `Config::pause`. While it's paused, no new tasks start, and running commands
+/// are frozen where they can be, with their timeouts stopped too.
+///
+/// ```no_run
+/// # async fn example(mut config: reach::Config) -> Result<(), reach::Error> {
+/// let token = reach::PauseToken::new();
+/// config.pause = Some(token.clone());
+/// let run = reach::run(config, ());
+/// // Later, from anywhere holding a clone of `token`:
+/// token.pause();
+/// token.resume();
+/// # run.await.map(|_| ())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PauseToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl PauseToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        PauseToken {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Pause every run given this token, or any clone of it, until it's resumed.
+    pub fn pause(&self) {
+        // Only fails if there are no receivers, but we always hold one.
+        let _ = self.sender.send(true);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.sender.send(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Completes once the token is paused or resumed, whichever it isn't now.
+    fn changed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut receiver = self.receiver.clone();
+        let paused = *receiver.borrow();
+        async move {
+            while *receiver.borrow() == paused {
+                if receiver.changed().await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }
+        }
+    }
+}
+
+impl Default for PauseToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which kind of cgroup freezer there is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Version {
+    /// `cgroup.freeze`, in the unified hierarchy.
+    V2,
+    /// `freezer.state`, in the freezer hierarchy of its own.
+    V1,
+}
+
+/// A cgroup of the run's own, that every command it starts is put in, so they can all be
+/// frozen at once. It's thawed and removed once it's dropped.
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    dir: PathBuf,
+    version: Version,
+    /// Writing `0` to this puts the process that writes it in the cgroup.
+    procs: File,
+}
+
+impl Cgroup {
+    /// Make a cgroup for the run, inside reach's own.
+    pub(crate) fn create() -> io::Result<Self> {
+        let unsupported = || error::unsupported(Unsupported::Freezer);
+        if !cfg!(target_os = "linux") {
+            return Err(unsupported());
+        }
+        let own = fs::read_to_string("/proc/self/cgroup")?;
+        let name = format!("reach-{}", std::process::id());
+        // Tried in order of preference, the unified hierarchy first, as it's where they're going.
+        let mut candidates = Vec::new();
+        for line in own.lines() {
+            let mut parts = line.splitn(3, ':');
+            let (controllers, path) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(_), Some(controllers), Some(path)) => (controllers, path),
+                _ => continue,
+            };
+            let path = path.trim_start_matches('/');
+            if controllers.is_empty() {
+                for root in &["/sys/fs/cgroup", "/sys/fs/cgroup/unified"] {
+                    let root = PathBuf::from(root);
+                    if root.join("cgroup.controllers").exists() {
+                        candidates.insert(0, (root.join(path).join(&name), Version::V2));
+                        break;
+                    }
+                }
+            } else if controllers.split(',').any(|c| c == "freezer") {
+                let root = PathBuf::from("/sys/fs/cgroup/freezer");
+                candidates.push((root.join(path).join(&name), Version::V1));
+            }
+        }
+        let mut error = unsupported();
+        for (dir, version) in candidates {
+            match Cgroup::create_at(dir, version) {
+                Ok(cgroup) => return Ok(cgroup),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn create_at(dir: PathBuf, version: Version) -> io::Result<Self> {
+        fs::create_dir(&dir)?;
+        let cgroup = OpenOptions::new()
+            .write(true)
+            .open(dir.join("cgroup.procs"))
+            .map(|procs| Cgroup {
+                dir: dir.clone(),
+                version,
+                procs,
+            });
+        match cgroup {
+            // Older kernels have cgroups without freezers of their own.
+            Ok(cgroup) if cgroup.freeze_file().exists() => Ok(cgroup),
+            result => {
+                let _ = fs::remove_dir(&dir);
+                result.and(Err(error::unsupported(Unsupported::Freezer)))
+            }
+        }
+    }
+
+    fn freeze_file(&self) -> PathBuf {
+        match self.version {
+            Version::V2 => self.dir.join("cgroup.freeze"),
+            Version::V1 => self.dir.join("freezer.state"),
+        }
+    }
+
+    /// Freeze everything in the cgroup, or thaw it.
+    pub(crate) fn freeze(&self, frozen: bool) -> io::Result<()> {
+        let state = match (self.version, frozen) {
+            (Version::V2, true) => "1",
+            (Version::V2, false) => "0",
+            (Version::V1, true) => "FROZEN",
+            (Version::V1, false) => "THAWED",
+        };
+        fs::write(self.freeze_file(), state)
+    }
+
+    /// Put the calling process in the cgroup. Only does what's safe between fork and exec.
+    #[cfg(unix)]
+    pub(crate) fn join(&self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        // SAFETY: `write` is async-signal-safe, and the file stays open as long as `self`.
+        if unsafe { libc::write(self.procs.as_raw_fd(), b"0".as_ptr().cast(), 1) } == 1 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Anything still in it, like a daemon a command left behind, keeps it from being removed.
+        let _ = self.freeze(false);
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+/// Pauses and resumes a run, as its token says.
+#[derive(Debug)]
+pub(crate) struct Pauser {
+    token: PauseToken,
+    cgroup: Option<Arc<Cgroup>>,
+    /// Why running commands can't be frozen, if they can't, for a run that can be paused.
+    unfreezable: Option<io::Error>,
+    /// Whether running commands are frozen now.
+    frozen: watch::Sender<bool>,
+    frozen_changes: watch::Receiver<bool>,
+}
+
+impl Pauser {
+    /// Pause and resume as `token` says, if there is one, freezing running commands too if
+    /// `freeze` says to, and they can be.
+    pub(crate) fn new(token: Option<PauseToken>, freeze: Result<(), Unsupported>) -> Self {
+        let (frozen, frozen_changes) = watch::channel(false);
+        let (cgroup, unfreezable) = match (&token, freeze) {
+            (None, _) => (None, None),
+            (Some(_), Err(why)) => (None, Some(error::unsupported(why))),
+            (Some(_), Ok(())) => match Cgroup::create() {
+                Ok(cgroup) => (Some(Arc::new(cgroup)), None),
+                Err(error) => (None, Some(error)),
+            },
+        };
+        Pauser {
+            token: token.unwrap_or_default(),
+            cgroup,
+            unfreezable,
+            frozen,
+            frozen_changes,
+        }
+    }
+
+    /// The cgroup to put commands in, if they can be frozen.
+    pub(crate) fn cgroup(&self) -> Option<Arc<Cgroup>> {
+        self.cgroup.clone()
+    }
+
+    /// Completes once the run isn't paused, straight away if it isn't now.
+    pub(crate) async fn resumed(&self) {
+        while self.token.is_paused() {
+            self.token.changed().await;
+        }
+    }
+
+    /// Freeze and thaw running commands as the run is paused and resumed, forever, saying
+    /// what happened with `report`.
+    pub(crate) async fn follow(&self, report: impl Fn(&Message<'_>)) {
+        let mut was_paused = false;
+        loop {
+            let paused = self.token.is_paused();
+            if paused != was_paused {
+                self.set_frozen(paused, &report);
+                was_paused = paused;
+            }
+            self.token.changed().await;
+        }
+    }
+
+    /// Freeze or thaw running commands, saying how it went with `report`.
+    fn set_frozen(&self, frozen: bool, report: &impl Fn(&Message<'_>)) {
+        let cgroup = match (&self.cgroup, &self.unfreezable) {
+            (Some(cgroup), _) => cgroup,
+            (None, Some(why)) if frozen => return report(&Message::PausedUnfrozen(why)),
+            (None, _) => return report(&Message::Resumed),
+        };
+        match cgroup.freeze(frozen) {
+            Ok(()) => {
+                let _ = self.frozen.send(frozen);
+                if frozen {
+                    report(&Message::PausedFrozen)
+                } else {
+                    report(&Message::Resumed)
+                }
+            }
+            Err(error) => report(&Message::FreezeFailed(&error)),
+        }
+    }
+
+    /// Thaw running commands, so that they can be stopped.
+    pub(crate) fn thaw(&self) {
+        if let Some(cgroup) = &self.cgroup {
+            if cgroup.freeze(false).is_ok() {
+                let _ = self.frozen.send(false);
+            }
+        }
+    }
+
+    /// Sleep for `duration`, not counting the time that running commands are frozen.
+    pub(crate) async fn sleep(&self, duration: Duration) {
+        let mut frozen = self.frozen_changes.clone();
+        let mut left = duration;
+        loop {
+            if *frozen.borrow() {
+                let _ = frozen.changed().await;
+                continue;
+            }
+            let start = Instant::now();
+            tokio::select! {
+                _ = time::sleep(left) => return,
+                _ = frozen.changed() => {
+                    left = left.saturating_sub(start.elapsed());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pause_token() {
+        let token = PauseToken::new();
+        let pauser = Pauser::new(Some(token.clone()), Err(Unsupported::FreezeOnWorkers));
+        assert_eq!(
+            Some(io::ErrorKind::Unsupported),
+            pauser.unfreezable.as_ref().map(io::Error::kind)
+        );
+        time::timeout(Duration::from_secs(1), pauser.resumed())
+            .await
+            .expect("It isn't paused");
+        token.pause();
+        assert!(token.is_paused());
+        assert!(time::timeout(Duration::from_millis(50), pauser.resumed())
+            .await
+            .is_err());
+        token.resume();
+        time::timeout(Duration::from_secs(1), pauser.resumed())
+            .await
+            .expect("It was resumed");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_freeze() -> io::Result<()> {
+        let cgroup = match Cgroup::create() {
+            Ok(cgroup) => Arc::new(cgroup),
+            // Only some machines let us make cgroups.
+            Err(_) => return Ok(()),
+        };
+        let dir = tempfile::tempdir()?;
+        let done = dir.path().join("done");
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("sleep 0.2; touch {}", done.display()));
+        crate::limits::Limits::default()
+            .within(Some(cgroup.clone()))
+            .apply(&mut command);
+        let mut child = command.spawn()?;
+        cgroup.freeze(true)?;
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(!done.exists());
+        cgroup.freeze(false)?;
+        assert!(child.wait().await?.success());
+        assert!(done.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sleep_while_frozen() {
+        let pauser = Pauser::new(None, Ok(()));
+        let start = Instant::now();
+        let sleep = pauser.sleep(Duration::from_millis(200));
+        let freeze = async {
+            time::sleep(Duration::from_millis(80)).await;
+            let _ = pauser.frozen.send(true);
+            time::sleep(Duration::from_millis(300)).await;
+            let _ = pauser.frozen.send(false);
+            future::pending::<()>().await;
+        };
+        tokio::select! {
+            _ = sleep => {}
+            _ = freeze => {}
+        }
+        // Real time, as tokio's paused clock can run on before the sleep sees it frozen.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+}
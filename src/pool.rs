@@ -0,0 +1,217 @@
+//! Long-lived workers that tasks take turns to use.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::template::Template;
+use crate::Process;
+
+/// The workers for a run.
+///
+/// Workers are only started when a task needs one and none are free, so a run
+/// never has more workers than it has tasks running at once.
+///
+/// Usually any idle worker will do. A pool with affinity instead gives each task
+/// the worker in the slot its key hashes to, waiting for it if it's busy, so that
+/// tasks with the same key always go to the same worker.
+pub(crate) struct Pool<W> {
+    idle: Mutex<Vec<W>>,
+    /// The template for each task's key, if the pool has affinity.
+    key: Option<Template>,
+    slots: Vec<Slot<W>>,
+}
+
+struct Slot<W> {
+    /// Held for as long as a task is using the slot's worker.
+    busy: Arc<Semaphore>,
+    worker: Mutex<Option<W>>,
+}
+
+/// A task's claim on a slot in a pool, or on any idle worker if the pool has no affinity.
+pub(crate) struct Reservation {
+    slot: Option<usize>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Reservation {
+    /// A claim on any idle worker, which is all that's needed outside a pool with affinity.
+    pub(crate) fn any() -> Self {
+        Reservation {
+            slot: None,
+            _permit: None,
+        }
+    }
+}
+
+/// A worker that a task has taken from a pool.
+pub(crate) struct Lease<W> {
+    worker: W,
+    reservation: Reservation,
+}
+
+impl<W> Pool<W> {
+    pub(crate) fn new() -> Self {
+        Pool {
+            idle: Mutex::new(Vec::new()),
+            key: None,
+            slots: Vec::new(),
+        }
+    }
+
+    /// A pool that gives each task the worker in one of `slots` slots, according to
+    /// its key: `key` filled in for the task.
+    pub(crate) fn with_affinity(key: Template, slots: usize) -> Self {
+        Pool {
+            idle: Mutex::new(Vec::new()),
+            key: Some(key),
+            slots: (0..slots.max(1))
+                .map(|_| Slot {
+                    busy: Arc::new(Semaphore::new(1)),
+                    worker: Mutex::new(None),
+                })
+                .collect(),
+        }
+    }
+
+    /// Wait for the slot for the task with input files `inputs` and destination directory
+    /// `task_dir` to be free, then claim it.
+    ///
+    /// Claims nothing if the pool has no affinity.
+    pub(crate) async fn reserve(&self, inputs: &[PathBuf], task_dir: &Path) -> Reservation {
+        let key = match &self.key {
+            Some(key) => key.render_arg(inputs, task_dir),
+            None => return Reservation::any(),
+        };
+        let slot = rendezvous(&key, self.slots.len());
+        let permit = self.slots[slot]
+            .busy
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Slots are never closed");
+        Reservation {
+            slot: Some(slot),
+            _permit: Some(permit),
+        }
+    }
+
+    /// The worker for `reservation`, or a new one from `start` if there isn't one.
+    pub(crate) fn take(
+        &self,
+        reservation: Reservation,
+        start: impl FnOnce() -> io::Result<W>,
+    ) -> io::Result<Lease<W>> {
+        let worker = match reservation.slot {
+            Some(slot) => self.slots[slot].worker.lock().unwrap().take(),
+            None => self.idle.lock().unwrap().pop(),
+        };
+        let worker = match worker {
+            Some(worker) => worker,
+            None => start()?,
+        };
+        Ok(Lease {
+            worker,
+            reservation,
+        })
+    }
+
+    /// Give back a worker that's ready for another task.
+    ///
+    /// A worker that isn't given back is dropped, and its slot gets a new one when it's next needed.
+    pub(crate) fn put(&self, lease: Lease<W>) {
+        match lease.reservation.slot {
+            Some(slot) => *self.slots[slot].worker.lock().unwrap() = Some(lease.worker),
+            None => self.idle.lock().unwrap().push(lease.worker),
+        }
+    }
+}
+
+impl<W> Deref for Lease<W> {
+    type Target = W;
+
+    fn deref(&self) -> &W {
+        &self.worker
+    }
+}
+
+impl<W> DerefMut for Lease<W> {
+    fn deref_mut(&mut self) -> &mut W {
+        &mut self.worker
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: Process + Send> Process for Lease<W> {
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.worker.wait().await
+    }
+
+    async fn terminate(&mut self) -> io::Result<()> {
+        self.worker.terminate().await
+    }
+}
+
+/// The slot for `key`, by rendezvous hashing.
+///
+/// Every slot gets a score for the key, and the highest wins. Changing the number
+/// of slots only moves the keys whose winning slot was added or removed.
+fn rendezvous(key: &impl Hash, slots: usize) -> usize {
+    (0..slots)
+        .max_by_key(|slot| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            slot.hash(&mut hasher);
+            hasher.finish()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuses_workers() -> io::Result<()> {
+        let pool = Pool::new();
+        let first = pool.take(Reservation::any(), || Ok(1))?;
+        let second = pool.take(Reservation::any(), || Ok(2))?;
+        pool.put(first);
+        assert_eq!(1, *pool.take(Reservation::any(), || Ok(3))?);
+        assert_eq!(2, *second);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pool_affinity() -> io::Result<()> {
+        let pool = Pool::with_affinity(Template::parse("{dir}"), 4);
+        let dest = Path::new("/dest");
+        let mut workers = Vec::new();
+        for input in &["/a/1.txt", "/b/1.txt", "/a/2.txt"] {
+            let reservation = pool.reserve(&[input.into()], dest).await;
+            let lease = pool.take(reservation, || Ok(workers.len()))?;
+            workers.push(*lease);
+            pool.put(lease);
+        }
+        assert_eq!(workers[0], workers[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rendezvous() {
+        let keys: Vec<_> = (0..100).map(|key| format!("key{}", key)).collect();
+        let slots = |n| -> Vec<_> { keys.iter().map(|key| rendezvous(key, n)).collect() };
+        let (four, five) = (slots(4), slots(5));
+        assert!(four.iter().all(|&slot| slot < 4));
+        // Only the keys that now belong to the new slot have moved.
+        for (before, after) in four.iter().zip(&five) {
+            assert!(before == after || *after == 4);
+        }
+        assert!(four.contains(&0) && four.contains(&3));
+    }
+}
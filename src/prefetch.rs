@@ -0,0 +1,57 @@
+//! Asking the kernel to start reading inputs before their tasks start, so that commands
+//! don't stall on cold reads from spinning disks or network filesystems.
+
+use std::io;
+use std::path::Path;
+use tokio::fs;
+
+use crate::Chunk;
+
+/// Tell the kernel that the file at `input`, or only `chunk` of it, will be read soon.
+///
+/// Only advice: the kernel may start reading it into its cache, or may ignore it,
+/// and on platforms without `posix_fadvise` this does nothing but open the file.
+pub(crate) async fn advise(input: &Path, chunk: Option<Chunk>) -> io::Result<()> {
+    let file = fs::File::open(input).await?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // A length of 0 means the rest of the file.
+        let (offset, length) = chunk.map_or((0, 0), |chunk| (chunk.offset, chunk.length));
+        let error = unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                length as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = chunk;
+    drop(file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_advise() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input");
+        std::fs::write(&input, "contents")?;
+        advise(&input, None).await?;
+        let chunk = Chunk {
+            offset: 2,
+            length: 4,
+        };
+        advise(&input, Some(chunk)).await?;
+        assert!(advise(&dir.path().join("missing"), None).await.is_err());
+        Ok(())
+    }
+}
@@ -1,48 +1,437 @@
-use console::Emoji;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::io;
+use serde_json::json;
+use std::io::{self, Write};
+use std::path::Path;
 use std::process::ExitStatus;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
 
-/// How `reach` reports progress.
+use crate::messages::error_json;
+#[cfg(feature = "progress-bar")]
+use crate::progress_bar;
+use crate::{status_of, CancelReason, Message, Status, TaskId, TaskResult};
+
+/// How `reach` reports progress, as events about the run and each of its tasks.
 ///
 /// Exists so we can have a "real" implementation that delegates to indicatif,
+/// one that writes JSON for other programs to read, a dashboard,
 /// and a "fake" implementation that does nothing and is used only in tests.
+/// Programs using reach as a library can implement it to report progress their own way.
+///
+/// Every event about a task has its ID, which is the same in every attempt and every run,
+/// and there's one `task_started` and one `task_completed` for each task that's run.
+/// Every input in a batch gets events of its own.
 pub trait Progress {
+    /// Called with how many tasks there are to run, and again whenever more turn up.
     fn set_num_tasks(&self, tasks: usize);
-    fn task_completed(&self, result: io::Result<ExitStatus>);
+
+    /// Called when the task with ID `id` for `input` is left out of the run, as it already
+    /// succeeded in an earlier one.
+    fn task_skipped(&self, _id: &TaskId, _input: &Path) {}
+
+    /// Called when the task with ID `id` for `input` starts, before its first attempt.
+    fn task_started(&self, _id: &TaskId, _input: &Path) {}
+
+    /// Called when the task with ID `id` for `input` is about to make attempt number
+    /// `attempt`, counting from one, because the one before ended with `result`.
+    fn task_retrying(
+        &self,
+        _id: &TaskId,
+        _input: &Path,
+        _attempt: u32,
+        _result: &io::Result<ExitStatus>,
+    ) {
+    }
+
+    /// Called when the task with ID `id` has finished, including any retries.
+    fn task_completed(&self, id: &TaskId, outcome: &TaskOutcome<'_>);
+
+    /// Called when something about the run deserves a warning, though it carries on.
+    ///
+    /// The warning is structured, so that it can be worded with `Message::text`, or
+    /// reported to other programs by its `Message::code` and `Message::fields`.
+    fn warn(&self, warning: &Message<'_>) {
+        eprintln!("{}", Message::Warning(&warning.text()).text());
+    }
 }
 
-static OK: Emoji<'_, '_> = Emoji("✅", "OK");
-static ERROR: Emoji<'_, '_> = Emoji("❌", "ERROR");
+impl<P: Progress + ?Sized> Progress for Box<P> {
+    fn set_num_tasks(&self, tasks: usize) {
+        (**self).set_num_tasks(tasks)
+    }
+
+    fn task_skipped(&self, id: &TaskId, input: &Path) {
+        (**self).task_skipped(id, input)
+    }
+
+    fn task_started(&self, id: &TaskId, input: &Path) {
+        (**self).task_started(id, input)
+    }
+
+    fn task_retrying(
+        &self,
+        id: &TaskId,
+        input: &Path,
+        attempt: u32,
+        result: &io::Result<ExitStatus>,
+    ) {
+        (**self).task_retrying(id, input, attempt, result)
+    }
+
+    fn task_completed(&self, id: &TaskId, outcome: &TaskOutcome<'_>) {
+        (**self).task_completed(id, outcome)
+    }
+
+    fn warn(&self, warning: &Message<'_>) {
+        (**self).warn(warning)
+    }
+}
 
-impl Progress for ProgressBar {
+/// Reports everything to both, like a progress bar for people and metrics for monitoring.
+impl<A: Progress, B: Progress> Progress for (A, B) {
     fn set_num_tasks(&self, tasks: usize) {
-        self.set_length(tasks as u64);
+        self.0.set_num_tasks(tasks);
+        self.1.set_num_tasks(tasks);
     }
 
-    fn task_completed(&self, result: io::Result<ExitStatus>) {
-        match result {
-            Ok(_) => self.inc(1),
-            Err(e) => {
-                self.println(format!("Error: {:?}", e));
-                self.set_prefix(format!("{} ", ERROR));
-                self.inc(1);
-            }
-        }
+    fn task_skipped(&self, id: &TaskId, input: &Path) {
+        self.0.task_skipped(id, input);
+        self.1.task_skipped(id, input);
+    }
+
+    fn task_started(&self, id: &TaskId, input: &Path) {
+        self.0.task_started(id, input);
+        self.1.task_started(id, input);
+    }
+
+    fn task_retrying(
+        &self,
+        id: &TaskId,
+        input: &Path,
+        attempt: u32,
+        result: &io::Result<ExitStatus>,
+    ) {
+        self.0.task_retrying(id, input, attempt, result);
+        self.1.task_retrying(id, input, attempt, result);
+    }
+
+    fn task_completed(&self, id: &TaskId, outcome: &TaskOutcome<'_>) {
+        self.0.task_completed(id, outcome);
+        self.1.task_completed(id, outcome);
+    }
+
+    fn warn(&self, warning: &Message<'_>) {
+        self.0.warn(warning);
+        self.1.warn(warning);
+    }
+}
+
+/// How a task finished, for `Progress::task_completed`.
+#[derive(Debug)]
+pub struct TaskOutcome<'a> {
+    /// What the run's summary records about the task, like its input, status, and duration.
+    pub task: &'a TaskResult,
+    /// How the task's last attempt ended, with the error itself if its command couldn't be run.
+    pub result: &'a io::Result<ExitStatus>,
+}
+
+impl TaskOutcome<'_> {
+    /// The task's input file.
+    pub fn input(&self) -> &Path {
+        &self.task.input
+    }
+
+    /// How long the task took, including every attempt.
+    pub fn duration(&self) -> Duration {
+        self.task.duration
     }
 }
 
 impl Progress for () {
     fn set_num_tasks(&self, _tasks: usize) {}
-    fn task_completed(&self, _result: io::Result<ExitStatus>) {}
+    fn task_completed(&self, _id: &TaskId, _outcome: &TaskOutcome<'_>) {}
+}
+
+/// Reports progress as JSON lines, one event per line, for other programs to read.
+///
+/// Every event has an `event` field: `tasks` says how many tasks there are,
+/// `skipped` that a task already succeeded in an earlier run, `started` that a task has started,
+/// `retrying` that it failed and is being tried again, and `finished` how it finished.
+/// Events about a task have its `id`, which is the same in every run of the same command.
+/// A `warning` has the warning's `code` and its `fields`, as `Message` gives them, and an
+/// error has its `kind`, like `timed_out`, rather than any wording, which can change.
+/// Errors writing events are ignored, as they are for progress bars.
+#[derive(Debug)]
+pub struct JsonProgress<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> JsonProgress<W> {
+    pub fn new(writer: W) -> Self {
+        JsonProgress {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{}", event).and_then(|()| writer.flush());
+    }
+}
+
+impl<W: Write> Progress for JsonProgress<W> {
+    fn set_num_tasks(&self, tasks: usize) {
+        self.emit(json!({"event": "tasks", "tasks": tasks}));
+    }
+
+    fn task_skipped(&self, id: &TaskId, input: &Path) {
+        self.emit(json!({
+            "event": "skipped",
+            "id": id.as_str(),
+            "input": input.to_string_lossy(),
+        }));
+    }
+
+    fn task_started(&self, id: &TaskId, input: &Path) {
+        self.emit(json!({
+            "event": "started",
+            "id": id.as_str(),
+            "input": input.to_string_lossy(),
+        }));
+    }
+
+    fn task_retrying(
+        &self,
+        id: &TaskId,
+        input: &Path,
+        attempt: u32,
+        result: &io::Result<ExitStatus>,
+    ) {
+        let status = status_of(result);
+        self.emit(json!({
+            "event": "retrying",
+            "id": id.as_str(),
+            "input": input.to_string_lossy(),
+            "attempt": attempt,
+            "exit_code": status.as_ref().and_then(Status::exit_code),
+            "status": status.as_ref().map(Status::to_string),
+            "error": result.as_ref().err().map(error_json),
+        }));
+    }
+
+    fn task_completed(&self, id: &TaskId, outcome: &TaskOutcome<'_>) {
+        let status = status_of(outcome.result);
+        self.emit(json!({
+            "event": "finished",
+            "id": id.as_str(),
+            "input": outcome.input().to_string_lossy(),
+            "exit_code": status.as_ref().and_then(Status::exit_code),
+            "status": status.as_ref().map(Status::to_string),
+            "signal": status.as_ref().and_then(Status::signal_name),
+            "cancelled": outcome.task.cancelled.as_ref().map(CancelReason::name),
+            "error": outcome.result.as_ref().err().map(error_json),
+            "duration_secs": outcome.duration().as_secs_f64(),
+            "retries": outcome.task.retries,
+        }));
+    }
+
+    fn warn(&self, warning: &Message<'_>) {
+        self.emit(json!({
+            "event": "warning",
+            "code": warning.code(),
+            "fields": warning.fields(),
+        }));
+    }
+}
+
+/// Which kind of progress reporting to use.
+///
+/// Only JSON lines and no reporting at all are there without the `progress-bar` feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// A progress bar, for people. Compact if the terminal is narrow.
+    #[cfg(feature = "progress-bar")]
+    Bar,
+    /// A progress bar with just the count, for narrow terminals and panes.
+    #[cfg(feature = "progress-bar")]
+    Compact,
+    /// JSON lines on standard output, for programs. See `JsonProgress`.
+    Json,
+    /// A dashboard of running and recently finished tasks, for people. See `Dashboard`.
+    #[cfg(feature = "progress-bar")]
+    Tui,
+    /// No progress reporting at all.
+    Quiet,
+}
+
+impl ProgressMode {
+    /// Construct the progress reporter for this mode.
+    pub fn progress(self) -> Box<dyn Progress> {
+        self.progress_with_template(None)
+    }
+
+    /// Like `progress`, but progress bars use `template` if there is one.
+    ///
+    /// The template is in indicatif's format, like `DEFAULT_TEMPLATE`.
+    #[cfg_attr(not(feature = "progress-bar"), allow(unused_variables))]
+    pub fn progress_with_template(self, template: Option<&str>) -> Box<dyn Progress> {
+        match self {
+            #[cfg(feature = "progress-bar")]
+            ProgressMode::Bar | ProgressMode::Compact => Box::new(progress_bar::progress_bar(
+                template.unwrap_or_else(|| progress_bar::bar_template(self)),
+            )),
+            ProgressMode::Json => Box::new(JsonProgress::new(io::stdout())),
+            #[cfg(feature = "progress-bar")]
+            ProgressMode::Tui => Box::new(crate::Dashboard::new()),
+            ProgressMode::Quiet => Box::new(()),
+        }
+    }
 }
 
-/// Construct a real progress bar for rendering to users.
-pub fn default_progress_bar() -> impl Progress {
-    ProgressBar::new(0)
-        .with_style(
-            ProgressStyle::default_bar()
-                .template("{prefix}{wide_bar} {pos}/{len} [{elapsed}<{eta}, {per_sec}]"),
-        )
-        .with_prefix(format!("{} ", OK))
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            #[cfg(feature = "progress-bar")]
+            "bar" => Ok(ProgressMode::Bar),
+            #[cfg(feature = "progress-bar")]
+            "compact" => Ok(ProgressMode::Compact),
+            "json" => Ok(ProgressMode::Json),
+            #[cfg(feature = "progress-bar")]
+            "tui" => Ok(ProgressMode::Tui),
+            #[cfg(not(feature = "progress-bar"))]
+            "bar" | "compact" | "tui" => Err(format!(
+                "ProgressMode {} needs reach's progress-bar feature",
+                s
+            )),
+            "quiet" => Ok(ProgressMode::Quiet),
+            _ => Err(format!("No such ProgressMode: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_progress() {
+        let progress = JsonProgress::new(Vec::new());
+        let input = Path::new("/src/a file.txt");
+        let id = TaskId::from_parts(&[Some(b"a file")]);
+        let completed = |result: io::Result<ExitStatus>, attempts, duration| {
+            let task = TaskResult::new(
+                id.clone(),
+                input.to_owned(),
+                "/dest/a file.txt".into(),
+                &result,
+                attempts,
+                duration,
+            );
+            progress.task_completed(
+                &id,
+                &TaskOutcome {
+                    task: &task,
+                    result: &result,
+                },
+            );
+        };
+        progress.set_num_tasks(2);
+        progress.task_skipped(&id, input);
+        progress.task_started(&id, input);
+        progress.task_retrying(
+            &id,
+            input,
+            2,
+            &Err(io::Error::new(io::ErrorKind::TimedOut, "Too slow")),
+        );
+        completed(
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Too slow")),
+            2,
+            Duration::from_millis(1500),
+        );
+        completed(
+            Err(io::Error::new(io::ErrorKind::NotFound, "No shell")),
+            1,
+            Duration::from_secs(0),
+        );
+        progress.warn(&Message::HashFailed {
+            input,
+            error: &io::Error::from_raw_os_error(libc::ENOENT),
+        });
+
+        let output = progress.writer.into_inner().unwrap();
+        let events: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let timed_out = json!({"kind": "timed_out", "os_error": null, "phase": null, "path": null});
+        assert_eq!(
+            vec![
+                json!({"event": "tasks", "tasks": 2}),
+                json!({"event": "skipped", "id": id.as_str(), "input": "/src/a file.txt"}),
+                json!({"event": "started", "id": id.as_str(), "input": "/src/a file.txt"}),
+                json!({
+                    "event": "retrying",
+                    "id": id.as_str(),
+                    "input": "/src/a file.txt",
+                    "attempt": 2,
+                    "exit_code": null,
+                    "status": "timed out",
+                    "error": timed_out,
+                }),
+                json!({
+                    "event": "finished",
+                    "id": id.as_str(),
+                    "input": "/src/a file.txt",
+                    "exit_code": null,
+                    "status": "timed out",
+                    "signal": null,
+                    "cancelled": null,
+                    "error": timed_out,
+                    "duration_secs": 1.5,
+                    "retries": 1,
+                }),
+                json!({
+                    "event": "finished",
+                    "id": id.as_str(),
+                    "input": "/src/a file.txt",
+                    "exit_code": null,
+                    "status": null,
+                    "signal": null,
+                    "cancelled": null,
+                    "error": {"kind": "not_found", "os_error": null, "phase": null, "path": null},
+                    "duration_secs": 0.0,
+                    "retries": 0,
+                }),
+                json!({
+                    "event": "warning",
+                    "code": "hash_failed",
+                    "fields": {
+                        "path": "/src/a file.txt",
+                        "error": {
+                            "kind": "not_found",
+                            "os_error": libc::ENOENT,
+                            "phase": null,
+                            "path": null,
+                        },
+                    },
+                }),
+            ],
+            events
+        );
+    }
+
+    #[cfg(feature = "progress-bar")]
+    #[test]
+    fn test_progress_mode_parse() {
+        assert_eq!(Ok(ProgressMode::Bar), "bar".parse());
+        assert_eq!(Ok(ProgressMode::Compact), "compact".parse());
+        assert_eq!(Ok(ProgressMode::Json), "json".parse());
+        assert_eq!(Ok(ProgressMode::Tui), "TUI".parse());
+        assert_eq!(Ok(ProgressMode::Quiet), "quiet".parse());
+        assert!("loud".parse::<ProgressMode>().is_err());
+    }
 }
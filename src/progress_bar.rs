@@ -0,0 +1,91 @@
+//! Progress bars for people, drawn by indicatif. Only with the `progress-bar` feature.
+
+use console::Emoji;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::{Message, Progress, ProgressMode, TaskId, TaskOutcome};
+
+static OK: Emoji<'_, '_> = Emoji("✅", "OK");
+static ERROR: Emoji<'_, '_> = Emoji("❌", "ERROR");
+
+impl Progress for ProgressBar {
+    fn set_num_tasks(&self, tasks: usize) {
+        self.set_length(tasks as u64);
+    }
+
+    fn task_completed(&self, _id: &TaskId, outcome: &TaskOutcome<'_>) {
+        // Errors say which input or output they're about, but failing commands are
+        // left to the summary.
+        if let Err(e) = outcome.result {
+            self.println(Message::Error(e).text());
+        }
+        if !outcome.task.succeeded() {
+            self.set_prefix(format!("{} ", ERROR));
+        }
+        self.inc(1);
+    }
+
+    fn warn(&self, warning: &Message<'_>) {
+        self.println(Message::Warning(&warning.text()).text());
+    }
+}
+
+/// The template for progress bars, in indicatif's format.
+pub const DEFAULT_TEMPLATE: &str = "{prefix}{wide_bar} {pos}/{len} [{elapsed}<{eta}, {per_sec}]";
+
+/// The template for compact progress bars, which leaves more of a narrow terminal for the bar.
+pub const COMPACT_TEMPLATE: &str = "{prefix}{wide_bar} {pos}/{len}";
+
+/// Terminals narrower than this get a compact progress bar, unless they ask for a full one.
+const COMPACT_WIDTH: u16 = 60;
+
+/// The template for a progress bar in `mode`, on standard error as it is now.
+pub(crate) fn bar_template(mode: ProgressMode) -> &'static str {
+    let width = console::Term::stderr()
+        .size_checked()
+        .map(|(_, width)| width);
+    bar_template_for(mode, width)
+}
+
+/// The template for a progress bar in `mode`, on a terminal `width` columns wide, if we know.
+fn bar_template_for(mode: ProgressMode, width: Option<u16>) -> &'static str {
+    match (mode, width) {
+        (ProgressMode::Compact, _) => COMPACT_TEMPLATE,
+        (_, Some(width)) if width < COMPACT_WIDTH => COMPACT_TEMPLATE,
+        _ => DEFAULT_TEMPLATE,
+    }
+}
+
+/// Construct a real progress bar for rendering to users.
+pub fn default_progress_bar() -> impl Progress {
+    progress_bar(DEFAULT_TEMPLATE)
+}
+
+/// Construct a real progress bar that renders with `template`, in indicatif's format.
+pub fn progress_bar(template: &str) -> impl Progress {
+    ProgressBar::new(0)
+        .with_style(ProgressStyle::default_bar().template(template))
+        .with_prefix(format!("{} ", OK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar_template() {
+        assert_eq!(
+            DEFAULT_TEMPLATE,
+            bar_template_for(ProgressMode::Bar, Some(120))
+        );
+        assert_eq!(DEFAULT_TEMPLATE, bar_template_for(ProgressMode::Bar, None));
+        assert_eq!(
+            COMPACT_TEMPLATE,
+            bar_template_for(ProgressMode::Bar, Some(40))
+        );
+        assert_eq!(
+            COMPACT_TEMPLATE,
+            bar_template_for(ProgressMode::Compact, Some(120))
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! A small JSON file saying how far a run has got and when it should finish, for dashboards
+//! and `watch cat` to follow a run that has no one watching its terminal.
+
+use serde_json::json;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::time;
+
+use crate::{Message, Progress, TaskId, TaskOutcome};
+
+/// How far a run has got, updated from progress events, with an estimate of when it'll finish.
+///
+/// Clones share the same counts, so one can be given to a run as part of its progress,
+/// as in `(progress, progress_file.clone())`, while another writes them.
+#[derive(Debug, Clone)]
+pub struct ProgressFile {
+    counts: Arc<Mutex<Counts>>,
+    start: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// How many tasks there are to run, not counting skipped ones.
+    tasks: usize,
+    started: usize,
+    succeeded: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl ProgressFile {
+    /// Counts for a run starting now.
+    pub fn new() -> Self {
+        ProgressFile {
+            counts: Arc::new(Mutex::new(Counts::default())),
+            start: Instant::now(),
+        }
+    }
+
+    /// How far the run has got, as JSON.
+    ///
+    /// The rate is how many tasks have finished a second, on average since the run started,
+    /// and the ETA is how many seconds the rest should take at that rate.
+    /// Both are null until a task has finished.
+    pub fn render(&self) -> serde_json::Value {
+        self.render_after(self.start.elapsed())
+    }
+
+    fn render_after(&self, elapsed: Duration) -> serde_json::Value {
+        let counts = self.counts.lock().unwrap();
+        let done = counts.succeeded + counts.failed;
+        let rate = match (done, elapsed.as_secs_f64()) {
+            (0, _) => None,
+            (_, secs) if secs <= 0.0 => None,
+            (done, secs) => Some(done as f64 / secs),
+        };
+        let remaining = counts.tasks.saturating_sub(done);
+        json!({
+            "total": counts.tasks,
+            "done": done,
+            "succeeded": counts.succeeded,
+            "failed": counts.failed,
+            "skipped": counts.skipped,
+            "in_flight": counts.started.saturating_sub(done),
+            "elapsed_secs": elapsed.as_secs_f64(),
+            "rate": rate,
+            "eta_secs": rate.map(|rate| remaining as f64 / rate),
+        })
+    }
+
+    /// Write the counts to `path`, replacing it atomically, so nothing reading it ever sees
+    /// half of them.
+    pub async fn write(&self, path: &Path) -> io::Result<()> {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        fs::write(&temp_path, format!("{}\n", self.render())).await?;
+        fs::rename(&temp_path, path).await
+    }
+
+    /// Write the counts to `path` every `interval`, until writing fails.
+    ///
+    /// Written on a timer rather than as tasks finish, so that the elapsed time and the ETA
+    /// keep moving even while every running task is a long one.
+    pub async fn keep_written(&self, path: &Path, interval: Duration) -> io::Result<()> {
+        loop {
+            self.write(path).await?;
+            time::sleep(interval).await;
+        }
+    }
+}
+
+impl Default for ProgressFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Progress for ProgressFile {
+    fn set_num_tasks(&self, tasks: usize) {
+        self.counts.lock().unwrap().tasks = tasks;
+    }
+
+    fn task_skipped(&self, _id: &TaskId, _input: &Path) {
+        self.counts.lock().unwrap().skipped += 1;
+    }
+
+    fn task_started(&self, _id: &TaskId, _input: &Path) {
+        self.counts.lock().unwrap().started += 1;
+    }
+
+    fn task_completed(&self, _id: &TaskId, outcome: &TaskOutcome<'_>) {
+        let mut counts = self.counts.lock().unwrap();
+        if outcome.task.succeeded() {
+            counts.succeeded += 1;
+        } else {
+            counts.failed += 1;
+        }
+    }
+
+    /// Warnings aren't progress, so they're left to whatever else reports it.
+    fn warn(&self, _warning: &Message<'_>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let progress_file = ProgressFile::new();
+        assert_eq!(
+            json!(null),
+            progress_file.render_after(Duration::from_secs(5))["eta_secs"]
+        );
+        *progress_file.counts.lock().unwrap() = Counts {
+            tasks: 10,
+            started: 6,
+            succeeded: 3,
+            failed: 1,
+            skipped: 2,
+        };
+        assert_eq!(
+            json!({
+                "total": 10,
+                "done": 4,
+                "succeeded": 3,
+                "failed": 1,
+                "skipped": 2,
+                "in_flight": 2,
+                "elapsed_secs": 8.0,
+                "rate": 0.5,
+                "eta_secs": 12.0,
+            }),
+            progress_file.render_after(Duration::from_secs(8))
+        );
+    }
+}
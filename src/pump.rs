@@ -0,0 +1,215 @@
+//! Copying bytes between asynchronous readers and writers.
+//!
+//! Wherever it can, reach connects a child process directly to a file by passing
+//! it a file descriptor, and the bytes never pass through reach at all.
+//! Where it can't, because a batch of several inputs goes to one command's standard
+//! input, one after another, the inputs are fed to it through a `Pump` instead. That's so
+//! whether the command runs here or on a worker, where its standard input is `ssh`'s.
+
+use futures::join;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// Copies everything from a reader to a writer through a fixed set of buffers.
+///
+/// Reading and writing happen concurrently, so the pump can read ahead by up to
+/// `buffers` chunks of `buffer_size` bytes while the writer catches up.
+/// Once every buffer is full, the pump stops reading until the writer frees one,
+/// so a slow writer pushes back on the reader rather than using unbounded memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pump {
+    buffer_size: usize,
+    buffers: usize,
+}
+
+impl Default for Pump {
+    fn default() -> Self {
+        Pump::new(64 * 1024, 4)
+    }
+}
+
+impl Pump {
+    /// A pump that reads in chunks of `buffer_size` bytes, keeping at most `buffers` of them in memory.
+    pub fn new(buffer_size: usize, buffers: usize) -> Self {
+        Pump {
+            buffer_size: buffer_size.max(1),
+            buffers: buffers.max(1),
+        }
+    }
+
+    /// Copy everything from `reader` to `writer`, then flush `writer`.
+    ///
+    /// Returns the number of bytes copied.
+    pub async fn copy<R, W>(&self, reader: &mut R, writer: &mut W) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let buffer_size = self.buffer_size;
+        let (filled_sender, mut filled) = mpsc::channel::<Vec<u8>>(self.buffers);
+        let (free_sender, mut free) = mpsc::channel::<Vec<u8>>(self.buffers);
+        for _ in 0..self.buffers {
+            free_sender
+                .try_send(Vec::with_capacity(buffer_size))
+                .expect("Free list has room for every buffer");
+        }
+
+        let read = async move {
+            // Stops when the writer is finished with the free list, which only happens if it failed.
+            while let Some(mut buffer) = free.recv().await {
+                buffer.resize(buffer_size, 0);
+                let size = reader.read(&mut buffer).await?;
+                if size == 0 {
+                    break;
+                }
+                buffer.truncate(size);
+                if filled_sender.send(buffer).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+
+        let write = async move {
+            let mut total = 0;
+            while let Some(buffer) = filled.recv().await {
+                writer.write_all(&buffer).await?;
+                total += buffer.len() as u64;
+                // Only fails once the reader has finished, when it no longer needs buffers.
+                let _ = free_sender.send(buffer).await;
+            }
+            writer.flush().await?;
+            Ok::<_, io::Error>(total)
+        };
+
+        let (read_result, write_result) = join!(read, write);
+        let total = write_result?;
+        read_result?;
+        Ok(total)
+    }
+
+    /// Copy everything from `reader` into a child process's standard input, then close it.
+    ///
+    /// A child is free to exit without reading all of its input, like `head` does.
+    /// That's not an error here: the child's exit status says whether it's a problem.
+    pub async fn feed<R, W>(&self, reader: &mut R, mut stdin: W) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin,
+    {
+        match self.copy(reader, &mut stdin).await {
+            Err(error) if error.kind() != io::ErrorKind::BrokenPipe => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+    use tokio::io::ReadBuf;
+
+    /// An endless stream of zeros that counts how much has been read from it.
+    struct CountingZeros {
+        read: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingZeros {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let size = buf.remaining();
+            buf.put_slice(&vec![0; size]);
+            self.read.fetch_add(size, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The standard input of a child like `head -c 10`, which goes away after reading a little.
+    struct HeadStdin {
+        remaining: usize,
+    }
+
+    impl AsyncWrite for HeadStdin {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.remaining == 0 {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            let size = buf.len().min(self.remaining);
+            self.remaining -= size;
+            Poll::Ready(Ok(size))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_throughput() -> io::Result<()> {
+        const SIZE: u64 = 64 * 1024 * 1024;
+        let (mut writer, mut drain) = tokio::io::duplex(1024 * 1024);
+        let mut reader = tokio::io::repeat(7).take(SIZE);
+
+        let start = Instant::now();
+        let pump = async move {
+            let copied = Pump::default().copy(&mut reader, &mut writer).await;
+            drop(writer);
+            copied
+        };
+        let mut sink = tokio::io::sink();
+        let (copied, drained) = join!(pump, tokio::io::copy(&mut drain, &mut sink));
+
+        assert_eq!(SIZE, copied?);
+        assert_eq!(SIZE, drained?);
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "Took {:?} to pump {} bytes",
+            start.elapsed(),
+            SIZE
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_feed_ignores_early_exit() -> io::Result<()> {
+        let mut stdin = HeadStdin { remaining: 10 };
+        let mut reader = tokio::io::repeat(7).take(1024 * 1024);
+        Pump::new(256, 2).feed(&mut reader, &mut stdin).await
+    }
+
+    #[tokio::test]
+    async fn test_copy_applies_backpressure() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let mut reader = CountingZeros { read: read.clone() };
+        // Nothing ever reads the other end, so writes stall once it holds 16 bytes.
+        let (mut writer, _stalled) = tokio::io::duplex(16);
+
+        let pump = Pump::new(1024, 2);
+        let result = tokio::time::timeout(
+            Duration::from_millis(100),
+            pump.copy(&mut reader, &mut writer),
+        )
+        .await;
+
+        assert!(result.is_err(), "Pump finished writing to a stalled writer");
+        // Two buffers waiting, plus the one being written.
+        assert!(read.load(Ordering::SeqCst) <= 3 * 1024);
+    }
+}
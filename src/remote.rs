@@ -0,0 +1,261 @@
+//! Running tasks on other machines, reached over SSH.
+//!
+//! Each task's command runs on one of the run's workers, as a POSIX shell script given to
+//! `sh` at the other end of an `ssh` connection. In stdin mode, the task's inputs are streamed
+//! over the connection. Otherwise, its input is first copied over the connection into a fresh
+//! temporary directory on the worker, and the command runs there, with the copy as its input.
+//! The command's exit status, standard output, and standard error come back over the connection
+//! into the task's destination directory, as if it had run locally.
+
+use async_trait::async_trait;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::capture::{Captured, Outputs};
+use crate::error;
+use crate::pool::Reservation;
+use crate::template::{shell_words, Quoting};
+use crate::{Launcher, Process, Refusal, Runner, Task, Worker};
+
+/// Launches each task's command on one of a run's workers, using `runner`'s script for it.
+pub(crate) struct OnWorkers<R> {
+    runner: R,
+    ssh: String,
+    outputs: Outputs,
+    workers: Vec<Worker>,
+    /// A permit for every slot on every worker.
+    slots: Arc<Semaphore>,
+    /// How many of each worker's slots are free.
+    free: Arc<Mutex<Vec<usize>>>,
+}
+
+impl<R> OnWorkers<R> {
+    /// Run tasks on `workers`, connecting to them with the program `ssh`, with their output
+    /// going to `outputs`.
+    pub(crate) fn new(runner: R, ssh: String, workers: Vec<Worker>, outputs: Outputs) -> Self {
+        OnWorkers {
+            runner,
+            ssh,
+            outputs,
+            slots: Arc::new(Semaphore::new(total_slots(&workers))),
+            free: Arc::new(Mutex::new(
+                workers.iter().map(|worker| worker.slots).collect(),
+            )),
+            workers,
+        }
+    }
+
+    /// Wait for a free slot, on whichever worker has the most of them.
+    async fn claim(&self) -> Slot {
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Worker slots are never closed");
+        let mut free = self.free.lock().unwrap();
+        // The permit means at least one worker has a free slot. Ties go to the first worker.
+        let worker = (0..free.len())
+            .rev()
+            .max_by_key(|&worker| free[worker])
+            .expect("There's always a worker");
+        free[worker] -= 1;
+        Slot {
+            worker,
+            free: self.free.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+/// How many tasks `workers` can run at once between them.
+pub(crate) fn total_slots(workers: &[Worker]) -> usize {
+    workers.iter().map(|worker| worker.slots).sum()
+}
+
+#[async_trait]
+impl<R: Runner + Sync> Launcher for OnWorkers<R> {
+    type Process = OnWorker;
+
+    async fn launch(&self, task: &Task<'_>, _reservation: Reservation) -> io::Result<OnWorker> {
+        let mut env = task.env();
+        let script = self.runner.script(task.inputs, task.dir())?;
+        let line = if script.stdin.is_empty() {
+            let copy = match task.inputs {
+                [input] => Path::new(".").join(input.file_name().unwrap_or_default()),
+                _ => return Err(error::refused(Refusal::WorkerCopies)),
+            };
+            let script = self
+                .runner
+                .script(std::slice::from_ref(&copy), task.dir())?;
+            for (name, value) in env.iter_mut() {
+                if *name == "REACH_INPUT" {
+                    *value = copy.clone().into();
+                }
+            }
+            copying_line(&script.text, &copy, &env)?
+        } else {
+            streaming_line(&script.text, &env)?
+        };
+        // As in stdin mode, a single input is the connection's standard input itself.
+        let stdin: Stdio = match task.inputs {
+            [input] => fs::File::open(input).await?.into_std().await.into(),
+            _ => Stdio::piped(),
+        };
+        let slot = self.claim().await;
+        let mut command = Command::new(&self.ssh);
+        command
+            .args(["-o", "BatchMode=yes", "--"])
+            .arg(&self.workers[slot.worker].destination)
+            .arg(line)
+            .stdin(stdin);
+        // Commands on workers are never confined, so there are no inputs to let them read.
+        let mut process = self
+            .outputs
+            .spawn(command, task.name(), &[], task.dir(), None)
+            .await?;
+        process.feed(task.inputs, None);
+        Ok(OnWorker {
+            process,
+            _slot: slot,
+        })
+    }
+}
+
+/// A task's command running on a worker, holding one of its slots until it's finished.
+pub(crate) struct OnWorker {
+    process: Captured,
+    _slot: Slot,
+}
+
+#[async_trait]
+impl Process for OnWorker {
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.process.wait().await
+    }
+
+    /// Stop the connection to the worker.
+    ///
+    /// Without a terminal, `ssh` doesn't pass signals on, so the command may carry on running
+    /// on the worker until it next writes any output.
+    async fn terminate(&mut self) -> io::Result<()> {
+        self.process.terminate().await
+    }
+
+    fn clipped(&self) -> Vec<String> {
+        self.process.clipped()
+    }
+}
+
+/// A claim on one of a worker's slots, given back when it's dropped.
+struct Slot {
+    worker: usize,
+    free: Arc<Mutex<Vec<usize>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.free.lock().unwrap()[self.worker] += 1;
+    }
+}
+
+/// The command line for `sh` on a worker to run `script`, reading its input from the connection,
+/// with environment variables `env`.
+fn streaming_line(script: &OsStr, env: &[(&str, OsString)]) -> io::Result<OsString> {
+    let mut line = exports(env)?;
+    line.push("eval ");
+    line.push(quote(script)?);
+    sh_line(line)
+}
+
+/// The command line for `sh` on a worker to copy the input it reads from the connection to
+/// `copy`, in a fresh temporary directory, and to run `script` in that directory, with
+/// environment variables `env`.
+///
+/// The directory is removed once the script has finished.
+fn copying_line(script: &OsStr, copy: &Path, env: &[(&str, OsString)]) -> io::Result<OsString> {
+    let mut line = OsString::from("d=$(mktemp -d) || exit; cd \"$d\" && cat > ");
+    line.push(quote(copy.as_os_str())?);
+    // The script runs in a subshell, so that the directory is removed even if it exits early.
+    line.push(" && ( ");
+    line.push(exports(env)?);
+    line.push("eval ");
+    line.push(quote(script)?);
+    line.push(" ); s=$?; cd / && rm -rf -- \"$d\"; exit $s");
+    sh_line(line)
+}
+
+/// An `export` of every one of `env`, followed by `; `, or nothing if there are none.
+fn exports(env: &[(&str, OsString)]) -> io::Result<OsString> {
+    let mut line = OsString::new();
+    if !env.is_empty() {
+        line.push("export");
+        for (name, value) in env {
+            line.push(format!(" {}=", name));
+            line.push(quote(value)?);
+        }
+        line.push("; ");
+    }
+    Ok(line)
+}
+
+/// The command to give `ssh`, which the worker's login shell runs, whatever shell that is.
+fn sh_line(script: OsString) -> io::Result<OsString> {
+    shell_words(&["sh".into(), "-c".into(), script])
+}
+
+fn quote(s: &OsStr) -> io::Result<OsString> {
+    Quoting::Posix.quote_os(s).map(|quoted| quoted.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Run a command line the way a worker's shell would, with `stdin` as its input.
+    fn run_line(line: &OsStr, stdin: &str) -> io::Result<(Option<i32>, String)> {
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(line)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(stdin.as_bytes())?;
+        let output = child.wait_with_output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        Ok((output.status.code(), stdout))
+    }
+
+    #[test]
+    fn test_streaming_line() -> io::Result<()> {
+        let env = vec![("REACH_INPUT_NAME", OsString::from("it's"))];
+        let line = streaming_line(OsStr::new("echo \"$REACH_INPUT_NAME\"; tr a-z A-Z"), &env)?;
+        assert_eq!(
+            (Some(0), "it's\nHELLO\n".into()),
+            run_line(&line, "hello\n")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copying_line() -> io::Result<()> {
+        let copy = Path::new("./in.txt");
+        let line = copying_line(OsStr::new("cat ./in.txt; pwd"), copy, &[])?;
+        let (status, output) = run_line(&line, "hello\n")?;
+        assert_eq!(Some(0), status);
+        let dir = output.strip_prefix("hello\n").unwrap().trim_end();
+        assert!(!Path::new(dir).exists(), "{} was left behind", dir);
+
+        let line = copying_line(OsStr::new("exit 3"), copy, &[])?;
+        assert_eq!(Some(3), run_line(&line, "")?.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! How each run was started, recorded in its state directory, so that some of its tasks can be
+//! run again later with `reach rerun-matching [--state-dir DIR] DESTINATION PATTERN`, without
+//! giving its command, source, and options all over again.
+
+use serde_json::json;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use reach::Message;
+
+/// The first argument that makes reach rerun some of an earlier run's tasks.
+pub(crate) const RERUN_MATCHING: &str = "rerun-matching";
+
+/// The file in the state directory that says how the last run was started.
+const INVOCATION_FILE: &str = "invocation.json";
+
+/// How reach was started: the directory it ran in, and its arguments, including the settings
+/// from its config file, if it had one, and the state directory it was recorded in, once it's
+/// been read back.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Invocation {
+    pub(crate) dir: PathBuf,
+    pub(crate) args: Vec<OsString>,
+    pub(crate) state_dir: Option<PathBuf>,
+}
+
+impl Invocation {
+    /// Record the invocation in the state directory `state_dir`, creating it if need be.
+    ///
+    /// Arguments that aren't unicode can't be recorded, and then nothing is, so the run
+    /// can't be rerun this way.
+    pub(crate) async fn write(&self, state_dir: &Path) -> io::Result<()> {
+        let state_dir = self.dir.join(state_dir);
+        let args: Option<Vec<&str>> = self.args.iter().map(|arg| arg.to_str()).collect();
+        let (dir, args, recorded_state_dir) = match (self.dir.to_str(), args, state_dir.to_str()) {
+            (Some(dir), Some(args), Some(state_dir)) => (dir, args, state_dir),
+            _ => return Ok(()),
+        };
+        let contents = format!(
+            "{:#}\n",
+            json!({"dir": dir, "args": args, "state_dir": recorded_state_dir})
+        );
+        fs::create_dir_all(&state_dir).await?;
+        let path = state_dir.join(INVOCATION_FILE);
+        let temp_path = state_dir.join(format!(".{}.tmp", INVOCATION_FILE));
+        fs::write(&temp_path, contents).await?;
+        fs::rename(&temp_path, &path).await
+    }
+
+    /// How the last run with the state directory `state_dir` and the destination
+    /// `destination_dir` was started.
+    pub(crate) async fn read(state_dir: &Path, destination_dir: &Path) -> io::Result<Self> {
+        let path = state_dir.join(INVOCATION_FILE);
+        let contents = match fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let message = Message::NoRunRecorded {
+                    destination: destination_dir,
+                };
+                return Err(io::Error::new(io::ErrorKind::NotFound, message.text()));
+            }
+            Err(error) => return Err(error),
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                Message::InvalidRunRecord(&path).text(),
+            )
+        };
+        let record: serde_json::Value = serde_json::from_str(&contents).map_err(|_| invalid())?;
+        let dir = record["dir"].as_str().ok_or_else(invalid)?;
+        let args = record["args"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|arg| arg.as_str().map(OsString::from))
+            .collect::<Option<Vec<_>>>()
+            .filter(|args| !args.is_empty())
+            .ok_or_else(invalid)?;
+        // Records from before the state directory was recorded were in the one they're read from.
+        let state_dir = record["state_dir"]
+            .as_str()
+            .map_or_else(|| state_dir.to_owned(), PathBuf::from);
+        Ok(Invocation {
+            dir: dir.into(),
+            args,
+            state_dir: Some(state_dir),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_invocation() -> io::Result<()> {
+        let destination = tempfile::tempdir()?;
+        let state_dir = destination.path().join("state");
+        let read = || Invocation::read(&state_dir, destination.path());
+        assert_eq!(io::ErrorKind::NotFound, read().await.unwrap_err().kind());
+
+        let invocation = Invocation {
+            dir: "/work".into(),
+            args: vec!["reach".into(), "wc -l".into(), "src".into()],
+            state_dir: None,
+        };
+        invocation.write(&state_dir).await?;
+        assert_eq!(
+            Invocation {
+                state_dir: Some(state_dir.clone()),
+                ..invocation.clone()
+            },
+            read().await?
+        );
+
+        std::fs::write(state_dir.join(INVOCATION_FILE), "{\"dir\": \"/work\"}")?;
+        assert_eq!(io::ErrorKind::InvalidData, read().await.unwrap_err().kind());
+        Ok(())
+    }
+}
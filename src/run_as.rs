@@ -0,0 +1,209 @@
+//! Running commands as another user, like `nobody`, when reach itself runs as root.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::process::Command;
+
+use crate::{error, Message};
+
+/// The user and group that commands run as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunAs {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl RunAs {
+    /// Check that reach is allowed to run commands as this user, which needs root,
+    /// unless it's who reach is running as already.
+    pub(crate) fn check_allowed(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        // SAFETY: These only read the process's own IDs, and can't fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        #[cfg(not(unix))]
+        let (uid, gid) = (0, 0);
+        if uid == 0 || (uid, gid) == (self.uid, self.gid) {
+            Ok(())
+        } else {
+            Err(error::new(
+                io::ErrorKind::PermissionDenied,
+                Message::RunAsNotRoot(self),
+            ))
+        }
+    }
+
+    /// Have `command` run as this user and group, with no supplementary groups.
+    pub(crate) fn apply(&self, command: &mut Command) {
+        #[cfg(unix)]
+        command.uid(self.uid).gid(self.gid);
+        #[cfg(not(unix))]
+        let _ = command;
+    }
+
+    /// Give `path` to this user and group, so that commands can write there.
+    pub(crate) fn own(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid))?;
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+}
+
+impl fmt::Display for RunAs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.uid, self.gid)
+    }
+}
+
+/// Parses `user:group`, or just `user` for the user's own group, where each is a name,
+/// like `nobody:nogroup`, or a number, like `65534:65534`.
+impl FromStr for RunAs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        let (uid, user_gid) = match user.parse() {
+            Ok(uid) => (uid, None),
+            Err(_) => {
+                let (uid, gid) = lookup_user(user)?;
+                (uid, Some(gid))
+            }
+        };
+        let gid = match (group, user_gid) {
+            (Some(group), _) => match group.parse() {
+                Ok(gid) => gid,
+                Err(_) => lookup_group(group)?,
+            },
+            (None, Some(gid)) => gid,
+            (None, None) => match lookup_uid(uid)? {
+                Some(gid) => gid,
+                None => {
+                    return Err(format!(
+                        "User {} has no group, so give one: {}:GROUP",
+                        uid, uid
+                    ))
+                }
+            },
+        };
+        Ok(RunAs { uid, gid })
+    }
+}
+
+/// The name's user ID, and the ID of its primary group.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<(u32, u32), String> {
+    let not_found = || format!("No such user: {:?}", name);
+    let c_name = std::ffi::CString::new(name).map_err(|_| not_found())?;
+    // SAFETY: `getpwnam_r` writes no more than `len` bytes to `buffer`.
+    let passwd = lookup(|passwd, buffer, len, result| unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), passwd, buffer, len, result)
+    })
+    .map_err(|error| format!("Couldn't look up user {:?}: {}", name, error))?;
+    passwd
+        .map(|passwd: libc::passwd| (passwd.pw_uid, passwd.pw_gid))
+        .ok_or_else(not_found)
+}
+
+/// The ID of the primary group of the user with ID `uid`, if there is such a user.
+#[cfg(unix)]
+fn lookup_uid(uid: u32) -> Result<Option<u32>, String> {
+    // SAFETY: As for `getpwnam_r`.
+    let passwd = lookup(|passwd, buffer, len, result| unsafe {
+        libc::getpwuid_r(uid, passwd, buffer, len, result)
+    })
+    .map_err(|error| format!("Couldn't look up user {}: {}", uid, error))?;
+    Ok(passwd.map(|passwd: libc::passwd| passwd.pw_gid))
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<u32, String> {
+    let not_found = || format!("No such group: {:?}", name);
+    let c_name = std::ffi::CString::new(name).map_err(|_| not_found())?;
+    // SAFETY: As for `getpwnam_r`.
+    let group = lookup(|group, buffer, len, result| unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), group, buffer, len, result)
+    })
+    .map_err(|error| format!("Couldn't look up group {:?}: {}", name, error))?;
+    group
+        .map(|group: libc::group| group.gr_gid)
+        .ok_or_else(not_found)
+}
+
+/// Look up a record with one of the reentrant `getpwnam_r` family, which fills in the
+/// record, with its strings in a buffer, and points the result at it if it's found.
+///
+/// The strings aren't needed, so they don't outlive the buffer.
+#[cfg(unix)]
+fn lookup<T>(
+    get: impl Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+) -> io::Result<Option<T>> {
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut record = std::mem::MaybeUninit::<T>::uninit();
+        let mut result = std::ptr::null_mut();
+        match get(
+            record.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        ) {
+            // SAFETY: A non-null result points at the record, which has been filled in.
+            0 if !result.is_null() => return Ok(Some(unsafe { record.assume_init() })),
+            0 => return Ok(None),
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            error => return Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup_user(name: &str) -> Result<(u32, u32), String> {
+    Err(format!("Users can only be looked up on Unix: {:?}", name))
+}
+
+#[cfg(not(unix))]
+fn lookup_uid(_uid: u32) -> Result<Option<u32>, String> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+fn lookup_group(name: &str) -> Result<u32, String> {
+    Err(format!("Groups can only be looked up on Unix: {:?}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(RunAs {
+                uid: 1000,
+                gid: 100
+            }),
+            "1000:100".parse()
+        );
+        assert_eq!(Ok(RunAs { uid: 0, gid: 0 }), "root".parse());
+        assert_eq!(Ok(RunAs { uid: 0, gid: 0 }), "root:root".parse());
+        assert_eq!(Ok(RunAs { uid: 0, gid: 7 }), "root:7".parse());
+        assert_eq!(Ok(RunAs { uid: 0, gid: 0 }), "0".parse());
+        assert!("no-such-user-here".parse::<RunAs>().is_err());
+        assert!("root:no-such-group-here".parse::<RunAs>().is_err());
+        assert_eq!(
+            "1000:100",
+            RunAs {
+                uid: 1000,
+                gid: 100
+            }
+            .to_string()
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! Persistent shell sessions, which run one task after another without starting a new shell for each.
+//!
+//! Each session is a POSIX shell reading a small protocol on its standard input.
+//! For every task, reach writes one line that runs the task's script in a subshell,
+//! with its standard input and output redirected to the task's files, then echoes
+//! its exit status. The subshell is a fork of an already-running shell, so a task
+//! costs one fork and whatever its script execs, rather than a fresh shell too.
+
+use async_trait::async_trait;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time;
+
+use crate::capture::Outputs;
+use crate::error;
+use crate::limits::{CpuClaim, Limits};
+use crate::pool::{Lease, Pool, Reservation};
+use crate::template::Quoting;
+use crate::{Capture, Launcher, Message, Process, Refusal, RunAs, Runner, Task, KILL_GRACE_PERIOD};
+
+/// A shell script that runs a task, and the files to give it as standard input.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Script {
+    pub(crate) text: OsString,
+    /// Read one after another. None at all means `/dev/null`.
+    pub(crate) stdin: Vec<PathBuf>,
+}
+
+/// Launches each task's command in a shell session, using `runner`'s script for it.
+pub(crate) struct InSessions<R> {
+    runner: R,
+    shell: String,
+    outputs: Outputs,
+    sessions: Pool<Session>,
+}
+
+impl<R> InSessions<R> {
+    /// Run tasks in sessions taken from `sessions`, which start `shell`, with their output
+    /// going to `outputs`.
+    pub(crate) fn new(
+        runner: R,
+        shell: String,
+        outputs: Outputs,
+        sessions: Pool<Session>,
+    ) -> io::Result<Self> {
+        if Quoting::for_shell(&shell) != Quoting::Posix {
+            return Err(error::new(
+                io::ErrorKind::InvalidInput,
+                Message::SessionsShell(&shell),
+            ));
+        }
+        // The shell redirects a task's output straight to its files, so there's nowhere to tag
+        // or clip it.
+        if outputs.capture() == Capture::Tag {
+            return Err(error::refused(Refusal::SessionsTagged));
+        }
+        if outputs.max_size().is_some() {
+            return Err(error::refused(Refusal::SessionsClipped));
+        }
+        Ok(InSessions {
+            runner,
+            shell,
+            outputs,
+            sessions,
+        })
+    }
+}
+
+#[async_trait]
+impl<R: Runner + Sync> Launcher for InSessions<R> {
+    type Process = Lease<Session>;
+
+    async fn reserve(&self, task: &Task<'_>) -> Reservation {
+        self.sessions.reserve(task.inputs, task.dir()).await
+    }
+
+    async fn launch(
+        &self,
+        task: &Task<'_>,
+        reservation: Reservation,
+    ) -> io::Result<Lease<Session>> {
+        let script = self.runner.script(task.inputs, task.dir())?;
+        let mut session = self.sessions.take(reservation, || {
+            Session::start(&self.shell, self.outputs.run_as(), self.outputs.limits())
+        })?;
+        let line = task_line(
+            &script,
+            &self.outputs,
+            task.dir(),
+            task.workdir,
+            &task.env(),
+        )?;
+        let result = session.start_task(&line).await;
+        match result {
+            Ok(()) => Ok(session),
+            Err(error) => {
+                self.finished(session);
+                Err(error)
+            }
+        }
+    }
+
+    /// Give back a session once its task has finished, unless it's no longer usable.
+    fn finished(&self, session: Lease<Session>) {
+        if !session.broken {
+            self.sessions.put(session);
+        }
+    }
+}
+
+/// A running shell that runs tasks one at a time.
+pub(crate) struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    /// What's been read of the current status line. Kept here so that a
+    /// cancelled `wait` doesn't lose part of it.
+    line: Vec<u8>,
+    /// Whether the session has died, or got out of step with its protocol.
+    broken: bool,
+    /// The CPUs the session has to itself, for as long as it runs.
+    _cpus: Option<CpuClaim>,
+}
+
+impl Session {
+    fn start(shell: &str, run_as: Option<RunAs>, limits: &Limits) -> io::Result<Self> {
+        let mut command = Command::new(shell);
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(run_as) = run_as {
+            run_as.apply(&mut command);
+        }
+        // Every task the session runs is a fork of it, so they're all within its limits.
+        let cpus = limits.apply(&mut command);
+        // Giving the session its own process group lets us terminate a task's whole process tree.
+        crate::in_own_process_group(&mut command);
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().expect("Session stdin is piped");
+        let stdout = child.stdout.take().expect("Session stdout is piped");
+        Ok(Session {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            line: Vec::new(),
+            broken: false,
+            _cpus: cpus,
+        })
+    }
+
+    /// Start running a task, given its line of the protocol.
+    async fn start_task(&mut self, line: &[u8]) -> io::Result<()> {
+        let result = self.stdin.write_all(line).await;
+        if result.is_err() {
+            self.broken = true;
+        }
+        result
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        if let Some(pid) = self.child.id() {
+            // SAFETY: `kill` has no memory safety requirements. The session leads its own
+            // process group, which can't be reused while our unreaped child is in it.
+            unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+        }
+    }
+}
+
+#[async_trait]
+impl Process for Session {
+    /// Wait for the current task to finish.
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        let size = self.stdout.read_until(b'\n', &mut self.line).await;
+        let line = std::mem::take(&mut self.line);
+        let status = match size {
+            Ok(0) => Err(error::new(
+                io::ErrorKind::UnexpectedEof,
+                Message::SessionExited,
+            )),
+            Ok(_) => parse_status(&line),
+            Err(error) => Err(error),
+        };
+        if status.is_err() {
+            self.broken = true;
+        }
+        status
+    }
+
+    /// Terminate the current task, and the session along with it.
+    ///
+    /// Sends SIGTERM to everything in the session's process group, waits up to
+    /// `KILL_GRACE_PERIOD` for the session to exit, then kills everything.
+    async fn terminate(&mut self) -> io::Result<()> {
+        self.broken = true;
+        self.signal(libc::SIGTERM);
+        let _ = time::timeout(KILL_GRACE_PERIOD, self.child.wait()).await;
+        // Even if the shell has gone, the task's own processes may have ignored SIGTERM.
+        self.signal(libc::SIGKILL);
+        self.child.wait().await.map(|_| ())
+    }
+}
+
+/// The line of the protocol that runs `script` for the task with destination directory
+/// `task_dir`, in `workdir` if it has one, with environment variables `env` and its
+/// output going to `outputs`.
+///
+/// The script is `eval`ed inside the subshell, so that even a syntax error only
+/// fails the task, with the shell's complaint in the task's `err`.
+fn task_line(
+    script: &Script,
+    outputs: &Outputs,
+    task_dir: &Path,
+    workdir: Option<&Path>,
+    env: &[(&str, OsString)],
+) -> io::Result<Vec<u8>> {
+    let quote = |s: &OsStr| Quoting::Posix.quote_os(s).map(|quoted| quoted.into_owned());
+    let mut line = OsString::new();
+    // A batch's inputs can't all be redirected to its standard input, so they're piped.
+    if script.stdin.len() > 1 {
+        line.push("cat --");
+        for input in &script.stdin {
+            line.push(" ");
+            line.push(quote(input.as_os_str())?);
+        }
+        line.push(" | ");
+    }
+    line.push("( ");
+    // Only the subshell moves, so the session stays where it started.
+    if let Some(workdir) = workdir {
+        line.push("cd -- ");
+        line.push(quote(workdir.as_os_str())?);
+        line.push(" || exit; ");
+    }
+    if !env.is_empty() {
+        line.push("export");
+        for (name, value) in env {
+            line.push(format!(" {}=", name));
+            line.push(quote(value)?);
+        }
+        line.push("; ");
+    }
+    line.push("eval ");
+    line.push(quote(&script.text)?);
+    line.push(" )");
+    match script.stdin.as_slice() {
+        [] => line.push(" < /dev/null"),
+        [input] => {
+            line.push(" < ");
+            line.push(quote(input.as_os_str())?);
+        }
+        _ => {}
+    }
+    line.push(" > ");
+    match outputs.stdout_path(task_dir) {
+        Some(stdout) => line.push(quote(stdout.as_os_str())?),
+        None => line.push("/dev/null"),
+    }
+    match outputs.stderr_path(task_dir) {
+        Some(stderr) => {
+            line.push(" 2> ");
+            line.push(quote(stderr.as_os_str())?);
+        }
+        None => line.push(" 2>&1"),
+    }
+    line.push("; echo \"$?\"\n");
+    use std::os::unix::ffi::OsStringExt;
+    Ok(line.into_vec())
+}
+
+/// Turn the shell's `$?` back into an exit status.
+///
+/// Shells report death by signal N as 128 + N, which can't be told apart from
+/// exiting with that code. Anything that could be a signal is taken to be one.
+fn parse_status(line: &[u8]) -> io::Result<ExitStatus> {
+    let code: i32 = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| {
+            error::new(
+                io::ErrorKind::InvalidData,
+                Message::SessionBadStatus(&String::from_utf8_lossy(line)),
+            )
+        })?;
+    // Linux has 64 signals, and other systems fewer.
+    Ok(match code - 128 {
+        signal @ 1..=64 => ExitStatus::from_raw(signal),
+        _ => ExitStatus::from_raw((code & 0xff) << 8),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(capture: Capture) -> Outputs {
+        Outputs::new(capture, "out", "err").unwrap()
+    }
+
+    #[test]
+    fn test_task_line() {
+        let script = Script {
+            text: "cat | tr a-z A-Z".into(),
+            stdin: vec![PathBuf::from("/src/it's.txt")],
+        };
+        assert_eq!(
+            r#"( eval 'cat | tr a-z A-Z' ) < '/src/it'\''s.txt' > /dest/out 2> /dest/err; echo "$?""#
+                .to_string()
+                + "\n",
+            String::from_utf8(
+                task_line(
+                    &script,
+                    &outputs(Capture::Separate),
+                    Path::new("/dest"),
+                    None,
+                    &[]
+                )
+                .unwrap()
+            )
+            .unwrap()
+        );
+        let line = |capture, env| {
+            let line =
+                task_line(&script, &outputs(capture), Path::new("/dest"), None, env).unwrap();
+            String::from_utf8(line).unwrap()
+        };
+        let env = [("REACH_ATTEMPT", OsString::from("1"))];
+        assert!(line(Capture::Separate, &env).starts_with("( export REACH_ATTEMPT=1; eval "));
+        assert!(line(Capture::Merge, &[]).contains(" > /dest/out 2>&1;"));
+        assert!(line(Capture::Discard, &[]).contains(" > /dev/null 2>&1;"));
+        let in_workdir = task_line(
+            &script,
+            &outputs(Capture::Separate),
+            Path::new("/dest"),
+            Some(Path::new("/scratch/a b")),
+            &env,
+        )
+        .unwrap();
+        assert!(String::from_utf8(in_workdir)
+            .unwrap()
+            .starts_with("( cd -- '/scratch/a b' || exit; export REACH_ATTEMPT=1; eval "));
+        let script = Script {
+            text: "wc -l".into(),
+            stdin: vec![PathBuf::from("/src/a"), PathBuf::from("/src/b")],
+        };
+        assert!(String::from_utf8(
+            task_line(
+                &script,
+                &outputs(Capture::Separate),
+                Path::new("/dest"),
+                None,
+                &[]
+            )
+            .unwrap()
+        )
+        .unwrap()
+        .starts_with("cat -- /src/a /src/b | ( eval 'wc -l' ) > /dest/out"));
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(Some(0), parse_status(b"0\n").unwrap().code());
+        assert_eq!(Some(3), parse_status(b"3\n").unwrap().code());
+        assert_eq!(Some(255), parse_status(b"255\n").unwrap().code());
+        assert_eq!(
+            Some(libc::SIGKILL),
+            parse_status(b"137\n").unwrap().signal()
+        );
+        assert!(parse_status(b"oops\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_runs_tasks_in_turn() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut session = Session::start("sh", None, &Limits::default())?;
+        for (text, code) in &[("exit 3", 3), ("if then", 2), ("echo ok", 0)] {
+            let script = Script {
+                text: (*text).into(),
+                stdin: Vec::new(),
+            };
+            session
+                .start_task(&task_line(
+                    &script,
+                    &outputs(Capture::Separate),
+                    dir.path(),
+                    None,
+                    &[],
+                )?)
+                .await?;
+            assert_eq!(Some(*code), session.wait().await?.code(), "{}", text);
+        }
+        assert_eq!("ok\n", std::fs::read_to_string(dir.path().join("out"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sessions_need_posix_shell() {
+        let new = |shell: &str, capture| {
+            InSessions::new((), shell.into(), outputs(capture), Pool::new()).is_ok()
+        };
+        assert!(new("/bin/bash", Capture::Separate));
+        assert!(!new("pwsh", Capture::Separate));
+        assert!(!new("/bin/bash", Capture::Tag));
+    }
+}
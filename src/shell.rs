@@ -0,0 +1,140 @@
+//! Shells that run command lines: POSIX shells everywhere, and on Windows, `cmd` and PowerShell.
+//!
+//! Each kind of shell is asked to run a command line in its own way, and takes its own
+//! quoting in it. The kind is told from the shell's name, as `Quoting::for_shell` does.
+
+use std::env;
+use std::ffi::OsString;
+use tokio::process::Command;
+
+use crate::template::Quoting;
+
+/// A shell, like `/bin/bash`, `pwsh`, or `C:\Windows\System32\cmd.exe`, that runs
+/// command lines for tasks and hooks.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Shell {
+    program: String,
+    quoting: Quoting,
+}
+
+impl Shell {
+    pub(crate) fn new(program: &str) -> Self {
+        Shell {
+            program: program.into(),
+            quoting: Quoting::for_shell(program),
+        }
+    }
+
+    /// How to quote values substituted into command lines for this shell.
+    pub(crate) fn quoting(&self) -> Quoting {
+        self.quoting
+    }
+
+    /// The shell followed by the arguments that have it run `script` and exit.
+    pub(crate) fn argv(&self, script: impl Into<OsString>) -> Vec<OsString> {
+        let script = script.into();
+        let program = OsString::from(&self.program);
+        match self.quoting {
+            Quoting::Posix => vec![program, "-c".into(), script],
+            Quoting::PowerShell => vec![
+                program,
+                "-NoLogo".into(),
+                "-NoProfile".into(),
+                "-NonInteractive".into(),
+                "-Command".into(),
+                script,
+            ],
+            // With `/S`, cmd takes off exactly the quotes around the script, and leaves any
+            // inside it alone. `/D` skips any AutoRun commands in the registry.
+            Quoting::Cmd => {
+                let mut quoted = OsString::from("\"");
+                quoted.push(script);
+                quoted.push("\"");
+                vec![program, "/D".into(), "/S".into(), "/C".into(), quoted]
+            }
+        }
+    }
+
+    /// A command that has the shell run `script`.
+    pub(crate) fn command(&self, script: impl Into<OsString>) -> Command {
+        command(self.argv(script))
+    }
+}
+
+/// A command that runs `argv`, a program followed by its arguments, exactly.
+///
+/// On Windows, a program gets one command line rather than separate arguments, and most
+/// split it the way the C runtime does, which is how `Command` quotes them. cmd doesn't:
+/// it runs the rest of its command line as it is, so its arguments are passed as they are.
+pub(crate) fn command(argv: Vec<OsString>) -> Command {
+    let mut words = argv.into_iter();
+    let program = words.next().expect("Commands are never empty");
+    #[cfg(windows)]
+    if Quoting::for_shell(&program.to_string_lossy()) == Quoting::Cmd {
+        use std::os::windows::process::CommandExt;
+        let mut command = std::process::Command::new(&program);
+        for word in words {
+            command.raw_arg(word);
+        }
+        return Command::from(command);
+    }
+    let mut command = Command::new(program);
+    command.args(words);
+    command
+}
+
+/// The shell to use if none is given: `$SHELL`, or `/bin/sh` if that isn't set,
+/// or on Windows, `%ComSpec%`, or `cmd.exe` if that isn't set.
+pub(crate) fn default_shell() -> String {
+    #[cfg(windows)]
+    let (variable, fallback) = ("ComSpec", "cmd.exe");
+    #[cfg(not(windows))]
+    let (variable, fallback) = ("SHELL", "/bin/sh");
+    env::var(variable).unwrap_or_else(|_| fallback.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argv() {
+        let argv = |shell: &str| {
+            Shell::new(shell)
+                .argv("echo hi")
+                .into_iter()
+                .map(|word| word.into_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["/bin/sh", "-c", "echo hi"], argv("/bin/sh"));
+        assert_eq!(
+            vec![
+                "pwsh",
+                "-NoLogo",
+                "-NoProfile",
+                "-NonInteractive",
+                "-Command",
+                "echo hi"
+            ],
+            argv("pwsh")
+        );
+        assert_eq!(
+            vec![
+                r"C:\Windows\System32\cmd.exe",
+                "/D",
+                "/S",
+                "/C",
+                "\"echo hi\""
+            ],
+            argv(r"C:\Windows\System32\cmd.exe")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command() -> std::io::Result<()> {
+        let output = Shell::new("sh").command("echo \"$0\"").output().await?;
+        assert_eq!(b"sh\n", &output.stdout[..]);
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! Signals by name, like `SIGKILL`, for recording how commands died and for configuring
+//! what to do about it.
+
+/// The signals with names, in the order `kill -l` lists them.
+#[cfg(unix)]
+const NAMES: &[(libc::c_int, &str)] = &[
+    (libc::SIGHUP, "SIGHUP"),
+    (libc::SIGINT, "SIGINT"),
+    (libc::SIGQUIT, "SIGQUIT"),
+    (libc::SIGILL, "SIGILL"),
+    (libc::SIGTRAP, "SIGTRAP"),
+    (libc::SIGABRT, "SIGABRT"),
+    (libc::SIGBUS, "SIGBUS"),
+    (libc::SIGFPE, "SIGFPE"),
+    (libc::SIGKILL, "SIGKILL"),
+    (libc::SIGUSR1, "SIGUSR1"),
+    (libc::SIGSEGV, "SIGSEGV"),
+    (libc::SIGUSR2, "SIGUSR2"),
+    (libc::SIGPIPE, "SIGPIPE"),
+    (libc::SIGALRM, "SIGALRM"),
+    (libc::SIGTERM, "SIGTERM"),
+    (libc::SIGCHLD, "SIGCHLD"),
+    (libc::SIGCONT, "SIGCONT"),
+    (libc::SIGSTOP, "SIGSTOP"),
+    (libc::SIGTSTP, "SIGTSTP"),
+    (libc::SIGTTIN, "SIGTTIN"),
+    (libc::SIGTTOU, "SIGTTOU"),
+    (libc::SIGURG, "SIGURG"),
+    (libc::SIGXCPU, "SIGXCPU"),
+    (libc::SIGXFSZ, "SIGXFSZ"),
+    (libc::SIGVTALRM, "SIGVTALRM"),
+    (libc::SIGPROF, "SIGPROF"),
+    (libc::SIGWINCH, "SIGWINCH"),
+    (libc::SIGIO, "SIGIO"),
+    (libc::SIGSYS, "SIGSYS"),
+];
+
+#[cfg(not(unix))]
+const NAMES: &[(i32, &str)] = &[];
+
+/// The name of `signal`, like `SIGKILL` for 9, if it has one here.
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|(number, _)| *number == signal)
+        .map(|(_, name)| *name)
+}
+
+/// Parse a signal given by name, with or without its `SIG`, in either case, like `KILL`
+/// or `sigkill`, or by number, like `9`.
+pub fn parse_signal(s: &str) -> Result<i32, String> {
+    if let Ok(number) = s.parse() {
+        return match number {
+            1..=127 => Ok(number),
+            _ => Err(format!("Invalid signal number: {}", s)),
+        };
+    }
+    let upper = s.to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    NAMES
+        .iter()
+        .find(|(_, known)| known[3..] == *name)
+        .map(|(number, _)| *number)
+        .ok_or_else(|| format!("No such signal: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signals() {
+        assert_eq!(Some("SIGKILL"), signal_name(libc::SIGKILL));
+        assert_eq!(Some("SIGSEGV"), signal_name(libc::SIGSEGV));
+        assert_eq!(None, signal_name(0));
+        assert_eq!(Ok(libc::SIGKILL), parse_signal("KILL"));
+        assert_eq!(Ok(libc::SIGKILL), parse_signal("sigkill"));
+        assert_eq!(Ok(libc::SIGTERM), parse_signal("SIGTERM"));
+        assert_eq!(Ok(9), parse_signal("9"));
+        assert!(parse_signal("0").is_err());
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("").is_err());
+    }
+}
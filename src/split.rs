@@ -0,0 +1,99 @@
+//! Splitting one huge file into chunks of bytes, each the input of a task of its own,
+//! so that a single file can be processed in parallel.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+/// The part of a file that's a task's input, when the file is split.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// Where the chunk starts, in bytes from the start of the file.
+    pub offset: u64,
+    /// How many bytes long the chunk is.
+    pub length: u64,
+}
+
+impl Chunk {
+    /// The chunks of `chunk_size` bytes that a file of `size` bytes splits into, in order,
+    /// where the last is shorter unless `size` is a multiple of `chunk_size`.
+    ///
+    /// An empty file has no chunks at all.
+    pub(crate) fn split(size: u64, chunk_size: u64) -> Vec<Chunk> {
+        let chunk_size = chunk_size.max(1);
+        (0..size)
+            .step_by(chunk_size as usize)
+            .map(|offset| Chunk {
+                offset,
+                length: chunk_size.min(size - offset),
+            })
+            .collect()
+    }
+
+    /// The name of the task for chunk `index` of the `count` that the file called `name` is
+    /// split into, like `big.bin.007`, padded so that the names sort in the chunks' order.
+    pub(crate) fn name(name: &OsStr, index: usize, count: usize) -> OsString {
+        let width = count.saturating_sub(1).to_string().len();
+        let mut chunk_name = name.to_owned();
+        chunk_name.push(format!(".{:0width$}", index, width = width));
+        chunk_name
+    }
+
+    /// Open the file at `path` to read only this chunk of it.
+    pub(crate) async fn open(&self, path: &Path) -> io::Result<impl AsyncRead + Unpin + Send> {
+        let mut file = fs::File::open(path).await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        Ok(file.take(self.length))
+    }
+}
+
+impl fmt::Display for Chunk {
+    /// The chunk's first and last bytes, like `bytes 0-1023` for the first kilobyte.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = (self.offset + self.length).saturating_sub(1);
+        write!(f, "bytes {}-{}", self.offset, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let chunk = |offset, length| Chunk { offset, length };
+        assert_eq!(
+            vec![chunk(0, 4), chunk(4, 4), chunk(8, 2)],
+            Chunk::split(10, 4)
+        );
+        assert_eq!(vec![chunk(0, 4), chunk(4, 4)], Chunk::split(8, 4));
+        assert_eq!(vec![chunk(0, 3)], Chunk::split(3, 4));
+        assert!(Chunk::split(0, 4).is_empty());
+
+        assert_eq!("big.bin.0", Chunk::name("big.bin".as_ref(), 0, 3));
+        assert_eq!("big.bin.07", Chunk::name("big.bin".as_ref(), 7, 11));
+        assert_eq!("big.bin.10", Chunk::name("big.bin".as_ref(), 10, 11));
+        assert_eq!("bytes 4-7", chunk(4, 4).to_string());
+    }
+
+    #[tokio::test]
+    async fn test_open() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("input");
+        std::fs::write(&path, "0123456789")?;
+        let mut contents = String::new();
+        Chunk {
+            offset: 3,
+            length: 4,
+        }
+        .open(&path)
+        .await?
+        .read_to_string(&mut contents)
+        .await?;
+        assert_eq!("3456", contents);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! Pipeline stages: shell commands that run after each task's command, one after another,
+//! each reading the standard output of the one before.
+//!
+//! The task's own command is the first stage, with its output and status in the task's
+//! destination directory as usual. Each stage after it gets a directory of its own in there,
+//! `then-1`, `then-2`, and so on, with its own output and status, and the task's `status`
+//! is that of the whole pipeline: the first stage that fails, or success if none do.
+//!
+//! A stage's directory only exists once every stage before it has succeeded, so a task that
+//! failed partway through can pick up from the stage that failed.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::capture::{Captured, Outputs};
+use crate::shell::Shell;
+use crate::template::Template;
+use crate::Status;
+
+/// A shell command like `gzip`, run on the output of the stage before it.
+#[derive(Debug)]
+pub(crate) struct Stage {
+    shell: Shell,
+    template: Template,
+}
+
+impl Stage {
+    pub(crate) fn new(shell: &str, command: &str) -> Self {
+        Stage {
+            shell: Shell::new(shell),
+            template: Template::parse(command),
+        }
+    }
+
+    /// Start the stage in directory `stage_dir` for the task with `inputs` and destination
+    /// directory `task_dir`, filling in its placeholders like the task's own command,
+    /// with the file `stdin` as its standard input and environment `env`.
+    ///
+    /// Its output goes where `outputs` says, in `stage_dir`.
+    pub(crate) async fn start(
+        &self,
+        inputs: &[PathBuf],
+        task_dir: &Path,
+        stage_dir: &Path,
+        stdin: &Path,
+        outputs: &Outputs,
+        env: &[(&str, OsString)],
+    ) -> io::Result<Captured> {
+        let script = self
+            .template
+            .render(inputs, task_dir, self.shell.quoting())?;
+        let stdin = fs::File::open(stdin).await?.into_std().await;
+        let mut command = self.shell.command(script);
+        command
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .stdin(stdin);
+        let name = task_dir.file_name().unwrap_or_default();
+        outputs.spawn(command, name, inputs, stage_dir, None).await
+    }
+}
+
+/// The directory for the `stage`th stage after the task's own command, counting from one,
+/// in the task's destination directory `task_dir`.
+pub(crate) fn dir(task_dir: &Path, stage: usize) -> PathBuf {
+    task_dir.join(format!("then-{}", stage))
+}
+
+/// Which stage a task with `stages` stages after its own command should start from:
+/// the first one that hasn't succeeded, or 0 for its own command if not even that has.
+pub(crate) async fn resume_from(task_dir: &Path, stages: usize) -> usize {
+    for stage in 1..=stages {
+        let stage_dir = dir(task_dir, stage);
+        match Status::read(&stage_dir).await {
+            Ok(Some(status)) if status.is_success() => continue,
+            // The first stage's directory is only made once the task's own command has succeeded.
+            _ if stage == 1 && fs::metadata(&stage_dir).await.is_err() => return 0,
+            _ => return stage,
+        }
+    }
+    // Every stage succeeded, so something went wrong after them all, and it's safest to start again.
+    0
+}
+
+/// Remove the directories of the stages from `first` on, left by an earlier attempt.
+pub(crate) async fn clear_from(task_dir: &Path, first: usize, stages: usize) -> io::Result<()> {
+    for stage in first..=stages {
+        match fs::remove_dir_all(dir(task_dir, stage)).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resume_from() -> io::Result<()> {
+        let task_dir = tempfile::tempdir()?;
+        let task_dir = task_dir.path();
+        assert_eq!(0, resume_from(task_dir, 2).await);
+
+        std::fs::create_dir(dir(task_dir, 1))?;
+        assert_eq!(1, resume_from(task_dir, 2).await);
+
+        Status::Exited(0)
+            .write(&dir(task_dir, 1), &[], None)
+            .await?;
+        assert_eq!(2, resume_from(task_dir, 2).await);
+
+        std::fs::create_dir(dir(task_dir, 2))?;
+        Status::Exited(1)
+            .write(&dir(task_dir, 2), &[], None)
+            .await?;
+        assert_eq!(2, resume_from(task_dir, 2).await);
+
+        Status::Exited(0)
+            .write(&dir(task_dir, 2), &[], None)
+            .await?;
+        assert_eq!(0, resume_from(task_dir, 2).await);
+
+        clear_from(task_dir, 2, 2).await?;
+        assert!(!dir(task_dir, 2).exists());
+        assert!(dir(task_dir, 1).exists());
+        Ok(())
+    }
+}
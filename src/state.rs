@@ -0,0 +1,93 @@
+//! reach's own bookkeeping: locks, caches, and records of previous runs.
+//!
+//! All of it lives in the state directory rather than next to the inputs,
+//! so that reach never writes to the source directory and can process
+//! corpora on read-only filesystems.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::{error, Message};
+
+/// The directory where reach keeps its bookkeeping.
+#[derive(Debug)]
+pub(crate) struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        StateDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Take exclusive ownership of the state directory, creating it if necessary.
+    ///
+    /// Stops two runs of reach from trampling on each other's bookkeeping.
+    /// The lock is held until the returned `Lock` is dropped,
+    /// and the operating system releases it if reach dies.
+    pub(crate) async fn lock(&self) -> io::Result<Lock> {
+        fs::create_dir_all(&self.path).await?;
+        let lock_path = self.path.join("lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        try_lock_exclusive(&file).map_err(|error| match error.kind() {
+            io::ErrorKind::WouldBlock => error::new(
+                io::ErrorKind::WouldBlock,
+                Message::StateDirLocked(&self.path),
+            ),
+            _ => error,
+        })?;
+        Ok(Lock { _file: file })
+    }
+}
+
+/// Exclusive ownership of a state directory. Released on drop.
+#[derive(Debug)]
+pub(crate) struct Lock {
+    _file: File,
+}
+
+#[cfg(unix)]
+fn try_lock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: `flock` has no memory safety requirements, and the descriptor
+    // stays open for as long as `file` is borrowed.
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock_exclusive(_file: &File) -> io::Result<()> {
+    // TODO: Use LockFileEx on Windows.
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let state_dir = StateDir::new(dir.path().join("state"));
+        let lock = state_dir.lock().await?;
+        let error = state_dir.lock().await.unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, error.kind());
+        drop(lock);
+        state_dir.lock().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+//! The `status` file that records what happened to each task.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::str::FromStr;
+use tokio::fs;
+
+use crate::signals::signal_name;
+use crate::CancelReason;
+
+/// What happened to a task, as recorded in the `status` file in its destination directory.
+///
+/// A task with no `status` file has never finished. The status is on the file's first line,
+/// and any lines after it are notes, like `clipped err` for an output file that had to be
+/// clipped to `Config::max_output_size`, `signal SIGKILL` naming the signal that killed
+/// the command, or `cancelled halt` saying why reach terminated it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// The command exited with this exit code.
+    Exited(i32),
+    /// The command was killed by this signal.
+    Signalled(i32),
+    /// The command ran for longer than the timeout, so reach killed it.
+    TimedOut,
+    /// reach was interrupted before the command finished.
+    Interrupted,
+}
+
+impl Status {
+    /// Whether the task does not need to be run again.
+    pub fn is_success(&self) -> bool {
+        *self == Status::Exited(0)
+    }
+
+    /// The command's exit code, if it exited at all.
+    pub fn exit_code(&self) -> Option<i32> {
+        match *self {
+            Status::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The signal that killed the command, if one did.
+    pub fn signal(&self) -> Option<i32> {
+        match *self {
+            Status::Signalled(signal) => Some(signal),
+            _ => None,
+        }
+    }
+
+    /// The name of the signal that killed the command, like `SIGKILL`, if one did and it has one.
+    pub fn signal_name(&self) -> Option<&'static str> {
+        self.signal().and_then(signal_name)
+    }
+
+    /// The status for a task whose command failed with `error`, if the command ran at all.
+    pub(crate) fn from_error(error: &io::Error) -> Option<Status> {
+        match error.kind() {
+            io::ErrorKind::TimedOut => Some(Status::TimedOut),
+            io::ErrorKind::Interrupted => Some(Status::Interrupted),
+            _ => None,
+        }
+    }
+
+    /// Read the status recorded in a task's destination directory, if there is one.
+    pub(crate) async fn read(task_dir: &Path) -> io::Result<Option<Status>> {
+        match fs::read_to_string(status_path(task_dir)).await {
+            Ok(contents) => contents
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Record this status in a task's destination directory, noting any of its output
+    /// files that were `clipped`, and why reach terminated the command, if it was `cancelled`.
+    ///
+    /// Replaces any existing status atomically, so an interrupted write never
+    /// leaves a half-written `status` behind.
+    pub(crate) async fn write(
+        &self,
+        task_dir: &Path,
+        clipped: &[String],
+        cancelled: Option<CancelReason>,
+    ) -> io::Result<()> {
+        let temp_path = task_dir.join("status.tmp");
+        let mut contents = format!("{}\n", self);
+        if let Some(name) = self.signal_name() {
+            contents.push_str(&format!("signal {}\n", name));
+        }
+        if let Some(reason) = cancelled {
+            contents.push_str(&format!("cancelled {}\n", reason));
+        }
+        for name in clipped {
+            contents.push_str(&format!("clipped {}\n", name));
+        }
+        fs::write(&temp_path, contents).await?;
+        fs::rename(&temp_path, status_path(task_dir)).await
+    }
+
+    /// Remove any status recorded in a task's destination directory.
+    pub(crate) async fn clear(task_dir: &Path) -> io::Result<()> {
+        match fs::remove_file(status_path(task_dir)).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The name of the file in each task's destination directory that records its status.
+pub(crate) const STATUS_FILE: &str = "status";
+
+fn status_path(task_dir: &Path) -> PathBuf {
+    task_dir.join(STATUS_FILE)
+}
+
+impl From<ExitStatus> for Status {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return Status::Signalled(signal);
+            }
+        }
+        Status::Exited(status.code().unwrap_or(-1))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Exited(code) => write!(f, "{}", code),
+            Status::Signalled(signal) => write!(f, "signal {}", signal),
+            Status::TimedOut => write!(f, "timed out"),
+            Status::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(code) = s.parse() {
+            return Ok(Status::Exited(code));
+        }
+        if let Some(signal) = s.strip_prefix("signal ") {
+            if let Ok(signal) = signal.parse() {
+                return Ok(Status::Signalled(signal));
+            }
+        }
+        match s {
+            "timed out" => Ok(Status::TimedOut),
+            "interrupted" => Ok(Status::Interrupted),
+            _ => Err(format!("No such Status: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_round_trip() {
+        for status in &[
+            Status::Exited(0),
+            Status::Exited(3),
+            Status::Signalled(9),
+            Status::TimedOut,
+            Status::Interrupted,
+        ] {
+            assert_eq!(Ok(status.clone()), status.to_string().parse());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status_notes() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        Status::Exited(2)
+            .write(dir.path(), &["out".into(), "err".into()], None)
+            .await?;
+        assert_eq!(
+            "2\nclipped out\nclipped err\n",
+            std::fs::read_to_string(status_path(dir.path()))?
+        );
+        assert_eq!(Some(Status::Exited(2)), Status::read(dir.path()).await?);
+
+        #[cfg(unix)]
+        {
+            Status::Signalled(libc::SIGKILL)
+                .write(dir.path(), &[], None)
+                .await?;
+            assert_eq!(
+                format!("signal {}\nsignal SIGKILL\n", libc::SIGKILL),
+                std::fs::read_to_string(status_path(dir.path()))?
+            );
+            assert_eq!(
+                Some(Status::Signalled(libc::SIGKILL)),
+                Status::read(dir.path()).await?
+            );
+        }
+
+        Status::Interrupted
+            .write(dir.path(), &[], Some(CancelReason::Halt))
+            .await?;
+        assert_eq!(
+            "interrupted\ncancelled halt\n",
+            std::fs::read_to_string(status_path(dir.path()))?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+//! What happened over the course of a run.
+
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::fs;
+
+use crate::{CancelReason, Chunk, Status, TaskId};
+
+/// In low-memory mode, the most failures a summary lists, and the most groups it keeps apart.
+pub const SUMMARY_LIMIT: usize = 1000;
+
+/// The group that tasks are counted in once there are too many groups, in low-memory mode.
+pub const OTHER_GROUP: &str = "(other)";
+
+/// How many tasks succeeded, failed, or were skipped in a run, and which ones failed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Summary {
+    /// Tasks whose command exited successfully.
+    pub succeeded: usize,
+    /// Tasks whose command exited unsuccessfully, timed out, or couldn't be run at all.
+    pub failed: usize,
+    /// Tasks that weren't run because they succeeded in an earlier run.
+    pub skipped: usize,
+    /// Tasks that were tried more than once, whether or not they eventually succeeded.
+    pub retried: usize,
+    /// How long the run took, from start to finish.
+    pub duration: Duration,
+    /// How many bytes the tasks that were run left in their destination directories.
+    ///
+    /// Only measured when there is a `Config::max_total_output`, and zero otherwise.
+    pub output_bytes: u64,
+    /// Every task that failed, in the order they finished.
+    pub failures: Vec<Failure>,
+    /// How many more tasks failed than are in `failures`, which only has the first
+    /// `SUMMARY_LIMIT` in low-memory mode. The run's journal has every one.
+    pub failures_omitted: usize,
+    /// How the tasks that were run went, grouped by `Config::group_by`, which is their
+    /// input's extension unless it's set.
+    ///
+    /// In low-memory mode, once there are `SUMMARY_LIMIT` groups, tasks in any other group
+    /// are counted in `OTHER_GROUP`.
+    pub groups: BTreeMap<String, Group>,
+    /// The failure that made `Config::halt` stop the run early, if it did.
+    pub halted_by: Option<Failure>,
+}
+
+/// How the tasks in one group went.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Group {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// How long the group's tasks took altogether, including every attempt.
+    pub duration: Duration,
+    /// How long the group's tasks waited to start altogether.
+    pub queued: Duration,
+}
+
+impl Group {
+    /// The fraction of the group's tasks that succeeded, between 0 and 1.
+    pub fn success_rate(&self) -> f64 {
+        self.succeeded as f64 / (self.succeeded + self.failed).max(1) as f64
+    }
+
+    /// How long the group's tasks took on average.
+    pub fn mean_duration(&self) -> Duration {
+        self.duration / (self.succeeded + self.failed).max(1) as u32
+    }
+
+    /// How long the group's tasks waited to start on average.
+    pub fn mean_queued(&self) -> Duration {
+        self.queued / (self.succeeded + self.failed).max(1) as u32
+    }
+}
+
+/// A task that failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// The input file that the task was processing.
+    pub input: PathBuf,
+    /// The part of the input file that the task was processing, if it was split.
+    pub chunk: Option<Chunk>,
+    /// How the task's last attempt ended, or `None` if its command couldn't be run at all.
+    pub status: Option<Status>,
+    /// Why the command couldn't be run, if it couldn't.
+    pub error: Option<String>,
+}
+
+impl Failure {
+    pub(crate) fn of(task: &TaskResult) -> Self {
+        Failure {
+            input: task.input.clone(),
+            chunk: task.chunk,
+            status: task.status.clone(),
+            error: task.error.clone(),
+        }
+    }
+}
+
+/// What happened to a single task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskResult {
+    /// The task's ID, which stays the same across retries, and across runs of the same command.
+    pub id: TaskId,
+    /// The input file that the task processed.
+    pub input: PathBuf,
+    /// The task's destination directory, where its output and status were written.
+    pub destination: PathBuf,
+    /// How the task's last attempt ended, or `None` if its command couldn't be run at all.
+    pub status: Option<Status>,
+    /// Why the command couldn't be run, if it couldn't.
+    pub error: Option<String>,
+    /// Why reach terminated the command before it finished, if it did.
+    pub cancelled: Option<CancelReason>,
+    /// How long the task took, including every attempt.
+    pub duration: Duration,
+    /// How long the task waited to start once its input was found, for a free process,
+    /// the throttle, or the rest of its batch.
+    ///
+    /// Tasks that spend longer queued than running would finish sooner with more processes.
+    pub queued: Duration,
+    /// How many times the task was retried after failing.
+    pub retries: u32,
+    /// The part of the input file that the task processed, if it was split.
+    pub chunk: Option<Chunk>,
+    /// The `key=value` pairs that the task's command or hooks wrote to the file named by
+    /// `REACH_ANNOTATIONS`, like the version of a model it used.
+    ///
+    /// Every input in a batch shares its batch's annotations.
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl TaskResult {
+    pub(crate) fn new(
+        id: TaskId,
+        input: PathBuf,
+        destination: PathBuf,
+        result: &io::Result<ExitStatus>,
+        attempts: u32,
+        duration: Duration,
+    ) -> Self {
+        let status = match result {
+            Ok(status) => Some(Status::from(*status)),
+            Err(error) => Status::from_error(error),
+        };
+        let error = match (&status, result) {
+            (None, Err(error)) => Some(error.to_string()),
+            _ => None,
+        };
+        TaskResult {
+            id,
+            input,
+            destination,
+            status,
+            error,
+            cancelled: None,
+            duration,
+            queued: Duration::default(),
+            retries: attempts.saturating_sub(1),
+            chunk: None,
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the task's command exited successfully.
+    pub fn succeeded(&self) -> bool {
+        matches!(&self.status, Some(status) if status.is_success())
+    }
+}
+
+impl Summary {
+    /// Whether every task that was run succeeded.
+    pub fn all_succeeded(&self) -> bool {
+        self.failed == 0
+    }
+
+    /// Whether every task that was run failed. False if no tasks were run.
+    pub fn all_failed(&self) -> bool {
+        self.failed > 0 && self.succeeded == 0
+    }
+
+    /// The number of tasks that were run, not counting skipped ones.
+    pub fn run(&self) -> usize {
+        self.succeeded + self.failed
+    }
+
+    /// The total number of tasks, including skipped ones.
+    pub fn total(&self) -> usize {
+        self.run() + self.skipped
+    }
+
+    /// Write this summary to `path` as JSON.
+    pub async fn write_json(&self, path: &Path) -> io::Result<()> {
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|failure| {
+                json!({
+                    "input": failure.input.to_string_lossy(),
+                    "offset": failure.chunk.map(|chunk| chunk.offset),
+                    "length": failure.chunk.map(|chunk| chunk.length),
+                    "exit_code": failure.status.as_ref().and_then(Status::exit_code),
+                    "status": failure.status.as_ref().map(Status::to_string),
+                    "signal": failure.status.as_ref().and_then(Status::signal_name),
+                    "error": failure.error,
+                })
+            })
+            .collect();
+        let groups: serde_json::Map<_, _> = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let group = json!({
+                    "succeeded": group.succeeded,
+                    "failed": group.failed,
+                    "success_rate": group.success_rate(),
+                    "mean_duration_secs": group.mean_duration().as_secs_f64(),
+                    "mean_queued_secs": group.mean_queued().as_secs_f64(),
+                });
+                (name.clone(), group)
+            })
+            .collect();
+        let report = json!({
+            "run": self.run(),
+            "skipped": self.skipped,
+            "succeeded": self.succeeded,
+            "failed": self.failed,
+            "retried": self.retried,
+            "duration_secs": self.duration.as_secs_f64(),
+            "output_bytes": self.output_bytes,
+            "failures": failures,
+            "failures_omitted": self.failures_omitted,
+            "groups": groups,
+        });
+        let mut contents = serde_json::to_vec_pretty(&report)?;
+        contents.push(b'\n');
+        fs::write(path, contents).await
+    }
+
+    /// Count `task`, which is in the group named `group`, keeping no more than `limit`
+    /// failures and groups, if there's a limit.
+    pub(crate) fn record(&mut self, task: &TaskResult, group: String, limit: Option<usize>) {
+        if task.retries > 0 {
+            self.retried += 1;
+        }
+        let group = match limit {
+            Some(limit) if self.groups.len() >= limit && !self.groups.contains_key(&group) => {
+                OTHER_GROUP.into()
+            }
+            _ => group,
+        };
+        let group = self.groups.entry(group).or_default();
+        group.duration += task.duration;
+        group.queued += task.queued;
+        if task.succeeded() {
+            group.succeeded += 1;
+            self.succeeded += 1;
+            return;
+        }
+        group.failed += 1;
+        self.failed += 1;
+        if matches!(limit, Some(limit) if self.failures.len() >= limit) {
+            self.failures_omitted += 1;
+            return;
+        }
+        self.failures.push(Failure::of(task));
+    }
+}
+
+impl fmt::Display for Summary {
+    /// A report for people, listing every failure.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ran {} tasks in {:.1?}: {} succeeded, {} failed",
+            self.run(),
+            self.duration,
+            self.succeeded,
+            self.failed
+        )?;
+        if self.retried > 0 {
+            write!(f, ", {} retried", self.retried)?;
+        }
+        if self.skipped > 0 {
+            write!(
+                f,
+                ", {} skipped because they already succeeded",
+                self.skipped
+            )?;
+        }
+        writeln!(f, ".")?;
+        // With only one group, it would just say the same again.
+        if self.groups.len() > 1 {
+            writeln!(f, "By group:")?;
+            for (name, group) in &self.groups {
+                let name = if name.is_empty() { "(none)" } else { name };
+                writeln!(
+                    f,
+                    "  {}: {} succeeded, {} failed ({:.0}%), {:.1?} on average after {:.1?} queued",
+                    name,
+                    group.succeeded,
+                    group.failed,
+                    group.success_rate() * 100.0,
+                    group.mean_duration(),
+                    group.mean_queued()
+                )?;
+            }
+        }
+        if !self.failures.is_empty() {
+            writeln!(f, "Failed:")?;
+        }
+        for failure in &self.failures {
+            write!(f, "  {}", failure.input.display())?;
+            if let Some(chunk) = failure.chunk {
+                write!(f, " ({})", chunk)?;
+            }
+            write!(f, ": ")?;
+            match (&failure.status, &failure.error) {
+                (Some(Status::Exited(code)), _) => writeln!(f, "exit code {}", code)?,
+                (Some(status @ Status::Signalled(_)), _) => match status.signal_name() {
+                    Some(name) => writeln!(f, "{} ({})", status, name)?,
+                    None => writeln!(f, "{}", status)?,
+                },
+                (Some(status), _) => writeln!(f, "{}", status)?,
+                (None, Some(error)) => writeln!(f, "{}", error)?,
+                (None, None) => writeln!(f, "unknown error")?,
+            }
+        }
+        if self.failures_omitted > 0 {
+            writeln!(
+                f,
+                "  and {} more, listed in the run's journal",
+                self.failures_omitted
+            )?;
+        }
+        Ok(())
+    }
+}
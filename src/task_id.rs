@@ -0,0 +1,96 @@
+//! Task IDs, which name a task the same way in every attempt and every run.
+
+use std::fmt;
+
+/// A task's ID: a hash of its input's path and the parts of the run's configuration
+/// that decide what it produces, as 16 hex digits.
+///
+/// The same input run the same way always gets the same ID, however many times it's retried
+/// or run again, so that other programs can tell which task an event or a journal entry is about.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TaskId(String);
+
+impl TaskId {
+    /// The ID that hashes `parts`, where `None` is distinct from every string.
+    pub(crate) fn from_parts(parts: &[Option<&[u8]>]) -> Self {
+        let mut hash = Fnv1a::default();
+        for part in parts {
+            match part {
+                Some(part) => {
+                    hash.write(&[1]);
+                    // Prefixed with its length, so that moving bytes between parts changes the hash.
+                    hash.write(&(part.len() as u64).to_le_bytes());
+                    hash.write(part);
+                }
+                None => hash.write(&[0]),
+            }
+        }
+        TaskId(hash.hex())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The 64-bit FNV-1a hash, which unlike the standard library's hasher is the same
+/// in every build of reach.
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// The hash of everything written so far, as 16 hex digits.
+    pub(crate) fn hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_id() {
+        let id = |parts: &[Option<&str>]| {
+            let parts: Vec<_> = parts.iter().map(|part| part.map(str::as_bytes)).collect();
+            TaskId::from_parts(&parts)
+        };
+        // Pinned, as IDs have to stay the same from one version of reach to the next.
+        assert_eq!(
+            "f0c37da78b36215b",
+            id(&[Some("cat"), Some("/src/a")]).as_str()
+        );
+        assert_eq!(
+            id(&[Some("cat"), Some("/src/a")]),
+            id(&[Some("cat"), Some("/src/a")])
+        );
+        assert_ne!(
+            id(&[Some("cat"), Some("/src/a")]),
+            id(&[Some("cat"), Some("/src/b")])
+        );
+        assert_ne!(
+            id(&[Some("ca"), Some("t/src/a")]),
+            id(&[Some("cat"), Some("/src/a")])
+        );
+        assert_ne!(id(&[None]), id(&[Some("")]));
+        assert_eq!(16, id(&[]).as_str().len());
+    }
+}
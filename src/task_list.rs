@@ -0,0 +1,72 @@
+//! Inputs named in a list of paths, like the output of `find`, rather than found by listing
+//! a source directory.
+
+use futures::stream::{self, Stream};
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// The source that means the list of inputs is read from standard input.
+pub(crate) const STDIN_SOURCE: &str = "-";
+
+/// The paths in `reader`, each ending in `delimiter`, or at the end of the list,
+/// as they're read, so that their tasks can start before the list is finished.
+///
+/// Empty paths, like blank lines, are left out. Ends at the first error.
+pub(crate) fn paths(
+    reader: impl AsyncRead + Unpin,
+    delimiter: u8,
+) -> impl Stream<Item = io::Result<PathBuf>> {
+    stream::unfold(Some(BufReader::new(reader)), move |reader| async move {
+        let mut reader = reader?;
+        loop {
+            let mut path = Vec::new();
+            match reader.read_until(delimiter, &mut path).await {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(error) => return Some((Err(error), None)),
+            }
+            if path.last() == Some(&delimiter) {
+                path.pop();
+            }
+            if !path.is_empty() {
+                return Some((path_from_bytes(path), Some(reader)));
+            }
+        }
+    })
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(|path| OsString::from(path).into())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_paths() -> io::Result<()> {
+        let read = |list: &'static [u8], delimiter| paths(list, delimiter).try_collect::<Vec<_>>();
+        let expected = vec![PathBuf::from("a.txt"), PathBuf::from("sub dir/b.txt")];
+        assert_eq!(expected, read(b"a.txt\nsub dir/b.txt\n", b'\n').await?);
+        assert_eq!(expected, read(b"a.txt\n\nsub dir/b.txt", b'\n').await?);
+        assert_eq!(expected, read(b"a.txt\0sub dir/b.txt\0", b'\0').await?);
+        assert_eq!(
+            vec![PathBuf::from("new\nline")],
+            read(b"new\nline\0", b'\0').await?
+        );
+        assert!(read(b"", b'\n').await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,719 @@
+//! Command templates: commands with placeholders for the details of each task.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{error, Message};
+
+/// A command with placeholders like `{}` and `{stem}` that are filled in for each task.
+///
+/// A task usually has one input. With batches, it has several: `{+}` is all of
+/// their paths, and the other placeholders are about the first one.
+///
+/// Substituted values are quoted for the shell, so filenames with spaces or
+/// quotes in them are passed through intact. See `Quoting`.
+///
+/// Anything in braces that isn't a placeholder, like `${HOME}` or awk's
+/// `{print $1}`, is left alone. To write a placeholder literally, double its
+/// braces: `{{}}` becomes `{}` and `{{stem}}` becomes `{stem}`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    /// `{}`: the path to the input file.
+    Input,
+    /// `{+}`: the paths to every input file in the task's batch, as separate words.
+    All,
+    /// `{basename}`: the input's file name, without its directory.
+    Basename,
+    /// `{stem}`: the input's file name, without its extension.
+    Stem,
+    /// `{ext}`: the input's extension, without the leading dot.
+    Ext,
+    /// `{dir}`: the directory containing the input.
+    Dir,
+    /// `{dest}`: the task's destination directory.
+    Dest,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "" => Some(Placeholder::Input),
+            "+" => Some(Placeholder::All),
+            "basename" => Some(Placeholder::Basename),
+            "stem" => Some(Placeholder::Stem),
+            "ext" => Some(Placeholder::Ext),
+            "dir" => Some(Placeholder::Dir),
+            "dest" => Some(Placeholder::Dest),
+            _ => None,
+        }
+    }
+
+    /// The value of this placeholder for a task with one input, `input`.
+    fn value<'a>(&self, input: &'a Path, task_dir: &'a Path) -> &'a OsStr {
+        let empty = OsStr::new("");
+        match self {
+            Placeholder::Input | Placeholder::All => input.as_os_str(),
+            Placeholder::Basename => input.file_name().unwrap_or(empty),
+            Placeholder::Stem => input.file_stem().unwrap_or(empty),
+            Placeholder::Ext => input.extension().unwrap_or(empty),
+            Placeholder::Dir => input.parent().map_or(empty, Path::as_os_str),
+            Placeholder::Dest => task_dir.as_os_str(),
+        }
+    }
+}
+
+impl Template {
+    pub(crate) fn parse(s: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if let Some(name) = escaped_placeholder(rest) {
+                literal.push('{');
+                literal.push_str(name);
+                literal.push('}');
+                rest = &rest[name.len() + 4..];
+            } else if let Some((placeholder, name)) = placeholder(rest) {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Placeholder(placeholder));
+                rest = &rest[name.len() + 2..];
+            } else {
+                literal.push('{');
+                rest = &rest[1..];
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Template { parts }
+    }
+
+    /// Whether the template has a `{}` or `{+}` placeholder for the input's path.
+    ///
+    /// Escaped placeholders like `{{}}` don't count.
+    pub(crate) fn has_input(&self) -> bool {
+        self.has(Placeholder::Input) || self.has(Placeholder::All)
+    }
+
+    fn has(&self, placeholder: Placeholder) -> bool {
+        self.parts.contains(&Part::Placeholder(placeholder))
+    }
+
+    /// Whether the template is `{+}` and nothing else.
+    fn is_all(&self) -> bool {
+        self.parts == [Part::Placeholder(Placeholder::All)]
+    }
+
+    /// Fill in the placeholders for a task with input files `inputs` and destination directory `task_dir`,
+    /// quoting their values with `quoting`.
+    pub(crate) fn render(
+        &self,
+        inputs: &[PathBuf],
+        task_dir: &Path,
+        quoting: Quoting,
+    ) -> io::Result<OsString> {
+        let mut rendered = OsString::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push(literal),
+                Part::Placeholder(Placeholder::All) => {
+                    for (i, input) in inputs.iter().enumerate() {
+                        if i > 0 {
+                            rendered.push(" ");
+                        }
+                        rendered.push(quoting.quote_os(&quoting.native_path(input.as_os_str()))?);
+                    }
+                }
+                Part::Placeholder(placeholder) => {
+                    let value = quoting.native_path(placeholder.value(first(inputs), task_dir));
+                    rendered.push(quoting.quote_os(&value)?)
+                }
+            }
+        }
+        Ok(rendered)
+    }
+
+    /// Fill in the placeholders for a task, for use as a single argument with no shell involved.
+    ///
+    /// Values are substituted exactly as they are, without quoting, and need not be unicode.
+    /// `{+}` becomes every input's path, separated by spaces.
+    pub(crate) fn render_arg(&self, inputs: &[PathBuf], task_dir: &Path) -> OsString {
+        let mut rendered = OsString::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push(literal),
+                Part::Placeholder(Placeholder::All) => {
+                    rendered.push(join_paths(inputs));
+                }
+                Part::Placeholder(placeholder) => {
+                    rendered.push(placeholder.value(first(inputs), task_dir))
+                }
+            }
+        }
+        rendered
+    }
+
+    /// Fill in the placeholders for a task, for use as arguments with no shell involved.
+    ///
+    /// A template that's just `{+}` becomes an argument for each input. Anything else
+    /// becomes one argument, as with `render_arg`.
+    fn render_words(&self, inputs: &[PathBuf], task_dir: &Path) -> Vec<OsString> {
+        if self.is_all() {
+            inputs.iter().map(OsString::from).collect()
+        } else {
+            vec![self.render_arg(inputs, task_dir)]
+        }
+    }
+}
+
+/// The first of a task's inputs, which every task has.
+fn first(inputs: &[PathBuf]) -> &Path {
+    inputs.first().expect("Tasks have at least one input")
+}
+
+fn join_paths(inputs: &[PathBuf]) -> OsString {
+    let mut joined = OsString::new();
+    for (i, input) in inputs.iter().enumerate() {
+        if i > 0 {
+            joined.push(" ");
+        }
+        joined.push(input);
+    }
+    joined
+}
+
+/// Check that `{+}` is only ever a word by itself in `words`, as it stands for any number of them.
+fn check_all_words(words: &[Template], s: &str) -> Result<(), String> {
+    if words
+        .iter()
+        .any(|word| word.has(Placeholder::All) && !word.is_all())
+    {
+        return Err(format!("{{+}} must be a word by itself: {:?}", s));
+    }
+    Ok(())
+}
+
+/// A command line, split into a program and its arguments, each of which is a `Template`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ArgsTemplate {
+    program: Template,
+    args: Vec<Template>,
+}
+
+impl ArgsTemplate {
+    /// Split `s` into words the way a POSIX shell would, then parse each word as a `Template`.
+    ///
+    /// Only quoting is supported: there are no variables, globs, pipes, or redirections.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let mut words = split_words(s)?.into_iter();
+        let program = words.next().ok_or_else(|| String::from("Empty command"))?;
+        let program = Template::parse(&program);
+        if program.has(Placeholder::All) {
+            return Err(format!("The program can't be {{+}}: {:?}", s));
+        }
+        let args: Vec<_> = words.map(|word| Template::parse(&word)).collect();
+        check_all_words(&args, s)?;
+        Ok(ArgsTemplate { program, args })
+    }
+
+    /// The program to run for a task.
+    pub(crate) fn program(&self, inputs: &[PathBuf], task_dir: &Path) -> OsString {
+        self.program.render_arg(inputs, task_dir)
+    }
+
+    /// The arguments to pass to the program for a task.
+    pub(crate) fn args<'a>(
+        &'a self,
+        inputs: &'a [PathBuf],
+        task_dir: &'a Path,
+    ) -> impl Iterator<Item = OsString> + 'a {
+        self.args
+            .iter()
+            .flat_map(move |arg| arg.render_words(inputs, task_dir))
+    }
+}
+
+/// A wrapper around every task's command, like `nice -n19 timeout 300 {cmd}`.
+///
+/// `{cmd}` must be a word on its own, and is replaced by every word of the task's
+/// command, exactly as they are. The wrapper is split into words like an
+/// `ArgsTemplate`, and its other words can use the usual placeholders.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WrapTemplate {
+    before: Vec<Template>,
+    after: Vec<Template>,
+}
+
+impl WrapTemplate {
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let words = split_words(s)?;
+        let position = words
+            .iter()
+            .position(|word| word == "{cmd}")
+            .ok_or_else(|| format!("Wrapper must include {{cmd}} as a word by itself: {:?}", s))?;
+        let (before, after) = (&words[..position], &words[position + 1..]);
+        if before.is_empty() && after.is_empty() {
+            return Err(format!("Wrapper doesn't wrap anything: {:?}", s));
+        }
+        if before
+            .iter()
+            .chain(after)
+            .any(|word| word.contains("{cmd}"))
+        {
+            return Err(format!("Wrapper can only include {{cmd}} once: {:?}", s));
+        }
+        let parse = |words: &[String]| -> Vec<_> {
+            words.iter().map(|word| Template::parse(word)).collect()
+        };
+        let (before, after) = (parse(before), parse(after));
+        check_all_words(&before, s)?;
+        check_all_words(&after, s)?;
+        Ok(WrapTemplate { before, after })
+    }
+
+    /// Wrap `command`, a program followed by its arguments, for a task.
+    pub(crate) fn wrap(
+        &self,
+        command: Vec<OsString>,
+        inputs: &[PathBuf],
+        task_dir: &Path,
+    ) -> Vec<OsString> {
+        let render = |words: &[Template]| {
+            words
+                .iter()
+                .flat_map(|word| word.render_words(inputs, task_dir))
+                .collect::<Vec<_>>()
+        };
+        let mut wrapped = render(&self.before);
+        wrapped.extend(command);
+        wrapped.extend(render(&self.after));
+        wrapped
+    }
+}
+
+/// Split a command line into words, following POSIX shell quoting rules.
+///
+/// Single quotes preserve everything up to the next single quote. Double quotes
+/// preserve everything except backslash escapes of `"`, `\`, `$`, and `` ` ``.
+/// Outside quotes, a backslash preserves the next character.
+fn split_words(s: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("Unterminated single quote in {:?}", s)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if "\"\\$`".contains(c) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(format!("Unterminated double quote in {:?}", s)),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(format!("Unterminated double quote in {:?}", s)),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(format!("Trailing backslash in {:?}", s)),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// If `s` starts with an escaped placeholder like `{{stem}}`, the placeholder's name.
+fn escaped_placeholder(s: &str) -> Option<&str> {
+    let name = &s.strip_prefix("{{")?[..s[2..].find("}}")?];
+    Placeholder::from_name(name).map(|_| name)
+}
+
+/// If `s` starts with a placeholder like `{stem}`, the placeholder and its name.
+fn placeholder(s: &str) -> Option<(Placeholder, &str)> {
+    let name = &s.strip_prefix('{')?[..s[1..].find('}')?];
+    Placeholder::from_name(name).map(|placeholder| (placeholder, name))
+}
+
+/// A POSIX shell command line that runs `words`, a program followed by its arguments, exactly.
+pub(crate) fn shell_words(words: &[OsString]) -> io::Result<OsString> {
+    let mut line = OsString::new();
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            line.push(" ");
+        }
+        line.push(Quoting::Posix.quote_os(word)?);
+    }
+    Ok(line)
+}
+
+/// How to quote values substituted into a command, which depends on the shell that will run it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Quoting {
+    /// For `sh`, `bash`, `zsh`, and other POSIX-like shells.
+    Posix,
+    /// For PowerShell, either `powershell` or `pwsh`.
+    PowerShell,
+    /// For Windows' `cmd`.
+    Cmd,
+}
+
+impl Quoting {
+    /// The quoting rules for `shell`, which is a path to the shell or just its name.
+    pub(crate) fn for_shell(shell: &str) -> Self {
+        // Windows paths use backslashes, which `Path` only understands on Windows.
+        let name = shell.rsplit(['/', '\\']).next().unwrap_or(shell);
+        let name = name.to_lowercase();
+        match name.strip_suffix(".exe").unwrap_or(&name) {
+            "powershell" | "pwsh" => Quoting::PowerShell,
+            "cmd" => Quoting::Cmd,
+            _ => Quoting::Posix,
+        }
+    }
+
+    /// `path` the way the shell expects to see it: for cmd, with backslashes rather than the
+    /// forward slashes it takes for the start of a switch, as in `type src/a.txt`.
+    pub(crate) fn native_path(self, path: &OsStr) -> Cow<'_, OsStr> {
+        match (self, path.to_str()) {
+            (Quoting::Cmd, Some(path)) if path.contains('/') => {
+                Cow::Owned(path.replace('/', "\\").into())
+            }
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    /// Whether command lines for the shell can hold any value, unicode or not, as
+    /// `quote_os` quotes it.
+    pub(crate) fn takes_any_bytes(self) -> bool {
+        cfg!(unix) && self == Quoting::Posix
+    }
+
+    /// Like `quote`, but for values that need not be unicode.
+    ///
+    /// POSIX shells take any bytes but NUL, so on Unix the value is quoted byte by byte.
+    /// PowerShell and cmd only ever see unicode, so non-unicode values are an error.
+    pub(crate) fn quote_os(self, s: &OsStr) -> io::Result<Cow<'_, OsStr>> {
+        if let Some(s) = s.to_str() {
+            return Ok(match self.quote(s)? {
+                Cow::Borrowed(quoted) => Cow::Borrowed(OsStr::new(quoted)),
+                Cow::Owned(quoted) => Cow::Owned(quoted.into()),
+            });
+        }
+        #[cfg(unix)]
+        if self.takes_any_bytes() {
+            use std::os::unix::ffi::{OsStrExt, OsStringExt};
+            let mut quoted = vec![b'\''];
+            for &byte in s.as_bytes() {
+                if byte == b'\'' {
+                    quoted.extend_from_slice(br"'\''");
+                } else {
+                    quoted.push(byte);
+                }
+            }
+            quoted.push(b'\'');
+            return Ok(Cow::Owned(OsString::from_vec(quoted)));
+        }
+        Err(error::new(
+            io::ErrorKind::Unsupported,
+            Message::NonUnicodeName(s),
+        ))
+    }
+
+    /// Quote `s` so the shell treats it as a single word, exactly as it is.
+    fn quote(self, s: &str) -> io::Result<Cow<'_, str>> {
+        let is_safe = |c: char| match self {
+            Quoting::Posix => c.is_ascii_alphanumeric() || "/._-+:,@%=".contains(c),
+            Quoting::PowerShell | Quoting::Cmd => {
+                c.is_ascii_alphanumeric() || "/\\._-:".contains(c)
+            }
+        };
+        if !s.is_empty() && s.chars().all(is_safe) {
+            return Ok(Cow::Borrowed(s));
+        }
+        let quoted = match self {
+            Quoting::Posix => format!("'{}'", s.replace('\'', r"'\''")),
+            // PowerShell also treats typographic single quotes as quotes, and all of them
+            // are escaped by doubling.
+            Quoting::PowerShell => {
+                let mut quoted = String::from("'");
+                for c in s.chars() {
+                    if "'\u{2018}\u{2019}\u{201a}\u{201b}".contains(c) {
+                        quoted.push(c);
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('\'');
+                quoted
+            }
+            // Double quotes protect everything but `%`, which can only be escaped outside them,
+            // so it gets its own unquoted, escaped section.
+            Quoting::Cmd => {
+                if s.contains('"') {
+                    return Err(error::new(
+                        io::ErrorKind::InvalidInput,
+                        Message::CmdUnquotable(s),
+                    ));
+                }
+                format!("\"{}\"", s.replace('%', "\"^%\""))
+            }
+        };
+        Ok(Cow::Owned(quoted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, input: &str) -> String {
+        render_for(Quoting::Posix, template, input).unwrap()
+    }
+
+    fn render_for(quoting: Quoting, template: &str, input: &str) -> io::Result<String> {
+        let rendered = Template::parse(template).render(
+            &[input.into()],
+            Path::new("/dest/photo.jpeg"),
+            quoting,
+        )?;
+        Ok(rendered.into_string().unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_non_unicode() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let input = &[PathBuf::from(OsStr::from_bytes(b"/src/caf\xe9's.txt"))];
+        let template = Template::parse("cat {} > {stem}.out");
+        assert_eq!(
+            OsString::from_vec(b"cat '/src/caf\xe9'\\''s.txt' > 'caf\xe9'\\''s'.out".to_vec()),
+            template
+                .render(input, Path::new("/dest"), Quoting::Posix)
+                .unwrap()
+        );
+        assert!(template
+            .render(input, Path::new("/dest"), Quoting::PowerShell)
+            .is_err());
+    }
+
+    #[test]
+    fn test_render_placeholders() {
+        assert_eq!(
+            "convert /src/photo.jpeg /dest/photo.jpeg/photo.png",
+            render("convert {} {dest}/{stem}.png", "/src/photo.jpeg")
+        );
+        assert_eq!(
+            "photo.jpeg photo jpeg /src",
+            render("{basename} {stem} {ext} {dir}", "/src/photo.jpeg")
+        );
+        assert_eq!("''", render("{ext}", "/src/README"));
+    }
+
+    #[test]
+    fn test_render_quotes_values() {
+        assert_eq!(
+            r"cat '/src/it'\''s a file'",
+            render("cat {}", "/src/it's a file")
+        );
+        assert_eq!("cat '/src/a;rm -rf ~'", render("cat {}", "/src/a;rm -rf ~"));
+    }
+
+    #[test]
+    fn test_render_quotes_for_powershell() {
+        let render = |input| render_for(Quoting::PowerShell, "Get-Content {}", input).unwrap();
+        assert_eq!(r"Get-Content C:\src\file.txt", render(r"C:\src\file.txt"));
+        assert_eq!(
+            r"Get-Content 'C:\My Files\a&b 100%.txt'",
+            render(r"C:\My Files\a&b 100%.txt")
+        );
+        assert_eq!("Get-Content 'it''s ‘‘here’’'", render("it's ‘here’"));
+        assert_eq!("Get-Content '$env:HOME;ls'", render("$env:HOME;ls"));
+    }
+
+    #[test]
+    fn test_render_quotes_for_cmd() {
+        let render = |input| render_for(Quoting::Cmd, "type {}", input);
+        assert_eq!(r"type C:\src\file.txt", render(r"C:\src\file.txt").unwrap());
+        assert_eq!(
+            r#"type "C:\My Files\a&b.txt""#,
+            render(r"C:\My Files\a&b.txt").unwrap()
+        );
+        assert_eq!(
+            r#"type "100"^%" "^%"PATH"^%".txt""#,
+            render("100% %PATH%.txt").unwrap()
+        );
+        assert!(render(r#"say "hi".txt"#).is_err());
+        // cmd would take `/file.txt` for a switch.
+        assert_eq!(r"type src\a\file.txt", render("src/a/file.txt").unwrap());
+        assert_eq!(
+            r"copy src\a.txt \dest\photo.jpeg",
+            render_for(Quoting::Cmd, "copy {} {dest}", "src/a.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_quoting_for_shell() {
+        assert_eq!(Quoting::Posix, Quoting::for_shell("/bin/bash"));
+        assert_eq!(Quoting::Posix, Quoting::for_shell("sh"));
+        assert_eq!(Quoting::PowerShell, Quoting::for_shell("pwsh"));
+        assert_eq!(
+            Quoting::PowerShell,
+            Quoting::for_shell("/usr/local/bin/pwsh")
+        );
+        assert_eq!(
+            Quoting::PowerShell,
+            Quoting::for_shell(r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe")
+        );
+        assert_eq!(
+            Quoting::Cmd,
+            Quoting::for_shell(r"C:\Windows\System32\CMD.EXE")
+        );
+    }
+
+    #[test]
+    fn test_render_leaves_other_braces_alone() {
+        assert_eq!(
+            "awk '{print $1}' ${HOME} {nope} /src/x {",
+            render("awk '{print $1}' ${HOME} {nope} {} {", "/src/x")
+        );
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            vec![
+                "ffmpeg",
+                "-i",
+                "{}",
+                "{dest}/out file.mp4",
+                "",
+                "a\"b",
+                "c d"
+            ],
+            split_words(r#"  ffmpeg -i {} "{dest}/out file.mp4" '' "a\"b" c\ d "#).unwrap()
+        );
+        assert!(split_words("echo 'oops").is_err());
+        assert!(split_words("echo \"oops").is_err());
+    }
+
+    #[test]
+    fn test_render_args_unquoted() {
+        let template = ArgsTemplate::parse("printf %s {} {stem}.out").unwrap();
+        let input = &[PathBuf::from("/src/it's a;file.txt")];
+        let task_dir = Path::new("/dest");
+        assert_eq!("printf", template.program(input, task_dir));
+        assert_eq!(
+            vec!["%s", "/src/it's a;file.txt", "it's a;file.out"],
+            template.args(input, task_dir).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_wrap() {
+        let wrap = WrapTemplate::parse("nice -n19 timeout 300 {cmd} --log {stem}.log").unwrap();
+        let command = vec![OsString::from("sh"), "-c".into(), "cat 'a b'".into()];
+        assert_eq!(
+            vec![
+                "nice",
+                "-n19",
+                "timeout",
+                "300",
+                "sh",
+                "-c",
+                "cat 'a b'",
+                "--log",
+                "x.log"
+            ],
+            wrap.wrap(command, &["/src/x.txt".into()], Path::new("/dest"))
+        );
+    }
+
+    #[test]
+    fn test_render_all_inputs() {
+        let inputs = &[PathBuf::from("/src/a b.txt"), PathBuf::from("/src/c.txt")];
+        let dest = Path::new("/dest/a b.txt");
+        assert_eq!(
+            "wc -l '/src/a b.txt' /src/c.txt > '/dest/a b.txt'/'a b'",
+            Template::parse("wc -l {+} > {dest}/{stem}")
+                .render(inputs, dest, Quoting::Posix)
+                .unwrap()
+        );
+        let template = ArgsTemplate::parse("wc -l {+} --").unwrap();
+        assert_eq!(
+            vec!["-l", "/src/a b.txt", "/src/c.txt", "--"],
+            template.args(inputs, dest).collect::<Vec<_>>()
+        );
+        assert!(ArgsTemplate::parse("cat --files={+}").is_err());
+        assert!(ArgsTemplate::parse("{+}").is_err());
+        assert!(WrapTemplate::parse("tee {+}.log {cmd}").is_err());
+        assert!(Template::parse("wc -l {+}").has_input());
+    }
+
+    #[test]
+    fn test_wrap_needs_cmd_word() {
+        assert!(WrapTemplate::parse("nice -n19").is_err());
+        assert!(WrapTemplate::parse("{cmd}").is_err());
+        assert!(WrapTemplate::parse("sh -c 'exec {cmd}'").is_err());
+        assert!(WrapTemplate::parse("tee {cmd} {cmd}").is_err());
+    }
+
+    #[test]
+    fn test_shell_words() {
+        let words = vec![OsString::from("printf"), r"%s\n".into(), "it's".into()];
+        assert_eq!(r"printf '%s\n' 'it'\''s'", shell_words(&words).unwrap());
+    }
+
+    #[test]
+    fn test_has_input() {
+        assert!(Template::parse("wc -c {}").has_input());
+        assert!(Template::parse("echo {{}} {}").has_input());
+        assert!(!Template::parse("wc -c").has_input());
+        assert!(!Template::parse("echo {{}}").has_input());
+        assert!(!Template::parse("gzip > {dest}/out.gz").has_input());
+        assert!(!Template::parse("awk '{print $1}'").has_input());
+    }
+
+    #[test]
+    fn test_render_escaped_placeholders() {
+        assert_eq!(
+            "echo {} {stem} /src/x",
+            render("echo {{}} {{stem}} {}", "/src/x")
+        );
+    }
+}
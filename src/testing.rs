@@ -0,0 +1,329 @@
+//! Stand-ins for testing how runs go, in reach's own tests and in programs that use it,
+//! without running real commands or waiting in real time. Only with the `testing` feature.
+//!
+//! A `Simulation` decides how each task's command ends, attempt by attempt, and how long it
+//! takes. Run with `block_on_paused`, that time is virtual: the clock only moves when every
+//! task is waiting on it, and then jumps straight to the next timer, so timeouts, retries,
+//! and halting behave the same every time, and take no time at all.
+//!
+//! Tasks still prepare their destination directories and write their statuses for real.
+//! Each task's own timeline is exact, but with more than one process, how their files are
+//! written can still change the order tasks finish in, so tests that need a particular
+//! order should run one process.
+
+use async_trait::async_trait;
+use futures::Future;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+use crate::pool::Reservation;
+use crate::{
+    progress, run_with, Config, Error, Launch, Launcher, Message, Process, Progress, Status,
+    Summary, Task, TaskId, TaskOutcome,
+};
+
+/// Run `future` on a runtime of a single thread whose clock is paused.
+pub fn block_on_paused<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .expect("A runtime of a single thread can always be built")
+        .block_on(future)
+}
+
+/// Like `crate::run`, but every task's command is simulated by `simulation`.
+pub async fn run_simulated(
+    config: Config,
+    progress_bar: impl progress::Progress,
+    simulation: &Simulation,
+) -> Result<Summary, Error> {
+    let launch = Launch::Simulated(simulation.clone());
+    run_with(
+        config,
+        progress_bar,
+        futures::future::pending(),
+        |_| (),
+        launch,
+    )
+    .await
+    .map_err(Error::from)
+}
+
+/// How a simulated command ends, and how long it takes to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    exit: Exit,
+    duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exit {
+    Code(i32),
+    #[cfg(unix)]
+    Signal(i32),
+    /// Never, unless it's terminated.
+    Hang,
+}
+
+impl Outcome {
+    /// The command exits with `code`, straight away.
+    pub fn exit(code: i32) -> Self {
+        Outcome {
+            exit: Exit::Code(code),
+            duration: Duration::default(),
+        }
+    }
+
+    /// The command succeeds, straight away.
+    pub fn succeed() -> Self {
+        Outcome::exit(0)
+    }
+
+    /// The command fails, exiting with 1, straight away.
+    pub fn fail() -> Self {
+        Outcome::exit(1)
+    }
+
+    /// The command is killed by `signal`, straight away.
+    #[cfg(unix)]
+    pub fn killed_by(signal: i32) -> Self {
+        Outcome {
+            exit: Exit::Signal(signal),
+            duration: Duration::default(),
+        }
+    }
+
+    /// The command never finishes, unless reach terminates it.
+    pub fn hang() -> Self {
+        Outcome {
+            exit: Exit::Hang,
+            duration: Duration::default(),
+        }
+    }
+
+    /// The same, but only once the command has run for `duration`.
+    pub fn after(self, duration: Duration) -> Self {
+        Outcome { duration, ..self }
+    }
+}
+
+/// Decides how each task's command ends, by the file name of its input, and keeps track of
+/// every attempt that's made.
+///
+/// Clones share their record of attempts.
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    /// What each attempt at the task for an input called this does, in turn,
+    /// with the last repeating.
+    scripts: HashMap<OsString, Vec<Outcome>>,
+    /// What every attempt at any other task does.
+    otherwise: Outcome,
+    attempts: Arc<Mutex<Vec<(PathBuf, u32)>>>,
+}
+
+impl Simulation {
+    /// A simulation where every command succeeds straight away.
+    pub fn new() -> Self {
+        Simulation {
+            scripts: HashMap::new(),
+            otherwise: Outcome::succeed(),
+            attempts: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Every command not scripted otherwise ends with `outcome`.
+    pub fn otherwise(mut self, outcome: Outcome) -> Self {
+        self.otherwise = outcome;
+        self
+    }
+
+    /// The command for the input called `name` ends with each of `outcomes` in turn,
+    /// one for each attempt, and the last one for every attempt after them.
+    pub fn script(mut self, name: impl Into<OsString>, outcomes: Vec<Outcome>) -> Self {
+        self.scripts.insert(name.into(), outcomes);
+        self
+    }
+
+    /// Every attempt made so far, as the task's first input and the attempt's number,
+    /// counting from one, in the order they started.
+    pub fn attempts(&self) -> Vec<(PathBuf, u32)> {
+        self.attempts.lock().unwrap().clone()
+    }
+
+    /// What attempt number `attempt` at the task for `input` does.
+    fn outcome(&self, input: &Path, attempt: u32) -> Outcome {
+        let script = input
+            .file_name()
+            .and_then(|name| self.scripts.get(name))
+            .filter(|script| !script.is_empty());
+        match script {
+            Some(script) => script[(attempt as usize - 1).min(script.len() - 1)],
+            None => self.otherwise,
+        }
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Launcher for Simulation {
+    type Process = Simulated;
+
+    async fn launch(&self, task: &Task<'_>, _reservation: Reservation) -> io::Result<Simulated> {
+        self.attempts
+            .lock()
+            .unwrap()
+            .push((task.input().to_owned(), task.attempt));
+        let outcome = self.outcome(task.input(), task.attempt);
+        Ok(Simulated {
+            exit: outcome.exit,
+            until: Instant::now() + outcome.duration,
+        })
+    }
+}
+
+/// A simulated command, which ends once the clock reaches `until`.
+#[derive(Debug)]
+pub(crate) struct Simulated {
+    exit: Exit,
+    until: Instant,
+}
+
+#[async_trait]
+impl Process for Simulated {
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        time::sleep_until(self.until).await;
+        match self.exit {
+            Exit::Code(code) => Ok(exit_status(code)),
+            #[cfg(unix)]
+            Exit::Signal(signal) => {
+                use std::os::unix::process::ExitStatusExt;
+                Ok(ExitStatus::from_raw(signal))
+            }
+            Exit::Hang => futures::future::pending().await,
+        }
+    }
+
+    /// Terminated commands are never waited for again, so there's nothing to do.
+    async fn terminate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+/// Something that happened in a run, as a `Recorder` remembers it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// How many tasks there are to run.
+    Tasks(usize),
+    /// The task for the input was skipped, as it succeeded in an earlier run.
+    Skipped(PathBuf),
+    Started(PathBuf),
+    /// The task for the input is about to make the numbered attempt.
+    Retrying(PathBuf, u32),
+    /// The task for the input finished with the status, if it has one,
+    /// after being retried this many times.
+    Finished(PathBuf, Option<Status>, u32),
+    /// A warning, in reach's own English.
+    Warning(String),
+}
+
+/// Progress that remembers every event in memory, for tests to check.
+///
+/// Clones share the same events, so one can be given to a run while another checks them.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every event so far, in the order they happened.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn record(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl Progress for Recorder {
+    fn set_num_tasks(&self, tasks: usize) {
+        self.record(Event::Tasks(tasks));
+    }
+
+    fn task_skipped(&self, _id: &TaskId, input: &Path) {
+        self.record(Event::Skipped(input.to_owned()));
+    }
+
+    fn task_started(&self, _id: &TaskId, input: &Path) {
+        self.record(Event::Started(input.to_owned()));
+    }
+
+    fn task_retrying(
+        &self,
+        _id: &TaskId,
+        input: &Path,
+        attempt: u32,
+        _result: &io::Result<ExitStatus>,
+    ) {
+        self.record(Event::Retrying(input.to_owned(), attempt));
+    }
+
+    fn task_completed(&self, _id: &TaskId, outcome: &TaskOutcome<'_>) {
+        self.record(Event::Finished(
+            outcome.input().to_owned(),
+            outcome.task.status.clone(),
+            outcome.task.retries,
+        ));
+    }
+
+    fn warn(&self, warning: &Message<'_>) {
+        self.record(Event::Warning(warning.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome() {
+        let simulation = Simulation::new()
+            .otherwise(Outcome::fail())
+            .script("a", vec![Outcome::exit(3), Outcome::succeed()])
+            .script("b", Vec::new());
+        let outcome = |input: &str, attempt| simulation.outcome(Path::new(input), attempt);
+        assert_eq!(Outcome::exit(3), outcome("/src/a", 1));
+        assert_eq!(Outcome::succeed(), outcome("/src/a", 2));
+        assert_eq!(Outcome::succeed(), outcome("/src/a", 5));
+        assert_eq!(Outcome::fail(), outcome("/src/b", 1));
+        assert_eq!(Outcome::fail(), outcome("/src/c", 1));
+    }
+}
@@ -0,0 +1,170 @@
+//! Holding back new tasks, so that a run doesn't swamp a machine it shares with others.
+
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+use crate::error;
+use crate::window::{self, Window};
+use crate::Message;
+
+/// How often to look at the load average again while it's too high.
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest to wait for the window to open before looking at the clock again,
+/// in case it has been changed, or the machine has been asleep.
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Decides when the next task may start, on top of the limit on processes.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    /// The time between task starts, and the earliest time the next one may start.
+    rate: Option<(Duration, Mutex<Instant>)>,
+    max_load: Option<f64>,
+    window: Option<Window>,
+}
+
+impl Throttle {
+    /// A throttle that starts at most `max_rate` tasks a second, none while
+    /// the load average is over `max_load`, and none outside `window`.
+    pub(crate) fn new(
+        max_rate: Option<f64>,
+        max_load: Option<f64>,
+        window: Option<Window>,
+    ) -> io::Result<Self> {
+        let rate = match max_rate {
+            Some(rate) if !(rate.is_finite() && rate > 0.0) => {
+                return Err(error::new(
+                    io::ErrorKind::InvalidInput,
+                    Message::InvalidRate(rate),
+                ))
+            }
+            Some(rate) => Some((
+                Duration::from_secs_f64(1.0 / rate),
+                Mutex::new(Instant::now()),
+            )),
+            None => None,
+        };
+        if max_load.is_some() {
+            // Better to find out now than when the first task is ready to start.
+            load_average()?;
+        }
+        if window.is_some() {
+            window::local_time_of_day()?;
+        }
+        Ok(Throttle {
+            rate,
+            max_load,
+            window,
+        })
+    }
+
+    /// The window that tasks may start in, if it's closed now.
+    pub(crate) fn closed_window(&self) -> Option<Window> {
+        self.window
+            .filter(|window| window_closed_for(window).is_some())
+    }
+
+    /// Wait until another task may start.
+    pub(crate) async fn wait(&self) {
+        if let Some(window) = &self.window {
+            while let Some(wait) = window_closed_for(window) {
+                time::sleep(wait.min(WINDOW_CHECK_INTERVAL)).await;
+            }
+        }
+        if let Some((interval, next)) = &self.rate {
+            let start = {
+                let mut next = next.lock().unwrap();
+                let start = (*next).max(Instant::now());
+                *next = start + *interval;
+                start
+            };
+            time::sleep_until(start.into()).await;
+        }
+        if let Some(max_load) = self.max_load {
+            // Having worked once, it's not going to stop working, so an error is as good as no load.
+            while load_average().unwrap_or(0.0) > max_load {
+                time::sleep(LOAD_CHECK_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// How long until `window` opens, or `None` if it's open now.
+fn window_closed_for(window: &Window) -> Option<Duration> {
+    // Having worked once, it's not going to stop working, so an error is as good as open.
+    window.closed_for(window::local_time_of_day().ok()?)
+}
+
+/// The system load average over the last minute.
+#[cfg(unix)]
+fn load_average() -> io::Result<f64> {
+    let mut load = [0.0];
+    // SAFETY: `load` has room for the one sample we ask for.
+    if unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } == 1 {
+        Ok(load[0])
+    } else {
+        Err(error::new(
+            io::ErrorKind::Other,
+            Message::LoadAverageUnreadable,
+        ))
+    }
+}
+
+#[cfg(not(unix))]
+fn load_average() -> io::Result<f64> {
+    Err(error::unsupported(crate::Unsupported::LoadAverage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_throttle_rate() -> io::Result<()> {
+        let throttle = Throttle::new(Some(20.0), None, None)?;
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.wait().await;
+        }
+        // The first task starts straight away, then each one 50ms after the last.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_throttle_needs_positive_rate() {
+        assert!(Throttle::new(Some(0.0), None, None).is_err());
+        assert!(Throttle::new(Some(-1.0), None, None).is_err());
+        assert!(Throttle::new(Some(f64::NAN), None, None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_throttle_load() -> io::Result<()> {
+        assert!(load_average()? >= 0.0);
+        let throttle = Throttle::new(None, Some(f64::MAX), None)?;
+        time::timeout(Duration::from_secs(1), throttle.wait())
+            .await
+            .expect("Load is never over the limit");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_throttle_window() -> io::Result<()> {
+        // A window that opens a minute from now and closes just before now, so it's closed.
+        let now = window::local_time_of_day()? / 60;
+        let time = |minutes: u32| format!("{:02}:{:02}", minutes / 60 % 24, minutes % 60);
+        let closed = format!("{}-{}", time(now + 1), time(now + 1439))
+            .parse()
+            .unwrap();
+        let throttle = Throttle::new(None, None, Some(closed))?;
+        assert_eq!(Some(closed), throttle.closed_window());
+        assert!(time::timeout(Duration::from_millis(50), throttle.wait())
+            .await
+            .is_err());
+        Ok(())
+    }
+}
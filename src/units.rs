@@ -0,0 +1,111 @@
+//! Durations and sizes written the way people write them, like `2h30m` and `500M`.
+//!
+//! These are what the command line accepts, so that anything else configuring reach
+//! can accept exactly the same.
+
+use std::time::Duration;
+
+/// Parse a duration like `90`, `90s`, `1500ms`, `5m`, `2h`, or `2h30m`.
+///
+/// A duration is one or more numbers, each followed by a unit: `ms`, `s`, `m`, `h`, or `d`.
+/// A bare number on its own is a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {:?}", s);
+    if s.is_empty() {
+        return Err(invalid());
+    }
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        return s.parse().map(Duration::from_secs).map_err(|_| invalid());
+    }
+    let mut total = Duration::default();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest);
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let split = after
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(split);
+        let part = match unit {
+            "ms" => Some(Duration::from_millis(number)),
+            "s" => Some(Duration::from_secs(number)),
+            "m" => number.checked_mul(60).map(Duration::from_secs),
+            "h" => number.checked_mul(60 * 60).map(Duration::from_secs),
+            "d" => number.checked_mul(24 * 60 * 60).map(Duration::from_secs),
+            _ => return Err(format!("Invalid duration unit {:?} in {:?}", unit, s)),
+        };
+        total = part
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| format!("Duration too long: {:?}", s))?;
+        rest = after;
+    }
+    Ok(total)
+}
+
+/// Parse a size in bytes like `1024`, `64K`, `500M`, `2G`, or `2T`.
+///
+/// Units are powers of 1024, and may be in either case, and followed by `B` or `iB`,
+/// as in `500GB` or `500GiB`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(s);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size: {:?}", s))?;
+    let upper = unit.to_uppercase();
+    let unit = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let power = match unit {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(format!("Invalid size unit {:?} in {:?}", unit, s)),
+    };
+    number
+        .checked_mul(1024u64.pow(power))
+        .ok_or_else(|| format!("Size too large: {:?}", s))
+}
+
+/// Split the digits off the start of `s`.
+fn split_number(s: &str) -> (&str, &str) {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(split)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(Ok(1024), parse_size("1024"));
+        assert_eq!(Ok(64 * 1024), parse_size("64K"));
+        assert_eq!(Ok(3 * 1024 * 1024), parse_size("3m"));
+        assert_eq!(Ok(500 * 1024 * 1024 * 1024), parse_size("500G"));
+        assert_eq!(Ok(500 * 1024 * 1024 * 1024), parse_size("500GB"));
+        assert_eq!(Ok(2 * 1024u64.pow(4)), parse_size("2TiB"));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("5P").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(Duration::from_secs(90)), parse_duration("90"));
+        assert_eq!(Ok(Duration::from_secs(90)), parse_duration("90s"));
+        assert_eq!(Ok(Duration::from_millis(1500)), parse_duration("1500ms"));
+        assert_eq!(Ok(Duration::from_secs(300)), parse_duration("5m"));
+        assert_eq!(Ok(Duration::from_secs(7200)), parse_duration("2h"));
+        assert_eq!(Ok(Duration::from_secs(9000)), parse_duration("2h30m"));
+        assert_eq!(Ok(Duration::from_secs(90)), parse_duration("1m30s"));
+        assert_eq!(Ok(Duration::from_secs(86400)), parse_duration("1d"));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("1m30").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+    }
+}
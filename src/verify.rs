@@ -0,0 +1,254 @@
+//! Checking that the results in a destination directory are what their runs left, without
+//! running anything, so that archived results can be trusted.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::checksums;
+use crate::error;
+use crate::naming::MANIFEST_FILE;
+use crate::status::STATUS_FILE;
+use crate::{Message, Status};
+
+/// Something wrong with a task's results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    /// The task's destination directory.
+    pub task_dir: PathBuf,
+    /// What's wrong, for people to read.
+    pub problem: String,
+}
+
+/// What `verify` found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Verification {
+    /// How many task directories were checked.
+    pub tasks: usize,
+    /// How many of them had their files' checksums checked too.
+    pub checksummed: usize,
+    /// Everything found wrong, in the order the task directories are named.
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Verification {
+    /// Whether nothing was found wrong.
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+
+    fn add(&mut self, task_dir: &Path, problem: impl Into<String>) {
+        self.discrepancies.push(Discrepancy {
+            task_dir: task_dir.to_owned(),
+            problem: problem.into(),
+        });
+    }
+}
+
+impl fmt::Display for Verification {
+    /// A report for people, listing every discrepancy.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for discrepancy in &self.discrepancies {
+            writeln!(
+                f,
+                "{}: {}",
+                discrepancy.task_dir.display(),
+                discrepancy.problem
+            )?;
+        }
+        let dirs: BTreeSet<_> = self.discrepancies.iter().map(|d| &d.task_dir).collect();
+        writeln!(
+            f,
+            "Verified {} tasks, {} with checksums: {} consistent, {} with discrepancies.",
+            self.tasks,
+            self.checksummed,
+            self.tasks.saturating_sub(dirs.len()),
+            dirs.len()
+        )
+    }
+}
+
+/// Check every task directory in `destination_dir`, without running anything: that each has
+/// a status saying its task succeeded, and no output that had to be clipped; that its files
+/// are as they were when it finished, if their checksums were recorded; and that they agree
+/// with the manifest, if there is one.
+///
+/// Only fails if the destination directory itself can't be read. Anything wrong with the
+/// tasks in it is in the `Verification`.
+pub async fn verify(destination_dir: &Path) -> io::Result<Verification> {
+    let mut verification = Verification::default();
+    let found = task_dirs(destination_dir).await?;
+    let listed = manifest_destinations(destination_dir).await?;
+    let names: BTreeSet<String> = match &listed {
+        Some(listed) => {
+            // Mirrored names are paths, so directories in the destination can hold tasks
+            // rather than be them.
+            for name in &found {
+                let prefix = format!("{}/", name);
+                if !listed.contains(name) && !listed.iter().any(|l| l.starts_with(&prefix)) {
+                    verification.add(&destination_dir.join(name), "isn't in the manifest");
+                }
+            }
+            listed.clone()
+        }
+        None => found,
+    };
+    for name in &names {
+        let task_dir = destination_dir.join(name);
+        if !fs::metadata(&task_dir).await.is_ok_and(|m| m.is_dir()) {
+            verification.add(&task_dir, "is in the manifest, but isn't there");
+            continue;
+        }
+        verification.tasks += 1;
+        verify_task(&task_dir, &mut verification).await;
+    }
+    verification
+        .discrepancies
+        .sort_by(|a, b| a.task_dir.cmp(&b.task_dir));
+    Ok(verification)
+}
+
+/// Check the task directory `task_dir`, adding what's wrong with it to `verification`.
+async fn verify_task(task_dir: &Path, verification: &mut Verification) {
+    match fs::read_to_string(task_dir.join(STATUS_FILE)).await {
+        Ok(contents) => {
+            let mut lines = contents.lines();
+            match lines.next().unwrap_or_default().trim().parse::<Status>() {
+                Ok(status) if status.is_success() => {}
+                Ok(status) => verification.add(task_dir, format!("failed: {}", status)),
+                Err(error) => verification.add(task_dir, format!("bad status: {}", error)),
+            }
+            for name in lines.filter_map(|line| line.strip_prefix("clipped ")) {
+                verification.add(task_dir, format!("{} was clipped", name));
+            }
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            verification.add(task_dir, "has no status, so its task never finished")
+        }
+        Err(error) => verification.add(task_dir, format!("status can't be read: {}", error)),
+    }
+    let recorded = match checksums::read(task_dir).await {
+        Ok(Some(recorded)) => recorded,
+        Ok(None) => return,
+        Err(error) => {
+            verification.add(task_dir, format!("checksums can't be read: {}", error));
+            return;
+        }
+    };
+    verification.checksummed += 1;
+    match checksums::compute(task_dir).await {
+        Ok(now) => {
+            for change in checksums::changes(&recorded, &now) {
+                verification.add(task_dir, change);
+            }
+        }
+        Err(error) => verification.add(task_dir, format!("files can't be read: {}", error)),
+    }
+}
+
+/// The names of the directories directly in `destination_dir`, apart from hidden ones,
+/// like the state directory.
+async fn task_dirs(destination_dir: &Path) -> io::Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    let mut entries = fs::read_dir(destination_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with('.') && entry.file_type().await?.is_dir() {
+            names.insert(name);
+        }
+    }
+    Ok(names)
+}
+
+/// The destination directories the manifest in `destination_dir` lists, if there is one.
+async fn manifest_destinations(destination_dir: &Path) -> io::Result<Option<BTreeSet<String>>> {
+    let path = destination_dir.join(MANIFEST_FILE);
+    let contents = match fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let manifest: serde_json::Value = serde_json::from_str(&contents).map_err(|error| {
+        error::new(
+            io::ErrorKind::InvalidData,
+            Message::InvalidManifest {
+                path: &path,
+                problem: &error.to_string(),
+            },
+        )
+    })?;
+    let inputs = manifest["inputs"].as_array().cloned().unwrap_or_default();
+    Ok(Some(
+        inputs
+            .iter()
+            .filter_map(|input| input["destination"].as_str().map(String::from))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let task = |name: &str, status: &str| {
+            let task_dir = dir.path().join(name);
+            std::fs::create_dir_all(&task_dir)?;
+            std::fs::write(task_dir.join("out"), "output\n")?;
+            std::fs::write(task_dir.join(STATUS_FILE), status)
+        };
+        task("a", "0\n")?;
+        task("b", "0\nclipped err\n")?;
+        task("c", "signal 9\nsignal SIGKILL\n")?;
+        std::fs::create_dir_all(dir.path().join("d"))?;
+        std::fs::create_dir_all(dir.path().join(crate::STATE_DIR))?;
+        checksums::record(&dir.path().join("a")).await?;
+        let verification = verify(dir.path()).await?;
+        assert_eq!(4, verification.tasks);
+        assert_eq!(1, verification.checksummed);
+        let problems: Vec<_> = verification
+            .discrepancies
+            .iter()
+            .map(|d| (d.task_dir.strip_prefix(dir.path()).unwrap(), &d.problem[..]))
+            .collect();
+        assert_eq!(
+            vec![
+                (Path::new("b"), "err was clipped"),
+                (Path::new("c"), "failed: signal 9"),
+                (Path::new("d"), "has no status, so its task never finished"),
+            ],
+            problems
+        );
+        assert!(verification.to_string().ends_with(
+            "Verified 4 tasks, 1 with checksums: 1 consistent, 3 with discrepancies.\n"
+        ));
+
+        std::fs::write(dir.path().join("a/out"), "out")?;
+        std::fs::write(
+            dir.path().join(MANIFEST_FILE),
+            r#"{"inputs": [{"destination": "a"}, {"destination": "e"}]}"#,
+        )?;
+        let verification = verify(dir.path()).await?;
+        assert_eq!(1, verification.tasks);
+        let problems: Vec<_> = verification
+            .discrepancies
+            .iter()
+            .map(|d| &d.problem[..])
+            .collect();
+        assert_eq!(
+            vec![
+                "out has been truncated to 3 bytes from 7",
+                "isn't in the manifest",
+                "isn't in the manifest",
+                "isn't in the manifest",
+                "is in the manifest, but isn't there",
+            ],
+            problems
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! The times of day when new tasks may start, so that a run can keep to off-peak hours.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error;
+use crate::Message;
+
+const SECONDS_IN_A_DAY: u32 = 24 * 60 * 60;
+
+/// A daily window of local time, like `22:00-06:00`, which wraps past midnight
+/// if it ends before it starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Window {
+    /// When it opens, in seconds since midnight.
+    start: u32,
+    /// When it closes, in seconds since midnight.
+    end: u32,
+}
+
+impl Window {
+    /// How long until the window next opens, from `now` seconds since midnight,
+    /// or `None` if it's open now.
+    pub(crate) fn closed_for(&self, now: u32) -> Option<Duration> {
+        let open = if self.start < self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        };
+        if open {
+            return None;
+        }
+        let wait = (self.start + SECONDS_IN_A_DAY - now) % SECONDS_IN_A_DAY;
+        Some(Duration::from_secs(wait.into()))
+    }
+
+    /// When it opens, as written.
+    pub(crate) fn opens(&self) -> String {
+        time_of_day(self.start)
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", time_of_day(self.start), time_of_day(self.end))
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid window: {:?}. Try one like '22:00-06:00'.", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time_of_day(start).ok_or_else(invalid)?;
+        let end = parse_time_of_day(end).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!(
+                "Invalid window: {:?}. It would never close, so there's no need for one.",
+                s
+            ));
+        }
+        Ok(Window { start, end })
+    }
+}
+
+/// Parse a time of day like `06:00` or `6:30` into seconds since midnight.
+fn parse_time_of_day(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    if minutes.len() != 2 {
+        return None;
+    }
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    // 24:00 is the end of the day, which is also its start.
+    match (hours, minutes) {
+        (24, 0) => Some(0),
+        (0..=23, 0..=59) => Some((hours * 60 + minutes) * 60),
+        _ => None,
+    }
+}
+
+fn time_of_day(seconds: u32) -> String {
+    format!("{:02}:{:02}", seconds / 3600, seconds / 60 % 60)
+}
+
+/// The local time now, in seconds since midnight.
+#[cfg(unix)]
+pub(crate) fn local_time_of_day() -> io::Result<u32> {
+    // SAFETY: `time` accepts a null pointer, and `localtime_r` only writes to `tm`,
+    // which it fills in if it succeeds.
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return Err(error::new(
+                io::ErrorKind::Other,
+                Message::LocalTimeUnreadable,
+            ));
+        }
+        Ok((tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec.min(59)) as u32)
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn local_time_of_day() -> io::Result<u32> {
+    Err(error::unsupported(crate::Unsupported::Windows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> u32 {
+        parse_time_of_day(time).unwrap()
+    }
+
+    #[test]
+    fn test_window() {
+        let night: Window = "22:00-06:00".parse().unwrap();
+        assert_eq!("22:00-06:00", night.to_string());
+        assert_eq!(None, night.closed_for(at("22:00")));
+        assert_eq!(None, night.closed_for(at("0:00")));
+        assert_eq!(None, night.closed_for(at("05:59")));
+        assert_eq!(
+            Some(Duration::from_secs(16 * 3600)),
+            night.closed_for(at("06:00"))
+        );
+        assert_eq!(Some(Duration::from_secs(60)), night.closed_for(at("21:59")));
+
+        let lunch: Window = "12:30-13:15".parse().unwrap();
+        assert_eq!(None, lunch.closed_for(at("13:00")));
+        assert_eq!(
+            Some(Duration::from_secs(23 * 3600 + 15 * 60)),
+            lunch.closed_for(at("13:15"))
+        );
+        assert_eq!("12:30", lunch.opens());
+        assert_eq!(
+            "00:00-09:00",
+            "24:00-9:00".parse::<Window>().unwrap().to_string()
+        );
+
+        for invalid in &["22:00", "22:00-6", "25:00-06:00", "22:60-06:00", "6:5-7:00"] {
+            assert!(invalid.parse::<Window>().is_err(), "{}", invalid);
+        }
+        assert!("06:00-06:00".parse::<Window>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_local_time_of_day() -> io::Result<()> {
+        assert!(local_time_of_day()? < SECONDS_IN_A_DAY);
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
-use reach;
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{env, fs, io};
-use tempfile;
 use tempfile::TempDir;
-use tokio;
 
 fn new_test_config<C, S, D>(
     command: C,
@@ -17,15 +18,145 @@ where
     S: Into<PathBuf>,
     D: Into<PathBuf>,
 {
+    let destination_dir = dest_dir.into();
     reach::Config {
         command: command.into(),
         shell: env::var("SHELL").unwrap_or(String::from("/bin/sh")),
         source_dir: source_dir.into(),
-        destination_dir: dest_dir.into(),
+        from_stdin: false,
+        null_separated: false,
+        state_dir: destination_dir.join(".reach"),
+        more_sources: Vec::new(),
+        include: Vec::new(),
+        exclude: Vec::new(),
+        skip_invalid_names: false,
+        destination_dir,
+        nested_destination: false,
+        order: reach::Order::Unordered,
+        seed: None,
+        naming: reach::Naming::Name,
+        manifest: false,
+        from_manifest: None,
         input_mode,
+        framing: reach::Framing::Length,
         num_processes: 1,
+        low_memory: false,
+        split_bytes: None,
+        batch: 1,
+        io_concurrency: 1,
+        prefetch: 0,
         recreate: true,
-        retries: 1,
+        allow_command_change: false,
+        hash_inputs: false,
+        checksums: false,
+        retry_failed: false,
+        rerun_matching: None,
+        watch: None,
+        notify_systemd: false,
+        retries: 0,
+        retry_signals: Vec::new(),
+        timeout: None,
+        halt: reach::Halt::Never,
+        max_total_output: None,
+        output_policy: reach::OutputPolicy::Stop,
+        capture: reach::Capture::Separate,
+        stdout_name: "out".into(),
+        stderr_name: "err".into(),
+        max_output_size: None,
+        group_by: None,
+        max_rate: None,
+        max_load: None,
+        window: None,
+        pause: None,
+        shell_sessions: false,
+        affinity: None,
+        setup: None,
+        teardown: None,
+        before_all: None,
+        after_all: None,
+        workdir: None,
+        then: Vec::new(),
+        wrap: None,
+        systemd_scope: false,
+        run_as: None,
+        nice: None,
+        memory_limit: None,
+        cpus_per_task: None,
+        task_max_fds: None,
+        task_max_procs: None,
+        restrict_fs: false,
+        restrict_fs_read: Vec::new(),
+        systemd_properties: Vec::new(),
+        workers: Vec::new(),
+        ssh: "ssh".into(),
+    }
+}
+
+/// A `Progress` that remembers the outcome of every task, and every event about one.
+#[derive(Default)]
+struct RecordingProgress {
+    results: Mutex<Vec<Result<ExitStatus, io::ErrorKind>>>,
+    events: Mutex<Vec<String>>,
+    warnings: Mutex<Vec<String>>,
+}
+
+impl RecordingProgress {
+    fn results(&self) -> Vec<Result<ExitStatus, io::ErrorKind>> {
+        self.results.lock().unwrap().clone()
+    }
+
+    fn record(&self, event: &str, input: &Path) {
+        let name = input.file_name().unwrap().to_string_lossy();
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", event, name));
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().clone()
+    }
+}
+
+impl reach::Progress for &RecordingProgress {
+    fn set_num_tasks(&self, _tasks: usize) {}
+
+    fn task_skipped(&self, _id: &reach::TaskId, input: &Path) {
+        self.record("skipped", input);
+    }
+
+    fn task_started(&self, _id: &reach::TaskId, input: &Path) {
+        self.record("started", input);
+    }
+
+    fn task_retrying(
+        &self,
+        _id: &reach::TaskId,
+        input: &Path,
+        attempt: u32,
+        _result: &io::Result<ExitStatus>,
+    ) {
+        self.record(&format!("attempt {} of", attempt), input);
+    }
+
+    fn task_completed(&self, id: &reach::TaskId, outcome: &reach::TaskOutcome<'_>) {
+        assert_eq!(id, &outcome.task.id);
+        self.record("completed", outcome.input());
+        self.results.lock().unwrap().push(
+            outcome
+                .result
+                .as_ref()
+                .map(|status| *status)
+                .map_err(|e| e.kind()),
+        );
+    }
+
+    fn warn(&self, warning: &reach::Message<'_>) {
+        self.warnings.lock().unwrap().push(warning.to_string());
     }
 }
 
@@ -39,7 +170,7 @@ where
         let (path, contents) = entry;
         let file_path = source_path.join(path);
         let mut file = fs::File::create(file_path)?;
-        file.write(contents)?;
+        file.write_all(contents)?;
     }
     Ok(source)
 }
@@ -53,7 +184,7 @@ where
 async fn test_stdin_empty() -> io::Result<()> {
     let source = tempfile::tempdir()?;
     let destination = tempfile::tempdir()?;
-    reach::run(
+    let summary = reach::run(
         new_test_config(
             "cat",
             source.path(),
@@ -62,7 +193,11 @@ async fn test_stdin_empty() -> io::Result<()> {
         ),
         (),
     )
-    .await
+    .await?;
+    assert_eq!(0, summary.total());
+    assert_eq!(0, summary.retried);
+    assert!(summary.failures.is_empty());
+    Ok(())
 }
 
 /// Basic test for stdin processing happy path.
@@ -70,7 +205,7 @@ async fn test_stdin_empty() -> io::Result<()> {
 /// We use `cat` as our command.
 /// The destination directory has a file in `out` matching each file in our source directory.
 /// All of the `err` files are empty,
-/// and the `status` files don't exist, because we haven't implemented them.
+/// and the `status` files record that the command exited successfully.
 #[tokio::test]
 async fn test_stdin() -> io::Result<()> {
     let source = make_source_directory(&[
@@ -96,7 +231,7 @@ async fn test_stdin() -> io::Result<()> {
         .collect::<Result<Vec<_>, io::Error>>()?;
     filenames.sort();
 
-    assert_eq!(vec!["file1.txt", "file2.txt"], filenames);
+    assert_eq!(vec![".reach", "file1.txt", "file2.txt"], filenames);
     assert_eq!(
         "Arbitrary content for file one\n",
         String::from_utf8_lossy(&fs::read(destination_path.join("file1.txt/out"))?)
@@ -105,7 +240,10 @@ async fn test_stdin() -> io::Result<()> {
         "",
         String::from_utf8_lossy(&fs::read(destination_path.join("file1.txt/err"))?)
     );
-    assert!(!destination_path.join("file1/status").exists());
+    assert_eq!(
+        "0\n",
+        String::from_utf8_lossy(&fs::read(destination_path.join("file1.txt/status"))?)
+    );
     assert_eq!(
         "Arbitrary content for file two\n",
         String::from_utf8_lossy(&fs::read(destination_path.join("file2.txt/out"))?)
@@ -114,7 +252,10 @@ async fn test_stdin() -> io::Result<()> {
         "",
         String::from_utf8_lossy(&fs::read(destination_path.join("file2.txt/err"))?)
     );
-    assert!(!destination_path.join("file2/status").exists());
+    assert_eq!(
+        "0\n",
+        String::from_utf8_lossy(&fs::read(destination_path.join("file2.txt/status"))?)
+    );
     Ok(())
 }
 
@@ -123,7 +264,7 @@ async fn test_stdin() -> io::Result<()> {
 /// We use `echo {}` as our command.
 /// The destination directory has a file in `out` matching each file in our source directory.
 /// All of the `err` files are empty,
-/// and the `status` files don't exist, because we haven't implemented them.
+/// and the `status` files record that the command exited successfully.
 #[tokio::test]
 async fn test_filename() -> io::Result<()> {
     let source = make_source_directory(&[
@@ -149,7 +290,7 @@ async fn test_filename() -> io::Result<()> {
         .collect::<Result<Vec<_>, io::Error>>()?;
     filenames.sort();
 
-    assert_eq!(vec!["file1.txt", "file2.txt"], filenames);
+    assert_eq!(vec![".reach", "file1.txt", "file2.txt"], filenames);
     assert_eq!(
         source.path().join("file1.txt").to_string_lossy(),
         String::from_utf8_lossy(&fs::read(destination_path.join("file1.txt/out"))?)
@@ -158,7 +299,10 @@ async fn test_filename() -> io::Result<()> {
         "",
         String::from_utf8_lossy(&fs::read(destination_path.join("file1.txt/err"))?)
     );
-    assert!(!destination_path.join("file1/status").exists());
+    assert_eq!(
+        "0\n",
+        String::from_utf8_lossy(&fs::read(destination_path.join("file1.txt/status"))?)
+    );
     assert_eq!(
         source.path().join("file2.txt").to_string_lossy(),
         String::from_utf8_lossy(&fs::read(destination_path.join("file2.txt/out"))?)
@@ -167,6 +311,2901 @@ async fn test_filename() -> io::Result<()> {
         "",
         String::from_utf8_lossy(&fs::read(destination_path.join("file2.txt/err"))?)
     );
-    assert!(!destination_path.join("file2/status").exists());
+    assert_eq!(
+        "0\n",
+        String::from_utf8_lossy(&fs::read(destination_path.join("file2.txt/status"))?)
+    );
+    Ok(())
+}
+
+/// Commands that run for longer than the timeout are killed and recorded as failures.
+#[tokio::test]
+async fn test_timeout() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "sleep 30",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.timeout = Some(Duration::from_millis(100));
+    let progress = RecordingProgress::default();
+
+    let start = Instant::now();
+    let summary = reach::run(config, &progress).await?;
+
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(vec![Err(io::ErrorKind::TimedOut)], progress.results());
+    assert_eq!(1, summary.failed);
+    Ok(())
+}
+
+/// A timeout stops everything the command started, not just the command itself.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_timeout_kills_process_group() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "sleep 37 & echo $! > \"$REACH_DEST_DIR/pid\"; wait",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.timeout = Some(Duration::from_millis(200));
+
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(1, summary.failed);
+    let pid = fs::read_to_string(destination.path().join("file1.txt/pid"))?;
+    assert!(exits_soon(pid.trim()).await, "sleep was left running");
+    Ok(())
+}
+
+/// A command that's being terminated can find out why from its cancel file before it's killed.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_cancel_reason() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "trap 'cp \"$REACH_CANCEL_FILE\" \"$REACH_DEST_DIR/saved\"; exit 3' TERM; sleep 30 & wait",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.timeout = Some(Duration::from_millis(200));
+
+    let tasks = reach::run_collect(config, ()).await?;
+
+    assert_eq!(Some(reach::CancelReason::Timeout), tasks[0].cancelled);
+    let task_dir = destination.path().join("file1.txt");
+    assert_eq!("timeout\n", fs::read_to_string(task_dir.join("saved"))?);
+    assert_eq!(
+        "timed out\ncancelled timeout\n",
+        fs::read_to_string(task_dir.join("status"))?
+    );
+    Ok(())
+}
+
+/// Every task gets an event when it's skipped, started, retried, and completed.
+#[tokio::test]
+async fn test_progress_events() -> io::Result<()> {
+    let source = make_source_directory(&[("good.txt", b"good\n"), ("bad.txt", b"bad\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = || {
+        let mut config = new_test_config(
+            "grep -q good",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.retries = 1;
+        config.recreate = false;
+        config.order = reach::Order::Name;
+        config.num_processes = 1;
+        config
+    };
+
+    let progress = RecordingProgress::default();
+    reach::run(config(), &progress).await?;
+    assert_eq!(
+        vec![
+            "started bad.txt",
+            "attempt 2 of bad.txt",
+            "completed bad.txt",
+            "started good.txt",
+            "completed good.txt",
+        ],
+        progress.events()
+    );
+
+    let progress = RecordingProgress::default();
+    reach::run(config(), &progress).await?;
+    assert_eq!(
+        vec![
+            "skipped good.txt",
+            "started bad.txt",
+            "attempt 2 of bad.txt",
+            "completed bad.txt",
+        ],
+        progress.events()
+    );
+    Ok(())
+}
+
+/// Metrics count tasks as they go, alongside other progress, and can be scraped over HTTP.
+#[tokio::test]
+async fn test_metrics() -> io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let source = make_source_directory(&[
+        ("good.txt", b"good\n"),
+        ("bad.txt", b"bad\n"),
+        ("also-good.txt", b"good\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "grep -q good",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.retries = 1;
+
+    let metrics = reach::Metrics::new();
+    let progress = RecordingProgress::default();
+    reach::run(config, (&progress, metrics.clone())).await?;
+    assert_eq!(7, progress.events().len());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let server = metrics.clone();
+    tokio::spawn(async move { server.serve(listener).await });
+    let mut connection = tokio::net::TcpStream::connect(address).await?;
+    connection
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    connection.read_to_string(&mut response).await?;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with(&metrics.render()));
+    for line in &[
+        "reach_tasks_started_total 3",
+        "reach_tasks_succeeded_total 2",
+        "reach_tasks_failed_total 1",
+        "reach_task_retries_total 1",
+        "reach_tasks_in_flight 0",
+        "reach_task_duration_seconds_count 3",
+    ] {
+        assert!(response.lines().any(|l| l == *line), "No {:?}", line);
+    }
+
+    let file = destination.path().join("metrics.txt");
+    metrics.write(&file).await?;
+    assert_eq!(metrics.render(), fs::read_to_string(&file)?);
+    Ok(())
+}
+
+/// The progress file counts the run's tasks, and once they're all done has nothing left to wait for.
+#[tokio::test]
+async fn test_progress_file() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("good.txt", b"good\n"),
+        ("bad.txt", b"bad\n"),
+        ("also-good.txt", b"good\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = new_test_config(
+        "grep -q good",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+
+    let progress_file = reach::ProgressFile::new();
+    reach::run(config, progress_file.clone()).await?;
+    let path = destination.path().join("progress.json");
+    progress_file.write(&path).await?;
+
+    let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    assert_eq!(3, written["total"]);
+    assert_eq!(3, written["done"]);
+    assert_eq!(1, written["failed"]);
+    assert_eq!(0, written["in_flight"]);
+    assert_eq!(0.0, written["eta_secs"]);
+    Ok(())
+}
+
+/// Only the files that are included and not excluded are inputs.
+#[tokio::test]
+async fn test_include_exclude() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("a.csv", b"a\n"),
+        ("b.csv", b"b\n"),
+        ("b.csv.tmp", b"b\n"),
+        ("c.json", b"c\n"),
+        ("notes.txt", b"notes\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = |exclude: &[&str]| {
+        let mut config = new_test_config(
+            "cat",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.include = vec!["*.csv*".into(), "*.json".into()];
+        config.exclude = exclude.iter().map(|&pattern| pattern.into()).collect();
+        config.order = reach::Order::Name;
+        config
+    };
+
+    let progress = RecordingProgress::default();
+    reach::run(config(&["*.tmp", "c.*"]), &progress).await?;
+    assert_eq!(
+        vec![
+            "started a.csv",
+            "completed a.csv",
+            "started b.csv",
+            "completed b.csv"
+        ],
+        progress.events()
+    );
+
+    let error = reach::run(config(&["["]), ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// In filename mode, files whose names a shell can't be given fail their tasks, unless
+/// they're left out with `skip_invalid_names`.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_skip_invalid_names() -> io::Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+    let source = make_source_directory(&[("ok.txt", b"ok\n")])?;
+    let invalid = OsStr::from_bytes(b"caf\xe9.txt");
+    fs::write(source.path().join(invalid), "invalid\n")?;
+    // Stands in for PowerShell, which only ever sees unicode command lines.
+    let bin = tempfile::tempdir()?;
+    let pwsh = bin.path().join("pwsh");
+    fs::write(&pwsh, "#!/bin/sh\nshift 4\nexec sh -c \"$1\"\n")?;
+    fs::set_permissions(&pwsh, fs::Permissions::from_mode(0o755))?;
+    let destination = tempfile::tempdir()?;
+    let config = |skip_invalid_names| {
+        let mut config = new_test_config(
+            "cat {}",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        );
+        config.shell = pwsh.to_str().unwrap().into();
+        config.skip_invalid_names = skip_invalid_names;
+        config
+    };
+
+    let summary = reach::run(config(false), ()).await?;
+    assert_eq!((1, 1), (summary.succeeded, summary.failed), "{}", summary);
+    fs::remove_dir_all(destination.path().join(invalid))?;
+
+    let summary = reach::run(config(true), ()).await?;
+    assert_eq!((1, 0), (summary.succeeded, summary.failed), "{}", summary);
+    assert_eq!(
+        "ok\n",
+        fs::read_to_string(destination.path().join("ok.txt/out"))?
+    );
+    assert!(!destination.path().join(invalid).exists());
+    Ok(())
+}
+
+/// A destination inside the source is refused unless it's allowed, and a source inside the
+/// destination always is, as the run could take its own outputs as inputs.
+#[tokio::test]
+async fn test_nested_destination() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = source.path().join("results");
+    let mut config = new_test_config("cat", source.path(), &destination, reach::InputMode::Stdin);
+    let error = reach::run(config, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+
+    config = new_test_config("cat", source.path(), &destination, reach::InputMode::Stdin);
+    config.nested_destination = true;
+    let summary = reach::run(config, ()).await?;
+    assert_eq!(1, summary.succeeded);
+    assert_eq!(1, summary.total());
+    assert!(destination.join("file1.txt").is_dir());
+
+    let config = new_test_config(
+        "cat",
+        destination.join("file1.txt"),
+        source.path(),
+        reach::InputMode::Stdin,
+    );
+    let error = reach::run(config, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// Errors say what reach was doing when it failed, and with which path.
+#[tokio::test]
+async fn test_error_phases() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = TempDir::new()?;
+    let missing = source.path().join("missing");
+    let config = new_test_config("cat", &missing, destination.path(), reach::InputMode::Stdin);
+    let error = reach::run(config, ()).await.unwrap_err();
+    assert!(matches!(error, reach::Error::Source { .. }));
+    assert_eq!(Some(missing.as_path()), error.path());
+    assert_eq!(io::ErrorKind::NotFound, error.kind());
+
+    let config = new_test_config(
+        "cat",
+        source.path(),
+        source.path().join("results"),
+        reach::InputMode::Stdin,
+    );
+    let error = reach::run(config, ()).await.unwrap_err();
+    assert!(matches!(error, reach::Error::Config(_)));
+
+    // A task whose command can't be started fails on its own, saying why.
+    let config = new_test_config(
+        "/no/such/program {}",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Exec,
+    );
+    let tasks = reach::run_collect(config, ()).await?;
+    let message = tasks[0].error.as_deref().unwrap_or_default();
+    let expected = format!(
+        "Could not start the command for {}",
+        source.path().join("file1.txt").display()
+    );
+    assert!(message.starts_with(&expected), "{}", message);
+    Ok(())
+}
+
+/// A task whose output files can't be created fails on its own, and is retried,
+/// while the rest of the run carries on.
+#[tokio::test]
+async fn test_output_error() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n"), ("file2.txt", b"two\n")])?;
+    let destination = TempDir::new()?;
+    let out_path = destination.path().join("file1.txt/out");
+    fs::create_dir_all(&out_path)?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.retries = 1;
+    let summary = reach::run(config, ()).await?;
+    assert_eq!(1, summary.succeeded);
+    assert_eq!(1, summary.failed);
+    assert_eq!(1, summary.retried);
+    assert!(!summary.all_succeeded());
+    let message = summary.failures[0].error.as_deref().unwrap_or_default();
+    let expected = format!("Could not write to {}", out_path.display());
+    assert!(message.starts_with(&expected), "{}", message);
+    Ok(())
+}
+
+/// Lists the names of the entries in a directory, sorted.
+fn list_dir(path: &Path) -> io::Result<Vec<std::ffi::OsString>> {
+    let mut filenames = fs::read_dir(path)?
+        .map(|res| res.map(|e| e.file_name()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    filenames.sort();
+    Ok(filenames)
+}
+
+/// reach never writes to the source directory, so it can process inputs on read-only filesystems.
+/// Commands run within the limits on niceness, memory, CPUs, open files, and processes.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_resource_limits() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = |shell_sessions, cpus_per_task| {
+        let mut config = new_test_config(
+            "nice; ulimit -v; nproc; ulimit -n; awk '/Max processes/ { print $3 }' /proc/self/limits",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.shell = "sh".into();
+        config.shell_sessions = shell_sessions;
+        config.nice = Some(10);
+        config.memory_limit = Some(1 << 30);
+        config.cpus_per_task = Some(cpus_per_task);
+        config.task_max_fds = Some(64);
+        config.task_max_procs = Some(4096);
+        config
+    };
+
+    for &shell_sessions in &[false, true] {
+        let summary = reach::run(config(shell_sessions, 1), ()).await?;
+        assert!(summary.all_succeeded(), "{}", summary);
+        assert_eq!(
+            "10\n1048576\n1\n64\n4096\n",
+            fs::read_to_string(destination.path().join("file1.txt/out"))?
+        );
+    }
+
+    let error = reach::run(config(false, 100_000), ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// Confined commands can read their inputs and write their results and temporary files,
+/// but can't change their sources. Only on kernels with Landlock.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_restrict_fs() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n"), ("file2.txt", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let planted = source.path().join("planted");
+    let config = |command: &str| {
+        let mut config = new_test_config(
+            command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.restrict_fs = true;
+        config
+    };
+    // Each task's destination directory is its own, to write in.
+    let summary = match reach::run(config("cat > \"$REACH_DEST_DIR/copy\""), ()).await {
+        Err(error) if error.kind() == io::ErrorKind::Unsupported => return Ok(()),
+        result => result?,
+    };
+    assert!(summary.all_succeeded(), "{}", summary);
+    assert_eq!(
+        "two\n",
+        fs::read_to_string(destination.path().join("file2.txt/copy"))?
+    );
+    let summary = reach::run(config(&format!("touch '{}'", planted.display())), ()).await?;
+    assert_eq!(2, summary.failed, "{}", summary);
+    assert!(!planted.exists());
+    // Each has a scratch directory of its own, which goes once it's finished.
+    let summary = reach::run(
+        config("cat > \"$TMPDIR/copy\" && echo \"$TMPDIR\" > \"$REACH_DEST_DIR/tmpdir\""),
+        (),
+    )
+    .await?;
+    assert!(summary.all_succeeded(), "{}", summary);
+    let scratch = fs::read_to_string(destination.path().join("file1.txt/tmpdir"))?;
+    assert_ne!(
+        scratch,
+        fs::read_to_string(destination.path().join("file2.txt/tmpdir"))?
+    );
+    assert!(!Path::new(scratch.trim_end()).exists());
+    Ok(())
+}
+
+/// Commands run as the given user, who owns their tasks' destination directories.
+/// Only root can do that.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run_as() -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    fs::set_permissions(destination.path(), fs::Permissions::from_mode(0o755))?;
+    let nobody = reach::RunAs {
+        uid: 65534,
+        gid: 65534,
+    };
+    let config = |nice| {
+        let mut config = new_test_config(
+            "id -u; id -g; touch \"$REACH_DEST_DIR/mine\"",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.run_as = Some(nobody);
+        config.then = vec!["cat; id -u".into()];
+        config.nice = nice;
+        config
+    };
+
+    // SAFETY: Only reads the process's own user ID.
+    if unsafe { libc::geteuid() } != 0 {
+        let error = reach::run(config(None), ()).await.unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+        return Ok(());
+    }
+    // Commands are no longer root by the time their niceness is set, so it can only go up.
+    let error = reach::run(config(Some(-5)), ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    let summary = reach::run(config(Some(5)), ()).await?;
+    assert!(summary.all_succeeded(), "{}", summary);
+    let task_dir = destination.path().join("file1.txt");
+    assert_eq!("65534\n65534\n", fs::read_to_string(task_dir.join("out"))?);
+    assert_eq!(
+        "65534\n65534\n65534\n",
+        fs::read_to_string(task_dir.join("then-1/out"))?
+    );
+    for path in &[
+        task_dir.clone(),
+        task_dir.join("mine"),
+        task_dir.join("then-1"),
+    ] {
+        let metadata = fs::metadata(path)?;
+        assert_eq!(
+            (65534, 65534),
+            (metadata.uid(), metadata.gid()),
+            "{:?}",
+            path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_read_only_source() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source = make_source_directory(&[
+        ("file1.txt", b"Arbitrary content for file one\n"),
+        ("file2.txt", b"Arbitrary content for file two\n"),
+    ])?;
+    for entry in fs::read_dir(source.path())? {
+        fs::set_permissions(entry?.path(), fs::Permissions::from_mode(0o444))?;
+    }
+    fs::set_permissions(source.path(), fs::Permissions::from_mode(0o555))?;
+
+    let destination = tempfile::tempdir()?;
+    let progress = RecordingProgress::default();
+    let result = reach::run(
+        new_test_config(
+            "cat",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        ),
+        &progress,
+    )
+    .await;
+    let source_contents = list_dir(source.path());
+    fs::set_permissions(source.path(), fs::Permissions::from_mode(0o755))?;
+
+    result?;
+    assert_eq!(vec!["file1.txt", "file2.txt"], source_contents?);
+    assert!(progress.results().iter().all(|result| result.is_ok()));
+    assert_eq!(
+        "Arbitrary content for file one\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join("file1.txt/out"))?)
+    );
+    Ok(())
+}
+
+/// All of reach's bookkeeping can be moved out of the destination directory.
+#[tokio::test]
+async fn test_separate_state_dir() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let state = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.state_dir = state.path().join("reach-state");
+    reach::run(config, ()).await?;
+
+    assert_eq!(vec!["file1.txt"], list_dir(destination.path())?);
+    assert_eq!(vec!["file1.txt"], list_dir(source.path())?);
+    assert!(state.path().join("reach-state").is_dir());
+    Ok(())
+}
+
+/// Limiting file-open concurrency below process concurrency still processes every input.
+#[tokio::test]
+async fn test_io_concurrency_below_processes() -> io::Result<()> {
+    let files: Vec<_> = (0..10)
+        .map(|i| (format!("file{}.txt", i), b"content\n" as &[u8]))
+        .collect();
+    let source = make_source_directory(&files)?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.num_processes = 4;
+    config.io_concurrency = 1;
+    reach::run(config, ()).await?;
+
+    for (name, contents) in files {
+        assert_eq!(
+            contents,
+            &fs::read(destination.path().join(name).join("out"))?[..]
+        );
+    }
+    Ok(())
+}
+
+/// A destination's manifest says which command made it, even once its journal is gone.
+#[tokio::test]
+async fn test_command_change_in_manifest() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = |command: &str| {
+        let mut config = new_test_config(
+            command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.recreate = false;
+        config.manifest = true;
+        config
+    };
+    reach::run(config("cat"), ()).await?;
+    fs::remove_dir_all(destination.path().join(".reach"))?;
+    let error = reach::run(config("wc -c"), ()).await.unwrap_err();
+    assert!(matches!(error, reach::Error::Config(_)));
+    let summary = reach::run(config("cat"), ()).await?;
+    assert_eq!(1, summary.skipped);
+    Ok(())
+}
+
+/// Tasks that have already succeeded are not run again, unless we ask to recreate them.
+#[tokio::test]
+async fn test_skip_completed() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let out_path = destination.path().join("file1.txt/out");
+    let config = |command, recreate| {
+        let mut config = new_test_config(
+            command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.recreate = recreate;
+        config
+    };
+
+    reach::run(config("cat", false), ()).await?;
+    assert_eq!(
+        b"Arbitrary content for file one\n",
+        &fs::read(&out_path)?[..]
+    );
+
+    // Results from a different command can't join the old ones without saying so.
+    let error = reach::run(config("echo again", false), ())
+        .await
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    assert!(error.to_string().contains("command was \"cat\""));
+    let progress = RecordingProgress::default();
+    let mut allowed = config("echo again", false);
+    allowed.allow_command_change = true;
+    reach::run(allowed, &progress).await?;
+    assert_eq!(
+        b"Arbitrary content for file one\n",
+        &fs::read(&out_path)?[..]
+    );
+    assert!(progress.results().is_empty());
+    assert_eq!(1, progress.warnings().len());
+
+    // Recreating every task with the new command is nothing to warn about.
+    let progress = RecordingProgress::default();
+    reach::run(config("echo again", true), &progress).await?;
+    assert_eq!(b"again\n", &fs::read(&out_path)?[..]);
+    let progress = RecordingProgress::default();
+    reach::run(config("echo once more", true), &progress).await?;
+    assert_eq!(Vec::<String>::new(), progress.warnings());
+    Ok(())
+}
+
+/// With input hashing, a task that succeeded is run again once its input changes.
+#[tokio::test]
+async fn test_hash_inputs() -> io::Result<()> {
+    let source = make_source_directory(&[("a", b"one\n"), ("b", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = || {
+        let mut config = new_test_config(
+            "cat",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.recreate = false;
+        config.hash_inputs = true;
+        config
+    };
+
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(2, summary.succeeded);
+
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(0, summary.succeeded);
+    assert_eq!(2, summary.skipped);
+
+    fs::write(source.path().join("b"), b"changed\n")?;
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(1, summary.succeeded);
+    assert_eq!(1, summary.skipped);
+    assert_eq!(
+        b"changed\n",
+        &fs::read(destination.path().join("b/out"))?[..]
+    );
+    Ok(())
+}
+
+/// A run with checksums verifies as consistent, until its results are tampered with.
+#[tokio::test]
+async fn test_verify() -> io::Result<()> {
+    let source = make_source_directory(&[("a", b"one\n"), ("b", b"two\n"), ("c", b"three\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.checksums = true;
+    config.manifest = true;
+    reach::run(config, ()).await?;
+    let verification = reach::verify(destination.path()).await?;
+    assert_eq!(3, verification.tasks);
+    assert_eq!(3, verification.checksummed);
+    assert!(verification.is_consistent(), "{}", verification);
+
+    fs::write(destination.path().join("a/out"), b"on")?;
+    fs::remove_file(destination.path().join("b/status"))?;
+    fs::remove_dir_all(destination.path().join("c"))?;
+    let verification = reach::verify(destination.path()).await?;
+    let problems: Vec<_> = verification
+        .discrepancies
+        .iter()
+        .map(|d| {
+            (
+                d.task_dir.strip_prefix(destination.path()).unwrap(),
+                &d.problem[..],
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            (Path::new("a"), "out has been truncated to 2 bytes from 4"),
+            (Path::new("b"), "has no status, so its task never finished"),
+            (Path::new("c"), "is in the manifest, but isn't there"),
+        ],
+        problems
+    );
+    Ok(())
+}
+
+/// Prefetching inputs doesn't change what the tasks read.
+#[tokio::test]
+async fn test_prefetch() -> io::Result<()> {
+    let files: &[(&str, &[u8])] = &[("a", b"one\n"), ("b", b"two\n"), ("c", b"three\n")];
+    let source = make_source_directory(files)?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.prefetch = 2;
+    let summary = reach::run(config, ()).await?;
+    assert_eq!(3, summary.succeeded);
+    for (name, contents) in files {
+        assert_eq!(
+            *contents,
+            &fs::read(destination.path().join(name).join("out"))?[..]
+        );
+    }
+    Ok(())
+}
+
+/// Interrupting a run stops running commands, starts no new ones,
+/// and marks the interrupted tasks in their status files.
+#[tokio::test]
+async fn test_interrupted() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("file1.txt", b"Arbitrary content for file one\n"),
+        ("file2.txt", b"Arbitrary content for file two\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = new_test_config(
+        "sleep 30",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    let progress = RecordingProgress::default();
+
+    let start = Instant::now();
+    let error = reach::run_until(
+        config,
+        &progress,
+        tokio::time::sleep(Duration::from_millis(200)),
+    )
+    .await
+    .unwrap_err();
+
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(io::ErrorKind::Interrupted, error.kind());
+    assert_eq!(vec![Err(io::ErrorKind::Interrupted)], progress.results());
+    let task_dirs: Vec<_> = list_dir(destination.path())?
+        .into_iter()
+        .filter(|name| name != ".reach")
+        .collect();
+    assert_eq!(1, task_dirs.len());
+    assert_eq!(
+        "interrupted\ncancelled shutdown\n",
+        String::from_utf8_lossy(&fs::read(
+            destination.path().join(&task_dirs[0]).join("status")
+        )?)
+    );
+    Ok(())
+}
+
+/// A run can be cancelled from another task with a token, which stops it as an interrupt does.
+#[tokio::test]
+async fn test_cancellation_token() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = new_test_config(
+        "sleep 30",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    let token = reach::CancellationToken::new();
+    let canceller = token.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        canceller.cancel();
+    });
+
+    let start = Instant::now();
+    let error = reach::run_until(config, (), token.cancelled())
+        .await
+        .unwrap_err();
+
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(io::ErrorKind::Interrupted, error.kind());
+    assert_eq!(
+        "interrupted\ncancelled shutdown\n",
+        fs::read_to_string(destination.path().join("file1.txt/status"))?
+    );
+    Ok(())
+}
+
+/// Whether the process with ID `pid` exits within two seconds, on Linux.
+#[cfg(target_os = "linux")]
+async fn exits_soon(pid: &str) -> bool {
+    let stat = Path::new("/proc").join(pid).join("stat");
+    for _ in 0..100 {
+        // A killed process that hasn't been reaped yet is a zombie, with state `Z`.
+        let alive = fs::read_to_string(&stat).is_ok_and(|stat| {
+            !stat
+                .rsplit(')')
+                .next()
+                .unwrap_or("")
+                .trim_start()
+                .starts_with('Z')
+        });
+        if !alive {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    false
+}
+
+/// Dropping a run part way through kills the commands it's running, rather than leaving them behind.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_dropped_run() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "echo $$ > ../pid; exec sleep 30",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.workdir = Some("{dest}".into());
+    let pid_file = destination.path().join("pid");
+    let wait_for_pid = async {
+        while fs::read_to_string(&pid_file).map_or(true, |pid| !pid.ends_with('\n')) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::select! {
+        _ = reach::run(config, ()) => panic!("The run finished"),
+        _ = tokio::time::timeout(Duration::from_secs(10), wait_for_pid) => {}
+    }
+
+    let pid = fs::read_to_string(&pid_file)?;
+    let alive = !exits_soon(pid.trim()).await;
+    assert!(!alive, "The command was left running");
+    Ok(())
+}
+
+/// Tasks killed by one of the retry signals are tried again, without needing any retries,
+/// and the signal's name is recorded with their status.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_retry_signals() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = |command: &str| {
+        let mut config = new_test_config(
+            command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.retry_signals = vec![libc::SIGKILL];
+        config
+    };
+
+    let summary = reach::run(config("[ $REACH_ATTEMPT -gt 1 ] || kill -KILL $$"), ()).await?;
+    assert_eq!((1, 1), (summary.succeeded, summary.retried));
+
+    let summary = reach::run(config("kill -TERM $$"), ()).await?;
+    assert_eq!((1, 0), (summary.failed, summary.retried));
+
+    let tasks = reach::run_collect(config("echo $REACH_ATTEMPT; kill -KILL $$"), ()).await?;
+    assert_eq!(3, tasks[0].retries);
+    assert_eq!(
+        format!("signal {}\nsignal SIGKILL\n", libc::SIGKILL),
+        fs::read_to_string(destination.path().join("file1.txt/status"))?
+    );
+    Ok(())
+}
+
+/// The summary says which failure halted the run, so the signal that caused it can be passed on.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_halted_by() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n"), ("file2.txt", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "grep -q two || kill -SEGV $$",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.halt = reach::Halt::KillOnError(1);
+    config.num_processes = 1;
+    config.order = reach::Order::Name;
+
+    let summary = reach::run(config, ()).await?;
+    assert!(summary.to_string().contains("(SIGSEGV)"));
+    let halted_by = summary.halted_by.expect("The run was halted");
+    assert_eq!(source.path().join("file1.txt"), halted_by.input);
+    assert_eq!(Some(libc::SIGSEGV), halted_by.status.unwrap().signal());
+    Ok(())
+}
+
+/// In low-memory mode, inputs are read as they're needed, with the same results.
+#[tokio::test]
+async fn test_low_memory() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("pass1.txt", b"pass\n"),
+        ("pass2.txt", b"pass\n"),
+        ("fail.txt", b"fail\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = || {
+        let mut config = new_test_config(
+            "grep -q pass",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.low_memory = true;
+        config.recreate = false;
+        config
+    };
+
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(
+        (2, 1, 0),
+        (summary.succeeded, summary.failed, summary.skipped)
+    );
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(
+        (0, 1, 2),
+        (summary.succeeded, summary.failed, summary.skipped)
+    );
+    assert_eq!(source.path().join("fail.txt"), summary.failures[0].input);
+
+    let mut ordered = config();
+    ordered.order = reach::Order::Name;
+    let error = reach::run(ordered, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// A file can be split into chunks, each of which is a task's standard input.
+#[tokio::test]
+async fn test_split_bytes() -> io::Result<()> {
+    let source = tempfile::tempdir()?;
+    let input = source.path().join("big.bin");
+    let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&input, &contents)?;
+    let destination = tempfile::tempdir()?;
+    let config = |command: &str, input_mode| {
+        let mut config = new_test_config(command, &input, destination.path(), input_mode);
+        config.split_bytes = Some(4096);
+        config
+    };
+
+    let summary = reach::run(config("cat", reach::InputMode::Stdin), ()).await?;
+    assert_eq!(3, summary.succeeded, "{}", summary);
+    let mut joined = Vec::new();
+    for chunk in &["big.bin.0", "big.bin.1", "big.bin.2"] {
+        joined.extend(fs::read(destination.path().join(chunk).join("out"))?);
+    }
+    assert_eq!(contents, joined);
+
+    let command = r#"echo "$REACH_OFFSET $REACH_LENGTH""#;
+    let summary = reach::run(config(command, reach::InputMode::Filename), ()).await?;
+    assert_eq!(3, summary.succeeded, "{}", summary);
+    assert_eq!(
+        "8192 1808\n",
+        fs::read_to_string(destination.path().join("big.bin.2/out"))?
+    );
+
+    // Only the chunk that failed is run again.
+    let command = r#"test "$REACH_OFFSET" != 4096"#;
+    let summary = reach::run(config(command, reach::InputMode::Filename), ()).await?;
+    let failed_chunk = reach::Chunk {
+        offset: 4096,
+        length: 4096,
+    };
+    assert_eq!(Some(failed_chunk), summary.failures[0].chunk);
+    let mut retry = config("true", reach::InputMode::Filename);
+    retry.retry_failed = true;
+    let summary = reach::run(retry, ()).await?;
+    assert_eq!((1, 0), (summary.succeeded, summary.failed), "{}", summary);
+
+    let mut batched = config("cat", reach::InputMode::Stdin);
+    batched.batch = 2;
+    let error = reach::run(batched, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    let mut directory = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    directory.split_bytes = Some(4096);
+    let error = reach::run(directory, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// In low-memory mode, the summary only keeps so many failures and groups.
+#[tokio::test]
+async fn test_low_memory_summary() -> io::Result<()> {
+    let source = tempfile::tempdir()?;
+    for i in 0..=reach::SUMMARY_LIMIT {
+        fs::write(source.path().join(format!("{}.txt", i)), b"")?;
+    }
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "exit 1",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.low_memory = true;
+    config.num_processes = 8;
+    config.group_by = Some("{stem}".into());
+
+    let summary = reach::run(config, ()).await?;
+    assert_eq!(reach::SUMMARY_LIMIT + 1, summary.failed);
+    assert_eq!(reach::SUMMARY_LIMIT, summary.failures.len());
+    assert_eq!(1, summary.failures_omitted);
+    assert_eq!(reach::SUMMARY_LIMIT + 1, summary.groups.len());
+    assert_eq!(1, summary.groups[reach::OTHER_GROUP].failed);
+    assert!(summary.to_string().contains("and 1 more"));
+    Ok(())
+}
+
+/// Failing commands don't make the run fail, but they are counted in the summary.
+#[tokio::test]
+async fn test_summary_counts_failures() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("pass.txt", b"Arbitrary content for file one\n"),
+        ("fail.txt", b"Arbitrary content for file two\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let summary = reach::run(
+        new_test_config(
+            "case {} in *fail*) exit 3;; esac",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        ),
+        (),
+    )
+    .await?;
+
+    assert_eq!(1, summary.succeeded);
+    assert_eq!(1, summary.failed);
+    assert_eq!(0, summary.skipped);
+    assert_eq!(
+        vec![reach::Failure {
+            input: source.path().join("fail.txt"),
+            chunk: None,
+            status: Some(reach::Status::Exited(3)),
+            error: None,
+        }],
+        summary.failures
+    );
+    assert!(!summary.all_succeeded());
+    assert!(!summary.all_failed());
+    assert_eq!(
+        "3\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join("fail.txt/status"))?)
+    );
+    Ok(())
+}
+
+/// Filename mode can substitute parts of the input's path, and the task's destination directory.
+#[tokio::test]
+async fn test_filename_placeholders() -> io::Result<()> {
+    let source = make_source_directory(&[("my photo.jpeg", b"Arbitrary content\n")])?;
+    let destination = tempfile::tempdir()?;
+    let summary = reach::run(
+        new_test_config(
+            "echo {basename}/{stem}/{ext} > {dest}/extra",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        ),
+        (),
+    )
+    .await?;
+
+    assert!(summary.all_succeeded());
+    assert_eq!(
+        "my photo.jpeg/my photo/jpeg\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join("my photo.jpeg/extra"))?)
+    );
+    Ok(())
+}
+
+/// In stdin mode, the command reads the input file directly rather than through a pipe.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_stdin_is_input_file() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"Arbitrary content for file one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let summary = reach::run(
+        new_test_config(
+            "test -f /dev/stdin && tail -c 4",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        ),
+        (),
+    )
+    .await?;
+
+    assert!(summary.all_succeeded());
+    assert_eq!(
+        "one\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join("file1.txt/out"))?)
+    );
+    Ok(())
+}
+
+/// Exec mode passes filenames to the command exactly, without a shell to misinterpret them.
+#[tokio::test]
+async fn test_exec() -> io::Result<()> {
+    let source = make_source_directory(&[("it's a;$(file).txt", b"Arbitrary content\n")])?;
+    let destination = tempfile::tempdir()?;
+    let summary = reach::run(
+        new_test_config(
+            "printf '%s|%s' {} {stem}",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Exec,
+        ),
+        (),
+    )
+    .await?;
+
+    assert!(summary.all_succeeded());
+    assert_eq!(
+        format!(
+            "{}|it's a;$(file)",
+            source.path().join("it's a;$(file).txt").display()
+        ),
+        String::from_utf8_lossy(&fs::read(
+            destination.path().join("it's a;$(file).txt/out")
+        )?)
+    );
+    Ok(())
+}
+
+/// Halting on error stops reach from starting new tasks once one has failed.
+#[tokio::test]
+async fn test_halt_on_error() -> io::Result<()> {
+    let files: Vec<_> = (0..5)
+        .map(|i| (format!("file{}.txt", i), b"content\n" as &[u8]))
+        .collect();
+    let source = make_source_directory(&files)?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "exit 1",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.halt = reach::Halt::OnError(2);
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(2, summary.failed);
+    assert_eq!(0, summary.succeeded);
+    Ok(())
+}
+
+/// Halting with kill-on-error also terminates tasks that are already running.
+#[tokio::test]
+async fn test_halt_kill_on_error() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("fail.txt", b"Arbitrary content for file one\n"),
+        ("slow.txt", b"Arbitrary content for file two\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "case {} in *fail*) exit 1;; *) sleep 30;; esac",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.num_processes = 2;
+    config.halt = reach::Halt::KillOnError(1);
+
+    let start = Instant::now();
+    let summary = reach::run(config, ()).await?;
+
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(2, summary.failed);
+    assert_eq!(
+        "interrupted\ncancelled halt\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join("slow.txt/status"))?)
+    );
+    Ok(())
+}
+
+/// A command that exits without reading all of its input succeeds or fails by its exit status alone.
+///
+/// The inputs are batched, so that they're piped to the command rather than given to it as files.
+#[tokio::test]
+async fn test_stdin_early_exit() -> io::Result<()> {
+    let big = vec![b'x'; 4 * 1024 * 1024];
+    let source = make_source_directory(&[("a.txt", &big[..]), ("b.txt", &big[..])])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "head -c 5",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.batch = 2;
+    let progress = RecordingProgress::default();
+    let summary = reach::run(config, &progress).await?;
+
+    assert!(summary.all_succeeded());
+    assert_eq!(2, progress.results().len());
+    assert!(progress.results().iter().all(|result| result.is_ok()));
+    // Only the first input in the batch has the task's output.
+    let outputs: Vec<_> = ["a.txt", "b.txt"]
+        .iter()
+        .filter_map(|name| fs::read(destination.path().join(name).join("out")).ok())
+        .collect();
+    assert_eq!(vec![b"xxxxx".to_vec()], outputs);
+    for name in &["a.txt", "b.txt"] {
+        let err = destination.path().join(name).join("err");
+        assert_eq!("", fs::read_to_string(err).unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Failing tasks are retried, and the summary says which ones needed it.
+#[tokio::test]
+async fn test_retries() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("flaky.txt", b"Fails the first time\n"),
+        ("steady.txt", b"Always succeeds\n"),
+        ("broken.txt", b"Always fails\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "case {} in \
+           *flaky*) test -e {dest}/tried || { touch {dest}/tried; exit 1; };; \
+           *broken*) exit 2;; \
+         esac",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.retries = 2;
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(2, summary.succeeded);
+    assert_eq!(1, summary.failed);
+    assert_eq!(2, summary.retried);
+    assert_eq!(
+        vec![source.path().join("broken.txt")],
+        summary
+            .failures
+            .iter()
+            .map(|failure| failure.input.clone())
+            .collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+/// Every task's command is told about the task in environment variables, in shell sessions too.
+#[tokio::test]
+async fn test_task_environment() -> io::Result<()> {
+    for &shell_sessions in &[false, true] {
+        let source = make_source_directory(&[("a.txt", b"First\n"), ("b.txt", b"Second\n")])?;
+        let destination = tempfile::tempdir()?;
+        let mut config = new_test_config(
+            "echo $REACH_INPUT_NAME $REACH_TASK_INDEX $REACH_ATTEMPT; \
+             test \"$REACH_INPUT\" = {} && test \"$REACH_DEST_DIR\" = {dest} && \
+             test $REACH_INPUT_NAME = a.txt -o $REACH_ATTEMPT = 2",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        );
+        config.order = reach::Order::Name;
+        config.retries = 1;
+        config.shell_sessions = cfg!(unix) && shell_sessions;
+        let summary = reach::run(config, ()).await?;
+
+        assert!(summary.all_succeeded(), "{}", summary);
+        let out = |name| fs::read_to_string(destination.path().join(name).join("out"));
+        assert_eq!("a.txt 0 1\n", out("a.txt")?);
+        assert_eq!("b.txt 1 2\n", out("b.txt")?);
+    }
+    Ok(())
+}
+
+/// A task's ID is the same in every attempt and every run of the same command, and
+/// different for different inputs and commands.
+#[tokio::test]
+async fn test_task_ids() -> io::Result<()> {
+    let source = make_source_directory(&[("a.txt", b"First\n"), ("b.txt", b"Second\n")])?;
+    let destination = tempfile::tempdir()?;
+    let run = |command: &str| {
+        let mut config = new_test_config(
+            command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.recreate = true;
+        config.retries = 1;
+        reach::run_collect(config, ())
+    };
+    let ids = |tasks: &[reach::TaskResult]| {
+        let mut ids: Vec<_> = tasks
+            .iter()
+            .map(|task| (task.input.clone(), task.id.clone()))
+            .collect();
+        ids.sort();
+        ids
+    };
+    let command = "echo $REACH_TASK_ID; test $REACH_ATTEMPT = 2";
+    let first = run(command).await?;
+    let second = run(command).await?;
+    let other = run("echo $REACH_TASK_ID").await?;
+
+    assert_eq!(ids(&first), ids(&second));
+    assert_ne!(ids(&first)[0].1, ids(&first)[1].1);
+    assert_ne!(ids(&first)[0].1, ids(&other)[0].1);
+    for task in &other {
+        assert_eq!(
+            format!("{}\n", task.id),
+            fs::read_to_string(task.destination.join("out"))?
+        );
+    }
+    Ok(())
+}
+
+/// Output can be merged, discarded, tagged, or written to files with other names,
+/// in shell sessions as well as processes of their own, where they can manage it.
+#[tokio::test]
+async fn test_capture() -> io::Result<()> {
+    let cases: &[(reach::Capture, &[(&str, &str)])] = &[
+        (
+            reach::Capture::Separate,
+            &[("stdout.txt", "one\n"), ("stderr.txt", "two\n")],
+        ),
+        (reach::Capture::Merge, &[("stdout.txt", "one\ntwo\n")]),
+        (reach::Capture::Discard, &[]),
+        (
+            reach::Capture::Tag,
+            &[("stdout.txt", "one\n"), ("stderr.txt", "two\n")],
+        ),
+    ];
+    for &(capture, files) in cases {
+        for &shell_sessions in &[false, true] {
+            if shell_sessions && (capture == reach::Capture::Tag || !cfg!(unix)) {
+                continue;
+            }
+            let source = make_source_directory(&[("file.txt", b"Arbitrary content\n")])?;
+            let destination = tempfile::tempdir()?;
+            let mut config = new_test_config(
+                "echo one; echo two >&2",
+                source.path(),
+                destination.path(),
+                reach::InputMode::Stdin,
+            );
+            config.capture = capture;
+            config.stdout_name = "stdout.txt".into();
+            config.stderr_name = "stderr.txt".into();
+            config.shell_sessions = shell_sessions;
+            let summary = reach::run(config, ()).await?;
+
+            assert!(summary.all_succeeded(), "{}", summary);
+            let task_dir = destination.path().join("file.txt");
+            let mut expected: Vec<_> = files.iter().map(|(name, _)| *name).collect();
+            expected.push("status");
+            expected.sort_unstable();
+            assert_eq!(expected, list_dir(&task_dir)?, "{:?}", capture);
+            for (name, contents) in files {
+                assert_eq!(*contents, fs::read_to_string(task_dir.join(name))?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Output past the limit is clipped from the middle, and the status file says so.
+#[tokio::test]
+async fn test_max_output_size() -> io::Result<()> {
+    let source = make_source_directory(&[("file.txt", b"Arbitrary content\n")])?;
+    for &capture in &[reach::Capture::Separate, reach::Capture::Merge] {
+        let destination = tempfile::tempdir()?;
+        let mut config = new_test_config(
+            "echo start; head -c 1000 /dev/zero | tr '\\0' x; echo; echo oops >&2; echo end",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.capture = capture;
+        config.max_output_size = Some(60);
+        let summary = reach::run(config, ()).await?;
+        assert!(summary.all_succeeded(), "{}", summary);
+
+        let task_dir = destination.path().join("file.txt");
+        let out = fs::read_to_string(task_dir.join("out"))?;
+        assert!(out.starts_with("start\nxxx"), "{:?}", out);
+        assert!(out.contains(" bytes clipped by reach ...]\n"), "{:?}", out);
+        // Merged output from the two streams can arrive in either order.
+        assert!(out.contains("xxx\n") && out.contains("end\n"), "{:?}", out);
+        assert!(out.len() < 100, "{:?}", out);
+        assert_eq!(
+            "0\nclipped out\n",
+            fs::read_to_string(task_dir.join("status"))?,
+            "{:?}",
+            capture
+        );
+        if capture == reach::Capture::Separate {
+            assert_eq!("oops\n", fs::read_to_string(task_dir.join("err"))?);
+        }
+    }
+    Ok(())
+}
+
+/// Tagging needs each task to have a process of its own.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_capture_tag_needs_processes() -> io::Result<()> {
+    let source = make_source_directory(&[("file.txt", b"Arbitrary content\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.capture = reach::Capture::Tag;
+    config.shell_sessions = true;
+    let error = reach::run(config, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// Batches give each command several inputs, and every input in a batch gets its status.
+#[tokio::test]
+async fn test_batch() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("a.txt", b"one\n"),
+        ("b.txt", b"two\n"),
+        ("c.txt", b"three\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = || {
+        let mut config = new_test_config(
+            "for input in {+}; do basename \"$input\"; done; echo $REACH_TASK_INDEX",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        );
+        config.order = reach::Order::Name;
+        config.batch = 2;
+        config.recreate = false;
+        config
+    };
+    let summary = reach::run(config(), ()).await?;
+
+    assert_eq!(3, summary.succeeded, "{}", summary);
+    let out = |name| fs::read_to_string(destination.path().join(name).join("out"));
+    assert_eq!("a.txt\nb.txt\n0\n", out("a.txt")?);
+    assert_eq!("c.txt\n1\n", out("c.txt")?);
+    assert_eq!(vec!["status"], list_dir(&destination.path().join("b.txt"))?);
+
+    // Every input in a batch counts as done.
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(3, summary.skipped, "{}", summary);
+    Ok(())
+}
+
+/// In stdin mode, a batch's command reads every input in turn, in shell sessions too.
+#[tokio::test]
+async fn test_batch_stdin() -> io::Result<()> {
+    for &shell_sessions in &[false, true] {
+        let source = make_source_directory(&[
+            ("a.txt", b"one\n"),
+            ("b.txt", b"two\n"),
+            ("c.txt", b"three\n"),
+        ])?;
+        let destination = tempfile::tempdir()?;
+        let mut config = new_test_config(
+            "cat",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.order = reach::Order::Name;
+        config.batch = 3;
+        config.shell_sessions = cfg!(unix) && shell_sessions;
+        let summary = reach::run(config, ()).await?;
+
+        assert!(summary.all_succeeded(), "{}", summary);
+        assert_eq!(
+            "one\ntwo\nthree\n",
+            fs::read_to_string(destination.path().join("a.txt").join("out"))?
+        );
+    }
+    Ok(())
+}
+
+/// The summary can be written out as JSON for other tools to read.
+#[tokio::test]
+async fn test_write_json_report() -> io::Result<()> {
+    let source = make_source_directory(&[("fail.txt", b"Arbitrary content\n")])?;
+    let destination = tempfile::tempdir()?;
+    let summary = reach::run(
+        new_test_config(
+            "exit 3",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        ),
+        (),
+    )
+    .await?;
+
+    let report_path = destination.path().join("report.json");
+    summary.write_json(&report_path).await?;
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&report_path)?)?;
+    assert_eq!(1, report["run"]);
+    assert_eq!(1, report["failed"]);
+    assert_eq!(0, report["succeeded"]);
+    assert_eq!(3, report["failures"][0]["exit_code"]);
+    assert_eq!(
+        source.path().join("fail.txt").to_str().unwrap(),
+        report["failures"][0]["input"]
+    );
+    assert_eq!(0.0, report["groups"]["txt"]["success_rate"]);
+    Ok(())
+}
+
+/// The summary breaks tasks down by their input's extension, or however else it's told to.
+#[tokio::test]
+async fn test_summary_groups() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("a.jpeg", b"Arbitrary content\n"),
+        ("b.jpeg", b"Arbitrary content\n"),
+        ("c.png", b"Arbitrary content\n"),
+        ("README", b"Arbitrary content\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = || {
+        new_test_config(
+            "case {} in *b.jpeg) exit 1;; esac",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        )
+    };
+    let summary = reach::run(config(), ()).await?;
+
+    let groups: Vec<_> = summary
+        .groups
+        .iter()
+        .map(|(name, group)| (name.as_str(), group.succeeded, group.failed))
+        .collect();
+    assert_eq!(vec![("", 1, 0), ("jpeg", 1, 1), ("png", 1, 0)], groups);
+    assert_eq!(0.5, summary.groups["jpeg"].success_rate());
+    assert!(summary
+        .to_string()
+        .contains("  jpeg: 1 succeeded, 1 failed (50%)"));
+
+    let mut config = config();
+    config.group_by = Some("{stem}x".into());
+    let summary = reach::run(config, ()).await?;
+    assert_eq!(
+        vec!["READMEx", "ax", "bx", "cx"],
+        summary.groups.keys().collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+/// A wrapper runs around the task's command, whichever way it is run.
+#[tokio::test]
+async fn test_wrap() -> io::Result<()> {
+    let source = make_source_directory(&[("it's a file.txt", b"Arbitrary content\n")])?;
+    for (input_mode, command) in &[
+        (reach::InputMode::Stdin, "echo $WRAPPED; cat"),
+        (reach::InputMode::Filename, "echo $WRAPPED; cat {}"),
+        (
+            reach::InputMode::Exec,
+            "sh -c 'echo $WRAPPED; cat \"$1\"' sh {}",
+        ),
+    ] {
+        let destination = tempfile::tempdir()?;
+        let mut config = new_test_config(*command, source.path(), destination.path(), *input_mode);
+        config.wrap = Some("env WRAPPED={stem} {cmd}".into());
+        let summary = reach::run(config, ()).await?;
+        assert!(summary.all_succeeded(), "{:?}: {}", input_mode, summary);
+        assert_eq!(
+            "it's a file\nArbitrary content\n",
+            String::from_utf8_lossy(&fs::read(destination.path().join("it's a file.txt/out"))?),
+            "{:?}",
+            input_mode
+        );
+    }
+    Ok(())
+}
+
+/// Setup runs before each task's command and teardown after it, and either failing fails the task.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_setup_and_teardown() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("pass.txt", b"Arbitrary content\n"),
+        ("fail.txt", b"Arbitrary content\n"),
+        ("no-setup.txt", b"Arbitrary content\n"),
+        ("no-teardown.txt", b"Arbitrary content\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat {dest}/prepared; case {} in *fail*) exit 3;; esac",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.setup =
+        Some("case {} in *no-setup*) exit 4;; esac; echo {stem} > {dest}/prepared".into());
+    config.teardown = Some(
+        "echo \"$REACH_STATUS\" > {dest}/torn-down; case {} in *no-teardown*) exit 5;; esac".into(),
+    );
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(1, summary.succeeded, "{}", summary);
+    assert_eq!(3, summary.failed, "{}", summary);
+    let dest = destination.path();
+    assert_eq!("pass\n", fs::read_to_string(dest.join("pass.txt/out"))?);
+    assert_eq!("0\n", fs::read_to_string(dest.join("pass.txt/torn-down"))?);
+    assert_eq!("3\n", fs::read_to_string(dest.join("fail.txt/torn-down"))?);
+    assert_eq!("3\n", fs::read_to_string(dest.join("fail.txt/status"))?);
+    // A failed setup means the command never runs, and nor does the teardown.
+    assert!(!dest.join("no-setup.txt/out").exists());
+    assert!(!dest.join("no-setup.txt/torn-down").exists());
+    assert!(!dest.join("no-setup.txt/status").exists());
+    // A failed teardown means the task didn't succeed, so it's run again next time.
+    assert_eq!(
+        "0\n",
+        fs::read_to_string(dest.join("no-teardown.txt/torn-down"))?
+    );
+    assert!(!dest.join("no-teardown.txt/status").exists());
+    let errors: Vec<_> = summary
+        .failures
+        .iter()
+        .filter_map(|failure| failure.error.as_deref())
+        .collect();
+    assert_eq!(2, errors.len(), "{:?}", summary.failures);
+    assert!(errors.contains(&"The setup hook failed with exit code 4"));
+    assert!(errors.contains(&"The teardown hook failed with exit code 5"));
+    Ok(())
+}
+
+/// The hooks around the whole run run once each, and a failing before-all hook stops the run.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_before_and_after_all() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("pass.txt", b"Arbitrary content\n"),
+        ("fail.txt", b"Arbitrary content\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = |before_all: &str| {
+        let mut config = new_test_config(
+            "case {} in *fail*) exit 3;; esac",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        );
+        config.before_all = Some(before_all.into());
+        config.after_all = Some("echo \"$REACH_SUCCEEDED $REACH_FAILED\"".into());
+        config.recreate = true;
+        config
+    };
+    let state_dir = destination.path().join(".reach");
+
+    let error = reach::run(config("exit 1"), ()).await.unwrap_err();
+    assert_eq!(
+        "The before-all hook failed with exit code 1",
+        error.to_string()
+    );
+    assert!(!destination.path().join("pass.txt").exists());
+    assert!(!state_dir.join("after-all.log").exists());
+
+    let summary = reach::run(config("echo \"$REACH_DEST_DIR\""), ()).await?;
+    assert_eq!(1, summary.failed);
+    assert_eq!(
+        format!("{}\n", destination.path().display()),
+        fs::read_to_string(state_dir.join("before-all.log"))?
+    );
+    assert_eq!(
+        "1 1\n",
+        fs::read_to_string(state_dir.join("after-all.log"))?
+    );
+    Ok(())
+}
+
+/// Each task's command can run in a working directory of its own, which is created if need be.
+#[tokio::test]
+async fn test_workdir() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n"), ("file2.txt", b"two\n")])?;
+    for &shell_sessions in &[false, true] {
+        if shell_sessions && !cfg!(unix) {
+            continue;
+        }
+        let destination = tempfile::tempdir()?;
+        let mut config = new_test_config(
+            "cat {} > scratch",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        );
+        config.workdir = Some("{dest}/work".into());
+        config.shell_sessions = shell_sessions;
+        let summary = reach::run(config, ()).await?;
+        assert!(summary.all_succeeded(), "{}", summary);
+        for (name, contents) in &[("file1.txt", "one\n"), ("file2.txt", "two\n")] {
+            assert_eq!(
+                *contents,
+                fs::read_to_string(destination.path().join(name).join("work/scratch"))?
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Simulated commands run in virtual time, so retries, timeouts, and halting can be checked
+/// exactly, without waiting for them.
+#[cfg(feature = "testing")]
+#[test]
+fn test_simulated() -> io::Result<()> {
+    use reach::testing::{self, Event, Outcome, Recorder, Simulation};
+    let source = make_source_directory(&[
+        ("a.txt", b"a\n"),
+        ("b.txt", b"b\n"),
+        ("c.txt", b"c\n"),
+        ("d.txt", b"d\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "true",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.order = reach::Order::Name;
+    config.num_processes = 1;
+    config.retries = 2;
+    config.timeout = Some(Duration::from_secs(10));
+    config.halt = reach::Halt::OnError(1);
+    let simulation = Simulation::new()
+        .script(
+            "a.txt",
+            vec![Outcome::fail(), Outcome::exit(2), Outcome::succeed()],
+        )
+        .script(
+            "b.txt",
+            vec![Outcome::succeed().after(Duration::from_secs(5))],
+        )
+        .script("c.txt", vec![Outcome::hang()]);
+    let recorder = Recorder::new();
+    let (summary, elapsed) = testing::block_on_paused(async {
+        let start = tokio::time::Instant::now();
+        let summary = testing::run_simulated(config, recorder.clone(), &simulation).await;
+        (summary, start.elapsed())
+    });
+    let summary = summary?;
+    assert_eq!(2, summary.succeeded, "{}", summary);
+    assert_eq!(1, summary.failed, "{}", summary);
+
+    let input = |name: &str| source.path().join(name);
+    assert_eq!(
+        vec![
+            (input("a.txt"), 1),
+            (input("a.txt"), 2),
+            (input("a.txt"), 3),
+            (input("b.txt"), 1),
+            (input("c.txt"), 1),
+            (input("c.txt"), 2),
+            (input("c.txt"), 3),
+        ],
+        simulation.attempts(),
+        "d.txt never starts, as the run halts once c.txt has failed"
+    );
+    // Five seconds for b.txt, and ten for each of c.txt's attempts to time out.
+    assert!(
+        elapsed >= Duration::from_secs(35) && elapsed < Duration::from_secs(36),
+        "{:?}",
+        elapsed
+    );
+    let events = recorder.events();
+    assert!(events.contains(&Event::Retrying(input("a.txt"), 3)));
+    assert!(events.contains(&Event::Finished(
+        input("a.txt"),
+        Some(reach::Status::Exited(0)),
+        2
+    )));
+    assert!(events.contains(&Event::Finished(
+        input("c.txt"),
+        Some(reach::Status::TimedOut),
+        2
+    )));
+    assert!(!events.contains(&Event::Started(input("d.txt"))));
+    Ok(())
+}
+
+/// Inputs in a random order are shuffled the same way every run with the same seed.
+#[cfg(feature = "testing")]
+#[test]
+fn test_seeded_order() -> io::Result<()> {
+    use reach::testing::{self, Simulation};
+    let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+    let files: Vec<_> = names.iter().map(|name| (*name, &b""[..])).collect();
+    let source = make_source_directory(&files)?;
+    let destination = tempfile::tempdir()?;
+    let order = |seed| {
+        let mut config = new_test_config(
+            "true",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.order = reach::Order::Random;
+        config.seed = Some(seed);
+        config.num_processes = 1;
+        let simulation = Simulation::new();
+        testing::block_on_paused(testing::run_simulated(config, (), &simulation))?;
+        Ok::<_, io::Error>(
+            simulation
+                .attempts()
+                .into_iter()
+                .map(|(input, _)| input)
+                .collect::<Vec<_>>(),
+        )
+    };
+    let shuffled = order(7)?;
+    assert_eq!(names.len(), shuffled.len());
+    assert_eq!(shuffled, order(7)?);
+    assert_ne!(shuffled, order(8)?);
+    Ok(())
+}
+
+/// Tasks can run on workers over SSH, here a stand-in for `ssh` that runs them locally,
+/// and their output and status come back into the destination directory.
+#[cfg(all(unix, feature = "remote"))]
+#[tokio::test]
+async fn test_workers() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let files: &[(&str, &[u8])] = &[("a", b"one\n"), ("b", b"two\n"), ("c", b"three\n")];
+    let source = make_source_directory(files)?;
+    let bin = tempfile::tempdir()?;
+    let ssh = bin.path().join("ssh");
+    fs::write(
+        &ssh,
+        "#!/bin/sh\n\
+         while [ \"$1\" != -- ]; do shift; done\n\
+         echo \"$2\" >> \"$(dirname \"$0\")/hosts\"\n\
+         exec sh -c \"$3\"\n",
+    )?;
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755))?;
+    let workers = vec!["me@one".parse().unwrap(), "me@two:2".parse().unwrap()];
+    for (command, input_mode) in &[
+        ("sleep 0.5; tr a-z A-Z", reach::InputMode::Stdin),
+        (
+            "sleep 0.5; tr a-z A-Z < {}; exit 3",
+            reach::InputMode::Filename,
+        ),
+    ] {
+        let destination = tempfile::tempdir()?;
+        let mut config = new_test_config(*command, source.path(), destination.path(), *input_mode);
+        config.workers = workers.clone();
+        config.ssh = ssh.to_string_lossy().into_owned();
+        let started = Instant::now();
+        let summary = reach::run(config, ()).await?;
+        // Every worker's slots are in use at once.
+        assert!(started.elapsed() < Duration::from_millis(1400));
+        for (name, contents) in files {
+            let upper = String::from_utf8_lossy(contents).to_uppercase();
+            assert_eq!(
+                upper,
+                fs::read_to_string(destination.path().join(name).join("out"))?
+            );
+        }
+        if *input_mode == reach::InputMode::Filename {
+            assert_eq!(3, summary.failed);
+        } else {
+            assert!(summary.all_succeeded(), "{}", summary);
+        }
+        let hosts = fs::read_to_string(bin.path().join("hosts"))?;
+        assert_eq!(1, hosts.lines().filter(|host| *host == "me@one").count());
+        assert_eq!(2, hosts.lines().filter(|host| *host == "me@two").count());
+        fs::remove_file(bin.path().join("hosts"))?;
+    }
+    Ok(())
+}
+
+/// Time that a task spends waiting for a free process counts as queued, not as running.
+#[tokio::test]
+async fn test_queued() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n"), ("file2.txt", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = new_test_config(
+        "sleep 0.3",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    let mut tasks = reach::run_collect(config, ()).await?;
+    tasks.sort_by_key(|task| task.queued);
+    assert!(
+        tasks[0].queued < Duration::from_millis(200),
+        "{:?}",
+        tasks[0]
+    );
+    assert!(
+        tasks[1].queued >= Duration::from_millis(250),
+        "{:?}",
+        tasks[1]
+    );
+    for task in &tasks {
+        assert!(task.duration >= Duration::from_millis(250), "{:?}", task);
+    }
+    Ok(())
+}
+
+/// Stages after a task's command each read the output of the one before, and a task that
+/// failed partway through picks up from the stage that failed.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_then() -> io::Result<()> {
+    let source = make_source_directory(&[("a", b"one\n"), ("b", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let scratch = tempfile::tempdir()?;
+    let log = scratch.path().join("log");
+    let flag = scratch.path().join("flag");
+    let config = || {
+        let mut config = new_test_config(
+            format!("echo own >> '{}'; cat", log.display()),
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.recreate = false;
+        config.then = vec![
+            format!("echo first >> '{}'; tr a-z A-Z", log.display()),
+            format!(
+                "echo second >> '{}'; sed s/^/x/; test ! -e '{}'",
+                log.display(),
+                flag.display()
+            ),
+        ];
+        config
+    };
+    let read = |path: &str| fs::read_to_string(destination.path().join(path));
+
+    fs::write(&flag, "")?;
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(2, summary.failed);
+    assert_eq!("one\n", read("a/out")?);
+    assert_eq!("ONE\n", read("a/then-1/out")?);
+    assert_eq!("xONE\n", read("a/then-2/out")?);
+    assert_eq!("0\n", read("a/then-1/status")?);
+    assert_eq!("1\n", read("a/then-2/status")?);
+    assert_eq!("1\n", read("a/status")?);
+
+    fs::remove_file(&flag)?;
+    fs::remove_file(&log)?;
+    let summary = reach::run(config(), ()).await?;
+    assert!(summary.all_succeeded(), "{}", summary);
+    assert_eq!("second\nsecond\n", fs::read_to_string(&log)?);
+    assert_eq!("xTWO\n", read("b/then-2/out")?);
+    assert_eq!("0\n", read("b/status")?);
+    Ok(())
+}
+
+/// Library users can get the details of every task, not just a summary.
+#[tokio::test]
+async fn test_run_collect() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("pass.txt", b"Arbitrary content for file one\n"),
+        ("fail.txt", b"Arbitrary content for file two\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "case {} in *fail*) exit 3;; esac",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.retries = 1;
+    let mut tasks = reach::run_collect(config, ()).await?;
+    tasks.sort_by(|a, b| a.input.cmp(&b.input));
+
+    assert_eq!(2, tasks.len());
+    let (fail, pass) = (&tasks[0], &tasks[1]);
+    assert_eq!(source.path().join("fail.txt"), fail.input);
+    assert_eq!(destination.path().join("fail.txt"), fail.destination);
+    assert_eq!(Some(reach::Status::Exited(3)), fail.status);
+    assert_eq!(1, fail.retries);
+    assert!(!fail.succeeded());
+    assert_eq!(source.path().join("pass.txt"), pass.input);
+    assert_eq!(destination.path().join("pass.txt"), pass.destination);
+    assert_eq!(Some(reach::Status::Exited(0)), pass.status);
+    assert_eq!(0, pass.retries);
+    assert!(pass.succeeded());
+    Ok(())
+}
+
+/// A task's command and hooks can annotate its result, and the annotations are kept.
+#[tokio::test]
+async fn test_annotations() -> io::Result<()> {
+    let source = make_source_directory(&[("file.txt", b"one\ntwo\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "echo rows=$(wc -l < {}) >> \"$REACH_ANNOTATIONS\"; test \"$REACH_ATTEMPT\" = 2",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.retries = 1;
+    config.setup = Some("echo attempt=$REACH_ATTEMPT >> \"$REACH_ANNOTATIONS\"".into());
+    let tasks = reach::run_collect(config, ()).await?;
+
+    // Each attempt starts afresh, so only the last one's annotations are left.
+    let expected: BTreeMap<String, String> = vec![
+        ("attempt".to_string(), "2".to_string()),
+        ("rows".to_string(), "2".to_string()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(1, tasks.len());
+    assert!(tasks[0].succeeded());
+    assert_eq!(expected, tasks[0].annotations);
+    let journal = fs::read_to_string(destination.path().join(".reach/journal"))?;
+    assert!(
+        journal.contains(r#""annotations":{"attempt":"2","rows":"2"}"#),
+        "{}",
+        journal
+    );
+    Ok(())
+}
+
+/// Filenames that aren't valid unicode are still substituted into the command intact.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_filename_non_unicode() -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let name = std::ffi::OsStr::from_bytes(b"caf\xe9's file.txt");
+    let source = make_source_directory(&[(name, b"Arbitrary content\n")])?;
+    let destination = tempfile::tempdir()?;
+    let summary = reach::run(
+        new_test_config(
+            "cat {} > {dest}/{stem}.copy",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        ),
+        (),
+    )
+    .await?;
+
+    assert!(summary.all_succeeded(), "{}", summary);
+    let copy = std::ffi::OsStr::from_bytes(b"caf\xe9's file.copy");
+    assert_eq!(
+        "Arbitrary content\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join(name).join(copy))?)
+    );
+    Ok(())
+}
+
+/// The builder fills in the same defaults as the command line.
+#[tokio::test]
+async fn test_config_builder_defaults() -> io::Result<()> {
+    let parent = tempfile::tempdir()?;
+    let source = parent.path().join("photos");
+    fs::create_dir(&source)?;
+    let config = reach::Config::builder("cat", &source).build()?;
+
+    let destination = parent.path().canonicalize()?.join("photos-results");
+    assert_eq!(destination, config.destination_dir);
+    assert_eq!(destination.join(".reach"), config.state_dir);
+    assert_eq!(source.canonicalize()?, config.source_dir);
+    assert_eq!(num_cpus::get(), config.num_processes);
+    assert_eq!(reach::InputMode::Stdin, config.input_mode);
+    assert!(!config.shell.is_empty());
+    // Building a config changes nothing; the destination is only made once it's run.
+    assert!(!destination.exists());
+    reach::run(config, ()).await?;
+    assert!(destination.is_dir());
+    Ok(())
+}
+
+/// Without an explicit input mode, commands with `{}` get the filename, and others get stdin.
+#[tokio::test]
+async fn test_config_builder_detects_input_mode() -> io::Result<()> {
+    let source = make_source_directory(&[("file.txt", b"Arbitrary content\n")])?;
+    let destination = tempfile::tempdir()?;
+    let builder = |command| {
+        reach::Config::builder(command, source.path())
+            .destination_dir(destination.path())
+            .num_processes(1)
+    };
+
+    let config = builder("wc -c {}").build()?;
+    assert_eq!(reach::InputMode::Filename, config.input_mode);
+    let summary = reach::run(config, ()).await?;
+    assert!(summary.all_succeeded(), "{}", summary);
+    let out = fs::read_to_string(destination.path().join("file.txt/out"))?;
+    assert!(out.contains("file.txt"), "{:?}", out);
+
+    assert_eq!(
+        reach::InputMode::Stdin,
+        builder("echo {{}}; wc -c").build()?.input_mode
+    );
+    Ok(())
+}
+
+/// The builder refuses a source directory that doesn't exist.
+#[tokio::test]
+async fn test_config_builder_missing_source() -> io::Result<()> {
+    let parent = tempfile::tempdir()?;
+    let result = reach::Config::builder("cat", parent.path().join("nope")).build();
+    assert_eq!(
+        io::ErrorKind::NotFound,
+        result.map(|_| ()).unwrap_err().kind()
+    );
+    Ok(())
+}
+
+/// Once tasks have written more than the output limit, no more are started.
+#[tokio::test]
+async fn test_max_total_output() -> io::Result<()> {
+    let files: Vec<_> = (0..5)
+        .map(|i| (format!("file{}.txt", i), b"content\n" as &[u8]))
+        .collect();
+    let source = make_source_directory(&files)?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "head -c 1000 /dev/zero",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.max_total_output = Some(1500);
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(2, summary.succeeded);
+    assert!(summary.output_bytes >= 2000, "{}", summary.output_bytes);
+    Ok(())
+}
+
+/// With the kill policy, passing the output limit also terminates running tasks.
+#[tokio::test]
+async fn test_max_total_output_kill() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("big.txt", b"Arbitrary content for file one\n"),
+        ("slow.txt", b"Arbitrary content for file two\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "case {} in *big*) head -c 1000 /dev/zero;; *) sleep 30;; esac",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.num_processes = 2;
+    config.max_total_output = Some(100);
+    config.output_policy = reach::OutputPolicy::Kill;
+
+    let start = Instant::now();
+    let summary = reach::run(config, ()).await?;
+
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(1, summary.succeeded);
+    assert_eq!(
+        "interrupted\ncancelled budget\n",
+        String::from_utf8_lossy(&fs::read(destination.path().join("slow.txt/status"))?)
+    );
+    Ok(())
+}
+
+/// With shell sessions, every task runs in the same shell, but in its own subshell.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shell_sessions() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("file1.txt", b"Arbitrary content for file one\n"),
+        ("file2.txt", b"Arbitrary content for file two\n"),
+        ("file3.txt", b"Arbitrary content for file three\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "echo $$ $LEAKED; LEAKED=yes; cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.shell_sessions = true;
+    let summary = reach::run(config, ()).await?;
+
+    assert!(summary.all_succeeded(), "{}", summary);
+    let outputs = ["file1.txt", "file2.txt", "file3.txt"]
+        .iter()
+        .map(|name| fs::read_to_string(destination.path().join(name).join("out")))
+        .collect::<io::Result<Vec<_>>>()?;
+    let first_lines: Vec<_> = outputs
+        .iter()
+        .map(|out| out.lines().next().unwrap_or_default())
+        .collect();
+    assert_eq!(
+        vec![first_lines[0]; 3],
+        first_lines,
+        "Tasks ran in different shells, or leaked"
+    );
+    assert!(!first_lines[0].contains("yes"));
+    assert!(outputs[1].ends_with("Arbitrary content for file two\n"));
+    Ok(())
+}
+
+/// Tasks in shell sessions can fail or time out without affecting the tasks after them.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shell_sessions_failures() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("a-fail.txt", b"Arbitrary content for file one\n"),
+        ("b-slow.txt", b"Arbitrary content for file two\n"),
+        ("c-pass.txt", b"Arbitrary content for file three\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "case {} in *fail*) exit 3;; *slow*) sleep 30;; *) wc -c < {};; esac",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Filename,
+    );
+    config.shell_sessions = true;
+    config.timeout = Some(Duration::from_millis(200));
+    let start = Instant::now();
+    let mut tasks = reach::run_collect(config, ()).await?;
+    tasks.sort_by(|a, b| a.input.cmp(&b.input));
+
+    assert!(start.elapsed() < Duration::from_secs(10));
+    let statuses: Vec<_> = tasks.iter().map(|task| task.status.clone()).collect();
+    assert_eq!(
+        vec![
+            Some(reach::Status::Exited(3)),
+            Some(reach::Status::TimedOut),
+            Some(reach::Status::Exited(0)),
+        ],
+        statuses
+    );
+    assert_eq!(
+        "33\n",
+        fs::read_to_string(destination.path().join("c-pass.txt/out"))?
+    );
+    Ok(())
+}
+
+/// Files can be processed largest first, or by name.
+#[tokio::test]
+async fn test_order() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("b.txt", b"medium\n"),
+        ("a.txt", b"tiny"),
+        ("c.txt", b"the largest of all\n"),
+    ])?;
+    for (order, expected) in &[
+        (reach::Order::Size, vec!["c.txt", "b.txt", "a.txt"]),
+        (reach::Order::Name, vec!["a.txt", "b.txt", "c.txt"]),
+    ] {
+        let destination = tempfile::tempdir()?;
+        let log = destination.path().join("order.log");
+        let mut config = new_test_config(
+            format!("echo {{basename}} >> {}", log.display()),
+            source.path(),
+            destination.path(),
+            reach::InputMode::Filename,
+        );
+        config.order = *order;
+        reach::run(config, ()).await?;
+        assert_eq!(
+            *expected,
+            fs::read_to_string(&log)?.lines().collect::<Vec<_>>(),
+            "{:?}",
+            order
+        );
+    }
+    Ok(())
+}
+
+/// Coprocesses handle one input after another, without being restarted.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_coprocess_line_framing() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("file1.txt", b"one\n"),
+        ("file2.txt", b"two"),
+        ("file3.txt", b"three\nand more\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "while read -r line; do echo \"$$ $line\"; done",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Coprocess,
+    );
+    config.framing = reach::Framing::Line;
+    config.order = reach::Order::Name;
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(2, summary.succeeded, "{}", summary);
+    assert_eq!(
+        vec![source.path().join("file3.txt")],
+        summary
+            .failures
+            .iter()
+            .map(|failure| failure.input.clone())
+            .collect::<Vec<_>>()
+    );
+    let one = fs::read_to_string(destination.path().join("file1.txt/out"))?;
+    let two = fs::read_to_string(destination.path().join("file2.txt/out"))?;
+    let (pid, line) = one.split_once(' ').unwrap();
+    assert_eq!("one\n", line);
+    assert_eq!(
+        format!("{} two\n", pid),
+        two,
+        "Tasks ran in different workers"
+    );
+    Ok(())
+}
+
+/// Length framing carries inputs of any shape, and a worker that dies is replaced.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_coprocess_length_framing() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("file1.txt", b"Arbitrary content\nfor file one\n"),
+        ("file2.txt", b"quit"),
+        ("file3.txt", b""),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "while read -r size; do \
+             data=$(dd bs=1 count=\"$size\" 2>/dev/null | tr a-z A-Z; echo .); \
+             [ \"$data\" = QUIT. ] && exit 1; \
+             printf '%s\\n%s' \"${#data}\" \"$data\"; \
+         done",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Coprocess,
+    );
+    config.order = reach::Order::Name;
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(
+        vec![source.path().join("file2.txt")],
+        summary
+            .failures
+            .iter()
+            .map(|failure| failure.input.clone())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        "ARBITRARY CONTENT\nFOR FILE ONE\n.",
+        fs::read_to_string(destination.path().join("file1.txt/out"))?
+    );
+    assert_eq!(
+        ".",
+        fs::read_to_string(destination.path().join("file3.txt/out"))?
+    );
+    Ok(())
+}
+
+/// A rate limit spaces out the starts of tasks, however many processes there are.
+#[tokio::test]
+async fn test_max_rate() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("file1.txt", b"Arbitrary content for file one\n"),
+        ("file2.txt", b"Arbitrary content for file two\n"),
+        ("file3.txt", b"Arbitrary content for file three\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.num_processes = 3;
+    config.max_rate = Some(10.0);
+    let start = Instant::now();
+    let summary = reach::run(config, ()).await?;
+
+    assert_eq!(3, summary.succeeded, "{}", summary);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    Ok(())
+}
+
+/// Outside its window, a run starts no tasks, and waits rather than finishing.
+#[tokio::test]
+async fn test_window() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = |window: &str| {
+        let mut config = new_test_config(
+            "cat",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.window = Some(window.into());
+        config
+    };
+    let error = reach::run(config("22:00"), ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+
+    // Open from two minutes from now, in local time, until two minutes ago.
+    let now = std::process::Command::new("date").arg("+%H %M").output()?;
+    let now: Vec<u32> = String::from_utf8_lossy(&now.stdout)
+        .split_whitespace()
+        .map(|part| part.parse().unwrap())
+        .collect();
+    let time = |minutes: u32| format!("{:02}:{:02}", minutes / 60 % 24, minutes % 60);
+    let now = now[0] * 60 + now[1] + 24 * 60;
+    let closed = format!("{}-{}", time(now + 2), time(now - 2));
+    let error = reach::run_until(
+        config(&closed),
+        (),
+        tokio::time::sleep(Duration::from_millis(200)),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(io::ErrorKind::Interrupted, error.kind());
+    assert!(!destination.path().join("file1.txt/out").exists());
+    Ok(())
+}
+
+/// While a run is paused, no tasks start, and once it's resumed, they all run.
+#[tokio::test]
+async fn test_pause() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n"), ("file2.txt", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    let token = reach::PauseToken::new();
+    token.pause();
+    config.pause = Some(token.clone());
+    let resume = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!destination.path().join("file1.txt/out").exists());
+        token.resume();
+    };
+    let (summary, ()) = tokio::join!(reach::run(config, ()), resume);
+    assert_eq!(2, summary?.succeeded);
+    Ok(())
+}
+
+/// With affinity, inputs with the same key go to the same worker, whichever worker is idle.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_affinity() -> io::Result<()> {
+    let names = ["1.a", "1.b", "2.a", "2.b", "3.a", "3.b"];
+    let files: Vec<(&str, &[u8])> = names.iter().map(|name| (*name, &b"x\n"[..])).collect();
+    let source = make_source_directory(&files)?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "while read -r line; do echo $$; done",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Coprocess,
+    );
+    config.framing = reach::Framing::Line;
+    config.num_processes = 4;
+    config.affinity = Some("{ext}".into());
+    let summary = reach::run(config, ()).await?;
+
+    assert!(summary.all_succeeded(), "{}", summary);
+    let worker = |name: &str| fs::read_to_string(destination.path().join(name).join("out"));
+    for ext in &["a", "b"] {
+        let first = worker(&format!("1.{}", ext))?;
+        assert_eq!(first, worker(&format!("2.{}", ext))?);
+        assert_eq!(first, worker(&format!("3.{}", ext))?);
+    }
+    Ok(())
+}
+
+/// Affinity means nothing when every task gets a new process.
+#[tokio::test]
+async fn test_affinity_needs_workers() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.affinity = Some("{dir}".into());
+    let error = reach::run(config, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// A run can pick up just the failures of the last one, using its journal.
+#[tokio::test]
+async fn test_retry_failed() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("file1.txt", b"Arbitrary content for file one\n"),
+        ("file2.txt", b"fail\n"),
+        ("file3.txt", b"Arbitrary content for file three\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = || {
+        new_test_config(
+            "grep -v fail",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        )
+    };
+    let mut first = config();
+    first.retry_failed = true;
+    let error = reach::run(first, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+
+    let summary = reach::run(config(), ()).await?;
+    assert_eq!(1, summary.failed, "{}", summary);
+    let journal = fs::read_to_string(destination.path().join(".reach/journal"))?;
+    assert_eq!(4, journal.lines().count(), "{}", journal);
+    assert!(journal.contains("grep -v fail"));
+
+    let mut retry = config();
+    retry.retry_failed = true;
+    let summary = reach::run(retry, ()).await?;
+    assert_eq!(
+        vec![source.path().join("file2.txt")],
+        summary
+            .failures
+            .iter()
+            .map(|failure| failure.input.clone())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(0, summary.succeeded, "{}", summary);
+    Ok(())
+}
+
+/// A run can re-run just the tasks whose inputs match a pattern, even ones that succeeded.
+#[tokio::test]
+async fn test_rerun_matching() -> io::Result<()> {
+    let source = make_source_directory(&[
+        ("customer-41-jan.csv", b"one\n"),
+        ("customer-42-jan.csv", b"two\n"),
+        ("customer-42-feb.csv", b"three\n"),
+    ])?;
+    let destination = tempfile::tempdir()?;
+    let config = |command: &str| {
+        new_test_config(
+            command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        )
+    };
+    let summary = reach::run(config("cat"), ()).await?;
+    assert_eq!(3, summary.succeeded, "{}", summary);
+
+    let mut rerun = config("tr a-z A-Z");
+    rerun.rerun_matching = Some("customer-42-*".into());
+    let summary = reach::run(rerun, ()).await?;
+    assert_eq!(2, summary.succeeded, "{}", summary);
+    let out = |name: &str| fs::read_to_string(destination.path().join(name).join("out"));
+    assert_eq!("one\n", out("customer-41-jan.csv")?);
+    assert_eq!("TWO\n", out("customer-42-jan.csv")?);
+    assert_eq!("THREE\n", out("customer-42-feb.csv")?);
+
+    let mut bad = config("cat");
+    bad.rerun_matching = Some("customer-[42".into());
+    let error = reach::run(bad, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// Inputs can be named on standard input, separated by NULs, rather than found in a source directory.
+#[cfg(all(unix, feature = "cli"))]
+#[test]
+fn test_from_stdin() -> io::Result<()> {
+    let source =
+        make_source_directory(&[("a.txt", b"a\n"), ("b.txt", b"bb\n"), ("c.csv", b"c\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut reach = std::process::Command::new(env!("CARGO_BIN_EXE_reach"))
+        .args([
+            "--from-stdin",
+            "-0",
+            "--exclude",
+            "*.csv",
+            "--progress",
+            "quiet",
+        ])
+        .arg("wc -c < {}")
+        .arg("-")
+        .arg(destination.path())
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+    let mut list = Vec::new();
+    for name in &["a.txt", "b.txt", "c.csv", "missing.txt"] {
+        list.extend(source.path().join(name).to_str().unwrap().as_bytes());
+        list.push(b'\0');
+    }
+    reach.stdin.take().unwrap().write_all(&list)?;
+
+    assert!(reach.wait()?.success());
+    let mut outputs = BTreeMap::new();
+    for name in list_dir(destination.path())? {
+        if name != ".reach" {
+            let out = fs::read_to_string(destination.path().join(&name).join("out"))?;
+            outputs.insert(name.to_string_lossy().into_owned(), out.trim().to_string());
+        }
+    }
+    assert_eq!(
+        vec![("a.txt", "2"), ("b.txt", "3")],
+        outputs
+            .iter()
+            .map(|(name, out)| (name.as_str(), out.as_str()))
+            .collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+/// Files from more than one source directory, and single files, are processed together,
+/// as long as their names don't clash.
+#[tokio::test]
+async fn test_more_sources() -> io::Result<()> {
+    let source = make_source_directory(&[("a.txt", b"one\n")])?;
+    let batch2 = make_source_directory(&[("b.txt", b"two\n")])?;
+    let batch3 = make_source_directory(&[("c.txt", b"three\n"), ("d.txt", b"four\n")])?;
+    let destination = tempfile::tempdir()?;
+    let config = |more_sources: Vec<PathBuf>| {
+        let mut config = new_test_config(
+            "cat",
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.more_sources = more_sources;
+        config
+    };
+    let summary = reach::run(
+        config(vec![batch2.path().into(), batch3.path().join("c.txt")]),
+        (),
+    )
+    .await?;
+    assert_eq!(3, summary.succeeded, "{}", summary);
+    let mut expected = vec!["a.txt", "b.txt", "c.txt"];
+    expected.push(".reach");
+    expected.sort_unstable();
+    assert_eq!(expected, list_dir(destination.path())?);
+    assert_eq!(
+        "three\n",
+        fs::read_to_string(destination.path().join("c.txt/out"))?
+    );
+
+    let error = reach::run(config(vec![source.path().join("a.txt")]), ())
+        .await
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// Inputs with the same name from different sources get destinations of their own when
+/// they're named some other way, and the manifest says which destination is whose.
+#[tokio::test]
+async fn test_naming() -> io::Result<()> {
+    let source = make_source_directory(&[("data.csv", b"one\n")])?;
+    let batch2 = make_source_directory(&[("data.csv", b"two\n")])?;
+    let config = |naming, destination: &Path| {
+        let mut config =
+            new_test_config("cat", source.path(), destination, reach::InputMode::Stdin);
+        config.more_sources = vec![batch2.path().into()];
+        config.naming = naming;
+        config.recreate = false;
+        config
+    };
+    for naming in &[
+        reach::Naming::Mirror,
+        reach::Naming::Hash,
+        reach::Naming::Index,
+    ] {
+        let destination = tempfile::tempdir()?;
+        let summary = reach::run(config(*naming, destination.path()), ()).await?;
+        assert_eq!(2, summary.succeeded, "{:?}: {}", naming, summary);
+        let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+            destination.path().join("manifest.json"),
+        )?)?;
+        let inputs = manifest["inputs"].as_array().unwrap();
+        assert_eq!(2, inputs.len(), "{:?}", naming);
+        for entry in inputs {
+            let input = entry["input"].as_str().unwrap();
+            let dir = destination
+                .path()
+                .join(entry["destination"].as_str().unwrap());
+            assert_eq!(
+                fs::read_to_string(input)?,
+                fs::read_to_string(dir.join("out"))?,
+                "{:?}",
+                naming
+            );
+        }
+        // Named the same way again, the tasks are already done.
+        let summary = reach::run(config(*naming, destination.path()), ()).await?;
+        assert_eq!(2, summary.skipped, "{:?}: {}", naming, summary);
+    }
+
+    let destination = tempfile::tempdir()?;
+    let error = reach::run(config(reach::Naming::Name, destination.path()), ())
+        .await
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// The manifest is written as the run starts, and a later run can process exactly the inputs
+/// it lists, even once the source directory has changed.
+#[tokio::test]
+async fn test_from_manifest() -> io::Result<()> {
+    let source = make_source_directory(&[("a.txt", b"one\n"), ("b.txt", b"two\n")])?;
+    let destination = tempfile::tempdir()?;
+    let manifest = destination.path().join("manifest.json");
+    // Each task checks that the manifest is already there.
+    let command = format!("cat && test -f '{}'", manifest.display());
+    let config = |from_manifest: Option<&Path>| {
+        let mut config = new_test_config(
+            &command,
+            source.path(),
+            destination.path(),
+            reach::InputMode::Stdin,
+        );
+        config.manifest = true;
+        config.from_manifest = from_manifest.map(Path::to_owned);
+        config
+    };
+    let summary = reach::run(config(None), ()).await?;
+    assert_eq!(2, summary.succeeded, "{}", summary);
+
+    fs::remove_file(source.path().join("b.txt"))?;
+    fs::write(source.path().join("c.txt"), b"three\n")?;
+    fs::write(source.path().join("a.txt"), b"uno\n")?;
+    let summary = reach::run(config(Some(destination.path())), ()).await?;
+    assert_eq!(1, summary.succeeded, "{}", summary);
+    assert_eq!(1, summary.failed, "{}", summary);
+    assert_eq!(
+        "uno\n",
+        fs::read_to_string(destination.path().join("a.txt/out"))?
+    );
+    assert!(!destination.path().join("c.txt").exists());
+    let listed: serde_json::Value = serde_json::from_str(&fs::read_to_string(&manifest)?)?;
+    let mut listed: Vec<_> = listed["inputs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["destination"].as_str().unwrap().to_owned())
+        .collect();
+    listed.sort();
+    assert_eq!(vec!["a.txt", "b.txt"], listed);
+
+    let mut ordered = config(Some(&manifest));
+    ordered.order = reach::Order::Name;
+    let error = reach::run(ordered, ()).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    Ok(())
+}
+
+/// In watch mode, files that turn up after the start are processed too, once they stop changing.
+#[tokio::test]
+async fn test_watch() -> io::Result<()> {
+    let source = make_source_directory(&[("file1.txt", b"one\n")])?;
+    let destination = tempfile::tempdir()?;
+    let mut config = new_test_config(
+        "cat",
+        source.path(),
+        destination.path(),
+        reach::InputMode::Stdin,
+    );
+    config.watch = Some(Duration::from_millis(50));
+    let new_file = source.path().join("file2.txt");
+    let later_out = destination.path().join("file2.txt/out");
+    let add_file = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        fs::write(&new_file, b"two\n").unwrap();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            if fs::read(&later_out).ok().as_deref() == Some(b"two\n") {
+                return;
+            }
+        }
+    };
+    let error = reach::run_until(config, (), add_file).await.unwrap_err();
+
+    assert_eq!(io::ErrorKind::Interrupted, error.kind());
+    assert_eq!(
+        "one\n",
+        fs::read_to_string(destination.path().join("file1.txt/out"))?
+    );
+    assert_eq!("two\n", fs::read_to_string(later_out)?);
     Ok(())
 }